@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+use crate::bus::{BusHandle, ControllerRequest};
+use crate::message;
+use crate::message::COMMAND_CHANNEL;
+use crate::outbox::Outbox;
+
+// Executor side of the bus: requests from every source arrive here, statuses go back over the application port
+pub struct ApplicationLink {
+    pub port: Box<dyn SerialPort>,
+    requests: Receiver<ControllerRequest>,
+    pending: VecDeque<ControllerRequest>,
+    reply: Option<Sender<String>>,
+    // Shared with the application port reader, which refuses frames once it reaches the capacity
+    queued_lines: Arc<AtomicUsize>,
+    // Shared with the reader, which writes its NACKs to the same port
+    writes: Arc<Mutex<()>>,
+    outbox: Outbox,
+}
+
+impl ApplicationLink {
+    pub fn new(port: Box<dyn SerialPort>, requests: Receiver<ControllerRequest>, bus: &BusHandle) -> ApplicationLink {
+        ApplicationLink {
+            port,
+            requests,
+            pending: VecDeque::new(),
+            reply: None,
+            queued_lines: bus.queued_lines(),
+            writes: bus.writes(),
+            outbox: Outbox::load(),
+        }
+    }
+
+    pub fn send(&mut self, channel: i8, data: &str) {
+        // Commas delimit frame fields
+        let data = data.replace(',', ";");
+        let _writing = self.writes.lock().unwrap_or_else(|e| e.into_inner());
+        self.outbox.send(&mut self.port, message::format_message(channel, &data));
+    }
+
+    // Statuses go to whoever submitted the request being handled
+    pub fn send_status(&mut self, data: &str) {
+        match &self.reply {
+            Some(reply) => { reply.send(data.to_string()).ok(); }
+            None => self.send(COMMAND_CHANNEL, data),
+        }
+    }
+
+    pub fn set_reply(&mut self, reply: Option<Sender<String>>) {
+        self.reply = reply;
+    }
+
+    fn taken(&self, request: ControllerRequest) -> ControllerRequest {
+        if let ControllerRequest::Line(_) = request {
+            self.queued_lines.fetch_sub(1, Ordering::SeqCst);
+        }
+        request
+    }
+
+    fn poll(&mut self) {
+        self.pending.extend(self.requests.try_iter());
+    }
+
+    // Picks the first control message out of the queue, leaving other requests for the executor loop
+    pub fn take_control(&mut self) -> Option<String> {
+        self.poll();
+        let index = self.pending.iter().position(ControllerRequest::is_control)?;
+        let request = self.pending.remove(index)?;
+        match self.taken(request) {
+            ControllerRequest::Line(line) => message::parse_to_message(line).map(|m| m.data),
+            ControllerRequest::Message { data, .. } => Some(data),
+            ControllerRequest::FramingLost => None,
+        }
+    }
+
+    pub fn drain_pending(&mut self) -> Vec<ControllerRequest> {
+        self.poll();
+        let requests: Vec<ControllerRequest> = self.pending.drain(..).collect();
+        requests.into_iter().map(|request| self.taken(request)).collect()
+    }
+
+    pub fn has_pending(&mut self) -> bool {
+        self.poll();
+        !self.pending.is_empty()
+    }
+
+    pub fn pending_count(&mut self) -> usize {
+        self.poll();
+        self.pending.len()
+    }
+
+    pub fn next_request_timeout(&mut self, timeout: Duration) -> Option<ControllerRequest> {
+        {
+            let _writing = self.writes.lock().unwrap_or_else(|e| e.into_inner());
+            self.outbox.flush(&mut self.port);
+        }
+        if let Some(request) = self.pending.pop_front() {
+            return Some(self.taken(request));
+        }
+        match self.requests.recv_timeout(timeout) {
+            Ok(request) => Some(self.taken(request)),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => panic!("All request sources are gone"),
+        }
+    }
+}
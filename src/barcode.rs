@@ -0,0 +1,52 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::deck::Coordinates;
+use crate::devices::DeviceKind;
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::{motion, unwrap_option, Controller};
+
+// The manifest names the reagent barcode expected in each tube, e.g. `META_reagent_5=CD3-0042`
+const MANIFEST_KEY_PREFIX: &str = "reagent_";
+
+fn expected_barcode(controller: &Controller, tube: &str) -> Option<String> {
+    controller.metadata.fields.get(&format!("{MANIFEST_KEY_PREFIX}{tube}")).cloned()
+}
+
+// SCAN_<tube>
+pub fn scan_tube(controller: &mut Controller, command: &str) -> ControlFlow<String> {
+    let settings = &CONFIG.barcode_scan;
+    let tube = unwrap_option!(command.strip_prefix("SCAN_").filter(|t| !t.is_empty()), format!("Cannot deduce tube from {command}"));
+    let expected = unwrap_option!(expected_barcode(controller, tube),
+        format!("{command}: the protocol manifest has no META_{MANIFEST_KEY_PREFIX}{tube} entry"));
+    let tube_position = match controller.calibration.tube_position(tube) {
+        Ok(position) => position,
+        Err(e) => return ControlFlow::Break(e),
+    };
+    let offset = settings.scanner_offset;
+    let scan_position = Coordinates {
+        x: tube_position.x - offset.x,
+        y: tube_position.y - offset.y,
+        z: motion::SAFE_Z - offset.z,
+    };
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..controller.router.position })?;
+    controller.router_move(scan_position)?;
+    let scanner = controller.devices.require(DeviceKind::Barcode, command)?;
+    flush_port(scanner);
+    if !settings.trigger.is_empty() && serial_write(scanner, &settings.trigger).is_err() {
+        return ControlFlow::Break("Barcode scanner - failed to send trigger".to_string());
+    }
+    // Scanners end a read with CR, some with CRLF
+    let scanned = serial_readline_timeout(scanner, "\r", Duration::from_millis(settings.timeout_ms))
+        .map(|code| code.trim().to_string())
+        .filter(|code| !code.is_empty());
+    match scanned {
+        Some(code) if code == expected => {
+            log::info!("Tube {} barcode {} matches the manifest", tube, code);
+            ControlFlow::Continue(())
+        }
+        Some(code) => ControlFlow::Break(format!("BARCODE MISMATCH tube={tube} expected={expected} scanned={code}")),
+        None => ControlFlow::Break(format!("BARCODE NO READ tube={tube}")),
+    }
+}
@@ -0,0 +1,237 @@
+use std::io::{BufRead, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serialport::{ClearBuffer, SerialPort};
+
+use crate::config;
+use crate::config::CONFIG;
+use crate::escape_chars;
+use crate::message::{COMMAND_CHANNEL, CONTROL_CHANNEL};
+use crate::port_operations::serial_write;
+use crate::{message, metadata};
+
+// Everything that wants the controller to do something goes through the bus, so only the
+// executor ever writes to the pump and router ports
+pub enum ControllerRequest {
+    // Framed line as received on the application port
+    Line(String),
+    // Unframed message from any other source; status lines produced while handling it go to `reply`
+    // instead of the application port
+    Message { channel: i8, data: String, reply: Option<Sender<String>> },
+    // The application port lost framing and was flushed; the sender has to resend
+    FramingLost,
+}
+
+impl ControllerRequest {
+    pub fn is_control(&self) -> bool {
+        match self {
+            ControllerRequest::Line(line) => line.starts_with(&format!("{CONTROL_CHANNEL},")),
+            ControllerRequest::Message { channel, .. } => *channel == CONTROL_CHANNEL,
+            ControllerRequest::FramingLost => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BusHandle {
+    sender: Sender<ControllerRequest>,
+    // Application lines submitted and not yet taken by the executor
+    queued_lines: Arc<AtomicUsize>,
+    // Held for every frame written to the application port, by the reader's NACKs and the
+    // executor's statuses, so the two never interleave on the wire
+    writes: Arc<Mutex<()>>,
+}
+
+impl BusHandle {
+    pub fn submit(&self, request: ControllerRequest) -> Result<(), String> {
+        if let ControllerRequest::Line(_) = request {
+            self.queued_lines.fetch_add(1, Ordering::SeqCst);
+        }
+        self.sender.send(request).map_err(|_| "Controller executor is not running".to_string())
+    }
+
+    pub fn queued_lines(&self) -> Arc<AtomicUsize> {
+        self.queued_lines.clone()
+    }
+
+    pub fn writes(&self) -> Arc<Mutex<()>> {
+        self.writes.clone()
+    }
+
+    fn queue_full(&self) -> bool {
+        self.queued_lines.load(Ordering::SeqCst) >= CONFIG.application_queue_capacity.max(1)
+    }
+}
+
+pub fn new_bus() -> (BusHandle, Receiver<ControllerRequest>) {
+    let (sender, receiver) = channel();
+    (BusHandle { sender, queued_lines: Arc::default(), writes: Arc::default() }, receiver)
+}
+
+// Keeps command frames from the application port to [message-limits] frames_per_sec, allowing
+// bursts of up to `burst` frames
+struct RateLimit {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimit {
+    fn new() -> RateLimit {
+        RateLimit { tokens: CONFIG.message_limits.burst.max(1) as f64, refilled: Instant::now() }
+    }
+
+    // None when a frame may pass now, else how long until one may
+    fn take(&mut self) -> Option<Duration> {
+        let limits = &CONFIG.message_limits;
+        if limits.frames_per_sec <= 0.0 {
+            return None;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.frames_per_sec).min(limits.burst.max(1) as f64);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / limits.frames_per_sec))
+    }
+}
+
+fn send_nack(port: &mut Box<dyn SerialPort>, bus: &BusHandle, nack: &str) {
+    let _writing = bus.writes.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = serial_write(port, &message::format_message(COMMAND_CHANNEL, nack)) {
+        log::error!("Failed to send [{}] to application: {}", nack, e);
+    }
+}
+
+fn refuse_oversized(port: &mut Box<dyn SerialPort>, bus: &BusHandle, bytes: usize) {
+    let limit = CONFIG.message_limits.max_frame_bytes;
+    log::warn!("Discarding {} byte application line, longer than {} bytes", bytes, limit);
+    send_nack(port, bus, &format!("NACK reason=frame_too_long bytes={bytes} limit={limit}"));
+}
+
+// Refuses a frame with too many commands, one the executor has no room for, or one sent faster
+// than the rate limit; the sender retries it later. Control frames are never refused so ABORT and
+// PAUSE get through a full queue or a flood.
+fn refuse(port: &mut Box<dyn SerialPort>, bus: &BusHandle, rate: &mut RateLimit, request: &ControllerRequest) -> bool {
+    if request.is_control() {
+        return false;
+    }
+    let ControllerRequest::Line(frame) = request else {
+        return false;
+    };
+    let message = message::parse_to_message(frame.clone());
+    let crc = message.as_ref().map(|m| m.crc).unwrap_or_default();
+    let commands = message.map_or(0, |m| m.data.split(' ').count());
+    let limit = CONFIG.message_limits.max_commands;
+    let nack = if commands > limit {
+        log::warn!("Refusing frame {:x} with {} commands, more than {}", crc, commands, limit);
+        format!("NACK reason=too_many_commands commands={commands} limit={limit} crc={crc:x}")
+    } else if bus.queue_full() {
+        log::warn!("Application queue full ({} lines), refusing frame {:x}", CONFIG.application_queue_capacity, crc);
+        format!("NACK reason=queue_full crc={crc:x}")
+    } else if let Some(wait) = rate.take() {
+        log::warn!("Application frames above {}/s, refusing frame {:x}", CONFIG.message_limits.frames_per_sec, crc);
+        format!("NACK reason=rate_limited retry_after_ms={} crc={crc:x}", wait.as_millis().max(1))
+    } else {
+        return false;
+    };
+    send_nack(port, bus, &nack);
+    true
+}
+
+// Reads the application port on its own thread so frames keep being taken off the OS buffer
+// while the executor is busy with a slow hardware step
+pub fn spawn_serial_source(mut port: Box<dyn SerialPort>, bus: BusHandle) {
+    config::spawn(move || {
+        let mut buffer = String::new();
+        let mut chunk = [0; 256];
+        let mut failures = 0;
+        let mut rate = RateLimit::new();
+        // Inside a line that was already refused as too long, dropping input up to its newline
+        let mut discarding = false;
+        loop {
+            match port.read(&mut chunk) {
+                Ok(n) => buffer.extend(chunk[..n].iter().map(|b| char::from(*b))),
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    log::error!("Failed to read from application port: {}", e);
+                    return;
+                }
+            }
+            while let Some(end) = buffer.find('\n') {
+                let line: String = buffer.drain(..=end).collect();
+                if std::mem::take(&mut discarding) {
+                    continue;
+                }
+                if line.len() > CONFIG.message_limits.max_frame_bytes {
+                    refuse_oversized(&mut port, &bus, line.len());
+                    continue;
+                }
+                log::trace!("Got [{}] from application port", metadata::redact(&escape_chars(&line)));
+                let request = match message::find_frame(line.trim_end_matches('\n')) {
+                    Some(frame) => {
+                        failures = 0;
+                        ControllerRequest::Line(frame)
+                    }
+                    None => {
+                        failures += 1;
+                        log::warn!("Discarding unframed application data ({} in a row)", failures);
+                        if failures < CONFIG.framing_failure_threshold.max(1) {
+                            continue;
+                        }
+                        log::error!("Application port out of sync, flushing and requesting resend");
+                        port.clear(ClearBuffer::Input).ok();
+                        buffer.clear();
+                        failures = 0;
+                        ControllerRequest::FramingLost
+                    }
+                };
+                if refuse(&mut port, &bus, &mut rate, &request) {
+                    continue;
+                }
+                if bus.submit(request).is_err() {
+                    return;
+                }
+            }
+            if buffer.len() > CONFIG.message_limits.max_frame_bytes {
+                if !discarding {
+                    refuse_oversized(&mut port, &bus, buffer.len());
+                    discarding = true;
+                }
+                buffer.clear();
+            }
+        }
+    });
+}
+
+// Lets an operator type commands on stdin while the controller runs; replies are printed back
+pub fn spawn_console_source(bus: BusHandle) {
+    let (reply, replies) = channel::<String>();
+    config::spawn(move || replies.iter().for_each(|line| println!("{line}")));
+    config::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if submit_line(&bus, line.trim(), reply.clone()).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+// Operator input: control words go to the control channel, everything else is a command message
+pub fn submit_line(bus: &BusHandle, line: &str, reply: Sender<String>) -> Result<(), String> {
+    let channel = if is_control_word(line) { CONTROL_CHANNEL } else { COMMAND_CHANNEL };
+    bus.submit(ControllerRequest::Message { channel, data: line.to_string(), reply: Some(reply) })
+}
+
+fn is_control_word(data: &str) -> bool {
+    matches!(data, "ABORT" | "SKIP" | "PAUSE" | "RESUME" | "MAINTENANCE_ON" | "MAINTENANCE_OFF" | "CLEARFAULT" | "HOME" | "UNLOCKPUMPS")
+        || data.starts_with("CANCEL_") || data.starts_with("MOVE_") || data.starts_with("POLLLOG_") || data.starts_with("LOGLEVEL_")
+}
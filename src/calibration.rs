@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{PidGains, CONFIG};
+use crate::deck;
+use crate::deck::Coordinates;
+use crate::units::Millimeters;
+
+// Key of the slide holder's slot surface in z_offsets
+pub const SLOT: &str = "slot";
+
+// Values measured on this instrument, kept in calibration_path across restarts
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Calibration {
+    // Heater gains by thermal zone, as found by TUNEPID_
+    pub pid: BTreeMap<String, PidGains>,
+    // Measured minus configured height, by probed holder: "slot", a tube of tube-holder-coordinates
+    // or a rack, as found by PROBE_
+    pub z_offsets: BTreeMap<String, Millimeters>,
+}
+
+impl Calibration {
+    pub fn load() -> Calibration {
+        let Ok(text) = std::fs::read_to_string(&CONFIG.calibration_path) else {
+            return Calibration::default();
+        };
+        toml::from_str(&text)
+            .map_err(|e| log::error!("Ignoring unreadable calibration {}: {}", CONFIG.calibration_path, e))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        let result = toml::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(&CONFIG.calibration_path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::error!("Failed to write calibration {}: {}", CONFIG.calibration_path, e);
+        }
+    }
+
+    // The configured position of `tube` moved by the offset of its holder. A holder that was never
+    // probed stays at its configured height; the slot surface has nothing to do with it.
+    pub fn tube_position(&self, tube: &str) -> Result<Coordinates, String> {
+        let position = deck::tube_position(tube)?;
+        let offset = self.z_offsets.get(holder(tube)).copied().unwrap_or_default();
+        Ok(Coordinates { z: position.z + offset, ..position })
+    }
+
+    // Flow cell wells sit on the slot and move with its probed surface
+    pub fn well_position(&self, well: &str) -> Result<Coordinates, String> {
+        let position = deck::well_position(well)?;
+        let offset = self.z_offsets.get(SLOT).copied().unwrap_or_default();
+        Ok(Coordinates { z: position.z + offset, ..position })
+    }
+
+    pub fn describe(&self) -> String {
+        let offsets: Vec<String> = self.z_offsets.iter().map(|(holder, offset)| format!("{holder}={offset}mm")).collect();
+        let zones: Vec<&str> = self.pid.keys().map(String::as_str).collect();
+        format!("CALIBRATION z_offsets=[{}] pid=[{}]", offsets.join(", "), zones.join(", "))
+    }
+}
+
+// Tubes of a rack share its offset; rack:row:col addresses name the rack first
+pub fn holder(tube: &str) -> &str {
+    match tube.split(':').collect::<Vec<&str>>()[..] {
+        [rack, _, _] => rack,
+        _ => tube,
+    }
+}
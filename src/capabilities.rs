@@ -0,0 +1,99 @@
+use std::fmt::{Display, Formatter};
+
+use crate::config::{ZoneDriver, CONFIG};
+use crate::devices::{DeviceKind, Devices};
+use crate::safe_mode::SafeMode;
+use crate::peripherals::{self, Peripherals};
+use crate::{deck, latency, metadata, thermal};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    Pump,
+    Router,
+    Device(DeviceKind),
+    // By prefix
+    Peripheral(&'static str),
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Pump => write!(f, "pump"),
+            Capability::Router => write!(f, "router"),
+            Capability::Device(kind) => write!(f, "{kind}"),
+            Capability::Peripheral(prefix) => write!(f, "{}", peripherals::configured(prefix).map_or(*prefix, |p| p.name.as_str())),
+        }
+    }
+}
+
+// What each command type needs to run; unknown commands need nothing here and fail when executed
+pub fn requirements(command: &str) -> Vec<Capability> {
+    if metadata::is_metadata(command) || latency::parse_budget(command).is_some() || command == latency::SECTION_END {
+        return Vec::new();
+    }
+    let parts: Vec<&str> = command.split('_').collect();
+    match parts[..] {
+        // Recovering the liquid later takes the needle
+        ["LA", _, _, _, _] => vec![Capability::Pump, Capability::Router],
+        // Wells are dispensed into through the needle
+        ["LA", _, destination, ..] if deck::well(destination).is_some() => vec![Capability::Pump, Capability::Router],
+        ["LA", from, ..] if deck::external_channel(from).is_some() => vec![Capability::Pump],
+        ["LA", ..] => vec![Capability::Pump, Capability::Router],
+        ["END"] => vec![Capability::Pump, Capability::Router],
+        ["MIXTUBE", ..] => vec![Capability::Pump, Capability::Router],
+        ["TIPCHANGE"] => vec![Capability::Router],
+        ["PROBE", _] => vec![Capability::Router],
+        ["SCAN", ..] => vec![Capability::Router, Capability::Device(DeviceKind::Barcode)],
+        ["SHAKE", ..] => vec![Capability::Device(DeviceKind::Shaker)],
+        ["TC" | "TUNEPID", zone, _] if thermal::zone(zone).is_some_and(|z| z.driver == ZoneDriver::Router) => vec![Capability::Router],
+        ["TC" | "TUNEPID", zone, _] if thermal::zone(zone).is_some() => vec![Capability::Device(DeviceKind::Thermal)],
+        ["TC", ..] | ["BTC", ..] if CONFIG.devices.thermal.is_none() => vec![Capability::Router],
+        ["TC", ..] | ["BTC", ..] => vec![Capability::Device(DeviceKind::Thermal)],
+        [prefix, ..] => peripherals::configured(prefix).map_or(Vec::new(), |p| vec![Capability::Peripheral(&p.prefix)]),
+        _ => Vec::new(),
+    }
+}
+
+fn available(capability: Capability, devices: &mut Devices, peripherals: &Peripherals, halted: Halted, safe_mode: &SafeMode) -> bool {
+    match capability {
+        // No liquid is handled in safe mode, whatever is missing; a halted router is back after HOME
+        // and locked pumps after UNLOCKPUMPS
+        Capability::Pump => !halted.pumps && !safe_mode.active(),
+        Capability::Router => !halted.router && !safe_mode.lacks("router"),
+        Capability::Device(kind) => devices.get(kind).is_some(),
+        Capability::Peripheral(prefix) => peripherals.available(prefix),
+    }
+}
+
+// Checks a whole batch before anything moves, listing every step that can't run on this instrument
+// What is held back until the operator intervenes
+#[derive(Clone, Copy)]
+pub struct Halted {
+    pub router: bool,
+    pub pumps: bool,
+}
+
+pub fn check_batch(commands: &[&str], devices: &mut Devices, peripherals: &Peripherals, halted: Halted, safe_mode: &SafeMode) -> Result<(), String> {
+    let mismatches: Vec<String> = commands.iter()
+        .filter_map(|command| {
+            let missing: Vec<String> = requirements(command).into_iter()
+                .filter(|c| !available(*c, devices, peripherals, halted, safe_mode))
+                .map(|c| c.to_string())
+                .collect();
+            (!missing.is_empty()).then(|| format!("{command} needs {}", missing.join(" and ")))
+        })
+        .collect();
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    let hint = if safe_mode.active() {
+        format!(" (safe mode, started without {})", safe_mode.subsystems().join(" and "))
+    } else if halted.router {
+        " (the router is halted until HOME)".to_string()
+    } else if halted.pumps {
+        " (the pumps are locked until UNLOCKPUMPS)".to_string()
+    } else {
+        String::new()
+    };
+    Err(format!("capability mismatch: {}{hint}", mismatches.join("; ")))
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::pump::PumpCommand;
+use crate::units::Microliters;
+use crate::port_operations::flush_port;
+use crate::{diagnostics, history, metadata, open_port, pump, template};
+
+const PUMP_USAGE: &str = "usage: pump <aspirate|dispense> --channel <n> --ul <volume> [--pump <address>]\n       pump <home|status> [--pump <address>]";
+const RUN_USAGE: &str = "usage: run <recipe.toml> [--param <name>=<value>]... [--id <run id>] [--print]\n           [--operator <name>] [--sample <id>]... [--meta <key>=<value>]...";
+const PUMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Returns None when the arguments don't name a subcommand and the controller should run normally
+pub fn run_subcommand(args: &[String]) -> Option<Result<(), String>> {
+    match args.first().map(String::as_str) {
+        Some("router-selftest") => Some(router_selftest(&args[1..])),
+        Some("pump") => Some(pump_subcommand(&args[1..])),
+        Some("history") => Some(history_subcommand(&args[1..])),
+        Some("stats") => Some(stats_subcommand(&args[1..])),
+        Some("run") => Some(run_recipe(&args[1..])),
+        _ => None,
+    }
+}
+
+// `--simulate [--time-scale <factor>]` runs against in-process devices with waits sped up by factor
+pub fn simulation_scale(args: &[String]) -> Result<Option<f64>, String> {
+    if !args.iter().any(|a| a == "--simulate") {
+        return Ok(None);
+    }
+    match flag(args, "--time-scale") {
+        Some(v) => v.parse::<f64>()
+            .ok()
+            .filter(|scale| *scale > 0.0)
+            .map(Some)
+            .ok_or(format!("--time-scale expects a positive number, got {v}")),
+        None => Ok(Some(1.0)),
+    }
+}
+
+// `--faults <scenario.toml>` makes the simulated devices misbehave as the scenario says
+pub fn fault_scenario(args: &[String]) -> Option<&str> {
+    flag(args, "--faults")
+}
+
+// `--instance <name>` picks the controller instance for subcommands, or runs only that instance
+pub fn take_instance(args: Vec<String>) -> (Option<String>, Vec<String>) {
+    let Some(i) = args.iter().position(|a| a == "--instance") else {
+        return (None, args);
+    };
+    let mut args = args;
+    let name = (i + 1 < args.len()).then(|| args.remove(i + 1));
+    args.remove(i);
+    (name, args)
+}
+
+fn flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+fn number_flag(args: &[String], name: &str) -> Result<Option<u64>, String> {
+    flag(args, name)
+        .map(|v| v.parse::<u64>().map_err(|_| format!("{name} expects a number, got {v}")))
+        .transpose()
+}
+
+fn required_number_flag(args: &[String], name: &str) -> Result<u64, String> {
+    number_flag(args, name)?.ok_or(format!("missing {name}\n{PUMP_USAGE}"))
+}
+
+fn router_selftest(args: &[String]) -> Result<(), String> {
+    let seconds = match args.first() {
+        Some(s) => s.parse::<u64>().map_err(|_| "Self-test duration must be a number of seconds".to_string())?,
+        None => CONFIG.router_selftest.duration_secs,
+    };
+    let mut router_port = open_port(&CONFIG.router_port_path, CONFIG.router_baud_rate);
+    std::thread::sleep(Duration::from_secs(5)); // router resets when the port is opened
+    let report = diagnostics::router_selftest(&mut router_port, Duration::from_secs(seconds));
+    log::info!("Router self-test: {}", report);
+    Ok(())
+}
+
+fn pump_subcommand(args: &[String]) -> Result<(), String> {
+    let verb = args.first().ok_or(PUMP_USAGE.to_string())?;
+    let address = number_flag(args, "--pump")?.unwrap_or(1);
+    if address == 0 || address > 9 {
+        return Err(format!("pump address must be between 1 and 9, got {address}"));
+    }
+    let address_char = char::from_digit(address as u32, 10).unwrap();
+    let command = match verb.as_str() {
+        "aspirate" => {
+            let units = Microliters(required_number_flag(args, "--ul")?).to_pump_units()?;
+            PumpCommand::new(address as u8).valve_in(required_number_flag(args, "--channel")? as u8).pick_up(units)
+        }
+        "dispense" => {
+            let units = Microliters(required_number_flag(args, "--ul")?).to_pump_units()?;
+            PumpCommand::new(address as u8).valve_out(required_number_flag(args, "--channel")? as u8).dispense(units)
+        }
+        "home" => PumpCommand::new(address as u8).initialize(),
+        "status" => {
+            let mut port = open_port(&CONFIG.pump_port_path, CONFIG.pump_baud_rate);
+            print_pump_status(&mut port, address_char);
+            return Ok(());
+        }
+        other => return Err(format!("unknown pump verb {other}\n{PUMP_USAGE}")),
+    };
+    let mut port = open_port(&CONFIG.pump_port_path, CONFIG.pump_baud_rate);
+    flush_port(&mut port);
+    println!("Sending {command}");
+    pump::write_command(&mut port, &command).map_err(|e| format!("failed to send {command}: {e}"))?;
+    match pump::wait_ready(&mut port, address_char, PUMP_TIMEOUT) {
+        Some(status) if status.ready => println!("Done: {status}"),
+        Some(status) => return Err(format!("pump still busy after {PUMP_TIMEOUT:?}: {status}")),
+        None => return Err("no reply from pump".to_string()),
+    }
+    print_pump_status(&mut port, address_char);
+    Ok(())
+}
+
+// history [--tenant <id>] [--meta <key>=<value>] [--csv]
+fn history_subcommand(args: &[String]) -> Result<(), String> {
+    let tenant = flag(args, "--tenant");
+    let mut runs = history::for_tenant(history::load()?, tenant);
+    if let Some(assignment) = flag(args, "--meta") {
+        let (key, value) = assignment.split_once('=').ok_or(format!("--meta expects key=value, got {assignment}"))?;
+        runs = history::with_metadata(runs, key, value);
+    }
+    if args.iter().any(|a| a == "--csv") {
+        print!("{}", history::to_csv(&runs));
+    } else {
+        runs.iter().for_each(|run| println!("{run}"));
+    }
+    Ok(())
+}
+
+// stats [--tenant <id>]
+fn stats_subcommand(args: &[String]) -> Result<(), String> {
+    let tenant = flag(args, "--tenant");
+    let runs = history::for_tenant(history::load()?, tenant);
+    println!("{}", history::RunStats::of(&runs, tenant));
+    Ok(())
+}
+
+// Renders a recipe and queues it on the running controller through the console socket
+fn run_recipe(args: &[String]) -> Result<(), String> {
+    let path = args.first().filter(|a| !a.starts_with("--")).ok_or(RUN_USAGE.to_string())?;
+    let mut parameters = HashMap::new();
+    for assignment in flags(args, "--param")? {
+        let (name, value) = assignment.split_once('=').ok_or(format!("--param expects name=value, got {assignment}"))?;
+        parameters.insert(name.trim().to_string(), value.trim().to_string());
+    }
+    let mut commands = template::load(path)?.render(&parameters)?;
+    for (key, value) in run_metadata(args)? {
+        commands += &format!(" {}", metadata::token(&key, &value));
+    }
+    if args.iter().any(|a| a == "--print") {
+        println!("{commands}");
+        return Ok(());
+    }
+    let id = match flag(args, "--id") {
+        Some(id) => id.to_string(),
+        None => Path::new(path).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+    };
+    submit_run(&id, &commands)
+}
+
+// Every value of a repeatable flag
+fn flags<'a>(args: &'a [String], name: &str) -> Result<Vec<&'a str>, String> {
+    args.iter()
+        .enumerate()
+        .filter(|(_, arg)| *arg == name)
+        .map(|(i, _)| args.get(i + 1).map(String::as_str).ok_or(format!("{name} expects a value\n{RUN_USAGE}")))
+        .collect()
+}
+
+// --operator, --sample and --meta as META_ tokens; several samples are sent as one comma list
+fn run_metadata(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut fields = Vec::new();
+    if let Some(operator) = flag(args, "--operator") {
+        fields.push(("operator".to_string(), operator.to_string()));
+    }
+    let samples = flags(args, "--sample")?;
+    if !samples.is_empty() {
+        fields.push(("samples".to_string(), samples.join(",")));
+    }
+    for assignment in flags(args, "--meta")? {
+        let (key, value) = assignment.split_once('=').ok_or(format!("--meta expects key=value, got {assignment}"))?;
+        fields.push((key.to_string(), value.to_string()));
+    }
+    match fields.iter().find(|(key, value)| key.is_empty() || value.is_empty() || format!("{key}{value}").contains(char::is_whitespace)) {
+        Some((key, value)) => Err(format!("Metadata {key}={value} is empty or contains whitespace")),
+        None => Ok(fields),
+    }
+}
+
+#[cfg(unix)]
+fn submit_run(id: &str, commands: &str) -> Result<(), String> {
+    let socket = CONFIG.console_socket_path.as_ref().ok_or("console_socket_path is not configured".to_string())?;
+    let mut stream = UnixStream::connect(socket).map_err(|e| format!("Failed to connect to controller at {socket}: {e}"))?;
+    writeln!(stream, "RUN_{id} {commands}").map_err(|e| format!("Failed to submit run: {e}"))?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).map_err(|e| format!("No reply from controller: {e}"))?;
+    let reply = reply.trim_end();
+    if !reply.starts_with("RUN ") {
+        return Err(reply.to_string());
+    }
+    println!("{reply}");
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn submit_run(_id: &str, _commands: &str) -> Result<(), String> {
+    Err("submitting needs the console socket, which is only available on Unix; use the HTTP API instead".to_string())
+}
+
+fn print_pump_status(port: &mut Box<dyn serialport::SerialPort>, address: char) {
+    let unknown = || "unknown".to_string();
+    let status = pump::query_status(port, address).map(|s| s.to_string()).unwrap_or_else(|| "no reply".to_string());
+    println!("Pump {address}");
+    println!("  status:   {status}");
+    println!("  firmware: {}", pump::query_firmware(port, address).unwrap_or_else(unknown));
+    println!("  plunger:  {}", pump::query_position(port, address, "?").unwrap_or_else(unknown));
+    println!("  valve:    {}", pump::query_position(port, address, pump::VALVE_REGISTER).unwrap_or_else(unknown));
+}
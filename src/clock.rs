@@ -0,0 +1,42 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Time source for waits and incubation timers; simulation swaps in a scaled one
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// Runs `scale` times faster than real time: a 1 h wait with scale 100 takes 36 s
+pub struct ScaledClock {
+    origin: Instant,
+    scale: f64,
+}
+
+impl ScaledClock {
+    pub fn new(scale: f64) -> ScaledClock {
+        ScaledClock { origin: Instant::now(), scale: scale.max(f64::EPSILON) }
+    }
+}
+
+impl Clock for ScaledClock {
+    fn now(&self) -> Instant {
+        self.origin + self.origin.elapsed().mul_f64(self.scale)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration.div_f64(self.scale));
+    }
+}
@@ -0,0 +1,94 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::firmware::Firmware;
+use crate::poll::{try_poll_until, Poll};
+use crate::pump::{PumpCommand, VALVE_REGISTER};
+use crate::pump_bus::Pump;
+use crate::units::PumpUnits;
+use crate::{pump_lock, Controller};
+
+enum Stroke {
+    Completed,
+    Clogged(u64),
+}
+
+pub fn is_monitored(firmware: &Firmware, command: &PumpCommand) -> bool {
+    CONFIG.clog_detection.enabled && firmware.load_register && command.stroke_units() >= CONFIG.clog_detection.min_stroke_units
+}
+
+// A clogged needle or line shows up as a plunger load far above that of a free-flowing stroke
+pub fn execute_monitored(controller: &mut Controller, command: &PumpCommand) -> ControlFlow<String> {
+    let settings = &CONFIG.clog_detection;
+    let pump = controller.pumps.pump(command.address());
+    let mut attempts = 0;
+    loop {
+        let load = match watch_stroke(controller, &pump, command)? {
+            Stroke::Completed => return ControlFlow::Continue(()),
+            Stroke::Clogged(load) => load,
+        };
+        let channel = if controller.firmware.valve_query { pump.query_position(VALVE_REGISTER) } else { None }
+            .unwrap_or_default();
+        log::error!("Pump {} clogged on channel {} (load {}), stopping stroke", command.address(), channel, load);
+        if let Err(e) = pump.terminate() {
+            return ControlFlow::Break(e);
+        }
+        if attempts == settings.unclog_attempts {
+            return ControlFlow::Break(format!("CLOG pump={} channel={} load={}", command.address(), channel, load));
+        }
+        attempts += 1;
+        // What was dispensed before the clog would be delivered again by repeating the command, so
+        // the line is only cleared
+        if command.dispenses() {
+            log::info!("Clearing pump {} without repeating the stroke", command.address());
+            unclog(controller, &pump, command.address(), channel.parse().ok())?;
+            return ControlFlow::Break(format!("CLOG pump={} channel={} load={} cleared, not repeated", command.address(), channel, load));
+        }
+        log::info!("Unclog attempt {} of {} on pump {}", attempts, settings.unclog_attempts, command.address());
+        controller.report.retry(format!("pump {} clogged on channel {} (load {}), unclog attempt {} of {}",
+            command.address(), channel, load, attempts, settings.unclog_attempts));
+        unclog(controller, &pump, command.address(), channel.parse().ok())?;
+    }
+}
+
+fn watch_stroke(controller: &mut Controller, pump: &Pump, command: &PumpCommand) -> ControlFlow<String, Stroke> {
+    let settings = &CONFIG.clog_detection;
+    pump_lock::send(controller, pump, command)?;
+    let poll = Poll::every(Duration::from_millis(settings.poll_interval_ms));
+    controller.clock.sleep(Duration::from_millis(settings.poll_interval_ms));
+    let stroke = try_poll_until(controller, poll, |controller| controller.clock.as_ref(), |controller| {
+        match pump.is_idle() {
+            Ok(true) => {
+                pump_lock::finished(controller, pump);
+                return ControlFlow::Continue(Some(Stroke::Completed));
+            }
+            Ok(false) => {}
+            Err(e) => return ControlFlow::Break(e),
+        }
+        pump_lock::check(controller, pump)?;
+        if let ControlFlow::Break(reason) = controller.poll_controls() {
+            if let Err(e) = pump.terminate() {
+                log::error!("{}", e);
+            }
+            return ControlFlow::Break(reason);
+        }
+        // Pumps without a load register never report a clog
+        let load = pump.query_position(&settings.load_query).and_then(|l| l.parse::<u64>().ok());
+        ControlFlow::Continue(load.filter(|l| *l > settings.max_load).map(Stroke::Clogged))
+    })?;
+    ControlFlow::Continue(stroke.expect("polled without a timeout"))
+}
+
+// Pushes a little liquid back out through the clogged channel, then empties the syringe to the purge port
+fn unclog(controller: &mut Controller, pump: &Pump, address: u8, channel: Option<u8>) -> ControlFlow<String> {
+    let settings = &CONFIG.clog_detection;
+    let mut routine = PumpCommand::new(address);
+    match channel {
+        Some(channel) => routine = routine.valve_out(channel).dispense(settings.reverse_units),
+        None => log::warn!("Pump {} valve position unknown, skipping reverse stroke", address),
+    }
+    let routine = routine.valve_out(settings.purge_port).move_to(PumpUnits::ZERO);
+    pump_lock::send(controller, pump, &routine)?;
+    controller.await_pump(pump)
+}
@@ -0,0 +1,137 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+// Boot numbers handed out in this process; instances started within the same millisecond still differ
+static LAST_BOOT: AtomicU64 = AtomicU64::new(0);
+
+// Identifies a received command message, "<boot>-<message>", or one of its steps,
+// "<boot>-<message>.<step>" with steps counted from 1. The boot part is the controller's start
+// time in milliseconds in base 36, so IDs stay unique across restarts, even quick ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct CommandId {
+    boot: u64,
+    message: u64,
+    // 0 for the message itself
+    step: usize,
+}
+
+impl CommandId {
+    // The id of the message's step at `index` into its commands
+    pub fn step(self, index: usize) -> CommandId {
+        CommandId { step: index + 1, ..self }
+    }
+
+    // Counted from 1, 0 for the message itself
+    pub fn step_number(self) -> usize {
+        self.step
+    }
+
+    pub fn message(self) -> CommandId {
+        CommandId { step: 0, ..self }
+    }
+}
+
+// Hands out the ids of received messages
+pub struct CommandIds {
+    boot: u64,
+    next: u64,
+}
+
+impl CommandIds {
+    pub fn new() -> CommandIds {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let previous = LAST_BOOT.fetch_max(now, Ordering::SeqCst);
+        let boot = if previous >= now { LAST_BOOT.fetch_add(1, Ordering::SeqCst) + 1 } else { now };
+        CommandIds { boot, next: 1 }
+    }
+
+    pub fn next(&mut self) -> CommandId {
+        let id = CommandId { boot: self.boot, message: self.next, step: 0 };
+        self.next += 1;
+        id
+    }
+}
+
+impl Display for CommandId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", base36(self.boot), self.message)?;
+        if self.step > 0 {
+            write!(f, ".{}", self.step)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CommandId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid command id [{s}]");
+        let (boot, rest) = s.split_once('-').ok_or_else(invalid)?;
+        let (message, step) = rest.split_once('.').unwrap_or((rest, "0"));
+        Ok(CommandId {
+            boot: u64::from_str_radix(boot, 36).map_err(|_| invalid())?,
+            message: message.parse().map_err(|_| invalid())?,
+            step: step.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl From<CommandId> for String {
+    fn from(id: CommandId) -> String {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for CommandId {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn base36(mut value: u64) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(std::char::from_digit((value % 36) as u32, 36).expect("digit below 36"));
+        value /= 36;
+        if value == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_text() {
+        let message = CommandIds::new().next();
+        for id in [message, message.step(4)] {
+            assert_eq!(id.to_string().parse::<CommandId>(), Ok(id));
+        }
+        assert_eq!(message.step(4).step_number(), 5);
+        assert_eq!(message.step(4).message(), message);
+    }
+
+    #[test]
+    fn boot_is_base_36() {
+        let id = CommandId { boot: 36 * 36 + 35, message: 7, step: 2 };
+        assert_eq!(id.to_string(), "10z-7.2");
+        assert!("10z".parse::<CommandId>().is_err());
+        assert!("10z-x".parse::<CommandId>().is_err());
+    }
+
+    #[test]
+    fn processes_started_together_get_different_boots() {
+        assert_ne!(CommandIds::new().next(), CommandIds::new().next());
+    }
+}
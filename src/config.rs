@@ -1,39 +1,1588 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
+use std::ops::Deref;
 use std::path::Path;
+use std::thread;
+use std::thread::JoinHandle;
 
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use toml::Value;
+
+use crate::deck::{Coordinates, HOME_POSITION, WASHING_POSITION};
+use crate::{config_query, migration, schema};
+use crate::units::{Microliters, Millimeters, PumpUnits};
+
+// Tube holders the router never moves to, e.g. for external tubes, are given a label instead
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum TubeHolderCoordinates {
+    Position(Coordinates),
+    Label(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum KeepOutZone {
+    Rectangle { name: String, x_min: Millimeters, x_max: Millimeters, y_min: Millimeters, y_max: Millimeters, z_min: Millimeters, z_max: Millimeters },
+    Cylinder { name: String, x: Millimeters, y: Millimeters, radius: Millimeters, z_min: Millimeters, z_max: Millimeters },
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverRangePolicy {
+    Reject,
+    Clamp,
+    #[default]
+    Split,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PumpDialect {
+    #[default]
+    Dt,
+    Oem,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    #[default]
+    None,
+    Software,
+    Hardware,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+    #[default]
+    Mask,
+    Hash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStoreBackend {
+    #[default]
+    File,
+    Sqlite,
+    Http,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TubeDetectionMethod {
+    #[default]
+    None,
+    Sensor,
+    Pressure,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct TubeDetectionSettings {
+    pub method: TubeDetectionMethod,
+    pub hover_mm: Millimeters,
+    pub sensor_query: String,
+    pub sensor_present_reply: String,
+    pub probe_ul: Microliters,
+    pub pressure_query: String,
+    pub min_pressure: u64,
+}
+
+impl Default for TubeDetectionSettings {
+    fn default() -> Self {
+        TubeDetectionSettings {
+            method: TubeDetectionMethod::None,
+            hover_mm: Millimeters(10.0),
+            sensor_query: "M119".to_string(),
+            sensor_present_reply: "TUBE:PRESENT".to_string(),
+            probe_ul: Microliters(5),
+            pressure_query: "?24".to_string(),
+            min_pressure: 1100,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ProbingSettings {
+    // {z} is replaced by the lowest height searched, {feed} by feedrate_mm_per_min
+    pub command: String,
+    pub feedrate_mm_per_min: f64,
+    // The probe starts this far above the expected height and gives up this far below it
+    pub search_mm: Millimeters,
+    pub timeout_secs: u64,
+    // Point on the slide holder's slot surface; z is where the surface is expected
+    pub slot: Option<Coordinates>,
+    // Expected height of a tube top above the tube's configured position
+    pub tube_top_mm: Millimeters,
+}
+
+impl Default for ProbingSettings {
+    fn default() -> Self {
+        ProbingSettings {
+            command: "G38.2Z{z}F{feed}".to_string(),
+            feedrate_mm_per_min: 60.0,
+            search_mm: Millimeters(10.0),
+            timeout_secs: 60,
+            slot: None,
+            tube_top_mm: Millimeters(40.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceRoutine {
+    PumpStroke,
+    NeedleRinse,
+    Park,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct IdleMaintenanceSettings {
+    pub enabled: bool,
+    pub idle_minutes: u64,
+    pub routines: Vec<MaintenanceRoutine>,
+    pub park_position: Coordinates,
+}
+
+impl Default for IdleMaintenanceSettings {
+    fn default() -> Self {
+        IdleMaintenanceSettings {
+            enabled: false,
+            idle_minutes: 30,
+            routines: vec![MaintenanceRoutine::PumpStroke, MaintenanceRoutine::NeedleRinse, MaintenanceRoutine::Park],
+            park_position: HOME_POSITION,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Rack {
+    pub name: String,
+    pub origin: Coordinates,
+    pub pitch: Millimeters,
+    #[serde(default)]
+    pub row_pitch: Option<Millimeters>,
+    pub rows: u32,
+    pub cols: u32,
+    #[serde(default)]
+    pub orientation: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct SerialWriteSettings {
+    pub chunk_size: usize,
+    pub application_timeout_ms: u64,
+    pub pump_timeout_ms: u64,
+    pub router_timeout_ms: u64,
+}
+
+impl Default for SerialWriteSettings {
+    fn default() -> Self {
+        SerialWriteSettings { chunk_size: 64, application_timeout_ms: 1000, pump_timeout_ms: 1000, router_timeout_ms: 1000 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct FeedrateSettings {
+    pub travel_mm_per_min: f64,
+    pub plunge_mm_per_min: f64,
+}
+
+impl Default for FeedrateSettings {
+    fn default() -> Self {
+        FeedrateSettings { travel_mm_per_min: 3000.0, plunge_mm_per_min: 600.0 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceSettings {
+    pub port_path: String,
+    #[serde(default = "default_device_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+fn default_device_baud_rate() -> u32 {
+    9600
+}
+
+fn default_router_baud_rate() -> u32 {
+    115200
+}
+
+// Without a [devices.thermal] table the router firmware's heater is used for temperature commands
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DevicesSettings {
+    pub thermal: Option<DeviceSettings>,
+    pub barcode: Option<DeviceSettings>,
+    pub balance: Option<DeviceSettings>,
+    pub shaker: Option<DeviceSettings>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PeripheralKind {
+    // One line per action, from the commands templates
+    #[default]
+    Serial,
+}
+
+// Lab device taking the steps <prefix>_<action>[_<arg>...], e.g. LED_ON or STIR_SET_300
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeripheralSettings {
+    pub name: String,
+    #[serde(default)]
+    pub kind: PeripheralKind,
+    pub prefix: String,
+    #[serde(flatten)]
+    pub device: DeviceSettings,
+    // Sent when the controller starts and when it shuts down, nothing when empty
+    #[serde(default)]
+    pub init: String,
+    #[serde(default)]
+    pub shutdown: String,
+    // Answered with one line reported as the device's status
+    #[serde(default)]
+    pub status_query: String,
+    // What every command is acknowledged with, no reply is read when empty
+    #[serde(default)]
+    pub reply: String,
+    #[serde(default = "default_peripheral_reply_timeout_ms")]
+    pub reply_timeout_ms: u64,
+    // Line sent for each action, with {1}, {2}, ... the step's arguments
+    #[serde(default)]
+    pub commands: HashMap<String, String>,
+}
+
+fn default_peripheral_reply_timeout_ms() -> u64 {
+    500
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContaminationAction {
+    Wash,
+    Reject,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ZoneDriver {
+    Router,
+    Thermal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct PidGains {
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+}
+
+// Separately regulated heater addressed as TC_<name>_<temp>, e.g. the chamber or the reagent block.
+// {heater} in the commands is the index of the heater and its sensor on the driving board.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThermalZone {
+    pub name: String,
+    pub driver: ZoneDriver,
+    pub heater: u32,
+    #[serde(default = "default_zone_set_command")]
+    pub set_command: String,
+    // Sent once at startup when set
+    #[serde(default)]
+    pub pid: Option<PidGains>,
+    #[serde(default = "default_zone_pid_command")]
+    pub pid_command: String,
+    pub min_celsius: f64,
+    pub max_celsius: f64,
+    // Regulated by the controller instead of the board's firmware when set
+    #[serde(default)]
+    pub software: Option<SoftwareLoop>,
+}
+
+// Audits of the zones holding a set point: a zone is read every audit_interval_secs once it first
+// came within tolerance_celsius of its set point, or failed to within settle_secs
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct TemperatureHoldSettings {
+    // 0 disables the audits
+    pub audit_interval_secs: u64,
+    pub tolerance_celsius: f64,
+    pub settle_secs: u64,
+    // Consecutive audits out of tolerance before execution is paused
+    pub pause_after: u32,
+}
+
+impl Default for TemperatureHoldSettings {
+    fn default() -> Self {
+        TemperatureHoldSettings { audit_interval_secs: 60, tolerance_celsius: 1.0, settle_secs: 900, pause_after: 3 }
+    }
+}
+
+// Boards that can only switch a heater output get a PID loop in the controller: the sensor is read
+// every poll_interval_ms between and during steps, and the output, clamped to output_min..output_max,
+// is sent through output_command. Both commands are answered with one line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct SoftwareLoop {
+    pub poll_interval_ms: u64,
+    pub sensor_command: String,
+    // The temperature follows this in the sensor reply, e.g. "T:" for "T:36.84"
+    pub sensor_prefix: String,
+    pub output_command: String,
+    pub output_min: f64,
+    pub output_max: f64,
+    pub reply_timeout_ms: u64,
+    // Oscillations measured by TUNEPID_ after the first one
+    pub tune_cycles: u32,
+    pub tune_hysteresis: f64,
+    pub tune_timeout_secs: u64,
+}
+
+impl Default for SoftwareLoop {
+    fn default() -> Self {
+        SoftwareLoop {
+            poll_interval_ms: 1000,
+            sensor_command: "M105T{heater}\r\n".to_string(),
+            sensor_prefix: "T:".to_string(),
+            output_command: "M42P{heater}S{output}\r\n".to_string(),
+            output_min: 0.0,
+            output_max: 255.0,
+            reply_timeout_ms: 500,
+            tune_cycles: 4,
+            tune_hysteresis: 0.5,
+            tune_timeout_secs: 1800,
+        }
+    }
+}
+
+fn default_zone_set_command() -> String {
+    "M104T{heater}S{temp}\r\n".to_string()
+}
+
+fn default_zone_pid_command() -> String {
+    "M301E{heater}P{p}I{i}D{d}\r\n".to_string()
+}
+
+// First entry whose device and text both match a failure names its remediation; `contains` is
+// looked for, ignoring case, in the error and in the device's raw reply
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorHint {
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub contains: Option<String>,
+    pub code: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+// Reagent class `after` must not be aspirated while the needle carries `before`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContaminationRule {
+    pub before: String,
+    pub after: String,
+    pub action: ContaminationAction,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct EndOfRunSettings {
+    pub drain_slot: bool,
+    pub final_wash: bool,
+    pub park_position: Option<Coordinates>,
+    pub zero_pumps: bool,
+    pub thermal_off: bool,
+}
+
+impl Default for EndOfRunSettings {
+    fn default() -> Self {
+        EndOfRunSettings { drain_slot: true, final_wash: true, park_position: None, zero_pumps: true, thermal_off: false }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub enabled: bool,
+    pub bind: String,
+    pub operator_token: String,
+    pub observer_token: String,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            enabled: false,
+            bind: "127.0.0.1:8080".to_string(),
+            operator_token: String::new(),
+            observer_token: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct RouterSelftestSettings {
+    pub query: String,
+    pub expected_reply: String,
+    pub duration_secs: u64,
+    pub reply_timeout_ms: u64,
+}
+
+impl Default for RouterSelftestSettings {
+    fn default() -> Self {
+        RouterSelftestSettings {
+            query: "G1".to_string(),
+            expected_reply: "G1:OK".to_string(),
+            duration_secs: 60,
+            reply_timeout_ms: 200,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ClogDetectionSettings {
+    pub enabled: bool,
+    pub load_query: String,
+    pub max_load: u64,
+    pub min_stroke_units: PumpUnits,
+    pub poll_interval_ms: u64,
+    pub unclog_attempts: u32,
+    pub reverse_units: PumpUnits,
+    pub purge_port: u8,
+}
+
+impl Default for ClogDetectionSettings {
+    fn default() -> Self {
+        ClogDetectionSettings {
+            enabled: false,
+            load_query: "?25".to_string(),
+            max_load: 900,
+            min_stroke_units: PumpUnits(3000),
+            poll_interval_ms: 250,
+            unclog_attempts: 1,
+            reverse_units: PumpUnits(240),
+            purge_port: 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct PumpResolutionSettings {
+    pub enabled: bool,
+    // Aspirations of fewer microliters are made in fine_mode
+    pub fine_below_ul: Microliters,
+    // N command argument of the fine positioning or micro-step mode
+    pub fine_mode: u8,
+    // Plunger increments per standard increment in fine_mode
+    pub fine_scale: u64,
+    pub mode_query: String,
+}
+
+impl Default for PumpResolutionSettings {
+    fn default() -> Self {
+        PumpResolutionSettings { enabled: false, fine_below_ul: Microliters(50), fine_mode: 1, fine_scale: 8, mode_query: "?28".to_string() }
+    }
+}
+
+// Command letters of pump_protocol = "oem"; the defaults are the Cavro letters the DT pumps take
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct OemCommandSettings {
+    pub initialize: String,
+    pub valve_in: String,
+    pub valve_out: String,
+    pub move_to: String,
+    pub pick_up: String,
+    pub dispense: String,
+    pub loop_start: String,
+    pub loop_end: String,
+    pub speed: String,
+    pub resolution: String,
+    pub execute: String,
+}
+
+impl Default for OemCommandSettings {
+    fn default() -> Self {
+        let letter = |l: &str| l.to_string();
+        OemCommandSettings {
+            initialize: letter("Z"),
+            valve_in: letter("I"),
+            valve_out: letter("O"),
+            move_to: letter("A"),
+            pick_up: letter("P"),
+            dispense: letter("D"),
+            loop_start: letter("g"),
+            loop_end: letter("G"),
+            speed: letter("V"),
+            resolution: letter("N"),
+            execute: letter("R"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CrcAlgorithm {
+    #[default]
+    Crc32,
+    Crc16,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct FramingSettings {
+    pub protocol_version: u32,
+    pub crc: CrcAlgorithm,
+}
+
+impl Default for FramingSettings {
+    fn default() -> Self {
+        FramingSettings { protocol_version: 1, crc: CrcAlgorithm::Crc32 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct RunStoreSettings {
+    pub backend: RunStoreBackend,
+    pub sqlite_path: String,
+    // Records are posted here by the http backend
+    pub url: Option<String>,
+}
+
+impl Default for RunStoreSettings {
+    fn default() -> Self {
+        RunStoreSettings { backend: RunStoreBackend::File, sqlite_path: "runs.db".to_string(), url: None }
+    }
+}
+
+// Checked before a message starts: the directories runs write to take a file, their disks have
+// min_free_mb left and the run store answers
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct PreflightSettings {
+    pub enabled: bool,
+    // 0 skips the disk space check
+    pub min_free_mb: u64,
+}
+
+impl Default for PreflightSettings {
+    fn default() -> Self {
+        PreflightSettings { enabled: true, min_free_mb: 200 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct HandshakeSettings {
+    // Refuse commands from the application port until it said HELLO
+    pub required: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct MessageLimitSettings {
+    pub max_frame_bytes: usize,
+    pub max_commands: usize,
+    // Sustained rate of command frames, 0 for no limit
+    pub frames_per_sec: f64,
+    pub burst: u32,
+}
+
+impl Default for MessageLimitSettings {
+    fn default() -> Self {
+        MessageLimitSettings { max_frame_bytes: 65536, max_commands: 2000, frames_per_sec: 20.0, burst: 50 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationEvent {
+    Completion,
+    Fault,
+    Estop,
+    Reservoir,
+    Temperature,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookKind {
+    Slack,
+    Http,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Webhook {
+    pub kind: WebhookKind,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailSettings {
+    pub smtp_server: String,
+    pub from: String,
+    pub to: Vec<String>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct TubeHolderCoordinates {
+#[serde(default)]
+pub struct NotificationSettings {
+    pub events: Vec<NotificationEvent>,
+    pub log_lines: usize,
+    pub webhooks: Vec<Webhook>,
+    pub email: Option<EmailSettings>,
 }
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        NotificationSettings {
+            events: vec![NotificationEvent::Completion, NotificationEvent::Fault, NotificationEvent::Estop, NotificationEvent::Reservoir,
+                NotificationEvent::Temperature],
+            log_lines: 20,
+            webhooks: Vec::new(),
+            email: None,
+        }
+    }
+}
+
+// Sent when the router answers a move unexpectedly; the state is then read with startup.position_query
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct RouterHaltSettings {
+    pub hold_command: String,
+    pub reply_timeout_ms: u64,
+    pub home_timeout_secs: u64,
+}
+
+impl Default for RouterHaltSettings {
+    fn default() -> Self {
+        RouterHaltSettings { hold_command: "M410".to_string(), reply_timeout_ms: 1000, home_timeout_secs: 60 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouterEcho {
+    // The firmware answers without echoing
+    #[default]
+    Off,
+    // The echo is read and dropped
+    Skip,
+    // The echo is compared with what was sent and a mangled move is resent
+    Verify,
+}
+
+// Router firmware that echoes each command line back before answering it, as prefix + the line
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct RouterEchoSettings {
+    pub mode: RouterEcho,
+    pub prefix: String,
+    pub max_resends: u32,
+}
+
+impl Default for RouterEchoSettings {
+    fn default() -> Self {
+        RouterEchoSettings { mode: RouterEcho::Off, prefix: String::new(), max_resends: 2 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ReagentExpirySettings {
+    // Refuse expired reagents instead of noting them, unless the message overrides it
+    pub block: bool,
+    // Per tube, how long its reagent is good after the first aspiration
+    pub minutes: HashMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct PumpRuntimeSettings {
+    // Longest a pump may stay busy with one command before it is terminated and the pumps locked
+    pub max_secs: u64,
+}
+
+impl Default for PumpRuntimeSettings {
+    fn default() -> Self {
+        PumpRuntimeSettings { max_secs: 600 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct PollingSettings {
+    // Between status queries while waiting for a stroke to finish
+    pub pump_busy_interval_ms: u64,
+    // Between status queries while waiting for a pump to report ready during startup
+    pub pump_ready_interval_ms: u64,
+    // Added at random to every polling interval
+    pub jitter_ms: u64,
+}
+
+impl Default for PollingSettings {
+    fn default() -> Self {
+        PollingSettings { pump_busy_interval_ms: 1000, pump_ready_interval_ms: 100, jitter_ms: 0 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct WatchdogSettings {
+    // Pet systemd's watchdog when the service sets WatchdogSec
+    pub systemd: bool,
+    // How long the executor may go without making progress before petting stops
+    pub stall_secs: u64,
+    // Serial hardware watchdog, written `pet` every interval_ms
+    pub port_path: Option<String>,
+    pub baud_rate: u32,
+    pub pet: String,
+    pub interval_ms: u64,
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        WatchdogSettings { systemd: true, stall_secs: 120, port_path: None, baud_rate: 9600, pet: "W".to_string(), interval_ms: 1000 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct StartupSettings {
+    pub position_query: String,
+    // Homing is only skipped when the reply to homed_query contains homed_reply
+    pub homed_query: String,
+    pub homed_reply: String,
+    pub query_timeout_ms: u64,
+    pub pump_waste_port: u8,
+    pub confirm_when_unsure: bool,
+    pub pump_init_timeout_secs: u64,
+}
+
+impl Default for StartupSettings {
+    fn default() -> Self {
+        StartupSettings {
+            position_query: "M114".to_string(),
+            homed_query: String::new(),
+            homed_reply: String::new(),
+            query_timeout_ms: 500,
+            pump_waste_port: 3,
+            confirm_when_unsure: false,
+            pump_init_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct FirmwareSettings {
+    pub router_query: String,
+    pub reply_timeout_ms: u64,
+    pub min_router_version: Option<String>,
+    pub min_pump_version: Option<String>,
+}
+
+impl Default for FirmwareSettings {
+    fn default() -> Self {
+        FirmwareSettings {
+            router_query: "M115".to_string(),
+            reply_timeout_ms: 500,
+            min_router_version: None,
+            min_pump_version: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct TipSettings {
+    pub enabled: bool,
+    pub rack: String,
+    pub eject_position: Coordinates,
+    pub eject_command: String,
+    pub press_depth_mm: Millimeters,
+    pub max_uses: u64,
+    pub change_on_contamination: bool,
+}
+
+impl Default for TipSettings {
+    fn default() -> Self {
+        TipSettings {
+            enabled: false,
+            rack: "T".to_string(),
+            eject_position: Coordinates { x: Millimeters(300.0), y: Millimeters(150.0), z: Millimeters(-40.0) },
+            eject_command: "M42 P4 S255".to_string(),
+            press_depth_mm: Millimeters(5.0),
+            max_uses: 0,
+            change_on_contamination: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct RollbackSettings {
+    pub return_to_source: bool,
+}
+
+impl Default for RollbackSettings {
+    fn default() -> Self {
+        RollbackSettings { return_to_source: true }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct WashStationSettings {
+    pub position: Coordinates,
+    pub flush_ul: Microliters,
+    pub oscillation_mm: Millimeters,
+    pub oscillations: u32,
+    pub air_strokes: u32,
+    pub on_reagent_change: bool,
+}
+
+impl Default for WashStationSettings {
+    fn default() -> Self {
+        WashStationSettings {
+            position: WASHING_POSITION,
+            flush_ul: Microliters(2 * 500),
+            oscillation_mm: Millimeters(0.0),
+            oscillations: 0,
+            air_strokes: 4,
+            on_reagent_change: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct BarcodeScanSettings {
+    pub trigger: String,
+    pub timeout_ms: u64,
+    pub scanner_offset: Coordinates,
+}
+
+impl Default for BarcodeScanSettings {
+    fn default() -> Self {
+        BarcodeScanSettings { trigger: "\u{16}T\r".to_string(), timeout_ms: 3000, scanner_offset: Coordinates { x: Millimeters(0.0), y: Millimeters(20.0), z: Millimeters(0.0) } }
+    }
+}
+
+// Serial protocol of the [devices.shaker]; {rpm} in start_command is replaced by the speed
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ShakerSettings {
+    pub start_command: String,
+    pub stop_command: String,
+    // Line the shaker answers every command with; empty for shakers that don't acknowledge
+    pub reply: String,
+    pub reply_timeout_ms: u64,
+    pub max_rpm: u32,
+    pub spin_down_ms: u64,
+}
+
+impl Default for ShakerSettings {
+    fn default() -> Self {
+        ShakerSettings {
+            start_command: "OUT_SP_4 {rpm}\r\nSTART_4\r\n".to_string(),
+            stop_command: "STOP_4\r\n".to_string(),
+            reply: String::new(),
+            reply_timeout_ms: 1000,
+            max_rpm: 3000,
+            spin_down_ms: 2000,
+        }
+    }
+}
+
+// Plunger speeds of MIXTUBE_ steps in pulses per second; default_speed is restored afterwards
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct TubeMixingSettings {
+    pub aspirate_speed: u32,
+    pub dispense_speed: u32,
+    pub default_speed: u32,
+    pub max_cycles: u32,
+}
+
+impl Default for TubeMixingSettings {
+    fn default() -> Self {
+        TubeMixingSettings { aspirate_speed: 600, dispense_speed: 1200, default_speed: 1400, max_cycles: 20 }
+    }
+}
+
+// Multi-sample flow cell on the slot, its wells addressed as slot:<well> in LA_ destinations
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct FlowCellSettings {
+    // Where the wells' offsets are measured from
+    pub position: Option<Coordinates>,
+    pub wells: HashMap<String, WellSettings>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WellSettings {
+    pub offset: Coordinates,
+    // Pump 2 valve channel the well is drained through; 1 is the slot line
+    #[serde(default = "default_well_drain_port")]
+    pub drain_port: u8,
+}
+
+fn default_well_drain_port() -> u8 {
+    1
+}
+
+// Bulk reservoir on a valve channel of pump 1; warned about once less than warn_below_ul is left
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReservoirSettings {
+    pub channel: u8,
+    pub capacity_ul: Microliters,
+    #[serde(default)]
+    pub warn_below_ul: Microliters,
+}
+
+// Counts at which a part is reported as due for maintenance; 0 disables a limit
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct WearLimitSettings {
+    // Aspirations per pump
+    pub pump_strokes: u64,
+    pub valve_actuations: u64,
+    pub router_travel_m: u64,
+    pub tips_used: u64,
+}
+
+impl Default for WearLimitSettings {
+    fn default() -> Self {
+        WearLimitSettings { pump_strokes: 100000, valve_actuations: 200000, router_travel_m: 10000, tips_used: 0 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct PumpPollLogSettings {
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+impl Default for PumpPollLogSettings {
+    fn default() -> Self {
+        PumpPollLogSettings { enabled: false, interval_secs: 10 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct TracingSettings {
+    // JSON lines file finished spans are appended to
+    pub file: Option<String>,
+    // OTLP/HTTP traces URL, e.g. "http://collector:4318/v1/traces"
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        TracingSettings { file: None, otlp_endpoint: None, service_name: "rusty_controller".to_string() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct SensorLogSettings {
+    pub enabled: bool,
+    pub interval_ms: u64,
+    pub sensors: Vec<String>,
+}
+
+impl Default for SensorLogSettings {
+    fn default() -> Self {
+        SensorLogSettings { enabled: false, interval_ms: 1000, sensors: Vec::new() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct EventLogSettings {
+    // JSON lines file the events are appended to
+    pub file: Option<String>,
+    // TCP address, e.g. "0.0.0.0:7070"; every connected client receives the events as they happen
+    pub socket: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct RunReportSettings {
+    pub enabled: bool,
+    pub directory: String,
+    // Shell command turning the HTML report into a PDF, e.g. "wkhtmltopdf {html} {pdf}"
+    pub pdf_command: Option<String>,
+}
+
+impl Default for RunReportSettings {
+    fn default() -> Self {
+        RunReportSettings { enabled: false, directory: "./reports".to_string(), pdf_command: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimelineFormat {
+    Json,
+    Csv,
+    Html,
+}
+
+// Modelled step timings of runs in --simulate mode
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct SimulationTimelineSettings {
+    pub enabled: bool,
+    pub directory: String,
+    pub formats: Vec<TimelineFormat>,
+    // Plunger speed in pulses per second until a step sets one, and the time one valve move takes
+    pub pump_speed: u32,
+    pub valve_move_ms: u64,
+}
+
+impl Default for SimulationTimelineSettings {
+    fn default() -> Self {
+        SimulationTimelineSettings {
+            enabled: true,
+            directory: "./timelines".to_string(),
+            formats: vec![TimelineFormat::Json, TimelineFormat::Csv],
+            pump_speed: 1400,
+            valve_move_ms: 250,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
+    #[serde(default = "default_instance_name")]
+    pub instance_name: String,
     pub application_port_path: String,
     pub pump_port_path: String,
     pub router_port_path: String,
+    #[serde(default = "default_device_baud_rate")]
+    pub application_baud_rate: u32,
+    #[serde(default = "default_device_baud_rate")]
+    pub pump_baud_rate: u32,
+    #[serde(default = "default_router_baud_rate")]
+    pub router_baud_rate: u32,
+    #[serde(default)]
+    pub pump_protocol: PumpDialect,
+    #[serde(default)]
+    pub application_flow_control: FlowControl,
     pub constant_cleaning: bool,
+    #[serde(default)]
+    pub waste_capacity_ul: Option<Microliters>,
+    #[serde(default)]
+    pub over_range_policy: OverRangePolicy,
+    #[serde(default)]
+    pub wash_between_cycles: bool,
+    #[serde(default)]
+    pub dual_pump_wash: bool,
+    #[serde(default = "default_drain_overdraw_ul")]
+    pub drain_overdraw_ul: Microliters,
+    #[serde(default = "default_legacy_unitless_volumes")]
+    pub legacy_unitless_volumes: bool,
+    #[serde(default)]
+    pub sensitive_metadata: Vec<String>,
+    #[serde(default)]
+    pub metadata_redaction: Redaction,
+    #[serde(default)]
+    pub metadata_hash_salt: String,
+    #[serde(default)]
+    pub required_metadata: Vec<String>,
+    #[serde(default = "default_framing_failure_threshold")]
+    pub framing_failure_threshold: u32,
+    #[serde(default = "default_application_queue_capacity")]
+    pub application_queue_capacity: usize,
+    #[serde(default = "default_inter_run_washes")]
+    pub inter_run_washes: u32,
+    #[serde(default = "default_manifest_timeout_secs")]
+    pub manifest_timeout_secs: u64,
+    #[serde(default)]
+    pub console_socket_path: Option<String>,
+    #[serde(default = "default_run_history_path")]
+    pub run_history_path: String,
+    #[serde(default = "default_journal_path")]
+    pub journal_path: String,
+    #[serde(default, rename(deserialize = "run-store"))]
+    pub run_store: RunStoreSettings,
+    #[serde(default)]
+    pub preflight: PreflightSettings,
+    #[serde(default = "default_wear_counters_path")]
+    pub wear_counters_path: String,
+    #[serde(default = "default_calibration_path")]
+    pub calibration_path: String,
+    #[serde(default = "default_reservoir_levels_path")]
+    pub reservoir_levels_path: String,
+    #[serde(default = "default_reagent_expiry_path")]
+    pub reagent_expiry_path: String,
+    #[serde(default = "default_outbox_path")]
+    pub outbox_path: String,
+    #[serde(default = "default_outbox_capacity")]
+    pub outbox_capacity: usize,
+    #[serde(default = "default_tenant_metadata_key")]
+    pub tenant_metadata_key: String,
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default, rename(deserialize = "log-levels"))]
+    pub log_levels: HashMap<String, String>,
+    #[serde(default = "default_wait_progress_interval_secs")]
+    pub wait_progress_interval_secs: u64,
+    #[serde(default)]
+    pub framing: FramingSettings,
+    #[serde(default, rename(deserialize = "message-limits"))]
+    pub message_limits: MessageLimitSettings,
+    #[serde(default)]
+    pub handshake: HandshakeSettings,
+    #[serde(default, rename(deserialize = "serial-write"))]
+    pub serial_write: SerialWriteSettings,
+    #[serde(default, rename(deserialize = "idle-maintenance"))]
+    pub idle_maintenance: IdleMaintenanceSettings,
+    #[serde(default)]
+    pub feedrates: FeedrateSettings,
+    #[serde(default)]
+    pub http: HttpSettings,
+    #[serde(default, rename(deserialize = "end-of-run"))]
+    pub end_of_run: EndOfRunSettings,
+    #[serde(default)]
+    pub devices: DevicesSettings,
+    #[serde(default)]
+    pub peripherals: Vec<PeripheralSettings>,
+    #[serde(default)]
+    pub startup: StartupSettings,
+    #[serde(default, rename(deserialize = "router-halt"))]
+    pub router_halt: RouterHaltSettings,
+    #[serde(default, rename(deserialize = "router-echo"))]
+    pub router_echo: RouterEchoSettings,
+    #[serde(default, rename(deserialize = "pump-runtime"))]
+    pub pump_runtime: PumpRuntimeSettings,
+    #[serde(default)]
+    pub watchdog: WatchdogSettings,
+    #[serde(default)]
+    pub polling: PollingSettings,
+    #[serde(default)]
+    pub firmware: FirmwareSettings,
+    #[serde(default)]
+    pub notifications: NotificationSettings,
+    #[serde(default, rename(deserialize = "router-selftest"))]
+    pub router_selftest: RouterSelftestSettings,
+    #[serde(default, rename(deserialize = "tube-detection"))]
+    pub tube_detection: TubeDetectionSettings,
+    #[serde(default)]
+    pub probing: ProbingSettings,
+    #[serde(default, rename(deserialize = "clog-detection"))]
+    pub clog_detection: ClogDetectionSettings,
+    #[serde(default, rename(deserialize = "pump-resolution"))]
+    pub pump_resolution: PumpResolutionSettings,
+    #[serde(default, rename(deserialize = "oem-commands"))]
+    pub oem_commands: OemCommandSettings,
+    #[serde(default)]
+    pub tips: TipSettings,
+    #[serde(default, rename(deserialize = "wash-station"))]
+    pub wash_station: WashStationSettings,
+    #[serde(default)]
+    pub rollback: RollbackSettings,
+    #[serde(default, rename(deserialize = "barcode-scan"))]
+    pub barcode_scan: BarcodeScanSettings,
+    #[serde(default)]
+    pub shaker: ShakerSettings,
+    #[serde(default, rename(deserialize = "tube-mixing"))]
+    pub tube_mixing: TubeMixingSettings,
+    #[serde(default, rename(deserialize = "run-report"))]
+    pub run_report: RunReportSettings,
+    #[serde(default, rename(deserialize = "simulation-timeline"))]
+    pub simulation_timeline: SimulationTimelineSettings,
+    #[serde(default, rename(deserialize = "pump-poll-log"))]
+    pub pump_poll_log: PumpPollLogSettings,
+    #[serde(default, rename(deserialize = "sensor-log"))]
+    pub sensor_log: SensorLogSettings,
+    #[serde(default, rename(deserialize = "event-log"))]
+    pub event_log: EventLogSettings,
+    #[serde(default)]
+    pub tracing: TracingSettings,
+    #[serde(default, rename(deserialize = "wear-limits"))]
+    pub wear_limits: WearLimitSettings,
     #[serde(rename(deserialize = "tube-holder-coordinates"))]
-    pub tube_holder_coordinates: HashMap<String, String>,
+    pub tube_holder_coordinates: HashMap<String, TubeHolderCoordinates>,
+    #[serde(default)]
+    pub racks: Vec<Rack>,
+    #[serde(default, rename(deserialize = "tube-volumes"))]
+    pub tube_volumes: HashMap<String, Microliters>,
+    #[serde(default, rename(deserialize = "reagent-expiry"))]
+    pub reagent_expiry: ReagentExpirySettings,
+    #[serde(default, rename(deserialize = "reagent-classes"))]
+    pub reagent_classes: HashMap<String, String>,
+    #[serde(default, rename(deserialize = "slot-recovery"))]
+    pub slot_recovery: HashMap<String, String>,
+    #[serde(default = "default_external_sources", rename(deserialize = "external-sources"))]
+    pub external_sources: HashMap<String, u8>,
+    #[serde(default)]
+    pub reservoirs: HashMap<String, ReservoirSettings>,
+    #[serde(default, rename(deserialize = "flow-cell"))]
+    pub flow_cell: FlowCellSettings,
+    #[serde(default, rename(deserialize = "contamination-rules"))]
+    pub contamination_rules: Vec<ContaminationRule>,
+    #[serde(default, rename(deserialize = "error-hints"))]
+    pub error_hints: Vec<ErrorHint>,
+    #[serde(default, rename(deserialize = "thermal-zones"))]
+    pub thermal_zones: Vec<ThermalZone>,
+    #[serde(default, rename(deserialize = "temperature-hold"))]
+    pub temperature_hold: TemperatureHoldSettings,
+    #[serde(default, rename(deserialize = "keep-out-zones"))]
+    pub keep_out_zones: Vec<KeepOutZone>,
+}
+
+fn default_external_sources() -> HashMap<String, u8> {
+    HashMap::from([("34".to_string(), 4), ("35".to_string(), 7), ("36".to_string(), 6)])
+}
+
+fn default_wait_progress_interval_secs() -> u64 {
+    60
+}
+
+fn default_instance_name() -> String {
+    "main".to_string()
+}
+
+fn default_framing_failure_threshold() -> u32 {
+    3
+}
+
+fn default_application_queue_capacity() -> usize {
+    32
+}
+
+fn default_inter_run_washes() -> u32 {
+    1
+}
+
+fn default_manifest_timeout_secs() -> u64 {
+    120
+}
+
+fn default_run_history_path() -> String {
+    "./run_history.toml".to_string()
+}
+
+fn default_journal_path() -> String {
+    "./journal.toml".to_string()
+}
+
+fn default_wear_counters_path() -> String {
+    "./wear_counters.toml".to_string()
+}
+
+fn default_reservoir_levels_path() -> String {
+    "./reservoir_levels.toml".to_string()
+}
+
+fn default_reagent_expiry_path() -> String {
+    "./reagent_first_use.toml".to_string()
+}
+
+fn default_drain_overdraw_ul() -> Microliters {
+    Microliters(200)
+}
+
+fn default_legacy_unitless_volumes() -> bool {
+    true
+}
+
+fn default_outbox_path() -> String {
+    "./outbox.log".to_string()
+}
+
+fn default_outbox_capacity() -> usize {
+    1000
+}
+
+fn default_calibration_path() -> String {
+    "./calibration.toml".to_string()
+}
+
+fn default_log_level() -> String {
+    "trace".to_string()
+}
+
+fn default_tenant_metadata_key() -> String {
+    "project".to_string()
 }
 
 static DEFAULT_CONFIG: &str = include_str!("../config.toml");
 
-fn load_config() -> Config {
+const ENV_PREFIX: &str = "RC_";
+const SET_FLAG: &str = "--set";
+
+// Precedence, highest first: `--set path=value` flags, RC_* environment variables, config.toml
+fn load_config() -> (Value, String) {
     if !Path::new("./config.toml").exists() {
         File::create(Path::new("./config.toml"))
             .and_then(|mut f| f.write(DEFAULT_CONFIG.as_bytes()))
             .expect("Failed to create config file");
         log::error!("config.toml file not found. Creating new one and using default configs");
     }
-    std::fs::read_to_string("./config.toml")
-        .map_err(|e| e.to_string())
-        .and_then(|s| toml::from_str(s.as_str()).map_err(|e| e.to_string()))
-        .expect("Unable to load configuration file")
+    let text = std::fs::read_to_string("./config.toml").expect("Unable to read configuration file");
+    let mut config: Value = match toml::from_str(&text) {
+        Ok(config) => config,
+        Err(e) => refuse(&[format!("config.toml is not valid TOML: {e}")]),
+    };
+    let text = migration::upgrade("./config.toml", text, &mut config).unwrap_or_else(|e| refuse(&[e]));
+    for (path, value) in env_overrides().into_iter().chain(cli_overrides(std::env::args())) {
+        let shown = if config_query::is_secret(&path.join(".")) { "***".to_string() } else { value.to_string() };
+        log::info!("Config override {} = {}", path.join("."), shown);
+        set_value(&mut config, &path, parse_value(&value));
+    }
+    (config, text)
+}
+
+// Every [[instances]] entry is another controller that inherits all top-level settings it doesn't override
+fn load_instances() -> Vec<Config> {
+    let (mut base, text) = load_config();
+    let problems = schema::validate(&text, &base);
+    if !problems.is_empty() {
+        refuse(&problems);
+    }
+    let overlays = match base.as_table_mut().and_then(|t| t.remove("instances")) {
+        Some(Value::Array(overlays)) => overlays,
+        _ => Vec::new(),
+    };
+    let mut configs = vec![base.clone()];
+    for overlay in overlays {
+        let mut config = base.clone();
+        merge(&mut config, overlay);
+        configs.push(config);
+    }
+    // What the schema can't express, e.g. the bounds a keep-out zone shape needs
+    let mut problems = Vec::new();
+    let mut instances = Vec::new();
+    for (i, config) in configs.into_iter().enumerate() {
+        match config.try_into::<Config>() {
+            Ok(config) if i == 0 => {
+                problems.extend(check_settings(&config));
+                instances.push(config);
+            }
+            Ok(config) => {
+                problems.extend(check_settings(&config).into_iter().map(|problem| format!("instances[{}]: {problem}", i - 1)));
+                instances.push(config);
+            }
+            Err(e) if i == 0 => problems.push(format!("{e}")),
+            Err(e) => problems.push(format!("instances[{}]: {e}", i - 1)),
+        }
+    }
+    if problems.is_empty() {
+        problems.extend(validate_instances(&instances).err());
+    }
+    if !problems.is_empty() {
+        refuse(&problems);
+    }
+    instances
+}
+
+// Settings that are valid one by one but not together
+fn check_settings(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    if config.metadata_redaction == Redaction::Hash && config.metadata_hash_salt.is_empty() {
+        problems.push("metadata_redaction = \"hash\" needs a metadata_hash_salt, unsalted hashes of IDs can be looked up".to_string());
+    }
+    if config.run_store.backend == RunStoreBackend::Sqlite && !cfg!(feature = "sqlite") {
+        problems.push("[run-store] backend = \"sqlite\" needs a build with the sqlite feature".to_string());
+    }
+    problems
+}
+
+fn refuse(problems: &[String]) -> ! {
+    for problem in problems {
+        log::error!("Configuration: {}", problem);
+    }
+    log::error!("Not starting: {} configuration problem(s) in config.toml", problems.len());
+    std::process::exit(1);
+}
+
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { base.insert(key, value); }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// Instances share the process, so anything they open or write must be their own
+fn validate_instances(instances: &[Config]) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for config in instances {
+        let mut exclusive = vec![
+            ("instance_name", config.instance_name.clone()),
+            ("run_history_path", config.run_history_path.clone()),
+            ("journal_path", config.journal_path.clone()),
+            ("outbox_path", config.outbox_path.clone()),
+            ("reservoir_levels_path", config.reservoir_levels_path.clone()),
+            ("wear_counters_path", config.wear_counters_path.clone()),
+            ("calibration_path", config.calibration_path.clone()),
+            ("reagent_expiry_path", config.reagent_expiry_path.clone()),
+            ("port", config.application_port_path.clone()),
+            ("port", config.pump_port_path.clone()),
+            ("port", config.router_port_path.clone()),
+        ];
+        exclusive.extend(config.console_socket_path.clone().map(|path| ("console_socket_path", path)));
+        if config.http.enabled {
+            exclusive.push(("http.bind", config.http.bind.clone()));
+        }
+        for (setting, value) in exclusive {
+            if !seen.insert((setting, value.clone())) {
+                return Err(format!("{setting} {value} is used by more than one instance (set in {})", config.instance_name));
+            }
+        }
+    }
+    Ok(())
+}
+
+// RC_PUMP_PORT_PATH sets pump_port_path, RC_SERIAL_WRITE__CHUNK_SIZE sets chunk_size in [serial-write]
+fn env_overrides() -> Vec<(Vec<String>, String)> {
+    let mut overrides: Vec<(Vec<String>, String)> = std::env::vars()
+        .filter_map(|(name, value)| {
+            let path = name.strip_prefix(ENV_PREFIX)?.split("__").map(str::to_string).collect();
+            Some((path, value))
+        })
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+// `--set serial-write.chunk_size=32`
+fn cli_overrides(args: impl Iterator<Item = String>) -> Vec<(Vec<String>, String)> {
+    let args: Vec<String> = args.collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == SET_FLAG)
+        .filter_map(|pair| {
+            let (path, value) = pair[1].split_once('=')?;
+            Some((path.split('.').map(str::to_string).collect(), value.to_string()))
+        })
+        .collect()
+}
+
+pub fn strip_cli_overrides(args: Vec<String>) -> Vec<String> {
+    let mut remaining = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == SET_FLAG {
+            args.next();
+        } else {
+            remaining.push(arg);
+        }
+    }
+    remaining
+}
+
+// Values are read as TOML where possible so numbers, booleans and arrays keep their type
+fn parse_value(raw: &str) -> Value {
+    toml::from_str::<Value>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|v| v.get("value").cloned())
+        .unwrap_or(Value::String(raw.to_string()))
+}
+
+// Path segments match keys regardless of case and of '-' versus '_'
+fn set_value(config: &mut Value, path: &[String], value: Value) {
+    let Some((segment, rest)) = path.split_first() else {
+        *config = value;
+        return;
+    };
+    let Some(table) = config.as_table_mut() else {
+        log::error!("Config override: {} is not a table", segment);
+        return;
+    };
+    let normalize = |key: &str| key.replace('-', "_").to_lowercase();
+    let key = table.keys()
+        .find(|key| normalize(key) == normalize(segment))
+        .cloned()
+        .unwrap_or_else(|| if rest.is_empty() { normalize(segment) } else { normalize(segment).replace('_', "-") });
+    let entry = table.entry(key).or_insert_with(|| Value::Table(Default::default()));
+    set_value(entry, rest, value);
 }
 
 lazy_static! {
-    pub static ref CONFIG: Config = load_config();
+    static ref INSTANCES: Vec<Config> = load_instances();
+}
+
+thread_local! {
+    static INSTANCE: Cell<usize> = const { Cell::new(0) };
+}
+
+// Resolves to the configuration of the controller instance the current thread works for
+pub struct InstanceConfig;
+
+pub static CONFIG: InstanceConfig = InstanceConfig;
+
+impl Deref for InstanceConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        &INSTANCES[INSTANCE.with(Cell::get)]
+    }
+}
+
+pub fn instance_count() -> usize {
+    INSTANCES.len()
+}
+
+pub fn select_instance(name: &str) -> Result<(), String> {
+    let index = INSTANCES.iter()
+        .position(|c| c.instance_name == name)
+        .ok_or(format!("No controller instance named {name}"))?;
+    INSTANCE.with(|i| i.set(index));
+    Ok(())
+}
+
+// Runs a whole controller instance on its own thread, named after the instance for the log
+pub fn spawn_instance<F: FnOnce() + Send + 'static>(index: usize, f: F) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name(INSTANCES[index].instance_name.clone())
+        .spawn(move || {
+            INSTANCE.with(|i| i.set(index));
+            f()
+        })
+        .expect("Failed to start controller instance")
+}
+
+// Threads started by an instance keep working for that instance
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> JoinHandle<()> {
+    let index = INSTANCE.with(Cell::get);
+    let mut builder = thread::Builder::new();
+    if let Some(name) = thread::current().name() {
+        builder = builder.name(name.to_string());
+    }
+    builder
+        .spawn(move || {
+            INSTANCE.with(|i| i.set(index));
+            f()
+        })
+        .expect("Failed to start thread")
 }
@@ -0,0 +1,62 @@
+use toml::Value;
+
+use crate::calibration::Calibration;
+use crate::config::CONFIG;
+
+pub const PREFIX: &str = "GETCONF_";
+
+// GETCONF_<key> and GET /config/<key>: the configured values under a dotted key as in config.toml,
+// e.g. wash-station.position or tube-holder-coordinates.3, one <key>=<value> per leaf so an
+// application can check the deck against its own layout. tube.<tube> is where the controller moves
+// to for a tube, rack tubes and probed offsets included. Tokens and webhook URLs are masked.
+pub fn lookup(key: &str, calibration: &Calibration) -> Result<Vec<String>, String> {
+    if let Some(tube) = key.strip_prefix("tube.") {
+        let position = calibration.tube_position(tube)?;
+        return Ok(vec![format!("{key}.x={}", position.x), format!("{key}.y={}", position.y), format!("{key}.z={}", position.z)]);
+    }
+    let root = Value::try_from(&*CONFIG).map_err(|e| e.to_string())?;
+    let mut value = &root;
+    let mut path = Vec::new();
+    for segment in key.split('.').filter(|segment| !segment.is_empty()) {
+        let (name, child) = child(value, segment).ok_or(format!("unknown configuration key {key}"))?;
+        path.push(name);
+        value = child;
+    }
+    if path.is_empty() {
+        return Err(format!("{PREFIX} needs a key, e.g. {PREFIX}wash-station"));
+    }
+    let mut fields = Vec::new();
+    flatten(&path.join("."), value, &mut fields);
+    Ok(fields)
+}
+
+// Section and field names are snake_case once loaded, while the file spells some in kebab-case
+fn child<'a>(value: &'a Value, segment: &str) -> Option<(String, &'a Value)> {
+    match value {
+        Value::Table(table) => {
+            let name = [segment.to_string(), segment.replace('-', "_")].into_iter().find(|name| table.contains_key(name))?;
+            let child = &table[&name];
+            Some((name, child))
+        }
+        Value::Array(items) => Some((segment.to_string(), items.get(segment.parse::<usize>().ok()?)?)),
+        _ => None,
+    }
+}
+
+fn flatten(path: &str, value: &Value, fields: &mut Vec<String>) {
+    match value {
+        Value::Table(table) if !table.is_empty() => {
+            table.iter().for_each(|(name, child)| flatten(&format!("{path}.{name}"), child, fields));
+        }
+        Value::Array(items) if !items.is_empty() => {
+            items.iter().enumerate().for_each(|(i, child)| flatten(&format!("{path}.{i}"), child, fields));
+        }
+        Value::Table(_) => fields.push(format!("{path}={{}}")),
+        _ if is_secret(path) => fields.push(format!("{path}=***")),
+        _ => fields.push(format!("{path}={value}")),
+    }
+}
+
+pub fn is_secret(path: &str) -> bool {
+    path.ends_with("token") || path.ends_with("salt") || (path.starts_with("notifications.webhooks.") && path.ends_with(".url"))
+}
@@ -0,0 +1,92 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+
+use crate::bus::BusHandle;
+use crate::config;
+use crate::config::CONFIG;
+use crate::status::SharedStatus;
+use crate::{bus, logtail, status};
+
+const SD_LISTEN_FDS_START: i32 = 3;
+const DEFAULT_TAIL: usize = 20;
+const HELP: &str = "commands: status | queue | slots | log tail [n] | help | <controller command or control word>";
+
+// Operator console on a UNIX socket, e.g. `socat - UNIX-CONNECT:/run/rusty_controller.sock`
+pub fn spawn_socket_console(bus: BusHandle, status: SharedStatus) {
+    let listener = match listener() {
+        Some(Ok(listener)) => listener,
+        Some(Err(e)) => {
+            log::error!("Failed to open console socket: {}", e);
+            return;
+        }
+        None => return,
+    };
+    config::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let (bus, status) = (bus.clone(), status.clone());
+            config::spawn(move || serve(stream, bus, status));
+        }
+    });
+}
+
+// A socket passed in by systemd (LISTEN_FDS) takes precedence over console_socket_path
+fn listener() -> Option<std::io::Result<UnixListener>> {
+    let activated = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id())
+        && std::env::var("LISTEN_FDS").is_ok_and(|fds| fds == "1");
+    if activated {
+        log::info!("Console socket passed in by service manager");
+        return Some(Ok(unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) }));
+    }
+    let path = CONFIG.console_socket_path.as_ref()?;
+    std::fs::remove_file(path).ok(); // left behind by a previous run
+    log::info!("Console listening on {}", path);
+    Some(UnixListener::bind(path))
+}
+
+fn serve(stream: UnixStream, bus: BusHandle, status: SharedStatus) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    let (reply, replies) = mpsc::channel::<String>();
+    let mut reply_writer = writer.try_clone().ok();
+    config::spawn(move || {
+        for line in replies.iter() {
+            if reply_writer.as_mut().is_some_and(|w| writeln!(w, "{line}").is_err()) {
+                return;
+            }
+        }
+    });
+    for line in BufReader::new(stream).lines().map_while(Result::ok) {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let answer = match introspect(line, &status) {
+            Some(answer) => answer,
+            None => match bus::submit_line(&bus, line, reply.clone()) {
+                Ok(()) => continue,
+                Err(e) => e,
+            },
+        };
+        if writeln!(writer, "{answer}").is_err() {
+            return;
+        }
+    }
+}
+
+// Answered from the status snapshot, so they work while a run is executing
+fn introspect(line: &str, status: &SharedStatus) -> Option<String> {
+    let snapshot = status::read(status);
+    let words: Vec<&str> = line.split_whitespace().collect();
+    Some(match words[..] {
+        ["status"] => snapshot.to_string().trim_end().to_string(),
+        ["queue"] => format!("{}\nqueued_requests={}", snapshot.runs, snapshot.queued),
+        ["slots"] => format!("slot_occupancy={}ul", snapshot.slot_occupancy),
+        ["log", "tail"] => logtail::tail(DEFAULT_TAIL).join("\n"),
+        ["log", "tail", n] => logtail::tail(n.parse().unwrap_or(DEFAULT_TAIL)).join("\n"),
+        ["help"] => HELP.to_string(),
+        _ => return None,
+    })
+}
@@ -0,0 +1,72 @@
+use crate::config::{ContaminationAction, ContaminationRule, CONFIG};
+use crate::deck;
+
+pub fn reagent_class(tube: &str) -> Option<&'static str> {
+    CONFIG.reagent_classes.get(tube).map(String::as_str)
+}
+
+// First rule broken by aspirating `tube` while the needle still carries `residues`
+pub fn violated(residues: &[String], tube: &str) -> Option<&'static ContaminationRule> {
+    let class = reagent_class(tube)?;
+    CONFIG.contamination_rules.iter()
+        .find(|rule| rule.after == class && residues.contains(&rule.before))
+}
+
+// With [wash-station] on_reagent_change, any other class still on the needle calls for a wash
+pub fn reagent_changed(residues: &[String], tube: &str, command: &str) -> Option<String> {
+    if !CONFIG.wash_station.on_reagent_change {
+        return None;
+    }
+    let class = reagent_class(tube)?;
+    let previous = residues.iter().find(|residue| *residue != class)?;
+    Some(format!("{command} ({class}) follows {previous}"))
+}
+
+pub fn describe(rule: &ContaminationRule, command: &str) -> String {
+    format!("{command} ({}) must not follow {} without a wash", rule.after, rule.before)
+}
+
+// Walks the batch the way the executor will, returning notes for inserted washes or the first rejection
+pub fn validate(commands: &[&str], residues: &[String]) -> Result<Vec<String>, String> {
+    let mut residues = residues.to_vec();
+    let mut notes = Vec::new();
+    for command in commands {
+        let parts: Vec<&str> = command.split('_').collect();
+        match parts[..] {
+            ["LA", from, ..] if deck::external_channel(from).is_some() => {}
+            ["LA", from, ..] => {
+                let rule = violated(&residues, from);
+                if let Some(rule) = rule.filter(|rule| rule.action == ContaminationAction::Reject) {
+                    return Err(describe(rule, command));
+                }
+                if rule.is_some() || reagent_changed(&residues, from, command).is_some() {
+                    let action = if CONFIG.tips.enabled && CONFIG.tips.change_on_contamination { "tip change" } else { "wash" };
+                    notes.push(format!("{action} before {command}"));
+                    residues.clear();
+                }
+                if CONFIG.constant_cleaning {
+                    continue;
+                }
+                if let Some(class) = reagent_class(from) {
+                    residues.push(class.to_string());
+                }
+            }
+            ["END"] if CONFIG.end_of_run.final_wash => residues.clear(),
+            ["MIXTUBE", tube, ..] => {
+                let rule = violated(&residues, tube);
+                if let Some(rule) = rule.filter(|rule| rule.action == ContaminationAction::Reject) {
+                    return Err(describe(rule, command));
+                }
+                if rule.is_some() || reagent_changed(&residues, tube, command).is_some() {
+                    let action = if CONFIG.tips.enabled && CONFIG.tips.change_on_contamination { "tip change" } else { "wash" };
+                    notes.push(format!("{action} before {command}"));
+                }
+                // The needle is washed after mixing
+                residues.clear();
+            }
+            ["TIPCHANGE"] => residues.clear(),
+            _ => {}
+        }
+    }
+    Ok(notes)
+}
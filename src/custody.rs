@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::command_id::CommandId;
+use crate::units::Microliters;
+
+pub struct Contribution {
+    pub source: String,
+    pub volume: Microliters,
+    pub timestamp_ms: u128,
+    pub command_id: CommandId,
+}
+
+impl Display for Contribution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "src={} vol={}ul t={} cmd={}", self.source, self.volume, self.timestamp_ms, self.command_id)
+    }
+}
+
+#[derive(Default)]
+pub struct CustodyLog {
+    wells: BTreeMap<String, Vec<Contribution>>,
+}
+
+impl CustodyLog {
+    pub fn record(&mut self, destination: &str, source: &str, volume: Microliters, command_id: CommandId) {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        log::trace!("Custody: {} <- {} {}ul (cmd {})", destination, source, volume, command_id);
+        self.wells.entry(destination.to_string()).or_default().push(Contribution {
+            source: source.to_string(),
+            volume,
+            timestamp_ms,
+            command_id,
+        });
+    }
+
+    pub fn describe_well(&self, destination: &str) -> String {
+        let contributions = self.wells.get(destination)
+            .map(|c| c.iter().map(|c| c.to_string()).collect::<Vec<String>>().join("; "))
+            .unwrap_or_default();
+        format!("WELL_{destination} {contributions}").trim_end().to_string()
+    }
+}
+
+impl Display for CustodyLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let wells = self.wells.keys()
+            .map(|well| self.describe_well(well))
+            .collect::<Vec<String>>()
+            .join(" | ");
+        write!(f, "{wells}")
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{KeepOutZone, Rack, TubeHolderCoordinates, CONFIG};
+use crate::units::Millimeters;
+
+pub const HOME_POSITION: Coordinates = Coordinates { x: Millimeters(0.0), y: Millimeters(0.0), z: Millimeters(0.0) };
+pub const WASHING_POSITION: Coordinates = Coordinates { x: Millimeters(315.0), y: Millimeters(142.0), z: Millimeters(-20.0) };
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Coordinates {
+    pub x: Millimeters,
+    pub y: Millimeters,
+    pub z: Millimeters,
+}
+
+impl FromStr for Coordinates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<Millimeters> = s.split(':')
+            .map(|p| p.trim().parse::<f64>().map(Millimeters))
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Invalid coordinates: [{s}]"))?;
+        match parts[..] {
+            [x, y, z] => Ok(Coordinates { x, y, z }),
+            _ => Err(format!("Expected x:y:z coordinates, got [{s}]")),
+        }
+    }
+}
+
+impl Display for Coordinates {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.x, self.y, self.z)
+    }
+}
+
+impl KeepOutZone {
+    pub fn name(&self) -> &str {
+        match self {
+            KeepOutZone::Rectangle { name, .. } => name,
+            KeepOutZone::Cylinder { name, .. } => name,
+        }
+    }
+
+    pub fn contains(&self, p: Coordinates) -> bool {
+        self.intersects_segment(p, p)
+    }
+
+    pub fn intersects_segment(&self, a: Coordinates, b: Coordinates) -> bool {
+        let (x, y, z) = ((b.x - a.x).0, (b.y - a.y).0, (b.z - a.z).0);
+        let range = match self {
+            KeepOutZone::Rectangle { x_min, x_max, y_min, y_max, z_min, z_max, .. } => {
+                slab(a.x.0, x, x_min.0, x_max.0)
+                    .and_then(|r| intersect(r, slab(a.y.0, y, y_min.0, y_max.0)?))
+                    .and_then(|r| intersect(r, slab(a.z.0, z, z_min.0, z_max.0)?))
+            }
+            KeepOutZone::Cylinder { x: cx, y: cy, radius, z_min, z_max, .. } => {
+                circle((a.x - *cx).0, (a.y - *cy).0, x, y, radius.0)
+                    .and_then(|r| intersect(r, slab(a.z.0, z, z_min.0, z_max.0)?))
+            }
+        };
+        range.and_then(|r| intersect(r, (0.0, 1.0))).is_some()
+    }
+}
+
+// Parameter range t for which `start + t * delta` lies within [min, max]
+fn slab(start: f64, delta: f64, min: f64, max: f64) -> Option<(f64, f64)> {
+    if delta.abs() < f64::EPSILON {
+        return if start >= min && start <= max { Some((f64::NEG_INFINITY, f64::INFINITY)) } else { None };
+    }
+    let (t1, t2) = ((min - start) / delta, (max - start) / delta);
+    Some((t1.min(t2), t1.max(t2)))
+}
+
+// Parameter range t for which the xy projection lies within a circle centered at the origin
+fn circle(x: f64, y: f64, dx: f64, dy: f64, radius: f64) -> Option<(f64, f64)> {
+    let a = dx * dx + dy * dy;
+    let c = x * x + y * y - radius * radius;
+    if a < f64::EPSILON {
+        return if c <= 0.0 { Some((f64::NEG_INFINITY, f64::INFINITY)) } else { None };
+    }
+    let b = 2.0 * (x * dx + y * dy);
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    Some(((-b - root) / (2.0 * a), (-b + root) / (2.0 * a)))
+}
+
+fn intersect(a: (f64, f64), b: (f64, f64)) -> Option<(f64, f64)> {
+    let range = (a.0.max(b.0), a.1.min(b.1));
+    if range.0 <= range.1 { Some(range) } else { None }
+}
+
+impl Rack {
+    pub fn position(&self, row: u32, col: u32) -> Result<Coordinates, String> {
+        if row == 0 || row > self.rows || col == 0 || col > self.cols {
+            return Err(format!("Rack {} has no position {}:{} ({}x{} grid)", self.name, row, col, self.rows, self.cols));
+        }
+        let origin = self.origin;
+        let u = (col - 1) as f64 * self.pitch.0;
+        let v = (row - 1) as f64 * self.row_pitch.unwrap_or(self.pitch).0;
+        let (sin, cos) = self.orientation.to_radians().sin_cos();
+        Ok(Coordinates {
+            x: round(origin.x.0 + u * cos - v * sin),
+            y: round(origin.y.0 + u * sin + v * cos),
+            z: origin.z,
+        })
+    }
+
+    pub fn positions(&self) -> Vec<(String, Result<Coordinates, String>)> {
+        (1..=self.rows)
+            .flat_map(|row| (1..=self.cols).map(move |col| (row, col)))
+            .map(|(row, col)| (format!("{}:{}:{}", self.name, row, col), self.position(row, col)))
+            .collect()
+    }
+}
+
+// Keeps rotated coordinates free of floating point noise in the generated G-code
+fn round(value: f64) -> Millimeters {
+    Millimeters((value * 1000.0).round() / 1000.0)
+}
+
+// Tubes are addressed either by their number in tube-holder-coordinates or as rack:row:col
+pub fn tube_position(tube: &str) -> Result<Coordinates, String> {
+    let parts: Vec<&str> = tube.split(':').collect();
+    if let [rack, row, col] = parts[..] {
+        let rack = CONFIG.racks.iter()
+            .find(|r| r.name == rack)
+            .ok_or(format!("Unknown rack {rack} in tube address {tube}"))?;
+        let row = row.parse().map_err(|_| format!("Invalid rack row in tube address {tube}"))?;
+        let col = col.parse().map_err(|_| format!("Invalid rack column in tube address {tube}"))?;
+        return rack.position(row, col);
+    }
+    match CONFIG.tube_holder_coordinates.get(tube) {
+        Some(TubeHolderCoordinates::Position(position)) => Ok(*position),
+        Some(TubeHolderCoordinates::Label(label)) => Err(format!("Tube {tube} is labelled {label} and has no x/y/z coordinates")),
+        None => Err(format!("Couldn't find x/y/z coordinates for tube {tube}")),
+    }
+}
+
+// Valve channel of pump 1 an external reservoir is drawn from
+pub fn external_channel(source: &str) -> Option<u8> {
+    CONFIG.external_sources.get(source).copied()
+}
+
+// Every LA_ source of the batch must be an external reservoir or a tube the router can reach
+pub fn check_sources(commands: &[&str]) -> Result<(), String> {
+    let unknown: Vec<String> = commands.iter()
+        .filter_map(|command| match command.split('_').collect::<Vec<&str>>()[..] {
+            ["LA", from, ..] if external_channel(from).is_none() => tube_position(from).err().map(|e| format!("{command}: {e}")),
+            _ => None,
+        })
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    let mut external: Vec<&str> = CONFIG.external_sources.keys().map(String::as_str).collect();
+    external.sort();
+    Err(format!("unknown source: {} (external sources are {})", unknown.join("; "), external.join(", ")))
+}
+
+// Flow cell well an LA_ destination of slot:<well> targets
+pub fn well(destination: &str) -> Option<&str> {
+    destination.strip_prefix("slot:")
+}
+
+pub fn well_position(well: &str) -> Result<Coordinates, String> {
+    let settings = &CONFIG.flow_cell;
+    let offset = settings.wells.get(well).ok_or(format!("Unknown flow cell well {well}"))?.offset;
+    let position = settings.position.ok_or("No [flow-cell] position configured".to_string())?;
+    Ok(Coordinates { x: round((position.x + offset.x).0), y: round((position.y + offset.y).0), z: round((position.z + offset.z).0) })
+}
+
+// Every slot:<well> destination of the batch must be a configured well; what is dispensed into a
+// well can't be recovered
+pub fn check_destinations(commands: &[&str]) -> Result<(), String> {
+    let unknown: Vec<String> = commands.iter()
+        .filter_map(|command| {
+            let parts: Vec<&str> = command.split('_').collect();
+            let well = match parts[..] {
+                ["LA", _, destination, ..] => well(destination)?,
+                _ => return None,
+            };
+            if parts.len() > 4 {
+                return Some(format!("{command}: flow cell wells can't be recovered from"));
+            }
+            well_position(well).err().map(|e| format!("{command}: {e}"))
+        })
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    let mut wells: Vec<&str> = CONFIG.flow_cell.wells.keys().map(String::as_str).collect();
+    wells.sort();
+    Err(format!("unknown destination: {} (flow cell wells are {})", unknown.join("; "), wells.join(", ")))
+}
+
+// Every tube the slot contents of the batch would be recovered into, from an R<tube> field or
+// [slot-recovery], must be reachable before any liquid goes into the slot
+pub fn check_recoveries(commands: &[&str]) -> Result<(), String> {
+    let unknown: Vec<String> = commands.iter()
+        .filter_map(|command| {
+            let parts: Vec<&str> = command.split('_').collect();
+            let tube = match parts[..] {
+                ["LA", _, _, _, marker, ..] => marker.strip_prefix('R')?,
+                ["LA", from, ..] => CONFIG.slot_recovery.get(from)?.as_str(),
+                _ => return None,
+            };
+            tube_position(tube).err().map(|e| format!("{command}: {e}"))
+        })
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(format!("unknown recovery tube: {}", unknown.join("; ")))
+}
+
+pub fn zone_containing(p: Coordinates) -> Option<&'static KeepOutZone> {
+    CONFIG.keep_out_zones.iter().find(|zone| zone.contains(p))
+}
+
+pub fn zone_crossed(a: Coordinates, b: Coordinates) -> Option<&'static KeepOutZone> {
+    CONFIG.keep_out_zones.iter().find(|zone| zone.intersects_segment(a, b))
+}
+
+pub fn validate_deck() -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut positions: Vec<(String, Coordinates)> = CONFIG.tube_holder_coordinates.iter()
+        .filter_map(|(tube, holder)| match holder {
+            TubeHolderCoordinates::Position(position) => Some((format!("tube {tube}"), *position)),
+            TubeHolderCoordinates::Label(_) => None,
+        })
+        .collect();
+    positions.sort_by(|a, b| a.0.cmp(&b.0));
+    for rack in &CONFIG.racks {
+        if CONFIG.racks.iter().filter(|r| r.name == rack.name).count() > 1 {
+            problems.push(format!("rack name {} is defined more than once", rack.name));
+        }
+        for (address, position) in rack.positions() {
+            match position {
+                Ok(coords) => positions.push((format!("rack tube {address}"), coords)),
+                Err(e) => problems.push(format!("rack tube {address}: {e}")),
+            }
+        }
+    }
+    for (source, channel) in &CONFIG.external_sources {
+        if matches!(channel, 1 | 2) {
+            problems.push(format!("external source {source} uses valve channel {channel}, which is the {} line",
+                if *channel == 1 { "needle" } else { "slot" }));
+        }
+    }
+    for (well, settings) in &CONFIG.flow_cell.wells {
+        if settings.drain_port == 2 {
+            problems.push(format!("flow cell well {well} drains through pump 2 channel 2, which is the waste line"));
+        }
+        match well_position(well) {
+            Ok(coords) => positions.push((format!("flow cell well {well}"), coords)),
+            Err(e) => problems.push(format!("flow cell well {well}: {e}")),
+        }
+    }
+    for (source, tube) in &CONFIG.slot_recovery {
+        if let Err(e) = tube_position(tube) {
+            problems.push(format!("slot recovery of {source}: {e}"));
+        }
+    }
+    positions.push(("wash station".to_string(), CONFIG.wash_station.position));
+    positions.push(("home position".to_string(), HOME_POSITION));
+    for (label, coords) in positions {
+        if let Some(zone) = zone_containing(coords) {
+            problems.push(format!("{label} ({coords}) is inside keep-out zone '{}'", zone.name()));
+        }
+    }
+    problems
+}
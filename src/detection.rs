@@ -0,0 +1,63 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::config::{TubeDetectionMethod, CONFIG};
+use crate::deck::Coordinates;
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::pump::PumpCommand;
+use crate::units::PumpUnits;
+use crate::{unwrap_option, unwrap_result, Controller};
+
+const SENSOR_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Runs before the needle goes down into `tube`; each tube is checked once per run
+pub fn check_tube_present(controller: &mut Controller, tube: &str, position: Coordinates) -> ControlFlow<String> {
+    let settings = &CONFIG.tube_detection;
+    let sensor_missing = settings.method == TubeDetectionMethod::Sensor && !controller.firmware.tube_sensor;
+    if settings.method == TubeDetectionMethod::None || sensor_missing || controller.present_tubes.contains(tube) {
+        return ControlFlow::Continue(());
+    }
+    let present = match settings.method {
+        TubeDetectionMethod::None => true,
+        TubeDetectionMethod::Sensor => {
+            controller.router_move(Coordinates { z: position.z + settings.hover_mm, ..position })?;
+            sensor_reports_tube(controller)?
+        }
+        TubeDetectionMethod::Pressure => {
+            controller.router_move(position)?;
+            pressure_indicates_liquid(controller)?
+        }
+    };
+    if !present {
+        return ControlFlow::Break(format!("tube missing at position {tube}"));
+    }
+    log::trace!("Tube {} present", tube);
+    controller.present_tubes.insert(tube.to_string());
+    ControlFlow::Continue(())
+}
+
+fn sensor_reports_tube(controller: &mut Controller) -> ControlFlow<String, bool> {
+    let settings = &CONFIG.tube_detection;
+    flush_port(&mut controller.router_port);
+    unwrap_result!(serial_write(&mut controller.router_port, &format!("{}\r\n", settings.sensor_query)),
+        "Router - failed to query tube sensor".to_string());
+    let reply = unwrap_option!(serial_readline_timeout(&mut controller.router_port, "\r\n", SENSOR_TIMEOUT),
+        "Router - no reply from tube sensor".to_string());
+    ControlFlow::Continue(reply == settings.sensor_present_reply)
+}
+
+// Draws a few microliters and reads the pressure; with no liquid under the needle it stays near ambient
+fn pressure_indicates_liquid(controller: &mut Controller) -> ControlFlow<String, bool> {
+    let settings = &CONFIG.tube_detection;
+    let probe = match settings.probe_ul.to_pump_units() {
+        Ok(probe) => probe,
+        Err(e) => return ControlFlow::Break(format!("Tube detection probe volume: {e}")),
+    };
+    controller.pump_execute(&PumpCommand::new(1).valve_in(1).move_to(probe))?;
+    let pressure = controller.pumps.pump(1).query_position(&settings.pressure_query)
+        .and_then(|p| p.parse::<u64>().ok());
+    controller.pump_execute(&PumpCommand::new(1).valve_in(1).move_to(PumpUnits::ZERO))?;
+    let pressure = unwrap_option!(pressure, "Pump - no pressure reading for tube detection".to_string());
+    log::trace!("Tube detection pressure {}", pressure);
+    ControlFlow::Continue(pressure >= settings.min_pressure)
+}
@@ -0,0 +1,32 @@
+// Virtual serial pair the application simulator talks to on a development machine. Devices can
+// also be left out entirely with --simulate, which needs nothing from the operating system.
+#[cfg(unix)]
+pub fn setup() {
+    use std::process::Command;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use sysinfo::{ProcessExt, SystemExt};
+
+    sysinfo::System::new_all()
+        .processes_by_name("socat")
+        .for_each(|p| { p.kill(); });
+    Command::new("socat").args(["-d", "-d", "pty,raw,echo=1,link=/tmp/app1", "pty,raw,echo=1,link=/tmp/app2"])
+        .spawn().ok();
+    sleep(Duration::from_secs(1));
+}
+
+// Windows has no ptys; the pair comes from com0com, created once with e.g.
+// `setupc install PortName=COM10 PortName=COM11`, with the application simulator on COM11
+#[cfg(windows)]
+pub fn setup() {
+    use crate::config::CONFIG;
+    use crate::port_operations::same_port;
+
+    let path = &CONFIG.application_port_path;
+    match serialport::available_ports() {
+        Ok(ports) if ports.iter().any(|p| same_port(&p.port_name, path)) => {}
+        Ok(_) => log::warn!("Application port {} not found; create a com0com pair to test without the instrument link", path),
+        Err(e) => log::warn!("Cannot list serial ports: {}", e),
+    }
+}
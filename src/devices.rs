@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::ops::ControlFlow;
+
+use serialport::SerialPort;
+
+use crate::config::{DeviceSettings, CONFIG};
+use crate::port_operations::same_port;
+use crate::safe_mode::SafeMode;
+use crate::sim::{SimDevice, SimulatedPort};
+use crate::try_open_port;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceKind {
+    Thermal,
+    Barcode,
+    Balance,
+    Shaker,
+}
+
+impl DeviceKind {
+    const ALL: [DeviceKind; 4] = [DeviceKind::Thermal, DeviceKind::Barcode, DeviceKind::Balance, DeviceKind::Shaker];
+
+    // The device's table under [devices]
+    pub fn key(&self) -> &'static str {
+        match self {
+            DeviceKind::Thermal => "thermal",
+            DeviceKind::Barcode => "barcode",
+            DeviceKind::Balance => "balance",
+            DeviceKind::Shaker => "shaker",
+        }
+    }
+
+    pub fn on_port(port_path: &str) -> Option<DeviceKind> {
+        DeviceKind::ALL.into_iter().find(|kind| kind.settings().is_some_and(|s| same_port(&s.port_path, port_path)))
+    }
+
+    fn settings(&self) -> Option<&'static DeviceSettings> {
+        match self {
+            DeviceKind::Thermal => CONFIG.devices.thermal.as_ref(),
+            DeviceKind::Barcode => CONFIG.devices.barcode.as_ref(),
+            DeviceKind::Balance => CONFIG.devices.balance.as_ref(),
+            DeviceKind::Shaker => CONFIG.devices.shaker.as_ref(),
+        }
+    }
+}
+
+impl Display for DeviceKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DeviceKind::Thermal => "temperature controller",
+            DeviceKind::Barcode => "barcode scanner",
+            DeviceKind::Balance => "balance",
+            DeviceKind::Shaker => "shaker",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// Peripherals beyond the pump and router; missing optional ones only disable the commands that use them
+#[derive(Default)]
+pub struct Devices {
+    ports: HashMap<DeviceKind, Box<dyn SerialPort>>,
+}
+
+impl Devices {
+    // A required device that can't be opened puts the controller in safe mode
+    pub fn open(safe_mode: &mut SafeMode) -> Devices {
+        let mut devices = Devices::default();
+        for kind in DeviceKind::ALL {
+            let Some(settings) = kind.settings() else {
+                continue;
+            };
+            match try_open_port(&settings.port_path, settings.baud_rate) {
+                Ok(port) => {
+                    log::info!("{} found on {}", kind, settings.port_path);
+                    devices.ports.insert(kind, port);
+                }
+                Err(e) if settings.optional => log::warn!("Optional {} unavailable, commands needing it will be refused: {}", kind, e),
+                Err(e) => safe_mode.record(&kind.to_string(), e),
+            }
+        }
+        devices
+    }
+
+    // Every configured device, answering like the real one where the simulator knows how
+    pub fn simulated() -> Devices {
+        let mut devices = Devices::default();
+        for kind in DeviceKind::ALL {
+            let Some(settings) = kind.settings() else {
+                continue;
+            };
+            let device = match kind {
+                DeviceKind::Barcode => SimDevice::Barcode,
+                DeviceKind::Shaker => SimDevice::Shaker,
+                DeviceKind::Thermal => SimDevice::Thermal,
+                _ => SimDevice::Application,
+            };
+            devices.ports.insert(kind, SimulatedPort::open(&settings.port_path, device));
+        }
+        devices
+    }
+
+    pub fn get(&mut self, kind: DeviceKind) -> Option<&mut Box<dyn SerialPort>> {
+        self.ports.get_mut(&kind)
+    }
+
+    pub fn require(&mut self, kind: DeviceKind, command: &str) -> ControlFlow<String, &mut Box<dyn SerialPort>> {
+        match self.ports.get_mut(&kind) {
+            Some(port) => ControlFlow::Continue(port),
+            None => ControlFlow::Break(format!("{command} needs the {kind}, which was not available at startup")),
+        }
+    }
+}
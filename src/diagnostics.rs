@@ -0,0 +1,164 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::config::CONFIG;
+use crate::port_operations::{flush_port, serial_readline_timeout, unlogged_serial_write};
+use crate::pump;
+use crate::pump::{PumpError, PumpStatus};
+
+const VALVE_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+const PUMP_ADDRESSES: [char; 15] = ['1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?'];
+
+#[derive(Debug)]
+pub enum PumpDiagnosis {
+    NoPower,
+    WrongAddress { responding: Vec<char> },
+    NoFirmwareReply,
+    PlungerJam(PumpError),
+    ValveJam,
+    DeviceError(PumpError),
+    StillBusy(Duration),
+    // Liquid left after a restart could not be pushed to the waste port
+    NotEmptied(Option<PumpError>),
+    Unknown(String),
+}
+
+impl Display for PumpDiagnosis {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PumpDiagnosis::NoPower => write!(f, "no pump answers on any address - check power and cabling"),
+            PumpDiagnosis::WrongAddress { responding } => {
+                write!(f, "pump does not answer on its address, but pumps answer on {responding:?} - check address switch")
+            }
+            PumpDiagnosis::NoFirmwareReply => write!(f, "pump answers status queries but not firmware query - unsupported firmware"),
+            PumpDiagnosis::PlungerJam(e) => write!(f, "plunger cannot move ({e:?}) - check syringe and plunger lock"),
+            PumpDiagnosis::ValveJam => write!(f, "valve overload during valve test - valve jammed"),
+            PumpDiagnosis::DeviceError(e) => write!(f, "pump reports error {e:?}"),
+            PumpDiagnosis::StillBusy(timeout) => write!(f, "pump still busy {}s after initialization - check syringe and valve", timeout.as_secs()),
+            PumpDiagnosis::NotEmptied(Some(e)) => write!(f, "syringe could not be emptied to the waste port ({e:?}) - check the waste line"),
+            PumpDiagnosis::NotEmptied(None) => write!(f, "syringe not emptied to the waste port in time - check the waste line"),
+            PumpDiagnosis::Unknown(reply) => write!(f, "unexpected reply [{reply}]"),
+        }
+    }
+}
+
+// The initialization must be acknowledged without an error and the pump then report ready within `timeout`
+pub fn check_pump_init(port: &mut Box<dyn SerialPort>, address: char, timeout: Duration) -> Result<PumpStatus, PumpDiagnosis> {
+    let reply = match pump::read_reply(port, address) {
+        Some(reply) => reply,
+        None => return Err(diagnose_pump(port, address)),
+    };
+    match pump::parse_status(&reply) {
+        Some(status) if status.error == PumpError::None => {}
+        Some(_) => return Err(diagnose_pump(port, address)),
+        None => return Err(PumpDiagnosis::Unknown(reply)),
+    }
+    match pump::wait_ready(port, address, timeout) {
+        Some(status) if status.ready && status.error == PumpError::None => Ok(status),
+        Some(status) if !status.ready => Err(PumpDiagnosis::StillBusy(timeout)),
+        _ => Err(diagnose_pump(port, address)),
+    }
+}
+
+pub fn diagnose_pump(port: &mut Box<dyn SerialPort>, address: char) -> PumpDiagnosis {
+    log::info!("Running diagnosis of pump {}", address);
+    let responding: Vec<char> = PUMP_ADDRESSES.iter()
+        .copied()
+        .filter(|a| pump::query_status(port, *a).is_some())
+        .collect();
+    if responding.is_empty() {
+        return PumpDiagnosis::NoPower;
+    }
+    if !responding.contains(&address) {
+        return PumpDiagnosis::WrongAddress { responding };
+    }
+    match pump::query_firmware(port, address) {
+        Some(version) => log::info!("Pump {} firmware: {}", address, version),
+        None => return PumpDiagnosis::NoFirmwareReply,
+    }
+    let status = pump::query_status(port, address);
+    if let Some(PumpStatus { error: e @ (PumpError::Initialization | PumpError::PlungerOverload), .. }) = status {
+        return PumpDiagnosis::PlungerJam(e);
+    }
+    let valve_test = pump::query(port, address, "I1R")
+        .and_then(|_| pump::wait_ready(port, address, VALVE_TEST_TIMEOUT));
+    match (valve_test, status) {
+        (Some(PumpStatus { error: PumpError::ValveOverload, .. }), _) => PumpDiagnosis::ValveJam,
+        (Some(PumpStatus { error: PumpError::None, .. }), Some(s)) if s.error != PumpError::None => PumpDiagnosis::DeviceError(s.error),
+        (Some(PumpStatus { error: PumpError::None, .. }), _) => PumpDiagnosis::Unknown("pump is healthy but did not confirm initialization".to_string()),
+        (Some(s), _) => PumpDiagnosis::DeviceError(s.error),
+        (None, _) => PumpDiagnosis::Unknown("no reply to valve test".to_string()),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SelftestReport {
+    pub sent: u64,
+    pub ok: u64,
+    pub garbled: u64,
+    pub dropped: u64,
+    pub write_failures: u64,
+    pub min_latency: Option<Duration>,
+    pub max_latency: Option<Duration>,
+    pub total_latency: Duration,
+}
+
+impl SelftestReport {
+    pub fn success_rate(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.ok as f64 * 100.0 / self.sent as f64
+    }
+
+    fn record_latency(&mut self, latency: Duration) {
+        self.min_latency = Some(self.min_latency.map_or(latency, |m| m.min(latency)));
+        self.max_latency = Some(self.max_latency.map_or(latency, |m| m.max(latency)));
+        self.total_latency += latency;
+    }
+}
+
+impl Display for SelftestReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let avg = if self.ok > 0 { self.total_latency / self.ok as u32 } else { Duration::ZERO };
+        write!(f, "sent {}, ok {}, garbled {}, dropped {}, write failures {}, success {:.3}%, latency min/avg/max {:?}/{:?}/{:?}",
+               self.sent, self.ok, self.garbled, self.dropped, self.write_failures, self.success_rate(),
+               self.min_latency.unwrap_or_default(), avg, self.max_latency.unwrap_or_default())
+    }
+}
+
+pub fn router_selftest(port: &mut Box<dyn SerialPort>, duration: Duration) -> SelftestReport {
+    let settings = &CONFIG.router_selftest;
+    let query = format!("{}\r\n", settings.query);
+    let reply_timeout = Duration::from_millis(settings.reply_timeout_ms);
+    let mut report = SelftestReport::default();
+    log::info!("Running router self-test for {:?}", duration);
+    flush_port(port);
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        report.sent += 1;
+        let sent_at = Instant::now();
+        if unlogged_serial_write(port, &query).is_err() {
+            report.write_failures += 1;
+            continue;
+        }
+        match serial_readline_timeout(port, "\r\n", reply_timeout) {
+            Some(reply) if reply == settings.expected_reply => {
+                report.ok += 1;
+                report.record_latency(sent_at.elapsed());
+            }
+            Some(reply) => {
+                log::warn!("Garbled router reply: [{}]", reply.escape_debug());
+                report.garbled += 1;
+            }
+            None => {
+                report.dropped += 1;
+                // A late reply would be mistaken for the next one
+                flush_port(port);
+            }
+        }
+    }
+    report
+}
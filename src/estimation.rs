@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use crate::config::{OverRangePolicy, CONFIG};
+use crate::deck::Coordinates;
+use crate::pump::MAX_STROKE_MICROLITER;
+use crate::units::Microliters;
+use crate::{deck, motion, units};
+
+pub const CLEANING_SOURCE: &str = "cleaning water";
+
+#[derive(Default, Debug, Clone)]
+pub struct VolumeReport {
+    pub consumption: BTreeMap<String, Microliters>,
+    pub waste: Microliters,
+}
+
+impl VolumeReport {
+    pub fn consume(&mut self, source: &str, microliters: Microliters) {
+        *self.consumption.entry(source.to_string()).or_default() += microliters;
+    }
+
+    // Liquid put back where it came from
+    pub fn give_back(&mut self, source: &str, microliters: Microliters) {
+        if let Some(consumed) = self.consumption.get_mut(source) {
+            *consumed = consumed.saturating_sub(microliters);
+        }
+    }
+
+    pub fn discard(&mut self, microliters: Microliters) {
+        self.waste += microliters;
+    }
+
+    pub fn check_waste_capacity(&self) -> Option<String> {
+        let capacity = CONFIG.waste_capacity_ul?;
+        if self.waste > capacity {
+            return Some(format!("Protocol generates {} ul of waste but waste capacity is {} ul", self.waste, capacity));
+        }
+        None
+    }
+}
+
+impl Display for VolumeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let consumption = self.consumption.iter()
+            .map(|(source, vol)| format!("{source}: {vol} ul"))
+            .collect::<Vec<String>>()
+            .join(", ");
+        write!(f, "consumption [{}], waste {} ul", consumption, self.waste)
+    }
+}
+
+pub fn split_volume(vol_microliter: Microliters) -> Vec<Microliters> {
+    let mut cycles = vec![MAX_STROKE_MICROLITER; (vol_microliter.0 / MAX_STROKE_MICROLITER.0) as usize];
+    let remainder = vol_microliter.0 % MAX_STROKE_MICROLITER.0;
+    if remainder > 0 {
+        cycles.push(Microliters(remainder));
+    }
+    cycles
+}
+
+pub fn tube_label(from: &str) -> String {
+    format!("tube {from}")
+}
+
+pub fn estimate_protocol(commands: &[&str], slot_occupancy: Microliters) -> VolumeReport {
+    let mut report = VolumeReport::default();
+    let mut slot = slot_occupancy;
+    // Recovered slot contents never reach the waste
+    let mut recovered = false;
+    let mut wells: BTreeMap<&str, Microliters> = BTreeMap::new();
+    for command in commands {
+        let parts: Vec<&str> = command.split('_').collect();
+        if parts.first() == Some(&"MIXTUBE") {
+            report.consume(CLEANING_SOURCE, CONFIG.wash_station.flush_ul);
+            report.discard(CONFIG.wash_station.flush_ul);
+            continue;
+        }
+        if parts.first() != Some(&"LA") {
+            continue;
+        }
+        let (Some(from), Some(vol)) = (parts.get(1), units::volume_field(command).and_then(|v| units::parse_volume(v).ok())) else {
+            continue;
+        };
+        let (vol, cycles) = match CONFIG.over_range_policy {
+            _ if vol <= MAX_STROKE_MICROLITER => (vol, 1),
+            OverRangePolicy::Clamp => (MAX_STROKE_MICROLITER, 1),
+            OverRangePolicy::Split | OverRangePolicy::Reject => (vol, split_volume(vol).len() as u64),
+        };
+        let washes = if CONFIG.wash_between_cycles { cycles } else { 1 };
+        if let Some(well) = parts.get(2).and_then(|destination| deck::well(destination)) {
+            // Only the well being filled is drained, and the needle dispenses whatever the source
+            report.discard(wells.insert(well, vol).unwrap_or_default());
+            report.consume(&tube_label(from), vol);
+            if CONFIG.constant_cleaning {
+                report.consume(CLEANING_SOURCE, CONFIG.wash_station.flush_ul * washes);
+                report.discard(CONFIG.wash_station.flush_ul * washes);
+            }
+            continue;
+        }
+        if !recovered {
+            report.discard(slot);
+        }
+        report.consume(&tube_label(from), vol);
+        slot = vol;
+        recovered = parts.get(4).is_some_and(|marker| marker.starts_with('R')) || CONFIG.slot_recovery.contains_key(*from);
+        let is_external = deck::external_channel(from).is_some();
+        if !is_external && CONFIG.constant_cleaning {
+            report.consume(CLEANING_SOURCE, CONFIG.wash_station.flush_ul * washes);
+            report.discard(CONFIG.wash_station.flush_ul * washes);
+        }
+    }
+    // Slot and wells are drained once the whole message is executed
+    if !recovered {
+        report.discard(slot);
+    }
+    wells.into_values().for_each(|well| report.discard(well));
+    report
+}
+
+// Router travel and wait steps only; pump strokes are not modelled
+pub fn estimate_duration(commands: &[&str], position: Coordinates) -> Duration {
+    let mut position = position;
+    let mut total = Duration::ZERO;
+    for command in commands {
+        let parts: Vec<&str> = command.split('_').collect();
+        match parts[..] {
+            ["W", ms, ..] => total += Duration::from_millis(ms.parse().unwrap_or(0)),
+            ["LA", from, destination, ..] => {
+                let tube = deck::tube_position(from).ok();
+                let well = deck::well(destination).and_then(|well| deck::well_position(well).ok());
+                if tube.is_none() && well.is_none() {
+                    continue;
+                }
+                for target in tube.into_iter().chain(well) {
+                    total += travel(&mut position, target);
+                    total += travel(&mut position, Coordinates { z: motion::SAFE_Z, ..target });
+                }
+                if CONFIG.constant_cleaning {
+                    total += travel(&mut position, CONFIG.wash_station.position);
+                }
+            }
+            ["MIXTUBE", tube, ..] => {
+                let Ok(tube) = deck::tube_position(tube) else {
+                    continue;
+                };
+                total += travel(&mut position, tube);
+                total += travel(&mut position, Coordinates { z: motion::SAFE_Z, ..tube });
+                total += travel(&mut position, CONFIG.wash_station.position);
+            }
+            _ => {}
+        }
+    }
+    total
+}
+
+fn travel(position: &mut Coordinates, target: Coordinates) -> Duration {
+    let path = motion::plan_move(*position, target).unwrap_or(vec![target]);
+    let duration = motion::path_duration(*position, &path);
+    *position = target;
+    duration
+}
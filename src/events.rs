@@ -0,0 +1,96 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::config::CONFIG;
+use crate::notifications::json_string;
+
+// Machine-readable record of what the controller does, one JSON object per line, e.g.
+// {"event":"dispense","wall_ms":1715000000123,"mono_ms":81234,"run_ms":5120,"destination":"A1",...}
+// mono_ms never jumps with the wall clock and run_ms is counted from the start of the current run,
+// so upstream software can line its own data (e.g. camera frames) up with liquid applications.
+pub struct EventLog {
+    sender: Option<Sender<String>>,
+    origin: Instant,
+    run_started: Option<Instant>,
+}
+
+impl EventLog {
+    // Events are written on a background thread; without a file or socket configured they are dropped
+    pub fn open() -> EventLog {
+        let settings = &CONFIG.event_log;
+        let mut log = EventLog { sender: None, origin: Instant::now(), run_started: None };
+        if settings.file.is_none() && settings.socket.is_none() {
+            return log;
+        }
+        let file = settings.file.as_ref().and_then(|path| {
+            OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| log::error!("Failed to open event log {}: {}", path, e))
+                .ok()
+        });
+        let subscribers: Arc<Mutex<Vec<TcpStream>>> = Arc::default();
+        if let Some(address) = &settings.socket {
+            match TcpListener::bind(address) {
+                Ok(listener) => {
+                    log::info!("Event stream listening on {}", address);
+                    let subscribers = subscribers.clone();
+                    config::spawn(move || {
+                        for stream in listener.incoming().map_while(Result::ok) {
+                            log::info!("Event stream subscriber connected from {:?}", stream.peer_addr());
+                            subscribers.lock().unwrap_or_else(|e| e.into_inner()).push(stream);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Failed to open event stream socket {}: {}", address, e),
+            }
+        }
+        let (sender, events) = channel::<String>();
+        config::spawn(move || {
+            let mut file = file;
+            for line in events.iter() {
+                if let Some(f) = file.as_mut() {
+                    if let Err(e) = writeln!(f, "{line}") {
+                        log::error!("Failed to write event log: {}", e);
+                        file = None;
+                    }
+                }
+                // Subscribers that went away are dropped
+                subscribers.lock().unwrap_or_else(|e| e.into_inner()).retain_mut(|s| writeln!(s, "{line}").is_ok());
+            }
+        });
+        log.sender = Some(sender);
+        log
+    }
+
+    pub fn start_run(&mut self, fields: &[(&str, String)]) {
+        self.run_started = Some(Instant::now());
+        self.emit("run_start", fields);
+    }
+
+    pub fn end_run(&mut self, fields: &[(&str, String)]) {
+        self.emit("run_end", fields);
+        self.run_started = None;
+    }
+
+    pub fn emit(&self, event: &str, fields: &[(&str, String)]) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let now = Instant::now();
+        let wall_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let mut line = format!("{{\"event\":{},\"instance\":{},\"wall_ms\":{},\"mono_ms\":{}",
+            json_string(event), json_string(&CONFIG.instance_name), wall_ms, now.duration_since(self.origin).as_millis());
+        if let Some(started) = self.run_started {
+            line += &format!(",\"run_ms\":{}", now.duration_since(started).as_millis());
+        }
+        for (key, value) in fields {
+            line += &format!(",{}:{}", json_string(key), json_string(value));
+        }
+        line.push('}');
+        sender.send(line).ok();
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::ControlFlow;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::Controller;
+
+const OVERRIDE_PREFIX: &str = "USEEXPIRED_";
+
+// Reagents in [reagent-expiry] are only good for so many minutes after they were first aspirated,
+// e.g. developer once it is mixed. First uses are kept across restarts in reagent_expiry_path until
+// REPLACED_<tube> says a fresh one was loaded.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ReagentExpiry {
+    // Unix seconds
+    first_used: BTreeMap<String, u64>,
+    // USEEXPIRED_<tube> of the running message
+    #[serde(skip)]
+    overridden: BTreeSet<String>,
+}
+
+impl ReagentExpiry {
+    pub fn load() -> ReagentExpiry {
+        let Ok(text) = std::fs::read_to_string(&CONFIG.reagent_expiry_path) else {
+            return ReagentExpiry::default();
+        };
+        toml::from_str(&text)
+            .map_err(|e| log::error!("Ignoring unreadable reagent first uses {}: {}", CONFIG.reagent_expiry_path, e))
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        let result = toml::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(&CONFIG.reagent_expiry_path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::error!("Failed to write reagent first uses {}: {}", CONFIG.reagent_expiry_path, e);
+        }
+    }
+
+    // After an aspiration from `tube`; only the first one starts the clock
+    pub fn record(&mut self, tube: &str) {
+        let Some(minutes) = CONFIG.reagent_expiry.minutes.get(tube) else {
+            return;
+        };
+        if self.first_used.contains_key(tube) {
+            return;
+        }
+        log::info!("Reagent in tube {} first used, expires in {} min", tube, minutes);
+        self.first_used.insert(tube.to_string(), now());
+        self.save();
+    }
+
+    // Why `tube` is no longer good, None while it is or it isn't tracked
+    pub fn expired(&self, tube: &str) -> Option<String> {
+        let minutes = *CONFIG.reagent_expiry.minutes.get(tube)?;
+        let age = now().saturating_sub(*self.first_used.get(tube)?) / 60;
+        (age >= minutes).then(|| format!("reagent in tube {tube} expired, first used {age} min ago and good for {minutes} min"))
+    }
+
+    // Takes the overrides of the message about to run
+    pub fn allow(&mut self, commands: &[&str]) {
+        self.overridden = commands.iter().filter_map(|c| c.strip_prefix(OVERRIDE_PREFIX)).map(str::to_string).collect();
+    }
+
+    // REPLACED_<tube>
+    pub fn replaced(&mut self, tube: &str) -> Result<String, String> {
+        if !CONFIG.reagent_expiry.minutes.contains_key(tube) {
+            return Err(format!("Tube {tube} has no [reagent-expiry]"));
+        }
+        self.first_used.remove(tube);
+        self.save();
+        log::info!("Reagent in tube {} replaced", tube);
+        Ok(format!("REAGENT {tube} replaced"))
+    }
+
+    pub fn describe(&self) -> String {
+        let mut tubes: Vec<&String> = CONFIG.reagent_expiry.minutes.keys().collect();
+        tubes.sort();
+        let ages: Vec<String> = tubes.iter()
+            .map(|tube| match self.first_used.get(*tube) {
+                Some(first) => format!("{tube}={}min/{}min", now().saturating_sub(*first) / 60, CONFIG.reagent_expiry.minutes[*tube]),
+                None => format!("{tube}=unused/{}min", CONFIG.reagent_expiry.minutes[*tube]),
+            })
+            .collect();
+        format!("REAGENTS [{}]", ages.join(", "))
+    }
+}
+
+// Refuses a message that would take up an expired reagent when [reagent-expiry] block is set,
+// unless it carries USEEXPIRED_<tube>
+pub fn check_batch(expiry: &ReagentExpiry, commands: &[&str]) -> Result<(), String> {
+    if !CONFIG.reagent_expiry.block {
+        return Ok(());
+    }
+    let expired: Vec<String> = commands.iter()
+        .filter_map(|command| command.strip_prefix("LA_")?.split('_').next())
+        .collect::<BTreeSet<&str>>()
+        .into_iter()
+        .filter(|tube| !commands.contains(&format!("{OVERRIDE_PREFIX}{tube}").as_str()))
+        .filter_map(|tube| expiry.expired(tube))
+        .collect();
+    if expired.is_empty() {
+        return Ok(());
+    }
+    Err(format!("{} (send {OVERRIDE_PREFIX}<tube> to use it anyway)", expired.join("; ")))
+}
+
+// Before aspirating from `tube`: a reagent that expired during the run is noted, or stops the run
+// when [reagent-expiry] block is set and the message doesn't override it
+pub fn before_use(controller: &mut Controller, tube: &str) -> ControlFlow<String> {
+    let Some(expired) = controller.expiry.expired(tube) else {
+        return ControlFlow::Continue(());
+    };
+    if CONFIG.reagent_expiry.block && !controller.expiry.overridden.contains(tube) {
+        return ControlFlow::Break(format!("{expired} (send {OVERRIDE_PREFIX}{tube} to use it anyway)"));
+    }
+    if !controller.notes.contains(&expired) {
+        log::warn!("Using {}", expired);
+        controller.events.emit("reagent_expired", &[("tube", tube.to_string())]);
+        controller.notes.push(expired);
+    }
+    ControlFlow::Continue(())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::message::{self, COMMAND_CHANNEL};
+use crate::sim::SimDevice;
+
+// Faults the simulated devices play back from the scenario given with `--simulate --faults <file>`,
+// so recovery, watchdogs and retries can be exercised the same way on every run:
+//
+//   [[faults]]
+//   device = "pump"       # pump, router, shaker, thermal or application
+//   fault = "busy"        # busy (pump only), error, no_reply or corrupt
+//   step = 7              # only triggered during step 7 of a message, as in its command ids
+//   match = "/1"          # only requests starting with this, e.g. "G1" or "/2Q"
+//   nth = 3               # triggered by the third such request since startup, or within the step
+//   times = 0             # requests affected from then on, 0 for the rest of the run
+//   code = 9              # pump error code for error, e.g. 9 for a plunger overload
+//
+// Once triggered a fault keeps affecting matching requests, whatever the step, until `times` runs out.
+// A busy pump reports itself busy to commands and polls, an error is a pump status with `code` or a
+// "<command>:ERR" from the others, and corrupt flips a bit in the middle of the reply.
+//
+// The simulated application port delivers the scenario's frames in order, each as a framed
+// channel,data,crc line `delay_ms` (of scaled time) after the one before:
+//
+//   [[frames]]
+//   data = "LA_14_1_100"  # matched by application faults
+//   channel = 4           # the command channel unless given
+//   delay_ms = 500
+//
+// An application fault affects such a frame: corrupt flips a bit in the middle of it, so the CRC
+// check fails, and no_reply loses it on the way.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    #[serde(default)]
+    faults: Vec<Fault>,
+    #[serde(default)]
+    frames: Vec<Frame>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Frame {
+    data: String,
+    #[serde(default = "command_channel")]
+    channel: i8,
+    #[serde(default)]
+    delay_ms: u64,
+}
+
+fn command_channel() -> i8 {
+    COMMAND_CHANNEL
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fault {
+    device: Device,
+    fault: Kind,
+    step: Option<usize>,
+    #[serde(default, rename = "match")]
+    prefix: String,
+    #[serde(default = "one")]
+    nth: u32,
+    #[serde(default = "one")]
+    times: u32,
+    #[serde(default = "plunger_overload")]
+    code: u8,
+    #[serde(skip)]
+    seen: u32,
+    #[serde(skip)]
+    affected: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+fn plunger_overload() -> u8 {
+    9
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Device {
+    Pump,
+    Router,
+    Shaker,
+    Thermal,
+    Application,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Busy,
+    Error,
+    NoReply,
+    Corrupt,
+}
+
+// What a simulated device does with the request instead of answering normally
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Injected {
+    Busy,
+    Error(u8),
+    NoReply,
+    Corrupt,
+}
+
+static SCENARIO: Mutex<Vec<Fault>> = Mutex::new(Vec::new());
+static FRAMES: Mutex<VecDeque<Frame>> = Mutex::new(VecDeque::new());
+// Step of the running message, 0 between steps
+static STEP: AtomicUsize = AtomicUsize::new(0);
+
+pub fn load(path: &str) -> Result<(), String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read fault scenario {path}: {e}"))?;
+    let scenario: Scenario = toml::from_str(&text).map_err(|e| format!("Invalid fault scenario {path}: {e}"))?;
+    for (i, fault) in scenario.faults.iter().enumerate() {
+        if fault.fault == Kind::Busy && fault.device != Device::Pump {
+            return Err(format!("Fault {} in {path}: only pumps can be busy", i + 1));
+        }
+        if fault.nth == 0 {
+            return Err(format!("Fault {} in {path}: nth counts from 1", i + 1));
+        }
+        if fault.code > 0x0F {
+            return Err(format!("Fault {} in {path}: pump error codes go up to 15", i + 1));
+        }
+        if fault.device == Device::Application && fault.fault == Kind::Error {
+            return Err(format!("Fault {} in {path}: application frames can only be corrupt or lost (no_reply)", i + 1));
+        }
+    }
+    log::warn!("Injecting {} fault(s) and {} application frame(s) from {}", scenario.faults.len(), scenario.frames.len(), path);
+    *SCENARIO.lock().unwrap() = scenario.faults;
+    *FRAMES.lock().unwrap() = scenario.frames.into();
+    Ok(())
+}
+
+// The next scenario frame for the simulated application port: its delay in unscaled time and the
+// bytes to deliver, None when the frame is lost. The outer None ends the scenario.
+pub fn next_frame() -> Option<(Duration, Option<Vec<u8>>)> {
+    let frame = FRAMES.lock().unwrap().pop_front()?;
+    let mut bytes = message::format_message(frame.channel, &frame.data).into_bytes();
+    let delivered = match inject(SimDevice::Application, &frame.data) {
+        Some(Injected::NoReply) => None,
+        Some(Injected::Corrupt) => {
+            flip_middle_bit(&mut bytes);
+            Some(bytes)
+        }
+        _ => Some(bytes),
+    };
+    Some((Duration::from_millis(frame.delay_ms), delivered))
+}
+
+// What corrupt does to a device reply or an application frame
+pub fn flip_middle_bit(bytes: &mut [u8]) {
+    let middle = bytes.len() / 2;
+    if let Some(byte) = bytes.get_mut(middle) {
+        *byte ^= 0x01;
+    }
+}
+
+pub fn enter_step(step: usize) {
+    STEP.store(step, Ordering::Relaxed);
+    // nth counts afresh within each step until the fault was triggered
+    SCENARIO.lock().unwrap().iter_mut().filter(|fault| fault.step.is_some() && fault.seen < fault.nth).for_each(|fault| fault.seen = 0);
+}
+
+pub fn leave_step() {
+    STEP.store(0, Ordering::Relaxed);
+}
+
+// The first fault that affects this request to `device`, if any
+pub fn inject(device: SimDevice, line: &str) -> Option<Injected> {
+    let device = match device {
+        SimDevice::Pump => Device::Pump,
+        SimDevice::Router => Device::Router,
+        SimDevice::Shaker => Device::Shaker,
+        SimDevice::Thermal => Device::Thermal,
+        SimDevice::Application => Device::Application,
+        SimDevice::Barcode | SimDevice::Peripheral => return None,
+    };
+    let step = STEP.load(Ordering::Relaxed);
+    let mut scenario = SCENARIO.lock().unwrap();
+    let fault = scenario.iter_mut().find_map(|fault| {
+        if fault.device != device || !line.starts_with(&fault.prefix) || (fault.times > 0 && fault.affected >= fault.times) {
+            return None;
+        }
+        if fault.seen < fault.nth {
+            if fault.step.is_some_and(|only| only != step) {
+                return None;
+            }
+            fault.seen += 1;
+            if fault.seen < fault.nth {
+                return None;
+            }
+        }
+        fault.affected += 1;
+        Some(fault)
+    })?;
+    // A lasting fault is only worth one warning, not one per poll
+    let level = if fault.affected == 1 { log::Level::Warn } else { log::Level::Debug };
+    log::log!(level, "Injected {:?} fault: {:?} request [{}] in step {}", fault.fault, device, line.escape_debug(), step);
+    Some(match fault.fault {
+        Kind::Busy => Injected::Busy,
+        Kind::Error => Injected::Error(fault.code),
+        Kind::NoReply => Injected::NoReply,
+        Kind::Corrupt => Injected::Corrupt,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupted_frame_fails_the_crc_check() {
+        let mut frame = message::format_message(COMMAND_CHANNEL, "LA_14_1_100").into_bytes();
+        assert!(message::find_frame(std::str::from_utf8(&frame).unwrap().trim_end()).is_some());
+        flip_middle_bit(&mut frame);
+        assert_eq!(message::find_frame(std::str::from_utf8(&frame).unwrap().trim_end()), None);
+    }
+
+    #[test]
+    fn link_corruption_scenario_parses() {
+        let scenario: Scenario = toml::from_str(include_str!("../scenarios/link_corruption.toml")).unwrap();
+        assert_eq!(scenario.faults[0].device, Device::Application);
+        assert_eq!(scenario.faults[0].fault, Kind::Corrupt);
+        assert_eq!(scenario.frames.len(), 4);
+        assert!(scenario.frames.iter().all(|frame| frame.channel == COMMAND_CHANNEL));
+    }
+}
@@ -0,0 +1,59 @@
+use crate::command_id::CommandId;
+use crate::config::{ErrorHint, CONFIG};
+use crate::devices::DeviceKind;
+use crate::escape_chars;
+use crate::port_operations::{last_exchange, same_port};
+
+// Why a step failed, as far as the controller can tell: the device it talked to last and its reply
+pub struct Fault {
+    step: String,
+    command_id: CommandId,
+    device: String,
+    response: Option<String>,
+    message: String,
+}
+
+impl Fault {
+    pub fn new(step: &str, command_id: CommandId, message: &str) -> Fault {
+        let exchange = last_exchange();
+        Fault {
+            step: step.to_string(),
+            command_id,
+            device: exchange.as_ref().map_or("controller".to_string(), |x| device_name(&x.port)),
+            response: exchange.and_then(|x| x.reply),
+            message: message.to_string(),
+        }
+    }
+
+    fn hint(&self) -> Option<&'static ErrorHint> {
+        let message = self.message.to_lowercase();
+        let response = self.response.as_deref().unwrap_or_default().to_lowercase();
+        CONFIG.error_hints.iter().find(|hint| {
+            hint.device.as_ref().is_none_or(|device| device.eq_ignore_ascii_case(&self.device))
+                && hint.contains.as_ref().map(|text| text.to_lowercase())
+                    .is_none_or(|text| message.contains(&text) || response.contains(&text))
+        })
+    }
+
+    // FAULT step=LA_3_1_100 command_id=mf3k2a-4.2 device=pump hint=CHECK_PUMP response=[/0i] message=Pump 1 error: ...
+    pub fn frame(&self) -> String {
+        let hint = self.hint();
+        if let Some(hint) = hint.filter(|h| !h.description.is_empty()) {
+            log::warn!("Remediation for the failed {}: {}", self.step, hint.description);
+        }
+        format!("FAULT step={} command_id={} device={} hint={} response={} message={}", self.step, self.command_id, self.device,
+                hint.map_or("NONE", |h| &h.code),
+                self.response.as_ref().map_or("none".to_string(), |r| format!("[{}]", escape_chars(r))),
+                escape_chars(&self.message))
+    }
+}
+
+fn device_name(port: &str) -> String {
+    if same_port(port, &CONFIG.router_port_path) {
+        return "router".to_string();
+    }
+    if same_port(port, &CONFIG.pump_port_path) {
+        return "pump".to_string();
+    }
+    DeviceKind::on_port(port).map_or(port.to_string(), |kind| kind.key().to_string())
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+use crate::config::{TubeDetectionMethod, CONFIG};
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::pump::{PumpError, VALVE_REGISTER};
+use crate::pump_bus::PUMP_ADDRESSES;
+use crate::{pump, Controller};
+
+// Firmware versions and what the firmware offers, found at startup
+#[derive(Debug, Default)]
+pub struct Firmware {
+    pub versions: BTreeMap<String, String>,
+    // Reported by the router as "Cap:<name>:<0|1>"; older firmware reports none
+    pub router_capabilities: BTreeMap<String, bool>,
+    pub tube_sensor: bool,
+    pub valve_query: bool,
+    pub load_register: bool,
+}
+
+// Firmware older than the configured minimum stops startup
+pub fn handshake(controller: &mut Controller) -> Result<Firmware, String> {
+    let settings = &CONFIG.firmware;
+    let mut firmware = Firmware::default();
+    // What safe mode started without has no firmware to check
+    if !controller.safe_mode.lacks("router") {
+        let (router_version, capabilities) = query_router(&mut controller.router_port);
+        log::info!("Router firmware: {}, capabilities {:?}", router_version.as_deref().unwrap_or("unknown"), capabilities);
+        check_version("Router", router_version.as_deref(), settings.min_router_version.as_deref())?;
+        firmware.versions.insert("router".to_string(), router_version.unwrap_or("unknown".to_string()));
+        firmware.tube_sensor = capabilities.get("TUBE_SENSOR").copied().unwrap_or(true);
+        firmware.router_capabilities = capabilities;
+    }
+
+    firmware.valve_query = true;
+    firmware.load_register = true;
+    let mut port = controller.pumps.lock();
+    let addresses = if controller.safe_mode.lacks("pump") { Vec::new() } else { PUMP_ADDRESSES.map(pump::address_char).to_vec() };
+    for address in addresses {
+        let version = pump::query_firmware(&mut port, address);
+        log::info!("Pump {} firmware: {}", address, version.as_deref().unwrap_or("unknown"));
+        check_version(&format!("Pump {address}"), version.as_deref(), settings.min_pump_version.as_deref())?;
+        firmware.versions.insert(format!("pump{address}"), version.unwrap_or("unknown".to_string()));
+        firmware.valve_query &= supports_register(&mut port, address, VALVE_REGISTER);
+        firmware.load_register &= supports_register(&mut port, address, &CONFIG.clog_detection.load_query);
+    }
+
+    if CONFIG.tube_detection.method == TubeDetectionMethod::Sensor && !firmware.tube_sensor {
+        log::warn!("Router firmware has no tube sensor, sensor tube detection is disabled");
+    }
+    if CONFIG.clog_detection.enabled && !firmware.load_register {
+        log::warn!("Pump firmware has no load register, clog detection is disabled");
+    }
+    if !firmware.valve_query {
+        log::warn!("Pump firmware cannot report the valve position, unclogging skips the reverse stroke");
+    }
+    Ok(firmware)
+}
+
+// Marlin style report: a FIRMWARE_NAME line, one Cap line per capability, then "ok"
+fn query_router(port: &mut Box<dyn SerialPort>) -> (Option<String>, BTreeMap<String, bool>) {
+    let settings = &CONFIG.firmware;
+    let mut version = None;
+    let mut capabilities = BTreeMap::new();
+    flush_port(port);
+    if serial_write(port, &format!("{}\r\n", settings.router_query)).is_err() {
+        return (version, capabilities);
+    }
+    while let Some(line) = serial_readline_timeout(port, "\r\n", Duration::from_millis(settings.reply_timeout_ms)) {
+        if let Some((name, enabled)) = line.strip_prefix("Cap:").and_then(|c| c.rsplit_once(':')) {
+            capabilities.insert(name.to_string(), enabled == "1");
+        } else if line.eq_ignore_ascii_case("ok") || line.ends_with(":OK") {
+            break;
+        } else if version.is_none() {
+            version = router_version(&line);
+        }
+    }
+    (version, capabilities)
+}
+
+fn router_version(line: &str) -> Option<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if let Some(version) = words.iter().find_map(|w| w.strip_prefix("FIRMWARE_VERSION:")) {
+        return Some(version.to_string());
+    }
+    // "FIRMWARE_NAME:Marlin 2.0.9 (Sep 2021) SOURCE_CODE_URL:..." runs up to the next field
+    let start = words.iter().position(|w| w.starts_with("FIRMWARE_NAME:"))?;
+    let name: Vec<&str> = std::iter::once(&words[start]["FIRMWARE_NAME:".len()..])
+        .chain(words[start + 1..].iter().copied().take_while(|w| !w.contains(':')))
+        .collect();
+    Some(name.join(" "))
+}
+
+// Pumps answer registers they don't have with an invalid command error
+fn supports_register(port: &mut Box<dyn SerialPort>, address: char, register: &str) -> bool {
+    pump::query(port, address, register)
+        .and_then(|reply| pump::parse_status(&reply))
+        .is_some_and(|status| status.error != PumpError::InvalidCommand)
+}
+
+fn check_version(device: &str, version: Option<&str>, minimum: Option<&str>) -> Result<(), String> {
+    let Some(minimum) = minimum else {
+        return Ok(());
+    };
+    let supported = match (version.and_then(version_numbers), version_numbers(minimum)) {
+        (Some(version), Some(minimum)) => compare(&version, &minimum).is_ge(),
+        _ => false,
+    };
+    if !supported {
+        return Err(format!("{} firmware {} is older than the minimum supported version {}", device, version.unwrap_or("unknown"), minimum));
+    }
+    Ok(())
+}
+
+// First dotted number in the text, e.g. [2, 0, 9] from "Marlin 2.0.9 (Sep 2021)"
+fn version_numbers(text: &str) -> Option<Vec<u64>> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let number: String = text[start..].chars().take_while(|c| c.is_ascii_digit() || *c == '.').collect();
+    number.split('.').filter(|part| !part.is_empty()).map(|part| part.parse().ok()).collect()
+}
+
+// Missing trailing numbers count as zero, so 2.1 equals 2.1.0
+fn compare(a: &[u64], b: &[u64]) -> std::cmp::Ordering {
+    let len = a.len().max(b.len());
+    let padded = |v: &[u64]| (0..len).map(|i| v.get(i).copied().unwrap_or(0)).collect::<Vec<u64>>();
+    padded(a).cmp(&padded(b))
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::deck::HOME_POSITION;
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::state::ControllerState;
+use crate::{startup, Controller};
+
+// The router answered a move with something other than its acknowledgement, so where it is and
+// what it will do next are unknown. Motion is held and queried, and every further move is refused
+// until HOME re-homes the router. Returns the error for the step.
+pub fn halt(controller: &mut Controller, command: &str, reply: &str) -> String {
+    let settings = &CONFIG.router_halt;
+    let timeout = Duration::from_millis(settings.reply_timeout_ms);
+    log::error!("Router replied [{}] to [{}], holding motion", reply.trim(), command.trim());
+    if serial_write(&mut controller.router_port, &format!("{}\r\n", settings.hold_command)).is_err() {
+        log::error!("Failed to send feed hold {}", settings.hold_command);
+    }
+    // Whatever the hold is acknowledged with goes, the position report is what's left to read
+    controller.clock.sleep(timeout);
+    flush_port(&mut controller.router_port);
+    let state = serial_write(&mut controller.router_port, &format!("{}\r\n", CONFIG.startup.position_query)).ok()
+        .and_then(|_| serial_readline_timeout(&mut controller.router_port, "\r\n", timeout));
+    let machine_state = match state.as_deref().and_then(startup::parse_position) {
+        Some(position) => {
+            // Later moves are planned from where the router actually stopped
+            controller.router.position = position;
+            position.to_string()
+        }
+        None => state.unwrap_or_else(|| "unknown".to_string()),
+    };
+    log::error!("Router halted, reported state {}", machine_state);
+    let reason = format!("reply [{}] to [{}]", reply.trim(), command.trim());
+    controller.events.emit("router_halted", &[("reason", reason.clone()), ("state", machine_state.clone())]);
+    controller.application.send_status(&format!("ROUTER HALTED state={machine_state}"));
+    controller.router.halted = Some(reason.clone());
+    format!("Router halted after {reason}, send HOME once the deck is clear")
+}
+
+// HOME control: re-homes a halted router; refused while a run is executing or without a router
+pub fn rehome(controller: &mut Controller) -> String {
+    if matches!(controller.state, ControllerState::Running | ControllerState::Paused) {
+        return "REFUSED control=HOME reason=run_in_progress".to_string();
+    }
+    if controller.safe_mode.lacks("router") {
+        return "REFUSED control=HOME reason=router_unavailable".to_string();
+    }
+    let previous = std::mem::replace(&mut controller.state, ControllerState::Homing);
+    controller.publish_status();
+    // Ends whatever partial line was sent last, e.g. an M104, so that G28 arrives on its own
+    serial_write(&mut controller.router_port, "\r\n").ok();
+    controller.clock.sleep(Duration::from_millis(CONFIG.router_halt.reply_timeout_ms));
+    flush_port(&mut controller.router_port);
+    let timeout = Duration::from_secs(CONFIG.router_halt.home_timeout_secs);
+    let reply = serial_write(&mut controller.router_port, "G28\r\n").ok()
+        .and_then(|_| serial_readline_timeout(&mut controller.router_port, "\r\n", timeout));
+    controller.state = previous;
+    match reply.as_deref() {
+        Some("G28:OK") => {
+            log::info!("Router re-homed");
+            controller.router.position = HOME_POSITION;
+            controller.router.halted = None;
+            "HOMED".to_string()
+        }
+        Some(reply) => format!("ERROR homing failed: router replied [{reply}]"),
+        None => "ERROR homing failed: no reply from router".to_string(),
+    }
+}
@@ -0,0 +1,97 @@
+use crate::config::CONFIG;
+use crate::Controller;
+
+// Version of the application protocol, i.e. the commands, controls and replies exchanged on the
+// application port; how they are framed is [framing]. Both sides introduce themselves on the
+// command channel with
+//
+//   HELLO version=<highest> [min_version=<lowest>] [capabilities=<flag>;<flag>...]
+//
+// with semicolons between the flags, since commas separate the frame's fields. The controller sends its own at startup and answers the application's with the version both
+// speak, or with "HELLO_REFUSED" when there is none. Commands from an application that offered no
+// common version are refused until it says HELLO again.
+pub const PROTOCOL_VERSION: u32 = 1;
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Default)]
+pub struct Handshake {
+    // None until the application said HELLO
+    peer: Option<Peer>,
+}
+
+struct Peer {
+    offered: u32,
+    // None when there is no version both sides speak
+    negotiated: Option<u32>,
+    capabilities: Vec<String>,
+}
+
+pub fn is_hello(data: &str) -> bool {
+    data == "HELLO" || data.starts_with("HELLO ")
+}
+
+fn capabilities() -> Vec<&'static str> {
+    let mut flags = vec!["nack", "manifest", "scripts", "metadata", "volume_units", "runs"];
+    if !CONFIG.flow_cell.wells.is_empty() {
+        flags.push("flow_cell");
+    }
+    flags
+}
+
+fn hello(version: u32) -> String {
+    format!("HELLO version={version} min_version={MIN_PROTOCOL_VERSION} framing={} capabilities={}",
+            CONFIG.framing.protocol_version, capabilities().join(";"))
+}
+
+pub fn announce(controller: &mut Controller) {
+    controller.application.send_status(&hello(PROTOCOL_VERSION));
+}
+
+// The application's HELLO; the reply goes back to it
+pub fn receive(controller: &mut Controller, data: &str) {
+    let fields: Vec<(&str, &str)> = data.split(' ').skip(1).filter_map(|field| field.split_once('=')).collect();
+    let field = |name: &str| fields.iter().find(|(key, _)| *key == name).map(|(_, value)| *value);
+    let Some(offered) = field("version").and_then(|v| v.parse::<u32>().ok()) else {
+        log::warn!("Application HELLO without a version: [{}]", data);
+        controller.application.send_status("HELLO_REFUSED reason=no_version");
+        return;
+    };
+    let lowest = field("min_version").and_then(|v| v.parse::<u32>().ok()).unwrap_or(offered);
+    let version = offered.min(PROTOCOL_VERSION);
+    let negotiated = (version >= lowest.max(MIN_PROTOCOL_VERSION)).then_some(version);
+    let capabilities: Vec<String> = field("capabilities").map_or(Vec::new(), |c| c.split(';').map(str::to_string).collect());
+    let reply = match negotiated {
+        Some(version) => {
+            log::info!("Application speaks protocol {} (offered {}), capabilities [{}]", version, offered, capabilities.join(", "));
+            hello(version)
+        }
+        None => {
+            log::error!("Application offered protocol {}-{}, controller speaks {}-{}", lowest, offered, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+            format!("HELLO_REFUSED reason=incompatible_version offered={lowest}-{offered} supported={MIN_PROTOCOL_VERSION}-{PROTOCOL_VERSION}")
+        }
+    };
+    controller.events.emit("hello", &[("offered", offered.to_string()), ("negotiated", negotiated.map_or("none".to_string(), |v| v.to_string()))]);
+    controller.handshake.peer = Some(Peer { offered, negotiated, capabilities });
+    controller.application.send_status(&reply);
+}
+
+impl Handshake {
+    // Why commands from the application port can't be executed, None when they can
+    pub fn refusal(&self) -> Option<String> {
+        match &self.peer {
+            None if CONFIG.handshake.required => Some("no HELLO from the application yet".to_string()),
+            Some(peer) if peer.negotiated.is_none() => Some(format!(
+                "incompatible protocol version {} (controller speaks {MIN_PROTOCOL_VERSION}-{PROTOCOL_VERSION}), send HELLO", peer.offered)),
+            _ => None,
+        }
+    }
+
+    // "protocol=1 capabilities=[...]" for state queries
+    pub fn describe(&self) -> String {
+        match &self.peer {
+            None => "protocol=none".to_string(),
+            Some(Peer { negotiated: None, offered, .. }) => format!("protocol=incompatible({offered})"),
+            Some(Peer { negotiated: Some(version), capabilities, .. }) => format!("protocol={version} capabilities=[{}]", capabilities.join(";")),
+        }
+    }
+}
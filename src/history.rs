@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::run_store;
+
+// One entry per executed message, kept by the [run-store] backend
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunRecord {
+    pub started: u64,
+    pub duration_secs: u64,
+    pub tenant: Option<String>,
+    pub outcome: String,
+    pub commands: usize,
+    pub consumed_ul: u64,
+    pub waste_ul: u64,
+    // Unredacted, which is why the history file is only readable by the controller's user
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl RunRecord {
+    pub fn succeeded(&self) -> bool {
+        self.outcome.starts_with("ACK")
+    }
+}
+
+impl Display for RunRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RUN started={} tenant={} duration={}s commands={} outcome={}",
+               self.started, self.tenant.as_deref().unwrap_or("-"), self.duration_secs, self.commands, self.outcome)?;
+        if !self.metadata.is_empty() {
+            write!(f, " metadata {}", metadata_text(&self.metadata))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn append(record: RunRecord) -> Result<(), String> {
+    run_store::open().append(&record)
+}
+
+pub fn load() -> Result<Vec<RunRecord>, String> {
+    run_store::open().load()
+}
+
+pub fn for_tenant(runs: Vec<RunRecord>, tenant: Option<&str>) -> Vec<RunRecord> {
+    match tenant {
+        Some(tenant) => runs.into_iter().filter(|r| r.tenant.as_deref() == Some(tenant)).collect(),
+        None => runs,
+    }
+}
+
+// Runs whose metadata has `value` under `key`, also as one entry of a comma list like samples
+pub fn with_metadata(runs: Vec<RunRecord>, key: &str, value: &str) -> Vec<RunRecord> {
+    runs.into_iter()
+        .filter(|r| r.metadata.get(key).is_some_and(|v| v == value || v.split(',').any(|entry| entry == value)))
+        .collect()
+}
+
+fn metadata_text(metadata: &BTreeMap<String, String>) -> String {
+    metadata.iter().map(|(key, value)| format!("{key}={value}")).collect::<Vec<String>>().join(" ")
+}
+
+pub struct RunStats {
+    pub tenant: Option<String>,
+    pub runs: usize,
+    pub failed: usize,
+    pub runtime_secs: u64,
+    pub consumed_ul: u64,
+    pub waste_ul: u64,
+}
+
+impl RunStats {
+    pub fn of(runs: &[RunRecord], tenant: Option<&str>) -> RunStats {
+        RunStats {
+            tenant: tenant.map(str::to_string),
+            runs: runs.len(),
+            failed: runs.iter().filter(|r| !r.succeeded()).count(),
+            runtime_secs: runs.iter().map(|r| r.duration_secs).sum(),
+            consumed_ul: runs.iter().map(|r| r.consumed_ul).sum(),
+            waste_ul: runs.iter().map(|r| r.waste_ul).sum(),
+        }
+    }
+}
+
+impl Display for RunStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "STATS tenant={} runs={} failed={} runtime={}s consumed={}ul waste={}ul",
+               self.tenant.as_deref().unwrap_or("all"), self.runs, self.failed, self.runtime_secs, self.consumed_ul, self.waste_ul)
+    }
+}
+
+pub fn to_csv(runs: &[RunRecord]) -> String {
+    let mut csv = "started,duration_secs,tenant,outcome,commands,consumed_ul,waste_ul,metadata\n".to_string();
+    for r in runs {
+        csv += &format!("{},{},{},\"{}\",{},{},{},\"{}\"\n", r.started, r.duration_secs, r.tenant.as_deref().unwrap_or(""),
+                        r.outcome.replace('"', "\"\""), r.commands, r.consumed_ul, r.waste_ul, metadata_text(&r.metadata).replace('"', "\"\""));
+    }
+    csv
+}
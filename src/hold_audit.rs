@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::{NotificationEvent, ThermalZone, CONFIG};
+use crate::notifications::{self, Notification};
+use crate::state::ControllerState;
+use crate::{thermal, Controller};
+
+// Zones holding a set point are read back on a schedule instead of trusting the heater to hold it
+// through an hour-long incubation; see [temperature-hold]
+#[derive(Default)]
+pub struct Holds {
+    zones: HashMap<String, Hold>,
+}
+
+struct Hold {
+    setpoint: f64,
+    set_at: Instant,
+    // Came within tolerance since it was set
+    settled: bool,
+    next_audit: Instant,
+    // Consecutive audits out of tolerance
+    deviations: u32,
+}
+
+pub fn hold(controller: &mut Controller, zone: &ThermalZone, setpoint: f64) {
+    let now = controller.clock.now();
+    controller.holds.zones.insert(zone.name.clone(), Hold { setpoint, set_at: now, settled: false, next_audit: now, deviations: 0 });
+}
+
+pub fn release(controller: &mut Controller, zone: &ThermalZone) {
+    controller.holds.zones.remove(&zone.name);
+}
+
+pub fn release_all(controller: &mut Controller) {
+    controller.holds.zones.clear();
+}
+
+// Audits the zones that are due; called with the thermal loops between steps and while waiting
+pub fn audit(controller: &mut Controller) {
+    let settings = &CONFIG.temperature_hold;
+    if settings.audit_interval_secs == 0 {
+        return;
+    }
+    let now = controller.clock.now();
+    let due: Vec<&ThermalZone> = CONFIG.thermal_zones.iter()
+        .filter(|zone| controller.holds.zones.get(&zone.name).is_some_and(|hold| now >= hold.next_audit))
+        .collect();
+    for zone in due {
+        let reading = thermal::read_zone(controller, zone);
+        let Some(hold) = controller.holds.zones.get_mut(&zone.name) else {
+            continue;
+        };
+        let interval = Duration::from_secs(settings.audit_interval_secs);
+        let in_band = reading.as_ref().is_ok_and(|celsius| (celsius - hold.setpoint).abs() <= settings.tolerance_celsius);
+        if !hold.settled {
+            if in_band {
+                log::info!("Zone {} reached its set point of {} °C", zone.name, hold.setpoint);
+                hold.settled = true;
+            } else if now < hold.set_at + Duration::from_secs(settings.settle_secs) {
+                // Still heating or cooling
+                hold.next_audit = now + interval;
+                continue;
+            }
+        }
+        hold.next_audit = now + interval;
+        if in_band {
+            if hold.deviations > 0 {
+                log::info!("Zone {} back within {} °C of {} °C", zone.name, settings.tolerance_celsius, hold.setpoint);
+                controller.events.emit("temperature_recovered", &[("zone", zone.name.clone())]);
+            }
+            hold.deviations = 0;
+            continue;
+        }
+        hold.deviations += 1;
+        let (setpoint, deviations) = (hold.setpoint, hold.deviations);
+        let reading = match reading {
+            Ok(celsius) => format!("{celsius:.2}"),
+            Err(e) => {
+                log::error!("{}", e);
+                "unreadable".to_string()
+            }
+        };
+        log::warn!("Zone {} at {} °C, outside {} ± {} °C ({} audit(s) in a row)", zone.name, reading, setpoint, settings.tolerance_celsius, deviations);
+        controller.events.emit("temperature_deviation", &[("zone", zone.name.clone()), ("celsius", reading.clone()),
+            ("setpoint", setpoint.to_string()), ("audits", deviations.to_string())]);
+        if deviations == settings.pause_after {
+            persisting(controller, zone, &reading, setpoint);
+        }
+    }
+}
+
+// Steps after an incubation at the wrong temperature would build on it, so they wait for the operator
+fn persisting(controller: &mut Controller, zone: &ThermalZone, reading: &str, setpoint: f64) {
+    let message = format!("Zone {} at {} °C instead of {} °C for {} audits", zone.name, reading, setpoint, CONFIG.temperature_hold.pause_after);
+    log::error!("{}, pausing execution", message);
+    if matches!(controller.state, ControllerState::Idle | ControllerState::Running) {
+        controller.state = ControllerState::Paused;
+    }
+    controller.notes.push(message.clone());
+    controller.application.send_status(&format!("TEMPERATURE DEVIATION zone={} celsius={reading} setpoint={setpoint} action=RESUME", zone.name));
+    notifications::notify(Notification {
+        event: NotificationEvent::Temperature,
+        run_id: controller.runs.current.clone(),
+        message,
+        metadata: controller.metadata.redacted_fields(),
+    });
+}
@@ -0,0 +1,195 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::bus::{BusHandle, ControllerRequest};
+use crate::calibration::Calibration;
+use crate::config;
+use crate::config::CONFIG;
+use crate::{config_query, logtail};
+use crate::message::{COMMAND_CHANNEL, CONTROL_CHANNEL};
+use crate::sensors::Feed;
+use crate::status;
+use crate::status::SharedStatus;
+use crate::websocket;
+
+const MAX_BODY: usize = 64 * 1024;
+// Longest wait for the next bytes of a request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Observer,
+    Operator,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+    websocket_key: Option<String>,
+    body: String,
+}
+
+pub fn spawn_server(bus: BusHandle, status: SharedStatus, feed: Feed) {
+    let settings = &CONFIG.http;
+    if !settings.enabled {
+        return;
+    }
+    let listener = match TcpListener::bind(&settings.bind) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to start HTTP API on {}: {}", settings.bind, e);
+            return;
+        }
+    };
+    log::info!("HTTP API listening on {}", settings.bind);
+    config::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let (bus, status, feed) = (bus.clone(), status.clone(), feed.clone());
+            config::spawn(move || handle_connection(stream, bus, status, feed));
+        }
+    });
+}
+
+fn handle_connection(mut stream: TcpStream, bus: BusHandle, status: SharedStatus, feed: Feed) {
+    // A client that connects and sends nothing, or sends it slowly, is dropped instead of holding its thread
+    if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        log::warn!("Failed to set the HTTP read timeout: {}", e);
+        return;
+    }
+    let (code, body) = match read_request(&stream) {
+        Ok(request) => match (request.path.as_str(), request.websocket_key.as_deref()) {
+            ("/sensors", Some(key)) if role(request.token.as_deref()).is_some() => return subscribe(stream, key, &feed),
+            _ => respond(request, &bus, &status),
+        },
+        Err(e) => (400, e),
+    };
+    let reason = match code {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Service Unavailable",
+    };
+    let response = format!("HTTP/1.1 {code} {reason}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        log::warn!("Failed to answer HTTP request: {}", e);
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request, String> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or("Empty request")?.to_string();
+    let target = parts.next().ok_or("Missing path")?;
+    // Only the Authorization header carries the token; one in the URL would end up in proxy and
+    // access logs
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let (mut token, mut websocket_key, mut length) = (None, None, 0);
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|e| e.to_string())?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_lowercase().as_str() {
+            "authorization" => token = value.trim().strip_prefix("Bearer ").map(str::to_string),
+            "sec-websocket-key" => websocket_key = Some(value.trim().to_string()),
+            "content-length" => length = value.trim().parse().map_err(|_| "Invalid Content-Length")?,
+            _ => {}
+        }
+    }
+    if length > MAX_BODY {
+        return Err(format!("Body larger than {MAX_BODY} bytes"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok(Request { method, path, token, websocket_key, body: String::from_utf8_lossy(&body).trim().to_string() })
+}
+
+// An empty observer token leaves the read-only endpoints open, e.g. for dashboards on lab displays
+fn role(token: Option<&str>) -> Option<Role> {
+    let settings = &CONFIG.http;
+    match token {
+        Some(t) if !settings.operator_token.is_empty() && same_token(t, &settings.operator_token) => Some(Role::Operator),
+        Some(t) if !settings.observer_token.is_empty() && same_token(t, &settings.observer_token) => Some(Role::Observer),
+        _ if settings.observer_token.is_empty() => Some(Role::Observer),
+        _ => None,
+    }
+}
+
+// Compares every byte whatever the first difference, so response times don't give the token away
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+fn respond(request: Request, bus: &BusHandle, status: &SharedStatus) -> (u16, String) {
+    let Some(role) = role(request.token.as_deref()) else {
+        return (401, "Missing or unknown token\n".to_string());
+    };
+    let snapshot = status::read(status);
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/state") => (200, format!("{}\n", snapshot.state)),
+        ("GET", "/queue") => (200, format!("queued={}\ncommand_id={}\n", snapshot.queued, snapshot.command_id)),
+        ("GET", "/telemetry") => (200, snapshot.to_string()),
+        ("GET", "/log-level") => (200, format!("{}\n", logtail::describe_levels())),
+        // The executor saves the calibration after every PROBE_, so the file holds the current offsets
+        ("GET", path) if path.starts_with("/config/") => match config_query::lookup(&path["/config/".len()..], &Calibration::load()) {
+            Ok(fields) => (200, fields.iter().map(|field| format!("{field}\n")).collect()),
+            Err(e) => (404, format!("{e}\n")),
+        },
+        ("POST", "/commands" | "/control" | "/log-level") if role != Role::Operator => (403, "Read-only observer role\n".to_string()),
+        ("POST", "/commands") => submit(bus, COMMAND_CHANNEL, request.body),
+        ("POST", "/control") => submit(bus, CONTROL_CHANNEL, request.body),
+        // Changed here rather than by the executor, so it takes effect even while a step blocks it
+        ("POST", "/log-level") => match logtail::set_level(&request.body) {
+            Ok(levels) => (200, format!("{levels}\n")),
+            Err(e) => (400, format!("{e}\n")),
+        },
+        _ => (404, "Unknown endpoint\n".to_string()),
+    }
+}
+
+// Upgrades GET /sensors to a WebSocket that receives every sensor sample as a JSON text frame
+fn subscribe(mut stream: TcpStream, key: &str, feed: &Feed) {
+    if let Err(e) = stream.write_all(websocket::handshake(key).as_bytes()) {
+        return log::warn!("Failed to accept sensor stream client: {}", e);
+    }
+    // A client that stops reading must not stall the executor while it samples
+    stream.set_write_timeout(Some(Duration::from_millis(200))).ok();
+    feed.lock().unwrap_or_else(|e| e.into_inner()).push(stream);
+}
+
+// Blocks until the executor has handled the message and returns every status line it produced
+fn submit(bus: &BusHandle, channel: i8, data: String) -> (u16, String) {
+    if data.is_empty() {
+        return (400, "Empty message\n".to_string());
+    }
+    let (reply, replies) = mpsc::channel::<String>();
+    if let Err(e) = bus.submit(ControllerRequest::Message { channel, data, reply: Some(reply) }) {
+        return (503, format!("{e}\n"));
+    }
+    (200, replies.iter().map(|line| format!("{line}\n")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_match_only_in_full() {
+        assert!(same_token("s3cret", "s3cret"));
+        assert!(!same_token("s3cres", "s3cret"));
+        assert!(!same_token("s3cre", "s3cret"));
+        assert!(!same_token("", "s3cret"));
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::command_id::CommandId;
+use crate::run_store;
+
+// Progress of the message being executed. It is rewritten whenever a command starts, so a
+// controller restarted with `--resume` continues where it stopped instead of from the top.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Journal {
+    pub data: String,
+    // Kept when the message is resumed, so its steps log under the same ids
+    #[serde(default)]
+    pub command_id: Option<CommandId>,
+    // Index into the space separated commands of `data`
+    pub next_command: usize,
+    // Unix milliseconds at which the wait at `next_command` ends; wall clock so it survives restarts
+    #[serde(default)]
+    pub wait_deadline: Option<u64>,
+    // Every IF condition evaluated so far, in order, so a resumed run takes the same branches
+    #[serde(default)]
+    pub branches: Vec<bool>,
+}
+
+impl Journal {
+    pub fn new(data: &str, command_id: CommandId) -> Journal {
+        Journal { data: data.to_string(), command_id: Some(command_id), next_command: 0, wait_deadline: None, branches: Vec::new() }
+    }
+
+    pub fn advance(&mut self, command: usize) {
+        if command > self.next_command {
+            self.next_command = command;
+            self.wait_deadline = None;
+            save(self);
+        }
+    }
+
+    pub fn record_branch(&mut self, taken: bool) {
+        self.branches.push(taken);
+        save(self);
+    }
+
+    pub fn record_wait(&mut self, remaining: Duration) {
+        self.wait_deadline = Some(unix_millis(SystemTime::now() + remaining));
+        save(self);
+    }
+
+    // Time left of a wait that was running when the controller stopped
+    pub fn remaining_wait(&self) -> Option<Duration> {
+        let deadline = self.wait_deadline?;
+        Some(Duration::from_millis(deadline.saturating_sub(unix_millis(SystemTime::now()))))
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+pub fn save(journal: &Journal) {
+    if let Err(e) = run_store::open().save_journal(journal) {
+        log::error!("{}", e);
+    }
+}
+
+pub fn load() -> Option<Journal> {
+    run_store::open().load_journal()
+        .map_err(|e| log::error!("Ignoring {}", e))
+        .ok()
+        .flatten()
+}
+
+pub fn clear() {
+    run_store::open().clear_journal();
+}
@@ -0,0 +1,66 @@
+use std::time::{Duration, Instant};
+
+use crate::{deck, script};
+
+// LB_<ms>[_HARD|_SOFT] opens a latency-sensitive section, LBEND closes it
+pub const SECTION_START: &str = "LB";
+pub const SECTION_END: &str = "LBEND";
+
+pub struct BudgetLimit {
+    limit: Duration,
+    hard: bool,
+}
+
+pub struct LatencyBudget {
+    limit: Duration,
+    hard: bool,
+    started: Instant,
+}
+
+pub fn parse_budget(command: &str) -> Option<Result<BudgetLimit, String>> {
+    let parts: Vec<&str> = command.split('_').collect();
+    if parts[0] != SECTION_START {
+        return None;
+    }
+    let limit = match parts.get(1).and_then(|ms| ms.parse::<u64>().ok()) {
+        Some(ms) => Duration::from_millis(ms),
+        None => return Some(Err(format!("Cannot deduce latency budget from {command}"))),
+    };
+    let hard = match parts.get(2).copied() {
+        None | Some("HARD") => true,
+        Some("SOFT") => false,
+        Some(mode) => return Some(Err(format!("Unknown latency budget mode {mode}"))),
+    };
+    Some(Ok(BudgetLimit { limit, hard }))
+}
+
+// Only a slot application can be pre-staged: one into a flow cell well goes through wells::apply.
+// Nothing past an IF, ELSE or ENDIF is, as whether it runs is only decided when the marker is reached.
+pub fn first_application_in_section(commands: &[&str], section_start: usize) -> Option<usize> {
+    commands[section_start + 1..].iter()
+        .take_while(|c| **c != SECTION_END && parse_budget(c).is_none() && !script::is_branch(c))
+        .position(|c| c.starts_with("LA_"))
+        .map(|offset| section_start + 1 + offset)
+        .filter(|j| commands[*j].split('_').nth(2).and_then(deck::well).is_none())
+}
+
+impl BudgetLimit {
+    pub fn start(self) -> LatencyBudget {
+        LatencyBudget { limit: self.limit, hard: self.hard, started: Instant::now() }
+    }
+}
+
+impl LatencyBudget {
+    pub fn finish(self) -> Result<String, String> {
+        let elapsed = self.started.elapsed();
+        let summary = format!("{}ms of {}ms", elapsed.as_millis(), self.limit.as_millis());
+        if elapsed <= self.limit {
+            return Ok(format!("Latency budget met: {summary}"));
+        }
+        if self.hard {
+            return Err(format!("Hard latency budget violated: {summary}"));
+        }
+        log::warn!("Soft latency budget exceeded: {}", summary);
+        Ok(format!("Soft latency budget exceeded: {summary}"))
+    }
+}
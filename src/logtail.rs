@@ -0,0 +1,167 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+
+use crate::command_id::CommandId;
+use crate::config::CONFIG;
+
+const CAPACITY: usize = 500;
+const CRATE: &str = env!("CARGO_CRATE_NAME");
+// Module groups that can be given a level by one name
+const GROUPS: [(&str, &[&str]); 2] = [
+    ("ports", &["port_operations", "pump_bus", "bus", "sim"]),
+    // The executor loop itself logs from the crate root
+    ("executor", &[""]),
+];
+
+lazy_static! {
+    static ref TAIL: Mutex<VecDeque<String>> = Mutex::new(VecDeque::with_capacity(CAPACITY));
+    static ref LEVELS: RwLock<Levels> = RwLock::new(Levels { default: LevelFilter::Trace, modules: BTreeMap::new() });
+}
+
+// Active log levels: log_level and [log-levels] at startup, changed by LOGLEVEL_ and the HTTP API
+struct Levels {
+    default: LevelFilter,
+    // By module path below the crate, "" for the crate root
+    modules: BTreeMap<String, LevelFilter>,
+}
+
+impl Levels {
+    fn level(&self, target: &str) -> LevelFilter {
+        let Some(module) = target.strip_prefix(CRATE).map(|rest| rest.trim_start_matches("::")) else {
+            return self.default;
+        };
+        // The most specific module configured wins
+        self.modules.iter()
+            .filter(|(name, _)| module == name.as_str() || (!name.is_empty() && module.starts_with(&format!("{name}::"))))
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn apply(&self) {
+        log::set_max_level(self.modules.values().copied().fold(self.default, Ord::max));
+    }
+
+    fn describe(&self) -> String {
+        let modules: Vec<String> = self.modules.iter()
+            .map(|(name, level)| format!("{}={}", if name.is_empty() { "executor" } else { name }, level))
+            .collect();
+        format!("LOGLEVEL default={}{}{}", self.default, if modules.is_empty() { "" } else { " " }, modules.join(" "))
+    }
+}
+
+thread_local! {
+    // The command the logging thread is executing; each controller instance runs in its own thread
+    static COMMAND: RefCell<Option<CommandId>> = const { RefCell::new(None) };
+}
+
+// Tags every line logged by this thread with `id` until it is set again
+pub fn set_command(id: Option<CommandId>) {
+    COMMAND.with(|command| *command.borrow_mut() = id);
+}
+
+// SimpleLogger output plus the last few hundred non-trace lines kept in memory for `log tail`
+struct TailLogger {
+    inner: SimpleLogger,
+}
+
+impl Log for TailLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let levels = LEVELS.read().unwrap_or_else(|e| e.into_inner());
+        metadata.level() <= levels.level(metadata.target()) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // With several controller instances, lines are tagged with the instance of the logging thread
+        let instance = std::thread::current().name().filter(|name| *name != "main").map(str::to_string);
+        let command = COMMAND.with(|command| *command.borrow());
+        let message = match (&instance, command) {
+            (Some(instance), Some(id)) => format!("[{}] [cmd={}] {}", instance, id, record.args()),
+            (Some(instance), None) => format!("[{}] {}", instance, record.args()),
+            (None, Some(id)) => format!("[cmd={}] {}", id, record.args()),
+            (None, None) => record.args().to_string(),
+        };
+        self.inner.log(&Record::builder()
+            .args(format_args!("{message}"))
+            .metadata(record.metadata().clone())
+            .module_path(record.module_path())
+            .file(record.file())
+            .line(record.line())
+            .build());
+        if record.level() == Level::Trace {
+            return;
+        }
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        if let Ok(mut tail) = TAIL.lock() {
+            if tail.len() == CAPACITY {
+                tail.pop_front();
+            }
+            tail.push_back(format!("{} {:<5} {}", millis, record.level(), message));
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+pub fn init() {
+    log::set_boxed_logger(Box::new(TailLogger { inner: SimpleLogger::new() })).expect("Failed to set up logging");
+    log::set_max_level(LevelFilter::Trace);
+}
+
+// Takes log_level and [log-levels] once the configuration is known
+pub fn configure() {
+    if let Err(e) = set_level(&CONFIG.log_level) {
+        log::error!("Ignoring log_level: {}", e);
+    }
+    let mut modules: Vec<(&String, &String)> = CONFIG.log_levels.iter().collect();
+    modules.sort();
+    for (module, level) in modules {
+        if let Err(e) = set_level(&format!("{module}={level}")) {
+            log::error!("Ignoring [log-levels] {}: {}", module, e);
+        }
+    }
+}
+
+// `<level>` for everything without a level of its own, `<module>=<level>` for a module of this
+// crate (with its submodules) or a group such as ports, `<module>=default` to drop it again.
+// Returns the levels now active.
+pub fn set_level(spec: &str) -> Result<String, String> {
+    let parse = |level: &str| LevelFilter::from_str(level).map_err(|_| format!("unknown log level {level}"));
+    let mut levels = LEVELS.write().unwrap_or_else(|e| e.into_inner());
+    match spec.split_once('=') {
+        None => levels.default = parse(spec)?,
+        Some((name, level)) => {
+            let modules = GROUPS.iter()
+                .find(|(group, _)| group.eq_ignore_ascii_case(name))
+                .map_or(vec![name.to_lowercase()], |(_, modules)| modules.iter().map(|m| m.to_string()).collect());
+            if level.eq_ignore_ascii_case("default") {
+                modules.iter().for_each(|module| { levels.modules.remove(module); });
+            } else {
+                let level = parse(level)?;
+                modules.into_iter().for_each(|module| { levels.modules.insert(module, level); });
+            }
+        }
+    }
+    levels.apply();
+    Ok(levels.describe())
+}
+
+pub fn describe_levels() -> String {
+    LEVELS.read().unwrap_or_else(|e| e.into_inner()).describe()
+}
+
+pub fn tail(lines: usize) -> Vec<String> {
+    let tail = TAIL.lock().map(|t| t.clone()).unwrap_or_default();
+    tail.iter().skip(tail.len().saturating_sub(lines)).cloned().collect()
+}
@@ -1,230 +1,1753 @@
+use std::collections::{BTreeMap, HashSet};
 use std::io::{Read, Write};
 use std::ops::{Add, ControlFlow};
-use std::process::Command;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use log::log;
 use serialport::SerialPort;
-use simple_logger::SimpleLogger;
-use sysinfo::{ProcessExt, SystemExt};
 
 use message::Message;
 
-use crate::config::CONFIG;
-use crate::port_operations::{flush_port, serial_readline, serial_write, unlogged_serial_readline, unlogged_serial_write};
+use crate::application::ApplicationLink;
+use crate::bus::ControllerRequest;
+use crate::config::{ContaminationAction, NotificationEvent, OverRangePolicy, CONFIG};
+use crate::deck::{Coordinates, HOME_POSITION};
+use crate::clock::{Clock, ScaledClock, SystemClock};
+use crate::custody::CustodyLog;
+use crate::devices::{DeviceKind, Devices};
+use crate::peripherals::Peripherals;
+use crate::estimation::VolumeReport;
+use crate::firmware::Firmware;
+use crate::journal::Journal;
+use crate::latency::LatencyBudget;
+use crate::metadata::RunMetadata;
+use crate::notifications::Notification;
+use crate::pump::{PumpCommand, PumpError, Resolution, FULL_STROKE, MAX_STROKE_MICROLITER};
+use crate::pump_bus::{Pump, PumpBus};
+use crate::state::ControllerState;
+use crate::status::{SharedStatus, StatusSnapshot};
+use crate::calibration::Calibration;
+use crate::command_id::{CommandId, CommandIds};
+use crate::sensors::SensorLog;
+use crate::manifest::{Assembly, PendingProtocol};
+use crate::runs::{QueuedRun, RunQueue};
+use crate::tubes::TubeInventory;
+use crate::tips::TipTracker;
+use crate::units::{Microliters, Millimeters, PumpUnits};
+use crate::report::RunReport;
+use crate::timeline::Timeline;
+use crate::wear::Wear;
+use crate::reservoirs::Reservoirs;
+use crate::expiry::ReagentExpiry;
+use crate::handshake::Handshake;
+use crate::rollback::{Held, Uncommitted};
+use crate::faults::Fault;
+use crate::events::EventLog;
+use crate::spans::Tracer;
+use crate::safe_mode::{MissingPort, SafeMode};
+use crate::watchdog::Watchdog;
+use crate::pump_lock::PumpLock;
+use crate::capabilities::Halted;
+use crate::poll::{try_poll_until, Poll};
+use crate::sim::{SimDevice, SimulatedPort};
+use crate::port_operations::{flush_port, serial_readline, serial_write};
 
 mod macros;
 mod message;
 mod config;
+mod config_query;
+mod schema;
+mod migration;
 mod port_operations;
+mod router_echo;
+mod deck;
+mod devices;
+mod capabilities;
+mod firmware;
+mod detection;
+mod motion;
+mod estimation;
+mod pump;
+mod pump_bus;
+mod pump_protocol;
+mod pump_lock;
+mod diagnostics;
+mod application;
+mod bus;
+mod handshake;
+mod state;
+mod status;
+mod http;
+mod latency;
+mod history;
+mod tubes;
+mod tips;
+mod barcode;
+mod shaker;
+mod peripherals;
+mod thermal;
+mod hold_audit;
+mod mixing;
+mod sensors;
+mod websocket;
+mod units;
+mod report;
+mod timeline;
+mod wear;
+mod reservoirs;
+mod expiry;
+mod rollback;
+mod wells;
+mod calibration;
+mod command_id;
+mod probing;
+mod faults;
+mod events;
+mod journal;
+mod run_store;
+mod preflight;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod notifications;
+mod metadata;
+mod custody;
+mod contamination;
+mod runs;
+mod manifest;
+mod maintenance;
+mod clog;
+mod cli;
+mod startup;
+mod template;
+#[cfg(unix)]
+mod console;
+mod logtail;
+mod clock;
+mod poll;
+mod sim;
+mod fault_injection;
+mod devenv;
+mod shutdown;
+mod halt;
+mod safe_mode;
+mod outbox;
+mod spans;
+mod script;
+mod watchdog;
+
+const HISTORY_QUERY_LIMIT: usize = 20;
+const ABORT_REASON: &str = "Aborted by control command";
+const SKIP_REASON: &str = "Skipped by control command";
+const MAINTENANCE_INTERRUPTED: &str = "Idle maintenance interrupted by incoming request";
 
 struct Controller {
     router_port: Box<dyn SerialPort>,
-    pump_port: Box<dyn SerialPort>,
-    application_port: Box<dyn SerialPort>,
-    slot_occupancy: u64,
+    pumps: PumpBus,
+    application: ApplicationLink,
+    slot_occupancy: Microliters,
+    // Flow cell wells on the slot, filled through the needle and drained one by one
+    wells: BTreeMap<String, Microliters>,
+    router: motion::Tracker,
+    volumes: VolumeReport,
+    state: ControllerState,
+    custody: CustodyLog,
+    command_ids: CommandIds,
+    // The received message or step being executed, or the last one
+    command_id: CommandId,
+    notes: Vec<String>,
+    metadata: RunMetadata,
+    present_tubes: HashSet<String>,
+    status: SharedStatus,
+    devices: Devices,
+    peripherals: Peripherals,
+    runs: RunQueue,
+    manifest: PendingProtocol,
+    needle_residues: Vec<String>,
+    clock: Box<dyn Clock>,
+    journal: Option<Journal>,
+    tubes: TubeInventory,
+    firmware: Firmware,
+    tips: TipTracker,
+    wear: Wear,
+    reservoirs: Reservoirs,
+    calibration: Calibration,
+    regulators: thermal::Regulators,
+    holds: hold_audit::Holds,
+    sensor_log: SensorLog,
+    report: RunReport,
+    timeline: Timeline,
+    // The step that failed the run
+    fault: Option<Fault>,
+    events: EventLog,
+    spans: Tracer,
+    // When the running SHAKE_ step is due to end
+    shaking_until: Option<Instant>,
+    // Slot volume pump 2 is still pumping out in the background
+    draining: Option<Microliters>,
+    // Tube the slot contents go back to instead of waste when they are displaced
+    slot_recovery: Option<String>,
+    // Pump 1 was verified to switch to [pump-resolution] fine_mode
+    fine_positioning: bool,
+    safe_mode: SafeMode,
+    watchdog: Watchdog,
+    pump_lock: PumpLock,
+    expiry: ReagentExpiry,
+    handshake: Handshake,
+    uncommitted: Option<Uncommitted>,
 }
 
 impl Controller {
+    fn set_command_id(&mut self, id: CommandId) {
+        self.command_id = id;
+        logtail::set_command(Some(id));
+    }
+
     pub fn router_execute(&mut self, command: &str) -> ControlFlow<String> {
-        serial_write(&mut self.router_port, command);
-        if serial_readline(&mut self.router_port, "\r\n") == "G1:OK" {
-            return ControlFlow::Continue(());
+        self.spans.enter("router", &[("gcode", command.trim().to_string())]);
+        let result = self.router_transaction(command);
+        self.spans.exit(&result);
+        result
+    }
+
+    fn router_transaction(&mut self, command: &str) -> ControlFlow<String> {
+        if let Some(reason) = &self.router.halted {
+            return ControlFlow::Break(format!("Router halted after {reason}, send HOME once the deck is clear"));
+        }
+        let port = self.router_port.name().unwrap_or_default();
+        let mut resends = 0;
+        loop {
+            // Only this command's echo counts
+            router_echo::take_mismatch(&port);
+            unwrap_result!(serial_write(&mut self.router_port, command), format!("Router - failed to send command: [{command}]"));
+            let Some(reply) = serial_readline(&mut self.router_port, "\r\n") else {
+                return ControlFlow::Break(halt::halt(self, command, "no reply, the router port failed"));
+            };
+            // The reply is to whatever the router made of the mangled line
+            if let Some(mismatch) = router_echo::take_mismatch(&port) {
+                if resends >= CONFIG.router_echo.max_resends {
+                    return ControlFlow::Break(halt::halt(self, command, &format!("echo {}", mismatch.echoed)));
+                }
+                resends += 1;
+                log::warn!("Resending [{}] ({} of {})", mismatch.sent, resends, CONFIG.router_echo.max_resends);
+                continue;
+            }
+            if reply == "G1:OK" {
+                return ControlFlow::Continue(());
+            }
+            return ControlFlow::Break(halt::halt(self, command, &reply));
         }
-        ControlFlow::Break(format!("Router - error executing command: [{command}]"))
     }
 
-    pub fn pump_execute(&mut self, command: &str) -> ControlFlow<String> {
-        flush_port(&mut self.pump_port);
-        serial_write(&mut self.pump_port, command);
-        sleep(Duration::from_secs(1));
-        await_pump_availability(&mut self.pump_port)
+    pub fn router_move(&mut self, target: Coordinates) -> ControlFlow<String> {
+        shaker::stop(self)?;
+        let path = match self.router.plan(target) {
+            Ok(path) => path,
+            Err(e) => return ControlFlow::Break(e),
+        };
+        let duration = motion::path_duration(self.router.position, &path);
+        log::trace!("Estimated move time {:?}", duration);
+        self.timeline.router(duration);
+        for point in path {
+            self.yield_to_requests()?;
+            self.router_execute(&motion::move_gcode(self.router.position, point))?;
+            let travelled = self.router.moved_to(point);
+            self.notes.extend(self.wear.record_travel(travelled));
+        }
+        ControlFlow::Continue(())
     }
 
-    pub fn pump_execute_async(&mut self, command: &str) -> ControlFlow<String> {
-        flush_port(&mut self.pump_port);
-        serial_write(&mut self.pump_port, command);
-        return ControlFlow::Continue(());
+    pub fn pump_execute(&mut self, command: &PumpCommand) -> ControlFlow<String> {
+        self.spans.enter("pump", &[("pump", command.address().to_string()), ("command", command.to_string())]);
+        let result = self.pump_transaction(command);
+        self.spans.exit(&result);
+        result
     }
-}
 
-fn await_pump_availability(pump_port: &mut Box<dyn SerialPort>) -> ControlFlow<String> {
-    loop {
-        unlogged_serial_write(pump_port, "/1Q29\r\n");
-        let mut status = unlogged_serial_readline(pump_port, "\r\n");
-        status.remove(0);
-        status.pop();
-        let is_free = status == "/0c";
-        if is_free {
+    fn pump_transaction(&mut self, command: &PumpCommand) -> ControlFlow<String> {
+        self.notes.extend(self.wear.record_pump(command));
+        self.record_reservoirs(command);
+        self.timeline.pump(command);
+        if clog::is_monitored(&self.firmware, command) {
+            return clog::execute_monitored(self, command);
+        }
+        let pump = self.pumps.pump(command.address());
+        pump_lock::send(self, &pump, command)?;
+        self.clock.sleep(Duration::from_secs(1));
+        self.await_pump(&pump)
+    }
+
+    // Controls are handled while the pump works, and a SKIP or ABORT stops the plunger where it is
+    pub fn await_pump(&mut self, pump: &Pump) -> ControlFlow<String> {
+        let poll = Poll::every(Duration::from_millis(CONFIG.polling.pump_busy_interval_ms));
+        try_poll_until(self, poll, |controller| controller.clock.as_ref(), |controller| {
+            match pump.is_idle() {
+                Ok(true) => {
+                    pump_lock::finished(controller, pump);
+                    return ControlFlow::Continue(Some(()));
+                }
+                Ok(false) => {}
+                Err(e) => return ControlFlow::Break(e),
+            }
+            pump_lock::check(controller, pump)?;
+            thermal::regulate(controller);
+            sensors::sample(controller);
+            if let ControlFlow::Break(reason) = controller.poll_controls() {
+                if let Err(e) = pump.terminate() {
+                    log::error!("{}", e);
+                }
+                return ControlFlow::Break(reason);
+            }
+            ControlFlow::Continue(None)
+        })?;
+        ControlFlow::Continue(())
+    }
+
+    pub fn await_pumps_idle(&mut self) -> ControlFlow<String> {
+        self.pumps.pumps().iter().try_for_each(|pump| self.await_pump(pump))
+    }
+
+    pub fn pump_execute_async(&mut self, command: &PumpCommand) -> ControlFlow<String> {
+        self.notes.extend(self.wear.record_pump(command));
+        self.record_reservoirs(command);
+        let pump = self.pumps.pump(command.address());
+        pump_lock::send(self, &pump, command)
+    }
+
+    // Bulk reservoirs are only ever drawn from by pump 1
+    fn record_reservoirs(&mut self, command: &PumpCommand) {
+        if command.address() != 1 {
+            return;
+        }
+        for (channel, volume) in command.drawn() {
+            let Some(warning) = self.reservoirs.draw(channel, volume) else {
+                continue;
+            };
+            self.events.emit("reservoir_low", &[("channel", channel.to_string())]);
+            self.notes.push(warning.clone());
+            notifications::notify(Notification {
+                event: NotificationEvent::Reservoir,
+                run_id: self.runs.current.clone(),
+                message: warning,
+                metadata: self.metadata.redacted_fields(),
+            });
+        }
+    }
+
+    // The router firmware drives the heater unless a separate temperature controller is configured
+    pub fn thermal_port(&mut self) -> Option<&mut Box<dyn SerialPort>> {
+        match CONFIG.devices.thermal {
+            Some(_) => self.devices.get(DeviceKind::Thermal),
+            None => Some(&mut self.router_port),
+        }
+    }
+
+    pub fn handle_control(&mut self, control: &str) -> ControlFlow<String> {
+        log::info!("Control command {} in state {}", control, self.state);
+        self.events.emit("control", &[("control", control.to_string()), ("state", self.state.name().to_string())]);
+        match control {
+            "ABORT" => return ControlFlow::Break(ABORT_REASON.to_string()),
+            "SKIP" if self.state == ControllerState::Running => return ControlFlow::Break(SKIP_REASON.to_string()),
+            "PAUSE" if matches!(self.state, ControllerState::Idle | ControllerState::Running) => self.state = ControllerState::Paused,
+            "RESUME" if self.state == ControllerState::Paused => self.state = ControllerState::Idle,
+            "MAINTENANCE_ON" if self.state == ControllerState::Idle => self.state = ControllerState::Maintenance,
+            "MAINTENANCE_OFF" if self.state == ControllerState::Maintenance => self.state = ControllerState::Idle,
+            "CLEARFAULT" if matches!(self.state, ControllerState::Faulted(_)) => self.state = ControllerState::Idle,
+            "HOME" => {
+                let reply = halt::rehome(self);
+                self.application.send_status(&reply);
+            }
+            "UNLOCKPUMPS" => {
+                let reply = pump_lock::unlock(self);
+                self.application.send_status(&reply);
+            }
+            _ if control.starts_with("CANCEL_") => return self.cancel_run(&control["CANCEL_".len()..]),
+            _ if control.starts_with("MOVE_") => self.move_run(&control["MOVE_".len()..]),
+            _ if control.starts_with("POLLLOG_") => self.set_poll_logging(&control["POLLLOG_".len()..]),
+            _ if control.starts_with("LOGLEVEL_") => self.set_log_level(&control["LOGLEVEL_".len()..]),
+            _ => {
+                log::warn!("Control command {} not applicable in state {}", control, self.state);
+                self.application.send_status(&format!("REFUSED control={control} state={}", self.state.name()));
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    pub fn publish_status(&mut self) {
+        let snapshot = StatusSnapshot {
+            state: self.state.to_string(),
+            queued: self.application.pending_count(),
+            command_id: self.command_id,
+            slot_occupancy: self.slot_occupancy,
+            router_position: self.router.position.to_string(),
+            volumes: self.volumes.to_string(),
+            runs: self.runs.describe(),
+            sensors: self.sensor_log.describe(),
+            safe_mode: self.safe_mode.describe(),
+            protocol: self.handshake.describe(),
+        };
+        if let Ok(mut status) = self.status.lock() {
+            *status = snapshot;
+        }
+    }
+
+    // CANCEL_<id> drops a queued run, or aborts it when it is the one executing
+    fn cancel_run(&mut self, id: &str) -> ControlFlow<String> {
+        if self.runs.current.as_deref() == Some(id) {
+            return ControlFlow::Break(format!("Run {id} cancelled"));
+        }
+        let reply = if self.runs.cancel(id) { format!("RUN {id} CANCELLED") } else { format!("REFUSED control=CANCEL_{id} reason=unknown_run") };
+        self.application.send_status(&reply);
+        ControlFlow::Continue(())
+    }
+
+    // POLLLOG_<secs> logs pump status polls at that interval, POLLLOG_OFF stops it
+    fn set_poll_logging(&mut self, interval: &str) {
+        let reply = match interval {
+            "OFF" => {
+                self.pumps.set_poll_logging(None);
+                "POLLLOG off".to_string()
+            }
+            _ => match interval.parse::<u64>() {
+                Ok(secs) if secs > 0 => {
+                    self.pumps.set_poll_logging(Some(Duration::from_secs(secs)));
+                    format!("POLLLOG interval={secs}s")
+                }
+                _ => format!("REFUSED control=POLLLOG_{interval} reason=bad_interval"),
+            },
+        };
+        self.application.send_status(&reply);
+    }
+
+    // LOGLEVEL_<level> or LOGLEVEL_<module>_<level>
+    fn set_log_level(&mut self, args: &str) {
+        let spec = match args.rsplit_once('_') {
+            Some((module, level)) => format!("{module}={level}"),
+            None => args.to_string(),
+        };
+        let reply = match logtail::set_level(&spec) {
+            Ok(levels) => {
+                log::info!("{}", levels);
+                levels
+            }
+            Err(_) => format!("REFUSED control=LOGLEVEL_{args} reason=unknown_level"),
+        };
+        self.application.send_status(&reply);
+    }
+
+    // MOVE_<id>_<position> reorders the queue, position 1 runs next
+    fn move_run(&mut self, args: &str) {
+        let moved = args.rsplit_once('_')
+            .and_then(|(id, position)| Some((id, position.parse::<usize>().ok()?)))
+            .is_some_and(|(id, position)| self.runs.move_to(id, position));
+        let reply = if moved { self.runs.describe() } else { format!("REFUSED control=MOVE_{args} reason=unknown_run") };
+        self.application.send_status(&reply);
+    }
+
+    // Controls that arrived while a step was running
+    pub fn poll_controls(&mut self) -> ControlFlow<String> {
+        self.watchdog.beat();
+        self.yield_to_requests()?;
+        while let Some(control) = self.application.take_control() {
+            self.handle_control(&control)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    // Idle upkeep stops the moment a request arrives, which is then handled as usual once idle
+    fn yield_to_requests(&mut self) -> ControlFlow<String> {
+        if self.state == ControllerState::IdleMaintenance && self.application.has_pending() {
+            return ControlFlow::Break(MAINTENANCE_INTERRUPTED.to_string());
+        }
+        ControlFlow::Continue(())
+    }
+
+    // Nothing runs between steps, so a SKIP seen here came too late to skip anything
+    fn handle_control_between_steps(&mut self, control: &str) -> ControlFlow<String> {
+        match self.handle_control(control) {
+            ControlFlow::Break(reason) if reason == SKIP_REASON => {
+                log::warn!("SKIP arrived after the step finished, ignoring it");
+                self.application.send_status("REFUSED control=SKIP reason=no_step_running");
+                ControlFlow::Continue(())
+            }
+            other => other,
+        }
+    }
+
+    // Applies queued control commands between steps and holds execution while paused
+    pub fn checkpoint(&mut self) -> ControlFlow<String> {
+        self.watchdog.beat();
+        self.publish_status();
+        thermal::regulate(self);
+        sensors::sample(self);
+        while let Some(control) = self.application.take_control() {
+            self.handle_control_between_steps(&control)?;
+        }
+        if self.state != ControllerState::Paused {
             return ControlFlow::Continue(());
         }
-        sleep(Duration::from_secs(1));
+        log::info!("Execution paused, waiting for RESUME");
+        self.application.send_status("paused");
+        while self.state == ControllerState::Paused {
+            self.watchdog.beat();
+            thermal::regulate(self);
+            sensors::sample(self);
+            match self.application.take_control() {
+                Some(control) => self.handle_control_between_steps(&control)?,
+                None => sleep(Duration::from_millis(100)),
+            }
+        }
+        self.state = ControllerState::Running;
+        ControlFlow::Continue(())
+    }
+}
+
+impl Drop for Controller {
+    fn drop(&mut self) {
+        shutdown::park(self);
     }
 }
 
 fn execute_command(ports: &mut Controller, command: &str) -> ControlFlow<String> {
-    await_pump_availability(&mut ports.pump_port)?;
+    let started = ports.clock.now();
+    ports.await_pumps_idle()?;
     let command_type = command.split('_').next().expect("Cannot get command type");
     match command_type {
         "LA" => handle_liquid_application(ports, command),
-        "W" => handle_waiting_command(command),
+        "W" => handle_waiting_command(ports, command, started),
         "TC" => handle_temperature_change(ports, command),
+        "END" => handle_end_of_run(ports),
+        "TIPCHANGE" => tips::change_tip(ports),
+        "SCAN" => barcode::scan_tube(ports, command),
+        "SHAKE" => shaker::start(ports, command),
+        "MIXTUBE" => mixing::mix_tube(ports, command),
+        "TUNEPID" => thermal::tune(ports, command),
+        "PROBE" => probing::probe(ports, command),
+        "REPLACED" => match ports.expiry.replaced(command.strip_prefix("REPLACED_").unwrap_or_default()) {
+            Ok(reply) => {
+                ports.application.send_status(&reply);
+                ControlFlow::Continue(())
+            }
+            Err(e) => ControlFlow::Break(e),
+        },
+        // Taken when the message is accepted
+        "USEEXPIRED" => ControlFlow::Continue(()),
         "BTC" => {
+            if CONFIG.devices.thermal.is_some() {
+                ports.devices.require(DeviceKind::Thermal, command)?;
+            }
             log::error!("PRETENDING TO DO TEMP CHANGE");
             ControlFlow::Continue(())
         }
-        _ => ControlFlow::Break("Unknown Command ".to_string().add(command))
+        _ => match ports.peripherals.execute(command_type, command) {
+            Some(result) => result,
+            None => ControlFlow::Break("Unknown Command ".to_string().add(command)),
+        },
     }
 }
 
 fn handle_temperature_change(controller: &mut Controller, command: &str) -> ControlFlow<String> {
-    let target_temp = *command.split('_').collect::<Vec<&str>>().get(1)
-        .expect(&*format!("Cannot deduce target temperature from {command}"));
-    serial_write(&mut controller.router_port, &*format!("M104S{target_temp}"));
+    if let Some(zone) = thermal::addressed_zone(command) {
+        return thermal::set_zone(controller, zone, command);
+    }
+    let target_temp = unwrap_option!(command.split('_').nth(1).filter(|t| !t.is_empty()),
+        format!("Cannot deduce target temperature from {command}"));
+    let port = match CONFIG.devices.thermal {
+        Some(_) => controller.devices.require(DeviceKind::Thermal, command)?,
+        None => &mut controller.router_port,
+    };
+    unwrap_result!(serial_write(port, &format!("M104S{target_temp}")),
+        format!("Failed to set temperature to {target_temp}"));
+    controller.events.emit("temperature_set", &[("celsius", target_temp.to_string())]);
     ControlFlow::Continue(())
 }
 
 fn handle_liquid_application(controller: &mut Controller, command: &str) -> ControlFlow<String> {
     log::trace!("Executing liquid application {}", command);
     flush_port(&mut controller.router_port);
-    flush_port(&mut controller.pump_port);
+    if let Some(well) = command.split('_').nth(2).and_then(deck::well) {
+        let application = parse_liquid_application(command)?;
+        return wells::apply(controller, &application, well);
+    }
+    start_slot_drain(controller)?;
+    let staged = stage_liquid_application(controller, command);
+    // Nothing goes into the slot before both pumps are done, even when staging failed
+    finish_slot_drain(controller)?;
+    finish_liquid_application(controller, staged?)
+}
+
+struct LiquidApplication {
+    command: String,
+    from: String,
+    destination: String,
+    vol_microliter: Microliters,
+    // LA_<from>_<destination>_<volume>_R<tube> recovers the applied liquid into <tube> once displaced
+    recover: Option<String>,
+}
+
+// Liquid that was taken up and sits in the line to the slot, waiting to be pushed in
+struct PreparedApplication {
+    source: PreparedSource,
+    from: String,
+    destination: String,
+    vol_microliter: Microliters,
+    command_id: CommandId,
+    recover: Option<String>,
+}
+
+enum PreparedSource {
+    Tube,
+    External,
+}
+
+// First cycle of an application taken up, the remaining cycles still to do
+struct StagedApplication {
+    application: LiquidApplication,
+    prepared: PreparedApplication,
+    remaining_cycles: Vec<Microliters>,
+}
+
+fn drain_command(volume: Microliters) -> PumpCommand {
+    drain_command_from(1, volume)
+}
+
+// Full strokes through pump 2 channel `port` and a partial last one covering `volume` and the
+// drain_overdraw_ul on top
+fn drain_command_from(port: u8, volume: Microliters) -> PumpCommand {
+    let strokes = estimation::split_volume(volume + CONFIG.drain_overdraw_ul);
+    let full = strokes.iter().filter(|s| **s == MAX_STROKE_MICROLITER).count() as u32;
+    let mut command = PumpCommand::new(2);
+    if full > 0 {
+        command = command.valve_in(port).move_to(FULL_STROKE).valve_out(2).move_to(PumpUnits::ZERO);
+        if full > 1 {
+            command = command.repeat(full);
+        }
+    }
+    match strokes.last().and_then(|last| last.to_pump_units().ok()).filter(|units| *units < FULL_STROKE) {
+        Some(partial) => command.valve_in(port).move_to(partial).valve_out(2).move_to(PumpUnits::ZERO),
+        None => command,
+    }
+}
+
+fn drain_slot(controller: &mut Controller) -> ControlFlow<String> {
     log::trace!("Slot occupancy - {}", controller.slot_occupancy);
-    if controller.slot_occupancy > 0 {
+    if controller.slot_occupancy > Microliters(0) {
+        if let Some(tube) = controller.slot_recovery.clone() {
+            return recover_slot(controller, &tube);
+        }
         log::trace!("Pumping liquid out of slot");
-        let vol = microliter_to_pumpunit(controller.slot_occupancy);
-        controller.pump_execute(&*format!("/2gI1A12000O2A0G4R\r\n"))?;
-        controller.slot_occupancy = 0;
+        controller.pump_execute(&drain_command(controller.slot_occupancy))?;
+        controller.volumes.discard(controller.slot_occupancy);
+        controller.slot_occupancy = Microliters(0);
     }
+    ControlFlow::Continue(())
+}
 
+// The slot contents are drawn back through pump 1 and pushed out of the needle into `tube`
+fn recover_slot(controller: &mut Controller, tube: &str) -> ControlFlow<String> {
+    let position = match controller.calibration.tube_position(tube) {
+        Ok(position) => position,
+        Err(e) => return ControlFlow::Break(format!("Cannot recover the slot contents: {e}")),
+    };
+    let volume = controller.slot_occupancy;
+    log::info!("Recovering {} ul from the slot into tube {}", volume, tube);
+    controller.router_move(position)?;
+    for cycle in estimation::split_volume(volume) {
+        let units = microliter_to_pumpunit(cycle, Resolution::Standard)?;
+        controller.pump_execute(&PumpCommand::new(1).valve_in(2).move_to(units).valve_out(1).move_to(PumpUnits::ZERO))?;
+    }
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..position })?;
+    if let Some(class) = contamination::reagent_class(tube) {
+        controller.needle_residues.push(class.to_string());
+    }
+    controller.tubes.refill(tube, volume);
+    controller.events.emit("recover", &[("tube", tube.to_string()), ("volume_ul", volume.to_string()),
+        ("command_id", controller.command_id.to_string())]);
+    controller.slot_occupancy = Microliters(0);
+    controller.slot_recovery = None;
+    ControlFlow::Continue(())
+}
+
+// With dual_pump_wash pump 2 empties the slot while pump 1 takes up liquid or washes the needle.
+// The drained volume only counts once finish_slot_drain has seen both pumps finish without error.
+fn start_slot_drain(controller: &mut Controller) -> ControlFlow<String> {
+    // Recovery needs pump 1 and the needle, so it can't run alongside
+    if !CONFIG.dual_pump_wash || controller.slot_recovery.is_some() {
+        return drain_slot(controller);
+    }
+    if controller.slot_occupancy == Microliters(0) || controller.draining.is_some() {
+        return ControlFlow::Continue(());
+    }
+    log::trace!("Pumping liquid out of slot in parallel");
+    controller.pump_execute_async(&drain_command(controller.slot_occupancy))?;
+    controller.draining = Some(controller.slot_occupancy);
+    ControlFlow::Continue(())
+}
+
+fn finish_slot_drain(controller: &mut Controller) -> ControlFlow<String> {
+    let Some(volume) = controller.draining.take() else {
+        return ControlFlow::Continue(());
+    };
+    controller.await_pumps_idle()?;
+    for pump in controller.pumps.pumps() {
+        match pump.status() {
+            Ok(status) if status.error == PumpError::None => {}
+            Ok(status) => return ControlFlow::Break(format!("Pump {} - failed during parallel wash: {:?}", pump.address(), status.error)),
+            Err(e) => return ControlFlow::Break(e),
+        }
+    }
+    controller.volumes.discard(volume);
+    controller.slot_occupancy = Microliters(0);
+    controller.slot_recovery = None;
+    ControlFlow::Continue(())
+}
+
+fn parse_liquid_application(command: &str) -> ControlFlow<String, LiquidApplication> {
     let parts: Vec<&str> = command.split('_').collect();
     let from = unwrap_option!(parts.get(1), "Cannot deduce 'from' part".to_string());
-    let from_number = unwrap_result!(from.parse::<u64>());
-    let vol_microliter = parts.get(3)
-        .and_then(|v| v.parse().ok())
-        .unwrap();
-    if from_number > 33 {
-        return handle_external_liquid_application(controller, from_number, vol_microliter);
+    let destination = unwrap_option!(parts.get(2), "Cannot deduce destination part".to_string());
+    let volume = unwrap_option!(units::volume_field(command), format!("Cannot deduce volume from {command}"));
+    let vol_microliter = match units::parse_volume(volume) {
+        Ok(vol) => vol,
+        Err(e) => return ControlFlow::Break(format!("{command}: {e}")),
+    };
+    let recover = match parts.get(4) {
+        Some(marker) => Some(unwrap_option!(marker.strip_prefix('R').filter(|tube| !tube.is_empty()),
+            format!("Cannot deduce recovery tube from {command}, expected R<tube>")).to_string()),
+        None => None,
+    };
+    ControlFlow::Continue(LiquidApplication {
+        command: command.to_string(),
+        from: from.to_string(),
+        destination: destination.to_string(),
+        vol_microliter,
+        recover,
+    })
+}
+
+fn stage_liquid_application(controller: &mut Controller, command: &str) -> ControlFlow<String, StagedApplication> {
+    let application = parse_liquid_application(command)?;
+    expiry::before_use(controller, &application.from)?;
+    let mut cycles = plan_cycles(controller, &application)?;
+    let first_cycle = cycles.remove(0);
+    let prepared = prepare_liquid_application(controller, &application, first_cycle)?;
+    ControlFlow::Continue(StagedApplication { application, prepared, remaining_cycles: cycles })
+}
+
+fn finish_liquid_application(controller: &mut Controller, staged: StagedApplication) -> ControlFlow<String> {
+    drain_slot(controller)?;
+    let wash_between = CONFIG.wash_between_cycles;
+    let cycles = staged.remaining_cycles.len();
+    complete_liquid_application(controller, staged.prepared, wash_between || cycles == 0)?;
+    for (i, vol_microliter) in staged.remaining_cycles.into_iter().enumerate() {
+        log::trace!("Transfer cycle {} of {}", i + 2, cycles + 1);
+        let prepared = prepare_liquid_application(controller, &staged.application, vol_microliter)?;
+        complete_liquid_application(controller, prepared, wash_between || i + 1 == cycles)?;
     }
-    let [x, y, z] = CONFIG.tube_holder_coordinates.get(&from.to_string())
-        .map(|coords| coords.split(":").collect::<Vec<&str>>())
-        .and_then(|coords| <[&str; 3]>::try_from(coords).ok())
-        .expect(format!("Couldn't find x/y/z coordinates from command: {command}").as_str());
+    ControlFlow::Continue(())
+}
 
-    controller.router_execute(&*format!("G1X{x}Y{y}Z{z}\r\n"))?;
-    let vol: u64 = microliter_to_pumpunit(vol_microliter);
+fn prepare_liquid_application(controller: &mut Controller, application: &LiquidApplication, vol_microliter: Microliters) -> ControlFlow<String, PreparedApplication> {
+    if let Some(channel) = deck::external_channel(&application.from) {
+        return prepare_external_liquid_application(controller, channel, application, vol_microliter);
+    }
+    let tube = match controller.calibration.tube_position(&application.from) {
+        Ok(tube) => tube,
+        Err(e) => return ControlFlow::Break(e),
+    };
+
+    clean_needle_for(controller, &application.from, &application.command)?;
+    tips::before_aspiration(controller)?;
+    detection::check_tube_present(controller, &application.from, tube)?;
+    controller.router_move(tube)?;
+    let resolution = aspiration_resolution(controller, vol_microliter);
+    let vol = microliter_to_pumpunit(vol_microliter, resolution)?;
 
     log::trace!("Taking liquid");
-    controller.pump_execute(&*format!("/1I1A{vol}O2A0R\r\n"))?;
-    controller.router_execute(&*format!("G1X{x}Y{y}Z0\r\n"))?;
+    controller.pump_execute(&PumpCommand::new(1).resolution(resolution).valve_in(1).move_to(vol).valve_out(2).move_to(PumpUnits::ZERO))?;
+    record_aspiration(controller, application, vol_microliter);
+    rollback::taken_up(controller, &application.from, vol_microliter, Held::Line);
+    if let Some(class) = contamination::reagent_class(&application.from) {
+        controller.needle_residues.push(class.to_string());
+    }
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..tube })?;
+    ControlFlow::Continue(PreparedApplication {
+        source: PreparedSource::Tube,
+        from: application.from.clone(),
+        destination: application.destination.clone(),
+        vol_microliter,
+        command_id: controller.command_id,
+        recover: application.recover.clone(),
+    })
+}
+
+fn prepare_external_liquid_application(controller: &mut Controller, channel: u8, application: &LiquidApplication, vol: Microliters) -> ControlFlow<String, PreparedApplication> {
+    let resolution = aspiration_resolution(controller, vol);
+    let pump_vol = microliter_to_pumpunit(vol, resolution)?;
+    controller.pump_execute(&PumpCommand::new(1).resolution(resolution).valve_in(channel).move_to(pump_vol).valve_out(2).move_to(PumpUnits::ZERO))?;
+    record_aspiration(controller, application, vol);
+    rollback::taken_up(controller, &application.from, vol, Held::Line);
+    ControlFlow::Continue(PreparedApplication {
+        source: PreparedSource::External,
+        from: application.from.clone(),
+        destination: application.destination.clone(),
+        vol_microliter: vol,
+        command_id: controller.command_id,
+        recover: application.recover.clone(),
+    })
+}
+
+fn record_aspiration(controller: &mut Controller, application: &LiquidApplication, vol: Microliters) {
+    let source = estimation::tube_label(&application.from);
+    controller.volumes.consume(&source, vol);
+    controller.tubes.draw(&application.from, vol);
+    controller.expiry.record(&application.from);
+    controller.events.emit("aspirate", &[("source", source), ("volume_ul", vol.to_string()),
+        ("command_id", controller.command_id.to_string())]);
+}
+
+fn record_dispense(controller: &mut Controller, prepared: &PreparedApplication) {
+    let source = estimation::tube_label(&prepared.from);
+    let recovery = prepared.recover.clone().or_else(|| CONFIG.slot_recovery.get(&prepared.from).cloned());
+    // Liquid mixed in from another source spoils what would be recovered
+    if controller.slot_occupancy > Microliters(0) && controller.slot_recovery != recovery {
+        if let Some(tube) = controller.slot_recovery.take() {
+            log::warn!("{} added to slot contents meant for tube {}, they will go to waste", source, tube);
+        }
+    } else {
+        controller.slot_recovery = recovery;
+    }
+    controller.slot_occupancy += prepared.vol_microliter;
+    controller.custody.record(&prepared.destination, &source, prepared.vol_microliter, prepared.command_id);
+    controller.events.emit("dispense", &[("source", source), ("destination", prepared.destination.clone()),
+        ("volume_ul", prepared.vol_microliter.to_string()), ("command_id", prepared.command_id.to_string())]);
+}
+
+fn complete_liquid_application(controller: &mut Controller, prepared: PreparedApplication, clean: bool) -> ControlFlow<String> {
+    rollback::dispensing(controller);
+    if let PreparedSource::External = prepared.source {
+        controller.pump_execute(&PumpCommand::new(1).valve_in(5).move_to(FULL_STROKE).valve_out(2).move_to(PumpUnits::ZERO).repeat(3))?;
+        rollback::committed(controller);
+        record_dispense(controller, &prepared);
+        return ControlFlow::Continue(());
+    }
     log::trace!("Pumping liquid");
-    // controller.pump_execute_async("/2gI1A12000O2A0G7R\r\n")?; // Using other pump to pump out liquid from slot
-    controller.pump_execute(&*format!("/1gI1A12000O2A0G6R\r\n"))?; // pumping to slot
-    controller.slot_occupancy = vol_microliter;
-    if CONFIG.constant_cleaning == false {
+    controller.pump_execute(&PumpCommand::new(1).valve_in(1).move_to(FULL_STROKE).valve_out(2).move_to(PumpUnits::ZERO).repeat(6))?; // pumping to slot
+    rollback::committed(controller);
+    record_dispense(controller, &prepared);
+    if !clean || !CONFIG.constant_cleaning {
         return ControlFlow::Continue(());
     }
+    wash_needle(controller)
+}
+
+// Applies the contamination rules, and with [wash-station] on_reagent_change any change of reagent
+// class, before the needle goes into `tube`
+fn clean_needle_for(controller: &mut Controller, tube: &str, command: &str) -> ControlFlow<String> {
+    let reason = match contamination::violated(&controller.needle_residues, tube) {
+        Some(rule) if rule.action == ContaminationAction::Reject => return ControlFlow::Break(contamination::describe(rule, command)),
+        Some(rule) => contamination::describe(rule, command),
+        None => match contamination::reagent_changed(&controller.needle_residues, tube, command) {
+            Some(reason) => reason,
+            None => return ControlFlow::Continue(()),
+        },
+    };
+    if CONFIG.tips.enabled && CONFIG.tips.change_on_contamination {
+        log::info!("{}, changing tip first", reason);
+        tips::change_tip(controller)
+    } else {
+        log::info!("{}, washing first", reason);
+        wash_needle(controller)
+    }
+}
+
+// The [wash-station] routine: water is flushed through the needle in the wash well while it moves
+// up and down, then air is pushed through to dry it
+fn wash_needle(controller: &mut Controller) -> ControlFlow<String> {
+    let station = &CONFIG.wash_station;
     log::trace!("Starting water cleaning");
-    controller.router_execute("G1X315Y142Z-20\r\n")?;
+    controller.router_move(station.position)?;
     log::trace!("Pumping water");
-    controller.pump_execute("/1gI4A12000O1A0G2R\r\n")?;
+    for stroke in estimation::split_volume(station.flush_ul) {
+        let units = microliter_to_pumpunit(stroke, Resolution::Standard)?;
+        controller.pump_execute(&PumpCommand::new(1).valve_in(4).move_to(units))?;
+        agitate(controller, &PumpCommand::new(1).valve_out(1).move_to(PumpUnits::ZERO))?;
+    }
+    controller.volumes.consume(estimation::CLEANING_SOURCE, station.flush_ul);
+    controller.volumes.discard(station.flush_ul);
     log::trace!("Pumping Air");
-    controller.pump_execute("/1gI5A12000O1A0G4R\r\n")?;
+    controller.pump_execute(&PumpCommand::new(1).valve_in(5).move_to(FULL_STROKE).valve_out(1).move_to(PumpUnits::ZERO).repeat(station.air_strokes))?;
+    controller.needle_residues.clear();
     ControlFlow::Continue(())
 }
 
-fn handle_external_liquid_application(controller: &mut Controller, from: u64, vol: u64) -> ControlFlow<String> {
-    let required_channel = match from {
-        34 => 4,
-        35 => 7,
-        36 => 6,
-        _ => return ControlFlow::Break("Developer is dumb".to_string())
-    };
-    let pump_vol = microliter_to_pumpunit(vol);
-    controller.pump_execute(&*format!("/1I{required_channel}A{pump_vol}O2A0gI5A12000O2A0G3R\r\n"))?;
-    controller.slot_occupancy += vol;
+// Dispenses while the needle oscillates oscillation_mm above the wash position
+fn agitate(controller: &mut Controller, dispense: &PumpCommand) -> ControlFlow<String> {
+    let station = &CONFIG.wash_station;
+    if station.oscillations == 0 || station.oscillation_mm == Millimeters(0.0) {
+        return controller.pump_execute(dispense);
+    }
+    controller.pump_execute_async(dispense)?;
+    let raised = Coordinates { z: station.position.z + station.oscillation_mm, ..station.position };
+    let moved = (0..station.oscillations).try_for_each(|_| {
+        controller.router_move(raised)?;
+        controller.router_move(station.position)
+    });
+    let pump = controller.pumps.pump(1);
+    if moved.is_break() {
+        if let Err(e) = pump.terminate() {
+            log::error!("{}", e);
+        }
+        return moved;
+    }
+    controller.await_pump(&pump)
+}
+
+fn handle_end_of_run(controller: &mut Controller) -> ControlFlow<String> {
+    let settings = &CONFIG.end_of_run;
+    log::info!("Running end-of-run sequence");
+    if settings.drain_slot {
+        wells::drain_all(controller)?;
+        start_slot_drain(controller)?;
+    }
+    let washed = if settings.final_wash { wash_needle(controller) } else { ControlFlow::Continue(()) };
+    finish_slot_drain(controller)?;
+    washed?;
+    if let Some(park) = settings.park_position {
+        controller.router_move(Coordinates { z: motion::SAFE_Z, ..controller.router.position })?;
+        controller.router_move(park)?;
+    }
+    if settings.zero_pumps {
+        controller.pump_execute(&PumpCommand::new(1).move_to(PumpUnits::ZERO))?;
+        controller.pump_execute(&PumpCommand::new(2).move_to(PumpUnits::ZERO))?;
+    }
+    if settings.thermal_off {
+        match controller.thermal_port() {
+            Some(port) => unwrap_result!(serial_write(port, "M104S0"), "Failed to power down thermal module".to_string()),
+            None => log::warn!("Thermal module unavailable, not powering it down"),
+        }
+        thermal::zones_off(controller);
+    }
+    let summary = format!("END {}", controller.volumes);
+    controller.application.send_status(&summary);
     ControlFlow::Continue(())
 }
 
-fn handle_waiting_command(command: &str) -> ControlFlow<String> {
+fn handle_waiting_command(controller: &mut Controller, command: &str, started: Instant) -> ControlFlow<String> {
     let parts: Vec<&str> = command.split('_').collect();
-    let time: u64 = parts.get(1)
-        .and_then(|t| t.parse().ok())
-        .expect("Cannot get time for wait command");
+    let time: u64 = unwrap_option!(parts.get(1).and_then(|t| t.parse().ok()),
+        format!("Cannot deduce wait time from {command}"));
+    // The deadline counts from the start of the step, so time spent waiting for the pump is not added on top
+    let deadline = match controller.journal.as_ref().and_then(Journal::remaining_wait) {
+        Some(remaining) => {
+            log::info!("Resuming wait, {} of {} milliseconds remaining", remaining.as_millis(), time);
+            controller.clock.now() + remaining
+        }
+        None => started + Duration::from_millis(time),
+    };
+    let remaining = deadline.saturating_duration_since(controller.clock.now());
+    if let Some(journal) = controller.journal.as_mut() {
+        journal.record_wait(remaining);
+    }
+    controller.timeline.wait(deadline.saturating_duration_since(started));
+    let progress_interval = Duration::from_secs(CONFIG.wait_progress_interval_secs.max(1));
+    let mut next_progress = controller.clock.now() + progress_interval;
     log::info!("Waiting for {} milliseconds", time);
-    sleep(Duration::from_millis(time));
-    ControlFlow::Continue(())
+    loop {
+        let now = controller.clock.now();
+        if now >= deadline {
+            return ControlFlow::Continue(());
+        }
+        shaker::stop_when_done(controller)?;
+        thermal::regulate(controller);
+        sensors::sample(controller);
+        match controller.poll_controls() {
+            ControlFlow::Break(reason) if reason == SKIP_REASON => return ControlFlow::Break(reason),
+            ControlFlow::Break(_) => return ControlFlow::Break(format!("Wait aborted with {}s remaining", (deadline - now).as_secs())),
+            ControlFlow::Continue(()) => {}
+        }
+        if now >= next_progress {
+            let status = format!("waiting {}s remaining", (deadline - now).as_secs());
+            log::info!("{}", status);
+            controller.application.send_status(&status);
+            next_progress += progress_interval;
+        }
+        controller.clock.sleep((deadline - now).min(Duration::from_millis(100)));
+    }
+}
+
+fn start_step(controller: &mut Controller, command: &str) -> SystemTime {
+    port_operations::forget_exchange();
+    fault_injection::enter_step(controller.command_id.step_number());
+    controller.spans.enter("command", &[("command", metadata::redact(command)), ("command_id", controller.command_id.to_string())]);
+    controller.timeline.start_step(command);
+    controller.events.emit("step_start", &[("command", metadata::redact(command)), ("command_id", controller.command_id.to_string())]);
+    SystemTime::now()
+}
+
+// A skipped step is recorded as failed and the run continues with the next one
+fn finish_step(controller: &mut Controller, command: &str, started: SystemTime, result: ControlFlow<String>) -> ControlFlow<String> {
+    controller.report.record(command, started, &result);
+    controller.spans.exit(&result);
+    controller.timeline.finish_step();
+    fault_injection::leave_step();
+    let outcome = result.clone().break_value().map_or("ok".to_string(), |e| format!("ERROR {e}"));
+    controller.events.emit("step_end", &[("command", metadata::redact(command)), ("command_id", controller.command_id.to_string()),
+        ("result", outcome)]);
+    match result {
+        ControlFlow::Break(e) if e == SKIP_REASON => {
+            log::warn!("Step {} skipped", command);
+            rollback::undo_step(controller);
+            controller.notes.push(format!("SKIPPED {command}"));
+            controller.application.send_status(&format!("SKIPPED step={command} command_id={}", controller.command_id));
+            ControlFlow::Continue(())
+        }
+        ControlFlow::Break(e) => {
+            if e != ABORT_REASON {
+                let id = controller.command_id;
+                controller.fault.get_or_insert_with(|| Fault::new(command, id, &e));
+            }
+            ControlFlow::Break(e)
+        }
+        done => done,
+    }
+}
+
+fn execute_batch(ports: &mut Controller, commands: &[&str]) -> ControlFlow<String> {
+    let mut budget: Option<LatencyBudget> = None;
+    let mut staged: Option<(usize, StagedApplication)> = None;
+    let message_id = ports.command_id.message();
+    let resume_from = ports.journal.as_ref().map_or(0, |j| j.next_command);
+    let mut branches = script::Branches::replaying(ports.journal.as_ref().map_or(&[][..], |j| j.branches.as_slice()));
+    for (i, command) in commands.iter().enumerate() {
+        ports.checkpoint()?;
+        shaker::stop_when_done(ports)?;
+        ports.set_command_id(message_id.step(i));
+        // Also before the resume point, so a resumed run is in the same blocks, with the decisions
+        // the interrupted run made
+        if script::is_branch(command) {
+            let evaluated = branches.enter(ports, command);
+            if let Some((journal, taken)) = ports.journal.as_mut().zip(evaluated) {
+                journal.record_branch(taken);
+            }
+            continue;
+        }
+        if i < resume_from {
+            continue;
+        }
+        if branches.skipping() {
+            log::info!("Skipping {}, its condition does not hold", command);
+            continue;
+        }
+        if let Some(journal) = ports.journal.as_mut() {
+            journal.advance(i);
+        }
+        if let Some(parsed) = latency::parse_budget(command) {
+            let limit = match parsed {
+                Ok(limit) => limit,
+                Err(e) => return ControlFlow::Break(e),
+            };
+            finish_budget(ports, budget.take())?;
+            // Everything up to the first dispense is done before the clock starts
+            if let Some(j) = latency::first_application_in_section(commands, i) {
+                log::info!("Pre-staging {} for latency-sensitive section", commands[j]);
+                ports.set_command_id(message_id.step(j));
+                staged = Some((j, stage_liquid_application(ports, commands[j])?));
+            }
+            budget = Some(limit.start());
+            continue;
+        }
+        if metadata::is_metadata(command) {
+            continue;
+        }
+        if *command == latency::SECTION_END {
+            finish_budget(ports, budget.take())?;
+            continue;
+        }
+        if let Some((_, prepared)) = staged.take_if(|(j, _)| *j == i) {
+            let started = start_step(ports, command);
+            let result = match ports.await_pumps_idle() {
+                ControlFlow::Continue(()) => finish_liquid_application(ports, prepared),
+                stopped => stopped,
+            };
+            finish_step(ports, command, started, result)?;
+            continue;
+        }
+        let started = start_step(ports, command);
+        let result = execute_command(ports, command);
+        finish_step(ports, command, started, result)?;
+    }
+    finish_budget(ports, budget)
+}
+
+fn finish_budget(ports: &mut Controller, budget: Option<LatencyBudget>) -> ControlFlow<String> {
+    let Some(budget) = budget else {
+        return ControlFlow::Continue(());
+    };
+    match budget.finish() {
+        Ok(report) => {
+            log::info!("{}", report);
+            ports.application.send_status(&report);
+            ControlFlow::Continue(())
+        }
+        Err(e) => {
+            ports.application.send_status(&e);
+            ControlFlow::Break(e)
+        }
+    }
+}
+
+fn handle_request(ports: &mut Controller, request: ControllerRequest) {
+    match request {
+        ControllerRequest::Line(line) => handle_line(ports, line),
+        ControllerRequest::FramingLost => ports.application.send_status("RESEND reason=framing"),
+        ControllerRequest::Message { channel, data, reply } => {
+            ports.application.set_reply(reply);
+            let crc = crc32fast::hash(data.as_bytes());
+            receive_message(ports, Message { channel, data, crc });
+            ports.application.set_reply(None);
+        }
+    }
 }
 
 fn handle_line(ports: &mut Controller, line: String) {
-    let msg = message::parse_to_message(line.clone());
-    match msg {
-        Some(v) => handle_message(ports, v),
-        None => log::error!("Invalid message: {}", line),
+    let Some(msg) = message::parse_to_message(line.clone()) else {
+        log::error!("Invalid message: {}", metadata::redact(&line));
+        return;
+    };
+    if msg.channel == message::COMMAND_CHANNEL {
+        if handshake::is_hello(&msg.data) {
+            return handshake::receive(ports, &msg.data);
+        }
+        // Controls and queries still work, so the application can find out what is wrong
+        if let Some(refusal) = ports.handshake.refusal().filter(|_| !msg.data.split(' ').all(is_query)) {
+            log::warn!("Refusing [{}]: {}", metadata::redact(&msg.data), refusal);
+            return ports.application.send_status(&format!("ERROR protocol: {refusal}"));
+        }
+    }
+    receive_message(ports, msg)
+}
+
+// Messages from the link, which may be parts of a protocol announced by a manifest
+fn receive_message(ports: &mut Controller, msg: Message) {
+    if msg.channel != message::COMMAND_CHANNEL || msg.data.split(' ').all(is_query) {
+        return handle_message(ports, msg);
+    }
+    if let Some(reply) = ports.manifest.announce(&msg.data) {
+        ports.application.send_status(&reply);
+        return;
+    }
+    match ports.manifest.add(&msg.data) {
+        None => handle_message(ports, msg),
+        Some(Assembly::Buffered(reply)) => ports.application.send_status(&reply),
+        Some(Assembly::Rejected(e)) => {
+            log::warn!("Discarding manifest protocol: {}", e);
+            ports.application.send_status(&format!("ERROR manifest: {e}"));
+        }
+        Some(Assembly::Expired(e)) => {
+            log::warn!("Discarding manifest protocol: {}", e);
+            ports.application.send_status(&format!("ERROR manifest: {e}"));
+            handle_message(ports, msg);
+        }
+        Some(Assembly::Complete(data)) => {
+            log::info!("Manifest protocol complete, checksum verified");
+            ports.application.send_status(&format!("MANIFEST COMPLETE steps={}", data.split(' ').count()));
+            let crc = crc32fast::hash(data.as_bytes());
+            handle_message(ports, Message { channel: msg.channel, data, crc });
+        }
     }
 }
 
 
 fn handle_message(ports: &mut Controller, msg: Message) {
-    log::trace!("Parsed message: {}, {}, {}", msg.channel, msg.data, msg.crc);
-    if msg.channel != 4 {
+    log::trace!("Parsed message: {}, {}, {}", msg.channel, metadata::redact(&msg.data), msg.crc);
+    if msg.channel == message::CONTROL_CHANNEL {
+        if let ControlFlow::Break(e) = ports.handle_control(&msg.data) {
+            log::info!("{} while not executing", e);
+        }
         return;
     }
-    match msg.data.split(' ').try_for_each(|c| execute_command(ports, c)) {
-        ControlFlow::Continue(_) => log::info!("Executed command successfully"),
-        ControlFlow::Break(e) => log::error!("ERROR: {}", escape_chars(e.as_str()))
+    if msg.channel != message::COMMAND_CHANNEL {
+        return;
+    }
+    if msg.data.split(' ').all(is_query) {
+        msg.data.split(' ').for_each(|query| answer_query(ports, query));
+        return;
+    }
+    if let Some(run) = runs::parse_run(&msg.data) {
+        let id = run.id.clone();
+        let reply = match ports.runs.enqueue(run) {
+            Ok(position) => format!("RUN {id} QUEUED position={position}"),
+            Err(e) => format!("REFUSED reason={e}"),
+        };
+        ports.application.send_status(&reply);
+        return;
+    }
+    let id = ports.command_ids.next();
+    ports.set_command_id(id);
+    execute_message(ports, msg);
+    logtail::set_command(None);
+}
+
+fn execute_message(ports: &mut Controller, msg: Message) {
+    let id = ports.command_id;
+    log::info!("Received command: {}", metadata::redact(&msg.data));
+    if let Some(refusal) = ports.state.refusal() {
+        log::warn!("Refusing [{}]: {}", metadata::redact(&msg.data), refusal);
+        ports.application.send_status(&format!("{refusal} command_id={id}"));
+        return;
+    }
+    let expanded = match script::expand(&msg.data.split(' ').collect::<Vec<&str>>()) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            log::warn!("Refusing [{}]: {}", metadata::redact(&msg.data), e);
+            ports.application.send_status(&format!("ERROR command_id={id} script: {e}"));
+            return;
+        }
+    };
+    let submitted: Vec<&str> = expanded.iter().map(String::as_str).collect();
+    let commands = motion::order_for_travel(&submitted);
+    if commands != submitted {
+        log::info!("Reordered barcode scans to shorten router travel: {}", metadata::redact(&commands.join(" ")));
+    }
+    if let Err(e) = capabilities::check_batch(&commands, &mut ports.devices, &ports.peripherals, Halted { router: ports.router.halted.is_some(), pumps: ports.pump_lock.locked.is_some() }, &ports.safe_mode) {
+        log::warn!("Refusing [{}]: {}", metadata::redact(&msg.data), e);
+        ports.application.send_status(&format!("ERROR command_id={id} {e}"));
+        return;
+    }
+    if let Err(e) = deck::check_sources(&commands)
+        .and_then(|_| deck::check_destinations(&commands))
+        .and_then(|_| deck::check_recoveries(&commands))
+        .and_then(|_| units::check_volumes(&commands))
+        .and_then(|_| metadata::check_required(&commands))
+        .and_then(|_| expiry::check_batch(&ports.expiry, &commands)) {
+        log::warn!("Refusing [{}]: {}", metadata::redact(&msg.data), e);
+        ports.application.send_status(&format!("ERROR command_id={id} {e}"));
+        return;
+    }
+    let inserted_washes = match contamination::validate(&commands, &ports.needle_residues) {
+        Ok(washes) => washes,
+        Err(e) => {
+            log::warn!("Refusing protocol: {}", e);
+            ports.application.send_status(&format!("ERROR command_id={id} contamination rule: {e}"));
+            return;
+        }
+    };
+    if let Err(e) = preflight::check(ports) {
+        log::error!("Refusing [{}]: preflight: {}", metadata::redact(&msg.data), e);
+        ports.application.send_status(&format!("ERROR command_id={id} preflight: {e}"));
+        return;
+    }
+    ports.state = ControllerState::Running;
+    let started = SystemTime::now();
+    let estimate = estimation::estimate_protocol(&commands, ports.slot_occupancy);
+    log::info!("Protocol estimate: {}, motion and waits {}s", estimate,
+        estimation::estimate_duration(&commands, ports.router.position).as_secs());
+    if let Some(warning) = estimate.check_waste_capacity() {
+        log::warn!("{}", warning);
+    }
+    ports.volumes = VolumeReport::default();
+    ports.custody = CustodyLog::default();
+    ports.notes.clear();
+    ports.notes.extend(inserted_washes);
+    ports.expiry.allow(&commands);
+    ports.present_tubes.clear();
+    ports.report = RunReport::default();
+    ports.timeline.start_run();
+    ports.fault = None;
+    ports.metadata = RunMetadata::from_commands(&commands);
+    let run_id = ports.runs.current.clone().or_else(|| ports.metadata.fields.get("run").cloned());
+    ports.sensor_log.start_run(run_id.as_deref());
+    ports.spans.start_run(&[("run_id", run_id.clone().unwrap_or_default()), ("steps", commands.len().to_string())]);
+    ports.events.start_run(&[("run_id", run_id.unwrap_or_default()), ("steps", commands.len().to_string())]);
+    if !ports.metadata.fields.is_empty() {
+        log::info!("Run metadata: {}", ports.metadata.redacted());
+    }
+    // A journal already set up for this message means it is being resumed
+    let journal = match ports.journal.take() {
+        Some(journal) if journal.data == msg.data => {
+            if let Some(original) = journal.command_id {
+                log::info!("Resuming command {} as {}", id, original);
+                ports.set_command_id(original);
+            }
+            journal
+        }
+        _ => Journal::new(&msg.data, id),
+    };
+    journal::save(&journal);
+    ports.journal = Some(journal);
+    let mut failure = None;
+    let result = execute_batch(ports, &commands);
+    ports.set_command_id(ports.command_id.message());
+    let id = ports.command_id;
+    let response = match result {
+        ControlFlow::Continue(_) => {
+            log::info!("Executed command successfully");
+            ports.state = ControllerState::Idle;
+            if ports.notes.is_empty() {
+                format!("ACK command_id={id}")
+            } else {
+                format!("ACK command_id={id} notes={}", ports.notes.join("; "))
+            }
+        }
+        ControlFlow::Break(e) => {
+            log::error!("ERROR: {}", escape_chars(e.as_str()));
+            ports.state = ControllerState::Faulted(escape_chars(e.as_str()));
+            let event = if e == ABORT_REASON { NotificationEvent::Estop } else { NotificationEvent::Fault };
+            failure = Some(event);
+            format!("ERROR command_id={id} {}", escape_chars(e.as_str()))
+        }
+    };
+    ports.application.send_status(&response);
+    if let Some(fault) = ports.fault.take() {
+        ports.application.send_status(&fault.frame());
+    }
+    if let ControlFlow::Break(e) = shaker::stop(ports) {
+        log::error!("{}", e);
+    }
+    // A step that was aborted or failed can leave the needle down in a tube
+    if failure.is_some() {
+        if let ControlFlow::Break(e) = ports.router_move(Coordinates { z: motion::SAFE_Z, ..ports.router.position }) {
+            log::error!("Failed to raise the needle after the run stopped: {}", e);
+        }
+        rollback::undo(ports);
+    }
+    if let Some(port) = ports.thermal_port() {
+        serial_write(port, "M104F").ok(); // sets temperature to normal
+    }
+    // A failed run still recovers what is in the slot before draining the rest
+    if ports.slot_recovery.is_some() {
+        if let ControlFlow::Break(e) = drain_slot(ports) {
+            log::error!("Failed to recover the slot contents: {}", e);
+        }
+    }
+    if ports.pump_execute(&drain_command(ports.slot_occupancy)).is_continue() { // pump out remaining liquid
+        ports.volumes.discard(ports.slot_occupancy);
+        ports.slot_occupancy = Microliters(0);
+        ports.slot_recovery = None;
+    }
+    if let ControlFlow::Break(e) = wells::drain_all(ports) {
+        log::error!("Failed to drain the flow cell wells: {}", e);
+    }
+    log::info!("Protocol volumes: {}", ports.volumes);
+    log::info!("Protocol custody: {}", ports.custody);
+    let mut summary = format!("SUMMARY {} custody {}", ports.volumes, ports.custody);
+    if !ports.metadata.fields.is_empty() {
+        summary += &format!(" metadata {}", ports.metadata);
+    }
+    ports.application.send_status(&summary);
+    notifications::notify(Notification {
+        event: failure.unwrap_or(NotificationEvent::Completion),
+        run_id: ports.runs.current.clone().or_else(|| ports.metadata.fields.get("run").cloned()),
+        message: match failure {
+            Some(_) => response.clone(),
+            None => ports.volumes.to_string(),
+        },
+        metadata: ports.metadata.redacted_fields(),
+    });
+    report::write(ports, started, &response);
+    let run_id = ports.runs.current.clone().or_else(|| ports.metadata.fields.get("run").cloned());
+    ports.timeline.write(started, run_id.as_deref());
+    ports.sensor_log.end_run();
+    ports.events.end_run(&[("outcome", metadata::redact(&response))]);
+    ports.spans.end_run(failure.is_some().then_some(response.as_str()));
+    let record = history::RunRecord {
+        started: started.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        duration_secs: started.elapsed().map(|d| d.as_secs()).unwrap_or(0),
+        tenant: ports.metadata.fields.get(&CONFIG.tenant_metadata_key).cloned(),
+        outcome: response,
+        commands: commands.iter().filter(|c| !metadata::is_metadata(c)).count(),
+        consumed_ul: ports.volumes.consumption.values().copied().sum::<Microliters>().0,
+        waste_ul: ports.volumes.waste.0,
+        metadata: ports.metadata.fields.clone(),
+    };
+    if let Err(e) = history::append(record) {
+        log::error!("{}", e);
+    }
+    ports.wear.save();
+    ports.reservoirs.save();
+    ports.journal = None;
+    journal::clear();
+}
+
+// Queries, and REFILLED_ which only resets a count, are answered in any state and do not start a run
+fn is_query(command: &str) -> bool {
+    command == "QRUNS" || command.starts_with("QWELL_") || command.starts_with("QHISTORY") || command.starts_with("QSTATS")
+        || command.starts_with("QUERY_") || command.starts_with(config_query::PREFIX) || command.starts_with("REFILLED_")
+}
+
+fn answer_query(ports: &mut Controller, query: &str) {
+    let reply = match query.split_once('_') {
+        Some(("QWELL", well)) => ports.custody.describe_well(well),
+        None if query == "QRUNS" => ports.runs.describe(),
+        Some(("QUERY", "SLOT")) => format!("SLOT occupancy={}ul{}", ports.slot_occupancy, wells::describe(&ports.wells)),
+        Some(("QUERY", "STATE")) => describe_state(ports),
+        Some(("QUERY", "POSITION")) => ports.router.describe(),
+        Some(("QUERY", "WEAR")) => ports.wear.describe(),
+        Some(("QUERY", "RESERVOIRS")) => ports.reservoirs.describe(),
+        Some(("QUERY", "REAGENTS")) => ports.expiry.describe(),
+        Some(("QUERY", "CALIBRATION")) => ports.calibration.describe(),
+        Some(("QUERY", "SENSORS")) => format!("SENSORS {}", ports.sensor_log.describe()),
+        Some(("QUERY", "PERIPHERALS")) => ports.peripherals.describe(),
+        Some(("QUERY", tube)) if tube.starts_with("TUBE_") => ports.tubes.describe(&tube["TUBE_".len()..]),
+        Some(("REFILLED", id)) => ports.reservoirs.refilled(id).unwrap_or_else(|e| format!("ERROR {e}")),
+        Some(("GETCONF", key)) => match config_query::lookup(key, &ports.calibration) {
+            Ok(fields) => format!("CONF {}", fields.join(" ")),
+            Err(e) => format!("ERROR {e}"),
+        },
+        _ if query.starts_with("QHISTORY") || query.starts_with("QSTATS") => return answer_history_query(ports, query),
+        _ => format!("UNKNOWN_QUERY {query}"),
+    };
+    ports.application.send_status(&reply);
+}
+
+fn describe_state(ports: &Controller) -> String {
+    let firmware: Vec<String> = ports.firmware.versions.iter().map(|(device, version)| format!("{device}={version}")).collect();
+    format!("STATE state={} command_id={} position={} run={} {} firmware=[{}] {}", ports.state.name(), ports.command_id,
+            ports.router.position, ports.runs.current.as_deref().unwrap_or("-"), ports.tips.describe(), firmware.join(", "),
+            ports.handshake.describe())
+}
+
+// QHISTORY[_<tenant>] lists the most recent runs, QSTATS[_<tenant>] totals them
+fn answer_history_query(ports: &mut Controller, query: &str) {
+    let (kind, tenant) = match query.split_once('_') {
+        Some((kind, tenant)) => (kind, Some(tenant)),
+        None => (query, None),
+    };
+    let runs = match history::load() {
+        Ok(runs) => history::for_tenant(runs, tenant),
+        Err(e) => return ports.application.send_status(&format!("ERROR {e}")),
+    };
+    if kind == "QSTATS" {
+        return ports.application.send_status(&history::RunStats::of(&runs, tenant).to_string());
+    }
+    for run in runs.iter().skip(runs.len().saturating_sub(HISTORY_QUERY_LIMIT)) {
+        ports.application.send_status(&run.to_string());
+    }
+}
+
+fn execute_queued_run(ports: &mut Controller, run: QueuedRun) {
+    log::info!("Starting queued run {}", run.id);
+    ports.application.send_status(&format!("RUN {} STARTED", run.id));
+    ports.runs.current = Some(run.id.clone());
+    let data = format!("META_run={} {}", run.id, run.data);
+    let crc = crc32fast::hash(data.as_bytes());
+    handle_message(ports, Message { channel: message::COMMAND_CHANNEL, data, crc });
+    ports.runs.current = None;
+    let outcome = if matches!(ports.state, ControllerState::Faulted(_)) { "FAILED" } else { "DONE" };
+    ports.application.send_status(&format!("RUN {} {}", run.id, outcome));
+    if outcome == "DONE" && !ports.runs.is_empty() && !ports.safe_mode.active() {
+        for _ in 0..CONFIG.inter_run_washes {
+            if let ControlFlow::Break(e) = wash_needle(ports) {
+                log::error!("Inter-run cleaning failed: {}", e);
+                ports.state = ControllerState::Faulted(e);
+                break;
+            }
+        }
     }
-    serial_write(&mut ports.router_port, &*"M104F"); // sets temperature to normal
-    ports.pump_execute(&*format!("/2gI1A12000O2A0G4R\r\n")); // pump out remaining liquid
 }
 
 fn escape_chars(st: &str) -> String {
     st.replace("\n", "\\n").replace("\r", "\\r")
 }
 
-fn microliter_to_pumpunit(microliters: u64) -> u64 {
-    let res = microliters * 24;
-    if res > 12000 {
-        log::error!("Calculated pump units over 12000")
+fn microliter_to_pumpunit(microliters: Microliters, resolution: Resolution) -> ControlFlow<String, PumpUnits> {
+    match microliters.to_pump_units_at(resolution) {
+        Ok(units) => ControlFlow::Continue(units),
+        Err(e) => ControlFlow::Break(e),
     }
-    res
 }
 
-fn test_env_setup() {
-    sysinfo::System::new_all()
-        .processes_by_name("socat")
-        .for_each(|p| { p.kill(); });
-    Command::new("socat").args(["-d", "-d", "pty,raw,echo=1,link=/tmp/app1", "pty,raw,echo=1,link=/tmp/app2"])
-        .spawn().ok();
-    // Command::new("socat").args(["-d", "-d", "pty,raw,echo=1,link=/tmp/pump1", "pty,raw,echo=1,link=/tmp/pump2"])
-    //     .spawn().ok();
-    // Command::new("socat").args(["-d", "-d", "pty,raw,echo=1,link=/tmp/router1", "pty,raw,echo=1,link=/tmp/router2"])
-    //     .spawn().ok();
-    sleep(Duration::from_secs(1));
+fn aspiration_resolution(controller: &Controller, vol: Microliters) -> Resolution {
+    if controller.fine_positioning && vol < CONFIG.pump_resolution.fine_below_ul {
+        Resolution::Fine
+    } else {
+        Resolution::Standard
+    }
+}
+
+// Splits an application into volumes that each fit in one plunger stroke
+fn plan_cycles(controller: &mut Controller, application: &LiquidApplication) -> ControlFlow<String, Vec<Microliters>> {
+    let (command, vol_microliter) = (&application.command, application.vol_microliter);
+    if vol_microliter <= MAX_STROKE_MICROLITER {
+        return ControlFlow::Continue(vec![vol_microliter]);
+    }
+    match CONFIG.over_range_policy {
+        OverRangePolicy::Reject => {
+            ControlFlow::Break(format!("{command}: {vol_microliter} ul exceeds plunger range of {MAX_STROKE_MICROLITER} ul"))
+        }
+        OverRangePolicy::Clamp => {
+            log::warn!("{}: clamping {} ul to {} ul", command, vol_microliter, MAX_STROKE_MICROLITER);
+            controller.notes.push(format!("clamped {command} to {MAX_STROKE_MICROLITER}ul"));
+            ControlFlow::Continue(vec![MAX_STROKE_MICROLITER])
+        }
+        OverRangePolicy::Split => {
+            let cycles = estimation::split_volume(vol_microliter);
+            log::info!("{}: splitting {} ul into {} transfer cycles", command, vol_microliter, cycles.len());
+            controller.notes.push(format!("split {command} into {} cycles", cycles.len()));
+            ControlFlow::Continue(cycles)
+        }
+    }
+}
+
+// For the one-shot tools, which have nothing to do without the port
+fn open_port(path: &str, baud_rate: u32) -> Box<dyn SerialPort> {
+    try_open_port(path, baud_rate).unwrap_or_else(|e| {
+        log::error!("Failed to open {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn try_open_port(path: &str, baud_rate: u32) -> Result<Box<dyn SerialPort>, String> {
+    serialport::new(port_operations::device_path(path), baud_rate)
+        .timeout(port_operations::write_timeout(path))
+        .flow_control(port_operations::flow_control(path))
+        .open()
+        .map_err(|e| format!("{path} at {baud_rate} baud: {e}"))
 }
 
 
 fn main() {
-    SimpleLogger::new().init().unwrap();
-    test_env_setup();
+    logtail::init();
+    shutdown::install_panic_hook();
+    let args = config::strip_cli_overrides(std::env::args().skip(1).collect());
+    let (instance, args) = cli::take_instance(args);
+    if let Some(name) = &instance {
+        if let Err(e) = config::select_instance(name) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    logtail::configure();
+    if let Some(result) = cli::run_subcommand(&args) {
+        if let Err(e) = result {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let simulation = cli::simulation_scale(&args).unwrap_or_else(|e| {
+        log::error!("{}", e);
+        std::process::exit(1);
+    });
+    let resume = args.iter().any(|a| a == "--resume");
+    if let Some(scale) = simulation {
+        log::info!("Simulating devices, waits run {}x faster", scale);
+        sim::set_time_scale(scale);
+        if let Some(Err(e)) = cli::fault_scenario(&args).map(fault_injection::load) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    } else {
+        devenv::setup();
+    }
+    watchdog::start(if instance.is_some() { 1 } else { config::instance_count() });
+    // The controller of a failed startup has been dropped, and its hardware parked, by the time it returns
+    if instance.is_some() || config::instance_count() == 1 {
+        if let Err(e) = run_controller(simulation, resume, true) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let instances: Vec<_> = (0..config::instance_count())
+        .map(|i| config::spawn_instance(i, move || {
+            if let Err(e) = run_controller(simulation, resume, i == 0) {
+                log::error!("Instance {} stopped: {}", CONFIG.instance_name, e);
+            }
+        }))
+        .collect();
+    instances.into_iter().for_each(|instance| { instance.join().ok(); });
+}
+
+// One instrument: its ports, request sources and executor loop. Only one instance reads stdin.
+fn run_controller(simulation: Option<f64>, resume: bool, interactive: bool) -> Result<(), String> {
+    log::info!("Starting controller instance {}", CONFIG.instance_name);
+    let mut safe_mode = SafeMode::default();
+    let mut open = |subsystem: &str, path: &str, baud_rate: u32, device: SimDevice| match simulation {
+        Some(_) => SimulatedPort::open(path, device),
+        None => try_open_port(path, baud_rate).unwrap_or_else(|e| {
+            safe_mode.record(subsystem, e);
+            MissingPort::open(path)
+        }),
+    };
+    let application_port = open("application port", &CONFIG.application_port_path, CONFIG.application_baud_rate, SimDevice::Application);
+    let pump_port = open("pump", &CONFIG.pump_port_path, CONFIG.pump_baud_rate, SimDevice::Pump);
+    let router_port = open("router", &CONFIG.router_port_path, CONFIG.router_baud_rate, SimDevice::Router);
+    let devices = match simulation {
+        Some(_) => Devices::simulated(),
+        None => Devices::open(&mut safe_mode),
+    };
+    let peripherals = match simulation {
+        Some(_) => Peripherals::simulated(&mut safe_mode),
+        None => Peripherals::open(&mut safe_mode),
+    };
+    let (bus, requests) = bus::new_bus();
+    bus::spawn_serial_source(application_port.try_clone().expect("Failed to clone application port"), bus.clone());
+    let status = SharedStatus::default();
+    let feed = sensors::Feed::default();
+    http::spawn_server(bus.clone(), status.clone(), feed.clone());
+    #[cfg(unix)]
+    console::spawn_socket_console(bus.clone(), status.clone());
+    #[cfg(not(unix))]
+    if let Some(path) = &CONFIG.console_socket_path {
+        log::warn!("Console socket {} is only available on Unix", path);
+    }
     let mut controller = Controller {
-        application_port: serialport::new(CONFIG.application_port_path.as_str(), 9600).open().unwrap(),
-        pump_port: serialport::new(CONFIG.pump_port_path.as_str(), 9600).open().unwrap(),
-        router_port: serialport::new(CONFIG.router_port_path.as_str(), 115200).open().unwrap(),
-        slot_occupancy: 0,
+        application: ApplicationLink::new(application_port, requests, &bus),
+        pumps: PumpBus::new(pump_port),
+        router_port,
+        slot_occupancy: Microliters(0),
+        wells: BTreeMap::new(),
+        router: motion::Tracker::new(HOME_POSITION),
+        volumes: VolumeReport::default(),
+        state: ControllerState::Homing,
+        custody: CustodyLog::default(),
+        command_ids: CommandIds::new(),
+        command_id: CommandId::default(),
+        notes: Vec::new(),
+        metadata: RunMetadata::default(),
+        present_tubes: HashSet::new(),
+        status,
+        devices,
+        peripherals,
+        runs: RunQueue::default(),
+        manifest: PendingProtocol::default(),
+        needle_residues: Vec::new(),
+        clock: match simulation {
+            Some(scale) => Box::new(ScaledClock::new(scale)),
+            None => Box::new(SystemClock),
+        },
+        journal: None,
+        tubes: TubeInventory::default(),
+        firmware: Firmware::default(),
+        tips: TipTracker::default(),
+        wear: Wear::load(),
+        reservoirs: Reservoirs::load(),
+        calibration: Calibration::load(),
+        regulators: thermal::Regulators::default(),
+        holds: hold_audit::Holds::default(),
+        sensor_log: SensorLog::new(feed),
+        report: RunReport::default(),
+        timeline: Timeline::new(simulation.is_some()),
+        fault: None,
+        events: EventLog::open(),
+        spans: Tracer::open(),
+        shaking_until: None,
+        draining: None,
+        slot_recovery: None,
+        fine_positioning: false,
+        safe_mode,
+        watchdog: Watchdog::register(simulation.is_some()),
+        pump_lock: PumpLock::default(),
+        expiry: ReagentExpiry::load(),
+        handshake: Handshake::default(),
+        uncommitted: None,
     };
+    deck::validate_deck().iter().for_each(|problem| log::error!("Deck configuration: {}", problem));
 
     flush_port(&mut controller.router_port);
-    sleep(Duration::from_secs(5));
-    serial_readline(&mut controller.router_port, "\r\n"); // read setup done
-    serial_write(&mut controller.router_port, "G28\r\n");
-    serial_write(&mut controller.pump_port, "/1ZgI4A12000O3A0G3R\r\n");
-    serial_write(&mut controller.pump_port, "/2ZR\r\n");
-    serial_readline(&mut controller.router_port, "\r\n");
+    controller.clock.sleep(Duration::from_secs(5));
+    let homing = if controller.safe_mode.lacks("router") {
+        false
+    } else {
+        startup::home_router(&mut controller).inspect_err(|e| {
+            controller.application.send_status(&format!("ERROR startup aborted, {e}"));
+        })?
+    };
+    if !controller.safe_mode.lacks("pump") {
+        let pump_inits = [
+            ('1', PumpCommand::new(1).initialize().valve_in(4).move_to(FULL_STROKE).valve_out(3).move_to(PumpUnits::ZERO).repeat(3)),
+            ('2', PumpCommand::new(2).initialize()),
+        ];
+        let failed: Vec<String> = pump_inits.iter()
+            .filter_map(|(address, init)| startup::init_pump(&mut controller, *address, init).err().map(|d| format!("pump {address}: {d}")))
+            .collect();
+        if !failed.is_empty() {
+            failed.iter().for_each(|failure| log::error!("Initialization failed for {}", failure));
+            controller.application.send_status(&format!("ERROR startup aborted, initialization failed for {}", failed.join("; ")));
+            return Err(format!("Initialization failed for {}", failed.join("; ")));
+        }
+        controller.fine_positioning = startup::check_resolution(&mut controller);
+    }
+    if homing && serial_readline(&mut controller.router_port, "\r\n").is_none() {
+        controller.application.send_status("ERROR startup aborted, the router port failed while homing");
+        return Err("Router port failed while homing".to_string());
+    }
+    controller.firmware = firmware::handshake(&mut controller).inspect_err(|e| {
+        controller.application.send_status(&format!("ERROR startup aborted, {e}"));
+    })?;
+    thermal::configure_zones(&mut controller);
+    // Started after initialization so stdin is free for startup confirmations
+    if interactive {
+        bus::spawn_console_source(bus);
+    }
+    // Anything that arrived while homing is answered with a refusal
+    for request in controller.application.drain_pending() {
+        handle_request(&mut controller, request);
+    }
+    controller.state = ControllerState::Idle;
+    controller.watchdog.ready();
+    handshake::announce(&mut controller);
+    if controller.safe_mode.active() {
+        let missing = controller.safe_mode.subsystems().join(";");
+        log::warn!("Safe mode: only queries and diagnostics are accepted until restarted with {}", missing);
+        controller.events.emit("safe_mode", &[("missing", missing.clone())]);
+        controller.application.send_status(&format!("SAFE MODE missing={missing}"));
+    }
+    match journal::load() {
+        Some(interrupted) if resume => {
+            log::info!("Resuming interrupted message at command {}", interrupted.next_command + 1);
+            let data = interrupted.data.clone();
+            let crc = crc32fast::hash(data.as_bytes());
+            controller.journal = Some(interrupted);
+            handle_message(&mut controller, Message { channel: message::COMMAND_CHANNEL, data, crc });
+        }
+        Some(interrupted) => {
+            log::warn!("A message was interrupted at command {}, start with --resume to continue it", interrupted.next_command + 1);
+        }
+        None => {}
+    }
     // ROUTER INIT: "G28\n\r" and then wait (10 sec)
     // PUMP INIT: "/1ZR\n\r"
+    let idle_period = Duration::from_secs(CONFIG.idle_maintenance.idle_minutes * 60);
+    let mut last_activity = Instant::now();
     loop {
-        let line = serial_readline(&mut controller.application_port, "\n");
-        handle_line(&mut controller, line)
+        controller.watchdog.beat();
+        controller.publish_status();
+        thermal::regulate(&mut controller);
+        if controller.state == ControllerState::Idle && !controller.application.has_pending() {
+            if let Some(run) = controller.runs.next() {
+                execute_queued_run(&mut controller, run);
+                last_activity = Instant::now();
+                continue;
+            }
+        }
+        match controller.application.next_request_timeout(thermal::idle_wait(&controller, Duration::from_secs(1))) {
+            Some(request) => {
+                handle_request(&mut controller, request);
+                last_activity = Instant::now();
+            }
+            None if CONFIG.idle_maintenance.enabled && controller.state == ControllerState::Idle && !controller.safe_mode.active()
+                && last_activity.elapsed() >= idle_period => {
+                maintenance::run_idle_maintenance(&mut controller);
+                last_activity = Instant::now();
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_takes_full_strokes_then_the_remainder() {
+        // 1100 ul with the overdraw: two full strokes and 100 ul
+        let command = drain_command(Microliters(1100 - CONFIG.drain_overdraw_ul.0));
+        assert_eq!(command.to_string(), "/2gI1A12000O2A0G2I1A2400O2A0R");
+        let command = drain_command_from(4, Microliters(MAX_STROKE_MICROLITER.0 - CONFIG.drain_overdraw_ul.0));
+        assert_eq!(command.to_string(), "/2I4A12000O2A0R");
     }
 }
@@ -0,0 +1,57 @@
+use std::ops::ControlFlow;
+
+use crate::config::{MaintenanceRoutine, CONFIG};
+use crate::pump::{PumpCommand, FULL_STROKE};
+use crate::units::PumpUnits;
+use crate::state::ControllerState;
+use crate::{Controller, MAINTENANCE_INTERRUPTED};
+
+const SMALL_STROKE: PumpUnits = PumpUnits(1200);
+
+// Runs in its own state, so status reports show the router and pumps moving; a request arriving
+// meanwhile stops the routine where it is, including a stroke or move in progress
+pub fn run_idle_maintenance(controller: &mut Controller) {
+    log::info!("Controller idle, running maintenance routines");
+    controller.state = ControllerState::IdleMaintenance;
+    controller.publish_status();
+    for routine in &CONFIG.idle_maintenance.routines {
+        // A new command always takes precedence over upkeep
+        if controller.application.has_pending() {
+            log::info!("{}", MAINTENANCE_INTERRUPTED);
+            break;
+        }
+        log::info!("Idle maintenance: {:?}", routine);
+        match run_routine(controller, *routine) {
+            ControlFlow::Break(e) if e == MAINTENANCE_INTERRUPTED => {
+                log::info!("{} during {:?}", e, routine);
+                break;
+            }
+            ControlFlow::Break(e) => {
+                log::error!("Idle maintenance {:?} failed: {}", routine, e);
+                break;
+            }
+            ControlFlow::Continue(()) => {}
+        }
+    }
+    controller.state = ControllerState::Idle;
+    controller.publish_status();
+    controller.wear.save();
+    controller.reservoirs.save();
+}
+
+fn run_routine(controller: &mut Controller, routine: MaintenanceRoutine) -> ControlFlow<String> {
+    match routine {
+        MaintenanceRoutine::PumpStroke => {
+            controller.pump_execute(&PumpCommand::new(1).valve_in(5).move_to(SMALL_STROKE).valve_out(1).move_to(PumpUnits::ZERO))?;
+            controller.pump_execute(&PumpCommand::new(2).valve_in(1).move_to(SMALL_STROKE).valve_out(2).move_to(PumpUnits::ZERO))
+        }
+        MaintenanceRoutine::NeedleRinse => {
+            controller.router_move(CONFIG.wash_station.position)?;
+            controller.pump_execute(&PumpCommand::new(1).valve_in(4).move_to(FULL_STROKE).valve_out(1).move_to(PumpUnits::ZERO))?;
+            controller.pump_execute(&PumpCommand::new(1).valve_in(5).move_to(FULL_STROKE).valve_out(1).move_to(PumpUnits::ZERO))
+        }
+        MaintenanceRoutine::Park => {
+            controller.router_move(CONFIG.idle_maintenance.park_position)
+        }
+    }
+}
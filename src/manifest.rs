@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+use crate::config::CONFIG;
+
+const PREFIX: &str = "MANIFEST_";
+
+// A protocol too long for one message is announced with `MANIFEST_<steps>_<crc>`, crc being the hex
+// CRC32 of all steps joined by single spaces. The messages that follow are buffered and only run
+// once all steps have arrived and the checksum matches.
+struct Manifest {
+    steps: usize,
+    crc: u32,
+    received: Vec<String>,
+    started: Instant,
+}
+
+pub enum Assembly {
+    Buffered(String),
+    Complete(String),
+    Rejected(String),
+    // The manifest was dropped and the frame is a protocol of its own
+    Expired(String),
+}
+
+#[derive(Default)]
+pub struct PendingProtocol {
+    manifest: Option<Manifest>,
+}
+
+impl PendingProtocol {
+    // None when `data` is not a manifest frame, otherwise the reply
+    pub fn announce(&mut self, data: &str) -> Option<String> {
+        let args = data.strip_prefix(PREFIX)?;
+        let parsed = args.split_once('_')
+            .and_then(|(steps, crc)| Some((steps.parse::<usize>().ok()?, u32::from_str_radix(crc, 16).ok()?)));
+        let Some((steps, crc)) = parsed.filter(|(steps, _)| *steps > 0) else {
+            return Some(format!("ERROR manifest: cannot read step count and checksum from {data}"));
+        };
+        if let Some(previous) = self.manifest.take() {
+            log::warn!("Manifest replaced with {} of {} steps received", previous.received.len(), previous.steps);
+        }
+        log::info!("Manifest announced: {} steps, CRC {:x}", steps, crc);
+        self.manifest = Some(Manifest { steps, crc, received: Vec::new(), started: Instant::now() });
+        Some(format!("MANIFEST ACCEPTED steps={steps}"))
+    }
+
+    // None when no manifest is pending and `data` is a protocol of its own
+    pub fn add(&mut self, data: &str) -> Option<Assembly> {
+        let mut manifest = self.manifest.take()?;
+        let timeout = Duration::from_secs(CONFIG.manifest_timeout_secs);
+        if manifest.started.elapsed() > timeout {
+            return Some(Assembly::Expired(format!("manifest expired after {}s with {} of {} steps received",
+                timeout.as_secs(), manifest.received.len(), manifest.steps)));
+        }
+        manifest.received.extend(data.split(' ').map(str::to_string));
+        let (received, steps) = (manifest.received.len(), manifest.steps);
+        if received < steps {
+            self.manifest = Some(manifest);
+            return Some(Assembly::Buffered(format!("MANIFEST PENDING received={received} steps={steps}")));
+        }
+        if received > steps {
+            return Some(Assembly::Rejected(format!("manifest announced {steps} steps, received {received}")));
+        }
+        let protocol = manifest.received.join(" ");
+        let crc = crc32fast::hash(protocol.as_bytes());
+        if crc != manifest.crc {
+            return Some(Assembly::Rejected(format!("checksum {crc:x} does not match manifest {:x}", manifest.crc)));
+        }
+        Some(Assembly::Complete(protocol))
+    }
+}
@@ -1,5 +1,9 @@
+use crate::config::{CrcAlgorithm, CONFIG};
 use crate::unwrap_or_none;
 
+pub const COMMAND_CHANNEL: i8 = 4;
+pub const CONTROL_CHANNEL: i8 = 5;
+
 pub struct Message {
     pub channel: i8,
     pub data: String,
@@ -14,10 +18,64 @@ pub fn parse_to_message(line: String) -> Option<Message> {
     let channel: i8 = unwrap_or_none!(parts.get(0).unwrap().parse());
     let data: String = parts.get(1).unwrap().to_string();
     let crc: u32 = unwrap_or_none!(u32::from_str_radix(parts.get(2).unwrap(), 16));
-    if crc32fast::hash(data.as_bytes()) != crc {
+    if checksum(channel, &data) != crc {
         log::error!("Invalid CRC");
         return None;
     }
     return Option::from(Message { channel, data, crc });
 }
 
+pub fn format_message(channel: i8, data: &str) -> String {
+    format!("{},{},{:x}\n", channel, data, checksum(channel, data))
+}
+
+// Protocol version 1 checksums the data field with CRC32; from version 2 the checksum covers
+// "channel,data" so a corrupted channel is caught too, using the link's configured algorithm
+pub fn checksum(channel: i8, data: &str) -> u32 {
+    let framing = &CONFIG.framing;
+    if framing.protocol_version < 2 {
+        return crc32fast::hash(data.as_bytes());
+    }
+    let covered = format!("{channel},{data}");
+    match framing.crc {
+        CrcAlgorithm::Crc32 => crc32fast::hash(covered.as_bytes()),
+        CrcAlgorithm::Crc16 => crc16(covered.as_bytes()) as u32,
+    }
+}
+
+// CRC-16/CCITT-FALSE, which small MCU senders usually have a table-free implementation of
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 }
+        })
+    })
+}
+
+// Looks for a valid frame inside a line that may carry stale or noisy bytes in front of it
+pub fn find_frame(line: &str) -> Option<String> {
+    line.char_indices()
+        .filter(|(i, c)| c.is_ascii_digit() && (*i == 0 || !line[..*i].ends_with(|p: char| p.is_ascii_digit())))
+        .map(|(i, _)| &line[i..])
+        .find(|candidate| candidate.split(',').count() == 3 && parse_to_message(candidate.to_string()).is_some())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The catalogued check value of CRC-16/CCITT-FALSE
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn formatted_frame_parses_back() {
+        let frame = format_message(COMMAND_CHANNEL, "LA_14_1_100ul");
+        let message = parse_to_message(frame.trim_end().to_string()).unwrap();
+        assert_eq!((message.channel, message.data.as_str()), (COMMAND_CHANNEL, "LA_14_1_100ul"));
+        assert_eq!(find_frame(&format!("\u{0}xx{}", frame.trim_end())).as_deref(), Some(frame.trim_end()));
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use crate::config::{Redaction, CONFIG};
+
+const PREFIX: &str = "META_";
+
+// Run metadata travels in the message as `META_<key>=<value>` tokens, e.g. `META_sample=S-0042`
+#[derive(Default, Debug, Clone)]
+pub struct RunMetadata {
+    pub fields: BTreeMap<String, String>,
+}
+
+impl RunMetadata {
+    pub fn from_commands(commands: &[&str]) -> RunMetadata {
+        RunMetadata { fields: commands.iter().filter_map(|c| parse(c)).collect() }
+    }
+
+    pub fn redacted_fields(&self) -> BTreeMap<String, String> {
+        self.fields.iter().map(|(key, value)| (key.clone(), redact_value(key, value))).collect()
+    }
+
+    pub fn redacted(&self) -> String {
+        self.fields.iter()
+            .map(|(key, value)| format!("{key}={}", redact_value(key, value)))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+// Unredacted; only for the application port and run records, never for logs
+impl Display for RunMetadata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let fields = self.fields.iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<String>>()
+            .join(" ");
+        write!(f, "{fields}")
+    }
+}
+
+// Messages that handle liquid must carry every key of required_metadata
+pub fn check_required(commands: &[&str]) -> Result<(), String> {
+    if !commands.iter().any(|c| c.starts_with("LA_")) {
+        return Ok(());
+    }
+    let metadata = RunMetadata::from_commands(commands);
+    let missing: Vec<&str> = CONFIG.required_metadata.iter()
+        .filter(|key| metadata.fields.get(*key).is_none_or(|value| value.is_empty()))
+        .map(String::as_str)
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(format!("missing metadata: {} (send them as {PREFIX}<key>=<value>)", missing.join(", ")))
+}
+
+pub fn token(key: &str, value: &str) -> String {
+    format!("{PREFIX}{key}={value}")
+}
+
+pub fn is_metadata(command: &str) -> bool {
+    command.starts_with(PREFIX)
+}
+
+fn parse(command: &str) -> Option<(String, String)> {
+    let (key, value) = command.strip_prefix(PREFIX)?.split_once('=')?;
+    Some((key.to_string(), value.to_string()))
+}
+
+fn redact_value(key: &str, value: &str) -> String {
+    if !CONFIG.sensitive_metadata.iter().any(|k| k == key) {
+        return value.to_string();
+    }
+    match CONFIG.metadata_redaction {
+        Redaction::Mask => "***".to_string(),
+        Redaction::Hash => {
+            let digest = sha256(format!("{}{value}", CONFIG.metadata_hash_salt).as_bytes());
+            format!("#{}", digest[..8].iter().map(|b| format!("{b:02x}")).collect::<String>())
+        }
+    }
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// FIPS 180-4, the same way websocket.rs does SHA-1 for its handshake
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([chunk[4 * i], chunk[4 * i + 1], chunk[4 * i + 2], chunk[4 * i + 3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for (k, word) in K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = hh.wrapping_add(s1).wrapping_add(choice).wrapping_add(*k).wrapping_add(word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+    let mut digest = [0u8; 32];
+    for (i, word) in h.iter().enumerate() {
+        digest[4 * i..4 * i + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+// Rewrites sensitive metadata tokens anywhere in a line that is about to be logged
+pub fn redact(text: &str) -> String {
+    if CONFIG.sensitive_metadata.is_empty() || !text.contains(PREFIX) {
+        return text.to_string();
+    }
+    text.split(' ')
+        .map(|token| match token.find(PREFIX).and_then(|start| Some((start, parse(&token[start..])?))) {
+            Some((start, (key, value))) => {
+                let (value, rest) = value.split_at(value.find([',', '\r', '\n', '\\']).unwrap_or(value.len()));
+                format!("{}{PREFIX}{key}={}{rest}", &token[..start], redact_value(&key, value))
+            }
+            None => token.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_matches_the_fips_180_vectors() {
+        let hex = |digest: [u8; 32]| digest.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        assert_eq!(hex(sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+}
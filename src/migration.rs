@@ -0,0 +1,200 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use toml::Value;
+
+use crate::deck::Coordinates;
+
+// Layout of config.toml this controller reads; files without config_version are version 1
+pub const CURRENT_VERSION: i64 = 2;
+
+struct Migration {
+    // Version of the file once applied
+    to: i64,
+    description: &'static str,
+    apply: fn(&mut toml::map::Map<String, Value>),
+    // The same change on one `key = value` line of the file under `[section]`: the value's new text,
+    // None to keep the line
+    edit: fn(section: &str, key: &str, value: &str) -> Option<String>,
+}
+
+// Each entry upgrades the previous version and must keep working on files of that version, so
+// earlier migrations are never edited after a release, only new ones appended
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 2,
+        description: "\"x:y:z\" coordinate strings are written as { x, y, z } tables",
+        apply: structured_coordinates,
+        edit: structured_coordinates_line,
+    },
+];
+
+// Upgrades `config`, read from `path`, to CURRENT_VERSION. The file is edited line by line, so its
+// comments and layout stay, and the original is kept next to it. The text now in effect is returned.
+pub fn upgrade(path: &str, text: String, config: &mut Value) -> Result<String, String> {
+    let version = match config.get("config_version") {
+        None => 1,
+        Some(Value::Integer(version)) => *version,
+        Some(_) => return Err("config_version must be an integer".to_string()),
+    };
+    if version > CURRENT_VERSION {
+        return Err(format!("config.toml has config_version {version}, this controller only understands up to {CURRENT_VERSION}"));
+    }
+    if version == CURRENT_VERSION {
+        return Ok(text);
+    }
+    let Some(table) = config.as_table_mut() else {
+        return Ok(text);
+    };
+    let migrations: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.to > version).collect();
+    for migration in &migrations {
+        log::warn!("Upgrading config.toml to config_version {}: {}", migration.to, migration.description);
+        (migration.apply)(table);
+        // [[instances]] overlays use the layout of the top level
+        if let Some(Value::Array(instances)) = table.get_mut("instances") {
+            instances.iter_mut().filter_map(Value::as_table_mut).for_each(migration.apply);
+        }
+    }
+    table.insert("config_version".to_string(), Value::Integer(CURRENT_VERSION));
+    let backup = backup_path(path, version);
+    let upgraded = edit_lines(&text, &migrations);
+    let upgraded = format!("# Upgraded from config_version {version}, the original is {}\n{upgraded}", backup.display());
+    // A line the edits couldn't follow, e.g. a value spread over several lines, leaves the file alone
+    if upgraded.parse::<Value>().ok().as_ref() != Some(config) {
+        log::error!("Could not upgrade {} in place, using the upgraded configuration without writing it: {}", path,
+            migrations.iter().map(|m| m.description).collect::<Vec<&str>>().join("; "));
+        return toml::to_string(config).map_err(|e| format!("Failed to write the upgraded config.toml: {e}"));
+    }
+    match std::fs::copy(path, &backup).and_then(|_| std::fs::write(path, &upgraded)) {
+        Ok(_) => log::warn!("Upgraded {} from config_version {} to {}, original kept as {}", path, version, CURRENT_VERSION, backup.display()),
+        // The upgraded configuration is still used, only the file stays as it was
+        Err(e) => log::error!("Failed to replace {} with its upgraded version: {}", path, e),
+    }
+    Ok(upgraded)
+}
+
+// Applies the migrations' line edits and sets config_version, leaving every other line as it was
+fn edit_lines(text: &str, migrations: &[&Migration]) -> String {
+    let mut section = String::new();
+    let mut versioned = false;
+    let mut lines: Vec<String> = text.lines().map(|line| {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('[') {
+            section = trimmed.trim_start_matches('[').split(']').next().unwrap_or_default().trim().replace('"', "");
+            return line.to_string();
+        }
+        let Some((key_part, value)) = trimmed.split_once('=').filter(|_| !trimmed.starts_with('#')) else {
+            return line.to_string();
+        };
+        let key = key_part.trim().trim_matches('"');
+        if section.is_empty() && key == "config_version" {
+            versioned = true;
+            return format!("config_version = {CURRENT_VERSION}");
+        }
+        // Only a quoted string value is ever migrated; whatever follows it, like a comment, stays
+        let Some((quoted, rest)) = value.trim_start().strip_prefix('"').and_then(|v| v.split_once('"')) else {
+            return line.to_string();
+        };
+        let section = section.strip_prefix("instances.").unwrap_or(&section);
+        match migrations.iter().find_map(|m| (m.edit)(section, key, quoted)) {
+            Some(edited) => format!("{}{key_part}= {edited}{rest}", &line[..line.len() - trimmed.len()]),
+            None => line.to_string(),
+        }
+    }).collect();
+    if !versioned {
+        lines.insert(0, format!("config_version = {CURRENT_VERSION}"));
+    }
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+fn backup_path(path: &str, version: i64) -> PathBuf {
+    let backup = PathBuf::from(format!("{path}.v{version}.bak"));
+    if !backup.exists() {
+        return backup;
+    }
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    PathBuf::from(format!("{path}.v{version}.{secs}.bak"))
+}
+
+// Version 2: every position is a { x, y, z } table. Strings that aren't coordinates, like the labels
+// of external tube holders, are left alone, as are malformed ones for the schema check to report.
+fn structured_coordinates(config: &mut toml::map::Map<String, Value>) {
+    for (table, key) in [("idle-maintenance", "park_position"), ("end-of-run", "park_position"), ("tips", "eject_position"),
+                         ("barcode-scan", "scanner_offset")] {
+        if let Some(value) = config.get_mut(table).and_then(|t| t.get_mut(key)) {
+            to_table(value);
+        }
+    }
+    if let Some(Value::Table(holders)) = config.get_mut("tube-holder-coordinates") {
+        holders.iter_mut().for_each(|(_, holder)| to_table(holder));
+    }
+    if let Some(Value::Array(racks)) = config.get_mut("racks") {
+        racks.iter_mut().filter_map(|rack| rack.get_mut("origin")).for_each(to_table);
+    }
+}
+
+fn structured_coordinates_line(section: &str, key: &str, value: &str) -> Option<String> {
+    let migrated = matches!((section, key), ("idle-maintenance" | "end-of-run", "park_position") | ("tips", "eject_position")
+        | ("barcode-scan", "scanner_offset") | ("tube-holder-coordinates", _) | ("racks", "origin"));
+    if !migrated {
+        return None;
+    }
+    let mut value = Value::String(value.to_string());
+    to_table(&mut value);
+    let table = value.as_table()?;
+    Some(format!("{{ x = {}, y = {}, z = {} }}", table["x"], table["y"], table["z"]))
+}
+
+fn to_table(value: &mut Value) {
+    let Some(Ok(position)) = value.as_str().filter(|s| s.contains(':')).map(str::parse::<Coordinates>) else {
+        return;
+    };
+    let mut table = toml::map::Map::new();
+    for (axis, mm) in [("x", position.x), ("y", position.y), ("z", position.z)] {
+        table.insert(axis.to_string(), Value::Float(mm.0));
+    }
+    *value = Value::Table(table);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrades_coordinate_strings_and_keeps_the_original() {
+        let directory = std::env::temp_dir().join(format!("migration-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("config.toml");
+        let path = path.to_str().unwrap();
+        let text = "# the deck\nport = 1\n[tube-holder-coordinates]\n1 = \"2:6:-90\"  # front left\n2 = \"external\"\n\
+                    [[racks]]\norigin = \"10:20:-80\"\n".to_string();
+        std::fs::write(path, &text).unwrap();
+        let mut config: Value = text.parse().unwrap();
+        let upgraded = upgrade(path, text.clone(), &mut config).unwrap();
+        assert_eq!(config["config_version"].as_integer(), Some(CURRENT_VERSION));
+        assert_eq!(config["tube-holder-coordinates"]["1"]["z"].as_float(), Some(-90.0));
+        assert_eq!(config["tube-holder-coordinates"]["2"].as_str(), Some("external"));
+        assert_eq!(config["racks"][0]["origin"]["x"].as_float(), Some(10.0));
+        assert!(upgraded.contains("# the deck\n"));
+        assert!(upgraded.contains("1 = { x = 2.0, y = 6.0, z = -90.0 }  # front left\n"));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), upgraded);
+        assert_eq!(std::fs::read_to_string(format!("{path}.v1.bak")).unwrap(), text);
+        std::fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn replaces_an_older_config_version_line() {
+        let text = "config_version = 1\n[tips]\neject_position = \"1:2:3\"\n";
+        let edited = edit_lines(text, &MIGRATIONS.iter().collect::<Vec<&Migration>>());
+        assert_eq!(edited, format!("config_version = {CURRENT_VERSION}\n[tips]\neject_position = {{ x = 1.0, y = 2.0, z = 3.0 }}\n"));
+    }
+
+    #[test]
+    fn leaves_current_files_alone() {
+        let text = format!("config_version = {CURRENT_VERSION}\n");
+        let mut config: Value = text.parse().unwrap();
+        assert_eq!(upgrade("/nonexistent/config.toml", text.clone(), &mut config), Ok(text));
+        let mut newer: Value = format!("config_version = {}\n", CURRENT_VERSION + 1).parse().unwrap();
+        assert!(upgrade("/nonexistent/config.toml", String::new(), &mut newer).is_err());
+    }
+}
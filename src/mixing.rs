@@ -0,0 +1,48 @@
+use std::ops::ControlFlow;
+
+use crate::config::CONFIG;
+use crate::deck::Coordinates;
+use crate::pump::PumpCommand;
+use crate::units::PumpUnits;
+use crate::{clean_needle_for, detection, motion, tips, units, unwrap_option, wash_needle, Controller};
+
+// MIXTUBE_<tube>_<vol>_<cycles> draws vol ul into the needle and pushes it back into the same tube
+// cycles times, so reagent that settled is resuspended before it is applied
+pub fn mix_tube(controller: &mut Controller, command: &str) -> ControlFlow<String> {
+    let settings = &CONFIG.tube_mixing;
+    let parts: Vec<&str> = command.split('_').collect();
+    let parsed = match parts[..] {
+        ["MIXTUBE", tube, vol, cycles] => units::parse_volume(vol).ok().zip(cycles.parse::<u32>().ok()).map(|(v, c)| (tube, v, c)),
+        _ => None,
+    };
+    let (tube, vol, cycles) = unwrap_option!(parsed, format!("Cannot deduce tube, volume and cycles from {command}"));
+    if vol.0 == 0 || cycles == 0 || cycles > settings.max_cycles {
+        return ControlFlow::Break(format!("{command}: volume must be positive and cycles between 1 and {}", settings.max_cycles));
+    }
+    let units = match vol.to_pump_units() {
+        Ok(units) => units,
+        Err(e) => return ControlFlow::Break(format!("{command}: {e}")),
+    };
+    if let Some(remaining) = controller.tubes.remaining(tube).filter(|remaining| *remaining < vol) {
+        return ControlFlow::Break(format!("{command}: tube {tube} holds only {remaining} ul"));
+    }
+    let position = match controller.calibration.tube_position(tube) {
+        Ok(position) => position,
+        Err(e) => return ControlFlow::Break(e),
+    };
+
+    clean_needle_for(controller, tube, command)?;
+    tips::before_aspiration(controller)?;
+    detection::check_tube_present(controller, tube, position)?;
+    controller.router_move(position)?;
+    log::info!("Mixing tube {} with {} ul for {} cycles", tube, vol, cycles);
+    controller.pump_execute(&PumpCommand::new(1)
+        .valve_in(1)
+        .speed(settings.aspirate_speed).move_to(units)
+        .speed(settings.dispense_speed).move_to(PumpUnits::ZERO)
+        .repeat(cycles)
+        .speed(settings.default_speed))?;
+    controller.events.emit("mix_tube", &[("tube", tube.to_string()), ("volume_ul", vol.to_string()), ("cycles", cycles.to_string())]);
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..position })?;
+    wash_needle(controller)
+}
@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::deck::{tube_position, zone_containing, zone_crossed, Coordinates, HOME_POSITION};
+use crate::units::Millimeters;
+
+pub const SAFE_Z: Millimeters = Millimeters(0.0);
+
+// Where the router was last sent, and how far it has gone since the controller started
+pub struct Tracker {
+    pub position: Coordinates,
+    // Why the router stopped, until it is homed again
+    pub halted: Option<String>,
+    travelled: Millimeters,
+    moves: u64,
+    skipped: u64,
+}
+
+impl Tracker {
+    pub fn new(position: Coordinates) -> Tracker {
+        Tracker { position, halted: None, travelled: Millimeters(0.0), moves: 0, skipped: 0 }
+    }
+
+    // Empty when the router is already there, so no G1 is sent at all
+    pub fn plan(&mut self, target: Coordinates) -> Result<Vec<Coordinates>, String> {
+        if target == self.position {
+            self.skipped += 1;
+            log::trace!("Router already at {}, skipping move", target);
+            return Ok(Vec::new());
+        }
+        plan_move(self.position, target)
+    }
+
+    // Returns the distance covered
+    pub fn moved_to(&mut self, point: Coordinates) -> Millimeters {
+        let travelled = distance(self.position, point);
+        self.travelled = self.travelled + travelled;
+        self.moves += 1;
+        self.position = point;
+        travelled
+    }
+
+    pub fn describe(&self) -> String {
+        format!("POSITION {} travelled={:.1}mm moves={} skipped={}", self.position, self.travelled.0, self.moves, self.skipped)
+    }
+}
+
+pub fn plan_move(from: Coordinates, to: Coordinates) -> Result<Vec<Coordinates>, String> {
+    if let Some(zone) = zone_containing(to) {
+        return Err(format!("Target {to} is inside keep-out zone '{}'", zone.name()));
+    }
+    if zone_crossed(from, to).is_none() {
+        return Ok(vec![to]);
+    }
+    // Route over the obstacle: retract, travel at safe height, plunge
+    let waypoints = [
+        Coordinates { z: SAFE_Z, ..from },
+        Coordinates { z: SAFE_Z, ..to },
+        to,
+    ];
+    let mut path = Vec::new();
+    let mut current = from;
+    for point in waypoints {
+        if point == current {
+            continue;
+        }
+        if let Some(zone) = zone_crossed(current, point) {
+            return Err(format!("Move {from} -> {to} would enter keep-out zone '{}'", zone.name()));
+        }
+        path.push(point);
+        current = point;
+    }
+    log::trace!("Routing move {} -> {} around keep-out zone via {} waypoints", from, to, path.len());
+    Ok(path)
+}
+
+// Purely vertical moves go into or out of tubes and the slot, so they use the slower plunge feed
+pub fn feedrate(from: Coordinates, to: Coordinates) -> f64 {
+    let horizontal = (to.x - from.x).0.hypot((to.y - from.y).0);
+    if horizontal < f64::EPSILON {
+        CONFIG.feedrates.plunge_mm_per_min
+    } else {
+        CONFIG.feedrates.travel_mm_per_min
+    }
+}
+
+pub fn move_gcode(from: Coordinates, to: Coordinates) -> String {
+    format!("G1X{}Y{}Z{}F{}\r\n", to.x, to.y, to.z, feedrate(from, to))
+}
+
+fn distance(from: Coordinates, to: Coordinates) -> Millimeters {
+    Millimeters(((to.x - from.x).0.powi(2) + (to.y - from.y).0.powi(2) + (to.z - from.z).0.powi(2)).sqrt())
+}
+
+pub fn move_duration(from: Coordinates, to: Coordinates) -> Duration {
+    Duration::from_secs_f64(distance(from, to).0 / (feedrate(from, to).max(1.0) / 60.0))
+}
+
+pub fn path_duration(from: Coordinates, path: &[Coordinates]) -> Duration {
+    let mut current = from;
+    path.iter()
+        .map(|point| {
+            let duration = move_duration(current, *point);
+            current = *point;
+            duration
+        })
+        .sum()
+}
+
+// The tube a step leaves the router over, if it visits one
+fn visited_tube(command: &str) -> Option<Coordinates> {
+    let parts: Vec<&str> = command.split('_').collect();
+    match parts[..] {
+        ["LA", from, ..] | ["SCAN", from] => tube_position(from).ok(),
+        _ => None,
+    }
+}
+
+// Consecutive SCAN_ steps only read labels, so they are visited nearest first. Everything else keeps
+// its place. The order depends on the commands alone, so a resumed run replays the same sequence.
+pub fn order_for_travel<'a>(commands: &[&'a str]) -> Vec<&'a str> {
+    let mut ordered: Vec<&'a str> = Vec::with_capacity(commands.len());
+    let mut position = HOME_POSITION;
+    let mut i = 0;
+    while i < commands.len() {
+        let run = commands[i..].iter().take_while(|c| c.starts_with("SCAN_") && visited_tube(c).is_some()).count();
+        if run < 2 {
+            ordered.push(commands[i]);
+            position = visited_tube(commands[i]).unwrap_or(position);
+            i += 1;
+            continue;
+        }
+        let mut remaining: Vec<&'a str> = commands[i..i + run].to_vec();
+        while !remaining.is_empty() {
+            let nearest = (0..remaining.len())
+                .min_by(|a, b| distance(position, visited_tube(remaining[*a]).unwrap_or(position)).0
+                    .total_cmp(&distance(position, visited_tube(remaining[*b]).unwrap_or(position)).0))
+                .unwrap_or(0);
+            let command = remaining.remove(nearest);
+            position = visited_tube(command).unwrap_or(position);
+            ordered.push(command);
+        }
+        i += run;
+    }
+    ordered
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::config::{EmailSettings, NotificationEvent, Webhook, WebhookKind, CONFIG};
+use crate::{config, logtail};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Notification {
+    pub event: NotificationEvent,
+    pub run_id: Option<String>,
+    pub message: String,
+    // The run's metadata, already redacted
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Display for Notification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {} run={}: {}", CONFIG.instance_name, self.event.name(), self.run_id.as_deref().unwrap_or("-"), self.message)?;
+        if !self.metadata.is_empty() {
+            let fields: Vec<String> = self.metadata.iter().map(|(key, value)| format!("{key}={value}")).collect();
+            write!(f, " (metadata {})", fields.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl NotificationEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NotificationEvent::Completion => "completion",
+            NotificationEvent::Fault => "fault",
+            NotificationEvent::Estop => "estop",
+            NotificationEvent::Reservoir => "reservoir",
+            NotificationEvent::Temperature => "temperature",
+        }
+    }
+}
+
+// Delivered from a background thread so a slow webhook or mail relay never holds up the executor
+pub fn notify(notification: Notification) {
+    let settings = &CONFIG.notifications;
+    if !settings.events.contains(&notification.event) || (settings.webhooks.is_empty() && settings.email.is_none()) {
+        return;
+    }
+    let log = logtail::tail(settings.log_lines);
+    config::spawn(move || {
+        for webhook in &CONFIG.notifications.webhooks {
+            if let Err(e) = post_webhook(webhook, &notification, &log) {
+                log::error!("Notification to {} failed: {}", webhook.url, e);
+            }
+        }
+        if let Some(email) = &CONFIG.notifications.email {
+            if let Err(e) = send_email(email, &notification, &log) {
+                log::error!("Notification email via {} failed: {}", email.smtp_server, e);
+            }
+        }
+    });
+}
+
+fn post_webhook(webhook: &Webhook, notification: &Notification, log: &[String]) -> Result<(), String> {
+    let payload = match webhook.kind {
+        WebhookKind::Slack => format!("{{\"text\":{}}}", json_string(&format!("{}\n```{}```", notification, log.join("\n")))),
+        WebhookKind::Http => format!(
+            "{{\"instance\":{},\"event\":{},\"run_id\":{},\"message\":{},\"metadata\":{{{}}},\"log\":[{}]}}",
+            json_string(&CONFIG.instance_name),
+            json_string(notification.event.name()),
+            notification.run_id.as_deref().map_or("null".to_string(), json_string),
+            json_string(&notification.message),
+            notification.metadata.iter().map(|(key, value)| format!("{}:{}", json_string(key), json_string(value))).collect::<Vec<String>>().join(","),
+            log.iter().map(|line| json_string(line)).collect::<Vec<String>>().join(","),
+        ),
+    };
+    post_json(&webhook.url, &payload)
+}
+
+// curl handles HTTPS, which Slack requires, without pulling a TLS stack into the controller
+pub fn post_json(url: &str, payload: &str) -> Result<(), String> {
+    let mut curl = Command::new("curl")
+        .args(["-sS", "-f", "-m", &TIMEOUT.as_secs().to_string(), "-H", "Content-Type: application/json", "--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run curl: {e}"))?;
+    curl.stdin.take().ok_or("curl has no stdin")?.write_all(payload.as_bytes()).map_err(|e| e.to_string())?;
+    let output = curl.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Plain SMTP to the site relay; no authentication or TLS
+fn send_email(email: &EmailSettings, notification: &Notification, log: &[String]) -> Result<(), String> {
+    let stream = TcpStream::connect(&email.smtp_server).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).ok();
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    let mut writer = stream;
+    let mut exchange = |line: Option<String>| -> Result<(), String> {
+        if let Some(line) = line {
+            write!(writer, "{line}\r\n").map_err(|e| e.to_string())?;
+        }
+        // Multi-line replies have a '-' after the code on every line but the last
+        loop {
+            let mut reply = String::new();
+            reader.read_line(&mut reply).map_err(|e| e.to_string())?;
+            match reply.as_bytes() {
+                [b'2' | b'3', _, _, b'-', ..] => continue,
+                [b'2' | b'3', ..] => return Ok(()),
+                _ => return Err(format!("relay answered [{}]", reply.trim_end())),
+            }
+        }
+    };
+    exchange(None)?;
+    exchange(Some(format!("HELO {}", CONFIG.instance_name)))?;
+    exchange(Some(format!("MAIL FROM:<{}>", email.from)))?;
+    for to in &email.to {
+        exchange(Some(format!("RCPT TO:<{to}>")))?;
+    }
+    exchange(Some("DATA".to_string()))?;
+    let body: Vec<String> = [notification.to_string(), String::new()].into_iter()
+        .chain(log.iter().cloned())
+        // Lines starting with a dot would end the message early
+        .map(|line| if line.starts_with('.') { format!(".{line}") } else { line })
+        .collect();
+    exchange(Some(format!("From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n.",
+        email.from, email.to.join(", "), subject(notification), body.join("\r\n"))))?;
+    exchange(Some("QUIT".to_string()))
+}
+
+fn subject(notification: &Notification) -> String {
+    format!("[{}] run {} {}", CONFIG.instance_name, notification.run_id.as_deref().unwrap_or("-"), notification.event.name())
+}
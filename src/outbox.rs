@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::config::CONFIG;
+use crate::message::{format_message, COMMAND_CHANNEL};
+use crate::port_operations::serial_write;
+
+// While the link is down a write is only tried this often; later frames queue behind the first
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const DROPPED: &str = "# dropped ";
+
+// Frames the application port didn't take, oldest first, kept in outbox_path until they are
+// delivered so a restart during a disconnection loses nothing either. The file holds one frame per
+// line as sent on the wire, after a "# dropped <n>" line; queued frames are appended to it and it is
+// only rewritten once it holds twice outbox_capacity lines or after a replay.
+#[derive(Default)]
+pub struct Outbox {
+    frames: VecDeque<String>,
+    // Oldest frames discarded once outbox_capacity was reached
+    dropped: u64,
+    // Frames in the file, including ones already dropped from `frames`
+    written: usize,
+    next_retry: Option<Instant>,
+}
+
+impl Outbox {
+    pub fn load() -> Outbox {
+        let Ok(text) = std::fs::read_to_string(&CONFIG.outbox_path) else {
+            return Outbox::default();
+        };
+        let mut outbox = Outbox::default();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            match line.strip_prefix(DROPPED) {
+                Some(count) => outbox.dropped += count.parse::<u64>().unwrap_or_default(),
+                None if line.starts_with(|c: char| c.is_ascii_digit()) => {
+                    outbox.push(format!("{line}\n"));
+                    outbox.written += 1;
+                }
+                None => log::error!("Ignoring unreadable outbox line in {}: {}", CONFIG.outbox_path, line),
+            }
+        }
+        if !outbox.frames.is_empty() {
+            log::warn!("{} status frames from before the restart are waiting for the application link", outbox.frames.len());
+        }
+        outbox
+    }
+
+    // Sends `frame`, or queues it when the link is down or older frames are still waiting
+    pub fn send(&mut self, port: &mut Box<dyn SerialPort>, frame: String) {
+        if self.flush(port) {
+            match serial_write(port, &frame) {
+                Ok(()) => return,
+                Err(e) => log::error!("Failed to send [{}] to application: {}", frame.trim_end(), e),
+            }
+            self.next_retry = Some(Instant::now() + RETRY_INTERVAL);
+        }
+        self.queue(frame);
+    }
+
+    // Delivers waiting frames once a retry is due, announced by how many there are and how many
+    // were dropped. Returns whether none are left.
+    pub fn flush(&mut self, port: &mut Box<dyn SerialPort>) -> bool {
+        if self.frames.is_empty() {
+            return true;
+        }
+        if self.next_retry.is_some_and(|due| Instant::now() < due) {
+            return false;
+        }
+        let notice = format!("OUTBOX REPLAY frames={} dropped={}", self.frames.len(), self.dropped);
+        if serial_write(port, &format_message(COMMAND_CHANNEL, &notice)).is_err() {
+            self.next_retry = Some(Instant::now() + RETRY_INTERVAL);
+            return false;
+        }
+        log::info!("Application link is back, replaying {} status frames", self.frames.len());
+        while let Some(frame) = self.frames.front() {
+            if serial_write(port, frame).is_err() {
+                self.next_retry = Some(Instant::now() + RETRY_INTERVAL);
+                self.save();
+                return false;
+            }
+            self.frames.pop_front();
+        }
+        // Only counted as reported once the replay it was announced with went through
+        self.dropped = 0;
+        self.next_retry = None;
+        self.save();
+        true
+    }
+
+    fn queue(&mut self, frame: String) {
+        if CONFIG.outbox_capacity == 0 {
+            return;
+        }
+        self.push(frame.clone());
+        if self.written >= 2 * CONFIG.outbox_capacity {
+            self.save();
+            return;
+        }
+        let path = &CONFIG.outbox_path;
+        let result = std::fs::OpenOptions::new().create(true).append(true).open(path)
+            .and_then(|mut file| file.write_all(frame.as_bytes()));
+        match result {
+            Ok(()) => self.written += 1,
+            Err(e) => log::error!("Failed to write outbox {}: {}", path, e),
+        }
+    }
+
+    fn push(&mut self, frame: String) {
+        if self.frames.len() >= CONFIG.outbox_capacity.max(1) {
+            self.frames.pop_front();
+            self.dropped += 1;
+        }
+        self.frames.push_back(frame);
+    }
+
+    // Rewrites the file with only the frames still waiting
+    fn save(&mut self) {
+        let path = &CONFIG.outbox_path;
+        self.written = self.frames.len();
+        if self.frames.is_empty() && self.dropped == 0 {
+            std::fs::remove_file(path).ok();
+            return;
+        }
+        let text = format!("{DROPPED}{}\n{}", self.dropped, self.frames.iter().map(String::as_str).collect::<String>());
+        if let Err(e) = std::fs::write(path, text) {
+            log::error!("Failed to write outbox {}: {}", path, e);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+use crate::config::{PeripheralKind, PeripheralSettings, CONFIG};
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::safe_mode::SafeMode;
+use crate::sim::{SimDevice, SimulatedPort};
+use crate::try_open_port;
+
+// A lab device beyond the pumps, router and [devices], added as a module implementing this plus a
+// [[peripherals]] kind that creates it. It takes every step whose command type is its prefix.
+pub trait Peripheral: Send {
+    // Brings the device into a known state at startup
+    fn init(&mut self) -> Result<(), String>;
+    fn execute(&mut self, command: &str) -> ControlFlow<String>;
+    // One line for QUERY_PERIPHERALS
+    fn status(&mut self) -> String;
+    // Leaves the device safe when the controller goes away, without waiting indefinitely
+    fn shutdown(&mut self);
+}
+
+fn create(settings: &'static PeripheralSettings, port: Box<dyn SerialPort>) -> Box<dyn Peripheral> {
+    match settings.kind {
+        PeripheralKind::Serial => Box::new(SerialPeripheral { settings, port }),
+    }
+}
+
+// The configured peripheral taking steps of this command type, whether or not it was opened
+pub fn configured(command_type: &str) -> Option<&'static PeripheralSettings> {
+    CONFIG.peripherals.iter().find(|settings| settings.prefix == command_type)
+}
+
+struct Registered {
+    settings: &'static PeripheralSettings,
+    device: Box<dyn Peripheral>,
+}
+
+// Peripherals by prefix; an optional one that can't be opened or initialized is left out
+#[derive(Default)]
+pub struct Peripherals {
+    registry: BTreeMap<&'static str, Registered>,
+}
+
+impl Peripherals {
+    // A required peripheral that can't be opened or initialized puts the controller in safe mode
+    pub fn open(safe_mode: &mut SafeMode) -> Peripherals {
+        Peripherals::register(safe_mode, |settings| try_open_port(&settings.device.port_path, settings.device.baud_rate))
+    }
+
+    pub fn simulated(safe_mode: &mut SafeMode) -> Peripherals {
+        Peripherals::register(safe_mode, |settings| Ok(SimulatedPort::open(&settings.device.port_path, SimDevice::Peripheral)))
+    }
+
+    fn register(safe_mode: &mut SafeMode, open: impl Fn(&PeripheralSettings) -> Result<Box<dyn SerialPort>, String>) -> Peripherals {
+        let mut peripherals = Peripherals::default();
+        for settings in &CONFIG.peripherals {
+            if let Some(taken) = peripherals.registry.get(settings.prefix.as_str()) {
+                log::error!("Peripheral {} ignored, {} already takes {}_ steps", settings.name, taken.settings.name, settings.prefix);
+                continue;
+            }
+            let device = open(settings).and_then(|port| {
+                let mut device = create(settings, port);
+                device.init().map(|()| device)
+            });
+            match device {
+                Ok(device) => {
+                    log::info!("Peripheral {} found on {}, taking {}_ steps", settings.name, settings.device.port_path, settings.prefix);
+                    peripherals.registry.insert(&settings.prefix, Registered { settings, device });
+                }
+                Err(e) if settings.device.optional => {
+                    log::warn!("Optional peripheral {} unavailable, {}_ steps will be refused: {}", settings.name, settings.prefix, e)
+                }
+                Err(e) => safe_mode.record(&settings.name, e),
+            }
+        }
+        peripherals
+    }
+
+    pub fn available(&self, command_type: &str) -> bool {
+        self.registry.contains_key(command_type)
+    }
+
+    // None when no peripheral takes the command type
+    pub fn execute(&mut self, command_type: &str, command: &str) -> Option<ControlFlow<String>> {
+        let registered = self.registry.get_mut(command_type)?;
+        log::info!("Peripheral {}: {}", registered.settings.name, command);
+        Some(registered.device.execute(command))
+    }
+
+    // "PERIPHERALS illuminator=[L100] stirrer=[...]"
+    pub fn describe(&mut self) -> String {
+        let statuses: Vec<String> = self.registry.values_mut()
+            .map(|registered| format!("{}=[{}]", registered.settings.name, registered.device.status()))
+            .collect();
+        format!("PERIPHERALS {}", statuses.join(" ")).trim_end().to_string()
+    }
+
+    pub fn shutdown(&mut self) {
+        self.registry.values_mut().for_each(|registered| registered.device.shutdown());
+    }
+}
+
+// Line device driven by the templates of its [[peripherals]] entry
+struct SerialPeripheral {
+    settings: &'static PeripheralSettings,
+    port: Box<dyn SerialPort>,
+}
+
+impl SerialPeripheral {
+    fn send(&mut self, text: &str) -> Result<(), String> {
+        let settings = self.settings;
+        flush_port(&mut self.port);
+        serial_write(&mut self.port, text).map_err(|_| format!("{} - failed to send command: [{}]", settings.name, text.trim_end()))?;
+        if settings.reply.is_empty() {
+            return Ok(());
+        }
+        match serial_readline_timeout(&mut self.port, "\r\n", Duration::from_millis(settings.reply_timeout_ms)) {
+            Some(reply) if reply.trim() == settings.reply => Ok(()),
+            Some(reply) => Err(format!("{} - error executing command: [{}] replied [{}]", settings.name, text.trim_end(), reply.trim())),
+            None => Err(format!("{} - no reply to command: [{}]", settings.name, text.trim_end())),
+        }
+    }
+}
+
+impl Peripheral for SerialPeripheral {
+    fn init(&mut self) -> Result<(), String> {
+        let settings = self.settings;
+        if settings.init.is_empty() {
+            return Ok(());
+        }
+        self.send(&settings.init)
+    }
+
+    fn execute(&mut self, command: &str) -> ControlFlow<String> {
+        let settings = self.settings;
+        let mut args = command[settings.prefix.len()..].split('_').skip(1);
+        let action = args.next().unwrap_or_default();
+        let Some(template) = settings.commands.get(action) else {
+            let mut actions: Vec<&str> = settings.commands.keys().map(String::as_str).collect();
+            actions.sort();
+            return ControlFlow::Break(format!("{command}: {} has no action {action}, only {}", settings.name, actions.join(", ")));
+        };
+        let text = args.enumerate().fold(template.clone(), |text, (i, arg)| text.replace(&format!("{{{}}}", i + 1), arg));
+        if (1..=9).any(|i| text.contains(&format!("{{{i}}}"))) {
+            return ControlFlow::Break(format!("{command}: too few arguments for {} action {action}", settings.name));
+        }
+        match self.send(&text) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(e) => ControlFlow::Break(e),
+        }
+    }
+
+    fn status(&mut self) -> String {
+        let settings = self.settings;
+        if settings.status_query.is_empty() {
+            return "ok".to_string();
+        }
+        flush_port(&mut self.port);
+        if serial_write(&mut self.port, &settings.status_query).is_err() {
+            return "unreachable".to_string();
+        }
+        serial_readline_timeout(&mut self.port, "\r\n", Duration::from_millis(settings.reply_timeout_ms))
+            .map_or("no reply".to_string(), |reply| reply.trim().to_string())
+    }
+
+    fn shutdown(&mut self) {
+        let settings = self.settings;
+        if settings.shutdown.is_empty() {
+            return;
+        }
+        match self.send(&settings.shutdown) {
+            Ok(()) => log::info!("Peripheral {} shut down", settings.name),
+            Err(e) => log::error!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::config::CONFIG;
+
+// How often poll_until asks and for how long
+#[derive(Clone, Copy, Debug)]
+pub struct Poll {
+    interval: Duration,
+    timeout: Option<Duration>,
+    // Up to this much is added to every interval at random, so pollers sharing a bus drift apart
+    jitter: Duration,
+}
+
+impl Poll {
+    // Without a timeout, with [polling] jitter_ms
+    pub fn every(interval: Duration) -> Poll {
+        Poll { interval, timeout: None, jitter: Duration::from_millis(CONFIG.polling.jitter_ms) }
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Poll {
+        Poll { timeout: Some(timeout), ..self }
+    }
+
+    fn next_interval(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.interval;
+        }
+        let random = RandomState::new().build_hasher().finish();
+        self.interval + self.jitter.mul_f64((random % 1000) as f64 / 1000.0)
+    }
+}
+
+// Calls `check` until it returns Some, sleeping on the clock between calls; None once the timeout
+// has passed without. The state is handed to `check`, so it can be the controller whose clock
+// paces the polling.
+pub fn poll_until<S: ?Sized, T>(state: &mut S, poll: Poll, clock: fn(&S) -> &dyn Clock, mut check: impl FnMut(&mut S) -> Option<T>) -> Option<T> {
+    let deadline = poll.timeout.map(|timeout| clock(state).now() + timeout);
+    loop {
+        if let Some(result) = check(state) {
+            return Some(result);
+        }
+        let now = clock(state).now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            return None;
+        }
+        let interval = poll.next_interval();
+        clock(state).sleep(deadline.map_or(interval, |deadline| interval.min(deadline - now)));
+    }
+}
+
+// poll_until for checks that can fail: a Break ends the polling with it, Continue(Some) with the
+// result and Continue(None) asks again. Continue(None) once the timeout has passed.
+pub fn try_poll_until<S: ?Sized, T>(state: &mut S, poll: Poll, clock: fn(&S) -> &dyn Clock,
+                                    mut check: impl FnMut(&mut S) -> ControlFlow<String, Option<T>>) -> ControlFlow<String, Option<T>> {
+    let polled = poll_until(state, poll, clock, |state| match check(state) {
+        ControlFlow::Continue(None) => None,
+        done => Some(done),
+    });
+    polled.unwrap_or(ControlFlow::Continue(None))
+}
@@ -1,57 +1,253 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{Mutex, MutexGuard};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::log;
-use serialport::SerialPort;
+use lazy_static::lazy_static;
+use serialport::{FlowControl, SerialPort};
 
+use crate::config;
+use crate::config::CONFIG;
 use crate::escape_chars;
+use crate::{metadata, router_echo};
 
-pub fn serial_write(port: &mut Box<dyn SerialPort>, msg: &str) {
-    let port_name = port.name().unwrap();
-    log::trace!("Writing to port {}: {}", port_name, escape_chars(msg));
-    port.write(msg.as_ref()).map_err(|e| log::error!("FAILED WRITE: {}", e));
+const READ_CHUNK: usize = 256;
+// Upper bound for one blocking read, so reads without a deadline still notice a closed port
+const MAX_READ_WAIT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref READ_BUFFERS: Mutex<HashMap<String, VecDeque<u8>>> = Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    // Per thread, as each controller instance talks to its own instruments from its own thread
+    static LAST_EXCHANGE: RefCell<Option<Exchange>> = const { RefCell::new(None) };
+}
+
+// The last command sent to an instrument and what came back, for fault reports
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub port: String,
+    pub reply: Option<String>,
+}
+
+pub fn last_exchange() -> Option<Exchange> {
+    LAST_EXCHANGE.with(|exchange| exchange.borrow().clone())
+}
+
+pub fn forget_exchange() {
+    LAST_EXCHANGE.with(|exchange| *exchange.borrow_mut() = None);
+}
+
+fn record_reply(port: &str, reply: &str) {
+    LAST_EXCHANGE.with(|exchange| {
+        if let Some(exchange) = exchange.borrow_mut().as_mut().filter(|x| x.port == port) {
+            exchange.reply = Some(reply.to_string());
+        }
+    });
+}
+
+// Windows ports may be configured as `com3` or `\\.\COM10`; the serial driver adds the `\\.\` itself
+pub fn device_path(port_path: &str) -> String {
+    let path = port_path.strip_prefix(r"\\.\").unwrap_or(port_path);
+    match path.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("COM") && path.len() > 3 && path[3..].bytes().all(|b| b.is_ascii_digit()) => {
+            format!("COM{}", &path[3..])
+        }
+        _ => path.to_string(),
+    }
+}
+
+pub fn same_port(a: &str, b: &str) -> bool {
+    device_path(a) == device_path(b)
+}
+
+pub fn write_timeout(port_path: &str) -> Duration {
+    let settings = &CONFIG.serial_write;
+    let millis = if same_port(port_path, &CONFIG.router_port_path) {
+        settings.router_timeout_ms
+    } else if same_port(port_path, &CONFIG.pump_port_path) {
+        settings.pump_timeout_ms
+    } else {
+        settings.application_timeout_ms
+    };
+    Duration::from_millis(millis)
+}
+
+// Only the application port is throttled; pump and router replies must never be held back
+pub fn flow_control(port_path: &str) -> FlowControl {
+    if !same_port(port_path, &CONFIG.application_port_path) {
+        return FlowControl::None;
+    }
+    match CONFIG.application_flow_control {
+        config::FlowControl::None => FlowControl::None,
+        config::FlowControl::Software => FlowControl::Software,
+        config::FlowControl::Hardware => FlowControl::Hardware,
+    }
+}
+
+pub fn serial_write(port: &mut Box<dyn SerialPort>, msg: &str) -> io::Result<()> {
+    serial_write_bytes(port, msg.as_bytes())
+}
+
+pub fn unlogged_serial_write(port: &mut Box<dyn SerialPort>, msg: &str) -> io::Result<()> {
+    unlogged_serial_write_bytes(port, msg.as_bytes())
 }
 
-pub fn unlogged_serial_write(port: &mut Box<dyn SerialPort>, msg: &str) {
-    let port_name = port.name().unwrap();
-    port.write(msg.as_ref()).map_err(|e| log::error!("FAILED WRITE: {}", e));
+// For binary frames; bytes are logged as Latin-1
+pub fn serial_write_bytes(port: &mut Box<dyn SerialPort>, bytes: &[u8]) -> io::Result<()> {
+    let port_name = port.name().unwrap_or_default();
+    let text: String = bytes.iter().map(|b| char::from(*b)).collect();
+    log::trace!("Writing to port {}: {}", port_name, metadata::redact(&escape_chars(&text)));
+    if !same_port(&port_name, &CONFIG.application_port_path) {
+        LAST_EXCHANGE.with(|exchange| *exchange.borrow_mut() = Some(Exchange { port: port_name.clone(), reply: None }));
+    }
+    router_echo::sent(&port_name, &text);
+    write_all(port, bytes)
+        .map_err(|e| { log::error!("FAILED WRITE to {}: {}", port_name, e); e })
 }
 
+pub fn unlogged_serial_write_bytes(port: &mut Box<dyn SerialPort>, bytes: &[u8]) -> io::Result<()> {
+    write_all(port, bytes)
+        .map_err(|e| { log::error!("FAILED WRITE: {}", e); e })
+}
+
+fn write_all(port: &mut Box<dyn SerialPort>, bytes: &[u8]) -> io::Result<()> {
+    let deadline = Instant::now() + write_timeout(&port.name().unwrap_or_default());
+    let mut written = 0;
+    for chunk in bytes.chunks(CONFIG.serial_write.chunk_size.max(1)) {
+        let mut offset = 0;
+        while offset < chunk.len() {
+            match port.write(&chunk[offset..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "port accepted no bytes")),
+                Ok(n) => offset += n,
+                Err(e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    sleep(Duration::from_millis(1));
+                }
+                Err(e) => return Err(e),
+            }
+            if offset < chunk.len() && Instant::now() >= deadline {
+                let msg = format!("wrote only {} of {} bytes before timeout", written + offset, bytes.len());
+                return Err(io::Error::new(ErrorKind::TimedOut, msg));
+            }
+        }
+        written += chunk.len();
+    }
+    port.flush()
+}
 
 pub fn flush_port(port: &mut Box<dyn SerialPort>) {
-    loop {
-        let mut buf: [u8; 1] = [0];
-        if port.bytes_to_read().unwrap() != 0 {
-            port.read(&mut buf);
-        } else {
+    read_buffer(port.as_ref()).clear();
+    router_echo::flushed(&port.name().unwrap_or_default());
+    let mut chunk = [0; READ_CHUNK];
+    while port.bytes_to_read().unwrap_or(0) != 0 {
+        if port.read(&mut chunk).is_err() {
             return;
         }
     }
 }
 
-pub fn serial_readline(port: &mut Box<dyn SerialPort>, end_delimiter: &str) -> String {
-    return _serial_readline(port, end_delimiter, |s| log::trace!("{}", s));
+// Waits as long as it takes; None only once the port fails, e.g. when the USB link drops
+pub fn serial_readline(port: &mut Box<dyn SerialPort>, end_delimiter: &str) -> Option<String> {
+    _serial_readline(port, end_delimiter, |s| log::trace!("{}", metadata::redact(&s)), None)
 }
 
-pub fn unlogged_serial_readline(port: &mut Box<dyn SerialPort>, end_delimiter: &str) -> String {
-    return _serial_readline(port, end_delimiter, |_| {});
+pub fn serial_readline_timeout(port: &mut Box<dyn SerialPort>, end_delimiter: &str, timeout: Duration) -> Option<String> {
+    _serial_readline(port, end_delimiter, |s| log::trace!("{}", metadata::redact(&s)), Some(Instant::now() + timeout))
+}
+
+// Bytes read past a delimiter stay in the port's buffer for the next call
+fn read_buffer(port: &dyn SerialPort) -> PortBuffer {
+    let name = port.name().unwrap_or_default();
+    let mut buffers = READ_BUFFERS.lock().unwrap_or_else(|e| e.into_inner());
+    buffers.entry(name.clone()).or_default();
+    PortBuffer { buffers, name }
+}
+
+struct PortBuffer {
+    buffers: MutexGuard<'static, HashMap<String, VecDeque<u8>>>,
+    name: String,
+}
+
+impl PortBuffer {
+    fn get(&mut self) -> &mut VecDeque<u8> {
+        self.buffers.get_mut(&self.name).expect("read buffer is created on access")
+    }
+
+    fn clear(&mut self) {
+        self.get().clear();
+    }
+
+    fn take_line(&mut self, end_delimiter: &str) -> Option<String> {
+        let delimiter = end_delimiter.as_bytes();
+        let buffer = self.get();
+        let end = buffer.make_contiguous().windows(delimiter.len()).position(|w| w == delimiter)?;
+        let line: String = buffer.drain(..end).map(char::from).collect();
+        buffer.drain(..delimiter.len());
+        Some(line)
+    }
+
+    fn take_bytes(&mut self, count: usize) -> Option<Vec<u8>> {
+        let buffer = self.get();
+        (buffer.len() >= count).then(|| buffer.drain(..count).collect())
+    }
+
+    fn pending(&mut self) -> String {
+        self.get().iter().map(|b| char::from(*b)).collect()
+    }
 }
 
-pub fn _serial_readline(port: &mut Box<dyn SerialPort>, end_delimiter: &str, logger: fn(s: String)) -> String {
-    let mut line = String::new();
+pub fn _serial_readline(port: &mut Box<dyn SerialPort>, end_delimiter: &str, logger: fn(s: String), deadline: Option<Instant>) -> Option<String> {
     loop {
-        let mut buf: [u8; 1] = [0];
-        if port.bytes_to_read().unwrap() != 0 {
-            port.read(&mut buf);
-            line.push(char::from(buf[0]));
-        } else {
-            sleep(Duration::from_micros(10));
+        let line = read_until(port, logger, deadline, |buffer| buffer.take_line(end_delimiter))?;
+        logger(format!("Got [{}] from port {}", escape_chars(&format!("{line}{end_delimiter}")), port.name().unwrap_or_default()));
+        if router_echo::received(&port.name().unwrap_or_default(), &line) {
             continue;
         }
-        if line.ends_with(end_delimiter) {
-            logger(format!("Got [{}] from port {}", escape_chars(&line), port.name().unwrap()));
-            return line.strip_suffix(end_delimiter).unwrap().to_string();
-        }
+        record_reply(&port.name().unwrap_or_default(), &line);
+        return Some(line);
     }
 }
 
+// Exactly `count` bytes, for binary fields that may contain any delimiter
+pub fn serial_read_bytes(port: &mut Box<dyn SerialPort>, count: usize, timeout: Duration) -> Option<Vec<u8>> {
+    read_until(port, |s| log::trace!("{}", s), Some(Instant::now() + timeout), |buffer| buffer.take_bytes(count))
+}
+
+// Reads whatever the driver has in one call and blocks in the driver, not in a polling loop,
+// until `take` finds what it needs in the buffer or the deadline passes
+fn read_until<T>(port: &mut Box<dyn SerialPort>, logger: fn(s: String), deadline: Option<Instant>, mut take: impl FnMut(&mut PortBuffer) -> Option<T>) -> Option<T> {
+    let original_timeout = port.timeout();
+    let mut chunk = [0; READ_CHUNK];
+    let result = loop {
+        if let Some(result) = take(&mut read_buffer(port.as_ref())) {
+            break Some(result);
+        }
+        let wait = match deadline {
+            Some(d) if Instant::now() >= d => {
+                let pending = read_buffer(port.as_ref()).pending();
+                logger(format!("Timed out reading from port {}, got [{}]", port.name().unwrap_or_default(), escape_chars(&pending)));
+                if !pending.is_empty() {
+                    record_reply(&port.name().unwrap_or_default(), &pending);
+                }
+                break None;
+            }
+            Some(d) => (d - Instant::now()).min(MAX_READ_WAIT),
+            None => MAX_READ_WAIT,
+        };
+        port.set_timeout(wait).ok();
+        match port.read(&mut chunk) {
+            Ok(n) => read_buffer(port.as_ref()).get().extend(&chunk[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+            Err(e) => {
+                logger(format!("Failed to read from port {}: {}", port.name().unwrap_or_default(), e));
+                break None;
+            }
+        }
+    };
+    port.set_timeout(original_timeout).ok();
+    result
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use sysinfo::{DiskExt, System, SystemExt};
+
+use crate::config::CONFIG;
+use crate::{run_store, Controller};
+
+const MB: u64 = 1024 * 1024;
+
+// Whether a message can run to the end without losing its records: a disk filling up or a
+// read-only mount would otherwise only show once the journal or history fails to save mid-run
+pub fn check(controller: &Controller) -> Result<(), String> {
+    let settings = &CONFIG.preflight;
+    if !settings.enabled {
+        return Ok(());
+    }
+    let directories = output_directories(controller.timeline.recording());
+    for directory in &directories {
+        writable(directory)?;
+    }
+    if settings.min_free_mb > 0 {
+        free_space(&directories, settings.min_free_mb)?;
+    }
+    run_store::open().check()
+}
+
+fn output_directories(timeline: bool) -> BTreeSet<PathBuf> {
+    // The sensor logs go next to the journal
+    let mut files = vec![
+        &CONFIG.run_history_path, &CONFIG.journal_path, &CONFIG.outbox_path, &CONFIG.wear_counters_path,
+        &CONFIG.reservoir_levels_path, &CONFIG.reagent_expiry_path, &CONFIG.calibration_path,
+    ];
+    files.extend(&CONFIG.event_log.file);
+    files.extend(&CONFIG.tracing.file);
+    let mut directories: BTreeSet<PathBuf> = files.into_iter().map(|file| parent(file)).collect();
+    if CONFIG.run_report.enabled {
+        directories.insert(PathBuf::from(&CONFIG.run_report.directory));
+    }
+    if timeline {
+        directories.insert(PathBuf::from(&CONFIG.simulation_timeline.directory));
+    }
+    directories
+}
+
+fn parent(file: &str) -> PathBuf {
+    match Path::new(file).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// Report and timeline directories are created when first written, so they are created here too
+fn writable(directory: &Path) -> Result<(), String> {
+    let probe = directory.join(format!(".preflight-{}", CONFIG.instance_name));
+    std::fs::create_dir_all(directory)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("{} is not writable: {}", directory.display(), e))
+}
+
+fn free_space(directories: &BTreeSet<PathBuf>, min_free_mb: u64) -> Result<(), String> {
+    let mut system = System::new();
+    system.refresh_disks_list();
+    system.refresh_disks();
+    for directory in directories {
+        let Ok(path) = directory.canonicalize() else {
+            continue;
+        };
+        // The innermost mount holding the directory
+        let Some(disk) = system.disks().iter()
+            .filter(|disk| path.starts_with(disk.mount_point()))
+            .max_by_key(|disk| disk.mount_point().as_os_str().len()) else {
+            continue;
+        };
+        let free_mb = disk.available_space() / MB;
+        if free_mb < min_free_mb {
+            return Err(format!("only {} MB free on {} for {}, [preflight] min_free_mb is {}",
+                free_mb, disk.mount_point().display(), directory.display(), min_free_mb));
+        }
+    }
+    Ok(())
+}
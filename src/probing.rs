@@ -0,0 +1,116 @@
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use crate::calibration;
+use crate::config::CONFIG;
+use crate::deck;
+use crate::deck::Coordinates;
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::units::Millimeters;
+use crate::{halt, motion, router_echo, unwrap_option, unwrap_result, Controller};
+
+// PROBE_SLOT finds the slot surface of the slide holder and PROBE_<tube> the top of a tube by
+// lowering the needle onto it until the router's probe input triggers (G38-style). How far it sits
+// from its configured height is stored in the calibration store and applied to later moves to the
+// holder's tubes, so a new holder is probed instead of tuned by hand.
+pub fn probe(controller: &mut Controller, command: &str) -> ControlFlow<String> {
+    let settings = &CONFIG.probing;
+    let target = command.strip_prefix("PROBE_").unwrap_or_default();
+    let (holder, expected) = if target == "SLOT" {
+        (calibration::SLOT, unwrap_option!(settings.slot, format!("{command}: no [probing] slot position configured")))
+    } else {
+        match deck::tube_position(target) {
+            Ok(tube) => (calibration::holder(target), Coordinates { z: tube.z + settings.tube_top_mm, ..tube }),
+            Err(e) => return ControlFlow::Break(format!("{command}: {e}")),
+        }
+    };
+    if controller.firmware.router_capabilities.get("Z_PROBE") == Some(&false) {
+        return ControlFlow::Break(format!("{command}: router firmware reports no probe input"));
+    }
+    let start = Coordinates { z: Millimeters((expected.z + settings.search_mm).0.min(motion::SAFE_Z.0)), ..expected };
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..controller.router.position })?;
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..start })?;
+    controller.router_move(start)?;
+    let lowest = expected.z - settings.search_mm;
+    let contact = touch_down(controller, start, lowest);
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..controller.router.position })?;
+    let Some(contact) = contact? else {
+        return ControlFlow::Break(format!("{command}: probe did not trigger between Z{} and Z{}", start.z, lowest));
+    };
+    let offset = Millimeters(((contact - expected.z).0 * 1000.0).round() / 1000.0);
+    match controller.calibration.z_offsets.insert(holder.to_string(), offset) {
+        Some(previous) => log::info!("Probed {} at Z{}, offset {} mm (was {} mm)", holder, contact, offset, previous),
+        None => log::info!("Probed {} at Z{}, offset {} mm", holder, contact, offset),
+    }
+    controller.calibration.save();
+    controller.events.emit("probe", &[("holder", holder.to_string()), ("z", contact.to_string()), ("offset_mm", offset.to_string())]);
+    controller.application.send_status(&format!("PROBE holder={holder} z={contact} offset={offset}mm"));
+    ControlFlow::Continue(())
+}
+
+// Height the probe triggered at, None when it reached `lowest` without contact
+fn touch_down(controller: &mut Controller, start: Coordinates, lowest: Millimeters) -> ControlFlow<String, Option<Millimeters>> {
+    let settings = &CONFIG.probing;
+    let gcode = settings.command.replace("{z}", &lowest.to_string()).replace("{feed}", &settings.feedrate_mm_per_min.to_string());
+    controller.spans.enter("router", &[("gcode", gcode.clone())]);
+    let result = probe_transaction(controller, &gcode);
+    controller.spans.exit(&result);
+    let (z, triggered) = result?;
+    // The probe only moves Z
+    let travelled = controller.router.moved_to(Coordinates { z, ..start });
+    controller.notes.extend(controller.wear.record_travel(travelled));
+    controller.timeline.router(Duration::from_secs_f64(travelled.0 / (settings.feedrate_mm_per_min / 60.0)));
+    ControlFlow::Continue(triggered.then_some(z))
+}
+
+// As Controller::router_transaction, but answered with a probe result instead of G1:OK
+fn probe_transaction(controller: &mut Controller, gcode: &str) -> ControlFlow<String, (Millimeters, bool)> {
+    let settings = &CONFIG.probing;
+    if let Some(reason) = &controller.router.halted {
+        return ControlFlow::Break(format!("Router halted after {reason}, send HOME once the deck is clear"));
+    }
+    let port = controller.router_port.name().unwrap_or_default();
+    let mut resends = 0;
+    'send: loop {
+        router_echo::take_mismatch(&port);
+        flush_port(&mut controller.router_port);
+        unwrap_result!(serial_write(&mut controller.router_port, &format!("{gcode}\r\n")), format!("Router - failed to send probe command: [{gcode}]"));
+        let deadline = Instant::now() + Duration::from_secs(settings.timeout_secs);
+        loop {
+            let line = serial_readline_timeout(&mut controller.router_port, "\r\n", deadline.saturating_duration_since(Instant::now()));
+            let line = unwrap_option!(line, format!("Router - no probe result within {}s of [{gcode}]", settings.timeout_secs));
+            if let Some(mismatch) = router_echo::take_mismatch(&port) {
+                if resends >= CONFIG.router_echo.max_resends {
+                    return ControlFlow::Break(halt::halt(controller, gcode, &format!("echo {}", mismatch.echoed)));
+                }
+                resends += 1;
+                log::warn!("Resending [{}] ({} of {})", mismatch.sent, resends, CONFIG.router_echo.max_resends);
+                continue 'send;
+            }
+            if let Some(result) = parse_result(&line) {
+                return ControlFlow::Continue(result);
+            }
+            log::trace!("Ignoring router reply [{}] while probing", line);
+        }
+    }
+}
+
+// "PRB:x,y,z:1", optionally in brackets; the last field says whether the probe triggered
+fn parse_result(line: &str) -> Option<(Millimeters, bool)> {
+    let (axes, triggered) = line.trim_matches(['[', ']']).strip_prefix("PRB:")?.rsplit_once(':')?;
+    let z = axes.split(',').nth(2)?.parse().ok()?;
+    Some((Millimeters(z), triggered == "1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_probe_reports() {
+        assert_eq!(parse_result("PRB:0.000,0.000,-88.250:1"), Some((Millimeters(-88.25), true)));
+        assert_eq!(parse_result("[PRB:1.000,2.000,-95.000:0]"), Some((Millimeters(-95.0), false)));
+        assert_eq!(parse_result("G38:OK"), None);
+        assert_eq!(parse_result("PRB:0.000,0.000:1"), None);
+    }
+}
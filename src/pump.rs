@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::clock::SystemClock;
+use crate::config::CONFIG;
+use crate::poll::{poll_until, Poll};
+use crate::port_operations::{flush_port, serial_write_bytes};
+use crate::pump_protocol::protocol;
+use crate::units::{Microliters, PumpUnits};
+
+pub const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+pub const FULL_STROKE: PumpUnits = PumpUnits(12000);
+// Nominal for the 500 ul syringes on both pumps. No per-pump volume calibration is measured, so
+// every conversion between volumes and plunger increments goes through this one factor.
+pub const UNITS_PER_MICROLITER: u64 = 24;
+pub const MAX_STROKE_MICROLITER: Microliters = FULL_STROKE.to_microliters();
+pub const VALVE_REGISTER: &str = "?6";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Initialize,
+    ValveIn(u8),
+    ValveOut(u8),
+    MoveTo(PumpUnits),
+    PickUp(PumpUnits),
+    Dispense(PumpUnits),
+    LoopStart,
+    LoopEnd(u32),
+    // Plunger top speed in pulses per second for the moves that follow
+    Speed(u32),
+    // N command, 0 is standard resolution
+    Resolution(u8),
+}
+
+// Plunger resolution a command's positions are given in
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Resolution {
+    #[default]
+    Standard,
+    Fine,
+}
+
+impl Resolution {
+    pub fn mode(self) -> u8 {
+        match self {
+            Resolution::Standard => 0,
+            Resolution::Fine => CONFIG.pump_resolution.fine_mode,
+        }
+    }
+
+    // Increments per standard increment
+    pub fn scale(self) -> u64 {
+        match self {
+            Resolution::Standard => 1,
+            Resolution::Fine => CONFIG.pump_resolution.fine_scale,
+        }
+    }
+}
+
+// A sequence of steps executed by one pump; the configured protocol decides how it is sent.
+// Displays in DT notation, e.g. `/1gI1A12000O2A0G6R`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PumpCommand {
+    address: u8,
+    steps: Vec<Step>,
+    loop_start: usize,
+    resolution: Resolution,
+}
+
+impl PumpCommand {
+    pub fn new(address: u8) -> PumpCommand {
+        PumpCommand { address, steps: Vec::new(), loop_start: 0, resolution: Resolution::Standard }
+    }
+
+    pub fn initialize(mut self) -> PumpCommand {
+        self.steps.push(Step::Initialize);
+        self.loop_start = self.steps.len();
+        self
+    }
+
+    pub fn valve_in(mut self, port: u8) -> PumpCommand {
+        self.steps.push(Step::ValveIn(port));
+        self
+    }
+
+    pub fn valve_out(mut self, port: u8) -> PumpCommand {
+        self.steps.push(Step::ValveOut(port));
+        self
+    }
+
+    pub fn move_to(mut self, position: PumpUnits) -> PumpCommand {
+        self.steps.push(Step::MoveTo(position));
+        self
+    }
+
+    pub fn pick_up(mut self, units: PumpUnits) -> PumpCommand {
+        self.steps.push(Step::PickUp(units));
+        self
+    }
+
+    pub fn dispense(mut self, units: PumpUnits) -> PumpCommand {
+        self.steps.push(Step::Dispense(units));
+        self
+    }
+
+    // Positions of this command are in `resolution` increments
+    pub fn resolution(mut self, resolution: Resolution) -> PumpCommand {
+        self.resolution = resolution;
+        self
+    }
+
+    pub fn speed(mut self, pulses_per_second: u32) -> PumpCommand {
+        self.steps.push(Step::Speed(pulses_per_second));
+        self
+    }
+
+    // Repeats every step added since the start or the previous loop
+    pub fn repeat(mut self, times: u32) -> PumpCommand {
+        self.steps.insert(self.loop_start, Step::LoopStart);
+        self.steps.push(Step::LoopEnd(times));
+        self.loop_start = self.steps.len();
+        self
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    // Longest single plunger movement in standard increments, assuming the plunger starts at zero
+    pub fn stroke_units(&self) -> PumpUnits {
+        let mut position = PumpUnits::ZERO;
+        let mut longest = PumpUnits::ZERO;
+        for step in &self.steps {
+            let travel = match *step {
+                Step::MoveTo(target) => std::mem::replace(&mut position, target).abs_diff(target),
+                Step::PickUp(units) => { position += units; units }
+                Step::Dispense(units) => { position = position.saturating_sub(units); units }
+                _ => PumpUnits::ZERO,
+            };
+            longest = longest.max(travel);
+        }
+        PumpUnits(longest.0 / self.resolution.scale())
+    }
+
+    // How often each step runs, counting loop repeats
+    fn repeats(&self) -> Vec<u64> {
+        let mut repeats = vec![1; self.steps.len()];
+        let mut loop_start = 0;
+        for (i, step) in self.steps.iter().enumerate() {
+            match *step {
+                Step::LoopStart => loop_start = i,
+                Step::LoopEnd(times) => repeats[loop_start..i].iter_mut().for_each(|r| *r = u64::from(times)),
+                _ => {}
+            }
+        }
+        repeats
+    }
+
+    // Aspirations per valve port the plunger drew through, and valve moves, counting loop repeats
+    pub fn wear(&self) -> (HashMap<u8, u64>, u64) {
+        let mut strokes = HashMap::new();
+        let mut valve_moves = 0;
+        let mut port = 0;
+        let mut position = PumpUnits::ZERO;
+        for (step, times) in self.steps.iter().zip(self.repeats()) {
+            let aspirates = match *step {
+                Step::ValveIn(p) => { port = p; valve_moves += times; false }
+                Step::ValveOut(_) => { valve_moves += times; false }
+                Step::MoveTo(target) => target > std::mem::replace(&mut position, target),
+                Step::PickUp(units) => { position += units; units > PumpUnits::ZERO }
+                Step::Dispense(units) => { position = position.saturating_sub(units); false }
+                _ => false,
+            };
+            if aspirates {
+                *strokes.entry(port).or_insert(0) += times;
+            }
+        }
+        (strokes, valve_moves)
+    }
+
+    // Expected run time: plunger travel at the speed in effect, starting at `speed` pulses per
+    // second, plus `valve_move` per valve move, counting loop repeats
+    pub fn duration(&self, speed: u32, valve_move: Duration) -> Duration {
+        let mut speed = speed.max(1);
+        let mut position = PumpUnits::ZERO;
+        let mut total = Duration::ZERO;
+        for (step, times) in self.steps.iter().zip(self.repeats()) {
+            let travel = match *step {
+                Step::Speed(pulses_per_second) => {
+                    speed = pulses_per_second.max(1);
+                    PumpUnits::ZERO
+                }
+                Step::ValveIn(_) | Step::ValveOut(_) => {
+                    total += valve_move * times as u32;
+                    PumpUnits::ZERO
+                }
+                Step::MoveTo(target) => std::mem::replace(&mut position, target).abs_diff(target),
+                Step::PickUp(units) => { position += units; units }
+                Step::Dispense(units) => { position = position.saturating_sub(units); units }
+                _ => PumpUnits::ZERO,
+            };
+            total += Duration::from_secs_f64((travel.0 / self.resolution.scale()) as f64 / f64::from(speed)) * times as u32;
+        }
+        total
+    }
+
+    // Volume drawn through each valve port, counting loop repeats and assuming the plunger starts at zero
+    pub fn drawn(&self) -> HashMap<u8, Microliters> {
+        let mut drawn = HashMap::new();
+        let mut port = 0;
+        let mut position = PumpUnits::ZERO;
+        for (step, times) in self.steps.iter().zip(self.repeats()) {
+            let units = match *step {
+                Step::ValveIn(p) => { port = p; PumpUnits::ZERO }
+                Step::MoveTo(target) => target.saturating_sub(std::mem::replace(&mut position, target)),
+                Step::PickUp(units) => { position += units; units }
+                Step::Dispense(units) => { position = position.saturating_sub(units); PumpUnits::ZERO }
+                _ => PumpUnits::ZERO,
+            };
+            if units > PumpUnits::ZERO {
+                *drawn.entry(port).or_default() += PumpUnits(units.0 / self.resolution.scale()).to_microliters() * times;
+            }
+        }
+        drawn
+    }
+
+    // Whether the plunger pushes liquid out anywhere, assuming it starts at zero
+    pub fn dispenses(&self) -> bool {
+        let mut position = PumpUnits::ZERO;
+        self.steps.iter().any(|step| match *step {
+            Step::MoveTo(target) => target < std::mem::replace(&mut position, target),
+            Step::PickUp(units) => { position += units; false }
+            Step::Dispense(units) => units > PumpUnits::ZERO,
+            _ => false,
+        })
+    }
+
+    // Command string without framing, ending with the execute command. With [pump-resolution]
+    // enabled every command sets its mode, so one stopped in fine mode doesn't skew the next.
+    pub fn text(&self) -> String {
+        let steps: String = self.steps.iter().map(|step| protocol().step(step)).collect();
+        let execute = protocol().execute();
+        if CONFIG.pump_resolution.enabled {
+            format!("{}{steps}{execute}", protocol().step(&Step::Resolution(self.resolution.mode())))
+        } else {
+            format!("{steps}{execute}")
+        }
+    }
+}
+
+impl Display for PumpCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "/{}{}", self.address, self.text())
+    }
+}
+
+pub fn address_char(address: u8) -> char {
+    char::from(b'0' + address)
+}
+
+pub fn write_frame(port: &mut Box<dyn SerialPort>, address: char, text: &str) -> std::io::Result<()> {
+    serial_write_bytes(port, &protocol().frame(address, text))
+}
+
+pub fn write_command(port: &mut Box<dyn SerialPort>, command: &PumpCommand) -> std::io::Result<()> {
+    write_frame(port, address_char(command.address), &command.text())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PumpError {
+    None,
+    Initialization,
+    InvalidCommand,
+    InvalidOperand,
+    InvalidSequence,
+    Eeprom,
+    NotInitialized,
+    PlungerOverload,
+    ValveOverload,
+    PlungerMoveNotAllowed,
+    CommandOverflow,
+    Unknown(u8),
+}
+
+impl PumpError {
+    fn from_code(code: u8) -> PumpError {
+        match code {
+            0 => PumpError::None,
+            1 => PumpError::Initialization,
+            2 => PumpError::InvalidCommand,
+            3 => PumpError::InvalidOperand,
+            4 => PumpError::InvalidSequence,
+            6 => PumpError::Eeprom,
+            7 => PumpError::NotInitialized,
+            9 => PumpError::PlungerOverload,
+            10 => PumpError::ValveOverload,
+            11 => PumpError::PlungerMoveNotAllowed,
+            15 => PumpError::CommandOverflow,
+            c => PumpError::Unknown(c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PumpStatus {
+    pub ready: bool,
+    pub error: PumpError,
+}
+
+impl Display for PumpStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?})", if self.ready { "ready" } else { "busy" }, self.error)
+    }
+}
+
+// Replies look like "<0xFF>/0<status><data><ETX>", where bit 5 of the status byte is the ready flag
+pub fn parse_status(reply: &str) -> Option<PumpStatus> {
+    let mut chars = reply.chars().skip_while(|c| *c != '/').skip(1);
+    if chars.next()? != '0' {
+        return None;
+    }
+    let status = chars.next()? as u32;
+    if status > 0x7F {
+        return None;
+    }
+    let status = status as u8;
+    Some(PumpStatus { ready: status & 0x20 != 0, error: PumpError::from_code(status & 0x0F) })
+}
+
+pub fn query(port: &mut Box<dyn SerialPort>, address: char, query: &str) -> Option<String> {
+    flush_port(port);
+    write_frame(port, address, query).ok()?;
+    read_reply(port, address)
+}
+
+// On a half-duplex RS-485 line every frame is echoed back, so the address of the last echoed request
+// tells which pump a "/0" reply belongs to. Replies following another pump's request are dropped.
+pub fn read_reply(port: &mut Box<dyn SerialPort>, address: char) -> Option<String> {
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    let mut requester = address;
+    loop {
+        let line = protocol().read_frame(port, deadline)?;
+        match line.chars().skip_while(|c| *c != '/').nth(1) {
+            Some('0') if requester == address => return Some(line),
+            Some('0') => log::warn!("Discarding pump reply meant for pump {}", requester),
+            Some(echoed) => requester = echoed,
+            None => log::warn!("Discarding unexpected pump data [{}]", line.escape_debug()),
+        }
+    }
+}
+
+pub fn query_status(port: &mut Box<dyn SerialPort>, address: char) -> Option<PumpStatus> {
+    query(port, address, "Q").and_then(|reply| parse_status(&reply))
+}
+
+pub fn query_firmware(port: &mut Box<dyn SerialPort>, address: char) -> Option<String> {
+    let reply = query(port, address, "&")?;
+    let version: String = reply.chars()
+        .skip_while(|c| *c != '/')
+        .skip(3)
+        .filter(|c| !c.is_control() && *c != '\u{ff}')
+        .collect();
+    Some(version)
+}
+
+pub fn query_position(port: &mut Box<dyn SerialPort>, address: char, register: &str) -> Option<String> {
+    let reply = query(port, address, register)?;
+    Some(reply.chars().skip_while(|c| *c != '/').skip(3).filter(|c| c.is_ascii_digit()).collect())
+}
+
+// The last status read, whether or not the pump got ready within `timeout`
+pub fn wait_ready(port: &mut Box<dyn SerialPort>, address: char, timeout: Duration) -> Option<PumpStatus> {
+    let poll = Poll::every(Duration::from_millis(CONFIG.polling.pump_ready_interval_ms)).timeout(timeout);
+    let mut last = None;
+    poll_until(port, poll, |_| &SystemClock, |port| {
+        last = query_status(port, address);
+        last.filter(|s| s.ready)
+    }).or(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_loops_in_dt_notation() {
+        let command = PumpCommand::new(1).valve_in(1).move_to(FULL_STROKE).valve_out(2).move_to(PumpUnits::ZERO).repeat(6);
+        assert_eq!(command.to_string(), "/1gI1A12000O2A0G6R");
+        let command = PumpCommand::new(2).initialize().valve_in(3).pick_up(PumpUnits(2400)).speed(800).dispense(PumpUnits(2400));
+        assert_eq!(command.to_string(), "/2ZI3P2400V800D2400R");
+    }
+
+    #[test]
+    fn stroke_units_is_the_longest_single_move() {
+        let command = PumpCommand::new(1).move_to(PumpUnits(3000)).move_to(PumpUnits(1000)).pick_up(PumpUnits(500)).dispense(PumpUnits(1500));
+        assert_eq!(command.stroke_units(), PumpUnits(3000));
+        let fine = PumpCommand::new(1).resolution(Resolution::Fine).move_to(PumpUnits(8000));
+        assert_eq!(fine.stroke_units(), PumpUnits(8000 / CONFIG.pump_resolution.fine_scale));
+    }
+
+    #[test]
+    fn only_plunger_moves_towards_zero_dispense() {
+        assert!(!PumpCommand::new(1).valve_in(1).move_to(FULL_STROKE).dispenses());
+        assert!(PumpCommand::new(1).valve_in(1).move_to(FULL_STROKE).valve_out(2).move_to(PumpUnits::ZERO).dispenses());
+        assert!(PumpCommand::new(1).dispense(PumpUnits(10)).dispenses());
+    }
+}
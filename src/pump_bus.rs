@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::config::CONFIG;
+use crate::port_operations::{flush_port, unlogged_serial_write_bytes};
+use crate::pump;
+use crate::pump::{PumpCommand, PumpError, PumpStatus};
+use crate::pump_protocol::protocol;
+
+pub const PUMP_ADDRESSES: [u8; 2] = [1, 2];
+const IDLE_REPLY: &str = "/0c";
+
+// All pumps share one RS-485 port. A transaction holds the bus from request to reply, so a status
+// query for one pump can never pick up the acknowledgement of a command sent to another.
+#[derive(Clone)]
+pub struct PumpBus {
+    port: Arc<Mutex<Box<dyn SerialPort>>>,
+    polls: Arc<Mutex<PollLog>>,
+}
+
+// Status polls are not logged, they would drown everything else. For debugging, one sample per
+// interval and every change of a pump's status can be logged.
+#[derive(Default)]
+struct PollLog {
+    interval: Option<Duration>,
+    last: HashMap<u8, (String, Instant)>,
+}
+
+impl PumpBus {
+    pub fn new(port: Box<dyn SerialPort>) -> PumpBus {
+        let settings = &CONFIG.pump_poll_log;
+        let interval = settings.enabled.then(|| Duration::from_secs(settings.interval_secs));
+        PumpBus { port: Arc::new(Mutex::new(port)), polls: Arc::new(Mutex::new(PollLog { interval, last: HashMap::new() })) }
+    }
+
+    pub fn pump(&self, address: u8) -> Pump {
+        Pump { address, bus: self.clone() }
+    }
+
+    pub fn pumps(&self) -> Vec<Pump> {
+        PUMP_ADDRESSES.iter().map(|a| self.pump(*a)).collect()
+    }
+
+    // Exclusive access for exchanges that don't fit the request/reply pattern, e.g. initialization
+    pub fn lock(&self) -> MutexGuard<'_, Box<dyn SerialPort>> {
+        // A thread that panicked mid-transaction leaves at worst a stray reply, which the next one flushes
+        self.port.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // None turns polling logs off
+    pub fn set_poll_logging(&self, interval: Option<Duration>) {
+        let mut polls = self.polls.lock().unwrap_or_else(|e| e.into_inner());
+        polls.interval = interval;
+        polls.last.clear();
+    }
+
+    fn log_poll(&self, address: u8, status: &str) {
+        let mut polls = self.polls.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(interval) = polls.interval else {
+            return;
+        };
+        let now = Instant::now();
+        match polls.last.get(&address) {
+            Some((previous, _)) if previous != status => {
+                log::debug!("Pump {} status {} -> {}", address, describe_status(previous), describe_status(status));
+            }
+            Some((_, logged)) if now.duration_since(*logged) < interval => return,
+            _ => log::debug!("Pump {} status {}", address, describe_status(status)),
+        }
+        polls.last.insert(address, (status.to_string(), now));
+    }
+}
+
+// Q29 answers "/0c" when idle; anything else is reported raw
+fn describe_status(reply: &str) -> String {
+    format!("{} [{}]", if reply == IDLE_REPLY { "idle" } else { "busy" }, reply.escape_debug())
+}
+
+#[derive(Clone)]
+pub struct Pump {
+    address: u8,
+    bus: PumpBus,
+}
+
+impl Pump {
+    fn address_char(&self) -> char {
+        pump::address_char(self.address)
+    }
+
+    // Sends a command and consumes its acknowledgement; the pump is usually still busy afterwards
+    pub fn send(&self, command: &PumpCommand) -> Result<(), String> {
+        if command.address() != self.address {
+            return Err(format!("Pump {} - refusing command addressed to pump {}: [{}]", self.address, command.address(), command));
+        }
+        let mut port = self.bus.lock();
+        flush_port(&mut port);
+        pump::write_command(&mut port, command).map_err(|_| format!("Pump - failed to send command: [{command}]"))?;
+        let reply = pump::read_reply(&mut port, self.address_char())
+            .ok_or(format!("Pump {} - no acknowledgement for command: [{}]", self.address, command))?;
+        match pump::parse_status(&reply) {
+            Some(status) if status.error != PumpError::None => {
+                Err(format!("Pump {} - rejected command [{}]: {:?}", self.address, command, status.error))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Stops the running stroke immediately; the plunger stays where it is
+    pub fn terminate(&self) -> Result<(), String> {
+        let mut port = self.bus.lock();
+        flush_port(&mut port);
+        pump::write_frame(&mut port, self.address_char(), "T")
+            .map_err(|_| format!("Pump {} - failed to send terminate", self.address))?;
+        pump::read_reply(&mut port, self.address_char())
+            .map(|_| ())
+            .ok_or(format!("Pump {} - no acknowledgement for terminate", self.address))
+    }
+
+    pub fn is_idle(&self) -> Result<bool, String> {
+        let mut port = self.bus.lock();
+        flush_port(&mut port);
+        unlogged_serial_write_bytes(&mut port, &protocol().frame(self.address_char(), "Q29"))
+            .map_err(|_| format!("Pump {} - failed to query status", self.address))?;
+        let reply = pump::read_reply(&mut port, self.address_char())
+            .ok_or(format!("Pump {} - no reply to status query", self.address))?;
+        let status = reply.trim_start_matches('\u{ff}').trim_end_matches('\u{3}');
+        self.bus.log_poll(self.address, status);
+        Ok(status == IDLE_REPLY)
+    }
+
+    pub fn status(&self) -> Result<PumpStatus, String> {
+        pump::query_status(&mut self.bus.lock(), self.address_char())
+            .ok_or(format!("Pump {} - no reply to status query", self.address))
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn query_position(&self, register: &str) -> Option<String> {
+        pump::query_position(&mut self.bus.lock(), self.address_char(), register)
+    }
+}
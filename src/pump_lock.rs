@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use crate::config::CONFIG;
+use crate::pump::PumpCommand;
+use crate::pump_bus::Pump;
+use crate::state::ControllerState;
+use crate::Controller;
+
+// Failsafe for the syringe drives: a pump still busy [pump-runtime] max_secs after its command was
+// sent, e.g. because the command repeats a stroke an absurd number of times, is terminated and every
+// further pump command is refused until UNLOCKPUMPS.
+#[derive(Default)]
+pub struct PumpLock {
+    // When the running command of each pump was sent, on the controller's clock
+    started: HashMap<u8, Instant>,
+    pub locked: Option<String>,
+}
+
+// Sends a command unless the pumps are locked, starting its runtime
+pub fn send(controller: &mut Controller, pump: &Pump, command: &PumpCommand) -> ControlFlow<String> {
+    if let Some(reason) = &controller.pump_lock.locked {
+        return ControlFlow::Break(format!("Pumps locked after {reason}, send UNLOCKPUMPS once the pumps are checked"));
+    }
+    let now = controller.clock.now();
+    controller.pump_lock.started.insert(command.address(), now);
+    if let Err(e) = pump.send(command) {
+        return ControlFlow::Break(e);
+    }
+    ControlFlow::Continue(())
+}
+
+// Called while waiting on a busy pump. One found busy with a command sent elsewhere, e.g. during
+// startup, is timed from then on.
+pub fn check(controller: &mut Controller, pump: &Pump) -> ControlFlow<String> {
+    let limit = Duration::from_secs(CONFIG.pump_runtime.max_secs);
+    let now = controller.clock.now();
+    let started = *controller.pump_lock.started.entry(pump.address()).or_insert(now);
+    let runtime = now.saturating_duration_since(started);
+    if runtime <= limit {
+        return ControlFlow::Continue(());
+    }
+    log::error!("Pump {} still busy after {}s, terminating", pump.address(), runtime.as_secs());
+    if let Err(e) = pump.terminate() {
+        log::error!("{}", e);
+    }
+    controller.pump_lock.started.remove(&pump.address());
+    let reason = format!("pump {} running for more than {}s", pump.address(), limit.as_secs());
+    controller.events.emit("pumps_locked", &[("pump", pump.address().to_string()), ("runtime_secs", runtime.as_secs().to_string())]);
+    controller.application.send_status(&format!("PUMPS LOCKED pump={} runtime={}s", pump.address(), runtime.as_secs()));
+    controller.pump_lock.locked = Some(reason.clone());
+    ControlFlow::Break(format!("Pumps locked after {reason}, send UNLOCKPUMPS once the pumps are checked"))
+}
+
+// Called once the pump reports idle
+pub fn finished(controller: &mut Controller, pump: &Pump) {
+    controller.pump_lock.started.remove(&pump.address());
+}
+
+// UNLOCKPUMPS control: the operator acknowledges the runaway pump
+pub fn unlock(controller: &mut Controller) -> String {
+    if matches!(controller.state, ControllerState::Running | ControllerState::Paused) {
+        return "REFUSED control=UNLOCKPUMPS reason=run_in_progress".to_string();
+    }
+    match controller.pump_lock.locked.take() {
+        Some(reason) => {
+            log::info!("Pumps unlocked, were locked after {}", reason);
+            controller.events.emit("pumps_unlocked", &[]);
+            "PUMPS UNLOCKED".to_string()
+        }
+        None => "REFUSED control=UNLOCKPUMPS reason=not_locked".to_string(),
+    }
+}
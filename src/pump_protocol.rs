@@ -0,0 +1,139 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Instant;
+
+use serialport::SerialPort;
+
+use crate::config::{PumpDialect, CONFIG};
+use crate::port_operations::{serial_read_bytes, serial_readline_timeout};
+use crate::pump::Step;
+
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
+
+// How commands and replies look on the wire for one pump family. Everything above this layer
+// (PumpCommand, status parsing, the bus) works with DT notation: replies are handed up as
+// "/<address><status><data>", whichever framing the pump uses.
+pub trait PumpProtocol: Sync {
+    // Command letter and operand of one step; the default is the Cavro command set
+    fn step(&self, step: &Step) -> String {
+        match step {
+            Step::Initialize => "Z".to_string(),
+            Step::ValveIn(port) => format!("I{port}"),
+            Step::ValveOut(port) => format!("O{port}"),
+            Step::MoveTo(position) => format!("A{position}"),
+            Step::PickUp(units) => format!("P{units}"),
+            Step::Dispense(units) => format!("D{units}"),
+            Step::LoopStart => "g".to_string(),
+            Step::LoopEnd(times) => format!("G{times}"),
+            Step::Speed(pulses) => format!("V{pulses}"),
+            Step::Resolution(mode) => format!("N{mode}"),
+        }
+    }
+
+    // Ends a command string so the pump runs it
+    fn execute(&self) -> String {
+        "R".to_string()
+    }
+
+    // Bytes that carry `text`, a command string or query, to the pump at `address`
+    fn frame(&self, address: char, text: &str) -> Vec<u8>;
+
+    // Next frame on the bus, including echoes of our own requests
+    fn read_frame(&self, port: &mut Box<dyn SerialPort>, deadline: Instant) -> Option<String>;
+}
+
+pub fn protocol() -> &'static dyn PumpProtocol {
+    match CONFIG.pump_protocol {
+        PumpDialect::Dt => &DtProtocol,
+        PumpDialect::Oem => &OemProtocol,
+    }
+}
+
+// ASCII lines, e.g. `/1A12000R`, answered with "<0xFF>/0<status><data><ETX>"
+pub struct DtProtocol;
+
+impl PumpProtocol for DtProtocol {
+    fn frame(&self, address: char, text: &str) -> Vec<u8> {
+        format!("/{address}{text}\r\n").into_bytes()
+    }
+
+    fn read_frame(&self, port: &mut Box<dyn SerialPort>, deadline: Instant) -> Option<String> {
+        serial_readline_timeout(port, "\r\n", deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+// Binary framing of the newer pumps: STX, address, sequence, text, ETX and an XOR checksum
+pub struct OemProtocol;
+
+// Sequence numbers 1 to 7 let a pump spot a repeated frame
+static SEQUENCE: AtomicU8 = AtomicU8::new(0);
+
+impl OemProtocol {
+    // Replies from the pump carry no sequence byte
+    pub fn encode(address: char, sequence: Option<u8>, text: &str) -> Vec<u8> {
+        let mut frame = vec![STX, address as u8];
+        frame.extend(sequence);
+        frame.extend(text.bytes());
+        frame.push(ETX);
+        frame.push(checksum(&frame));
+        frame
+    }
+
+    // Address and content of a frame with a valid checksum; the sequence byte of requests is dropped
+    pub fn decode(frame: &[u8]) -> Option<(char, String)> {
+        let (&check, body) = frame.split_last()?;
+        let start = body.iter().rposition(|b| *b == STX)?;
+        let body = &body[start..];
+        if body.len() < 3 || body.last() != Some(&ETX) || checksum(body) != check {
+            return None;
+        }
+        let address = char::from(body[1]);
+        let content = if address == '0' { &body[2..body.len() - 1] } else { &body[3..body.len() - 1] };
+        Some((address, content.iter().map(|b| char::from(*b)).collect()))
+    }
+}
+
+impl PumpProtocol for OemProtocol {
+    fn step(&self, step: &Step) -> String {
+        let letters = &CONFIG.oem_commands;
+        match step {
+            Step::Initialize => letters.initialize.clone(),
+            Step::ValveIn(port) => format!("{}{port}", letters.valve_in),
+            Step::ValveOut(port) => format!("{}{port}", letters.valve_out),
+            Step::MoveTo(position) => format!("{}{position}", letters.move_to),
+            Step::PickUp(units) => format!("{}{units}", letters.pick_up),
+            Step::Dispense(units) => format!("{}{units}", letters.dispense),
+            Step::LoopStart => letters.loop_start.clone(),
+            Step::LoopEnd(times) => format!("{}{times}", letters.loop_end),
+            Step::Speed(pulses) => format!("{}{pulses}", letters.speed),
+            Step::Resolution(mode) => format!("{}{mode}", letters.resolution),
+        }
+    }
+
+    fn execute(&self) -> String {
+        CONFIG.oem_commands.execute.clone()
+    }
+
+    fn frame(&self, address: char, text: &str) -> Vec<u8> {
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed) % 7 + 1;
+        OemProtocol::encode(address, Some(0x30 | sequence), text)
+    }
+
+    fn read_frame(&self, port: &mut Box<dyn SerialPort>, deadline: Instant) -> Option<String> {
+        loop {
+            let head = serial_readline_timeout(port, "\u{3}", deadline.saturating_duration_since(Instant::now()))?;
+            let check = serial_read_bytes(port, 1, deadline.saturating_duration_since(Instant::now()))?;
+            let mut frame: Vec<u8> = head.chars().map(|c| c as u8).collect();
+            frame.push(ETX);
+            frame.extend(check);
+            match OemProtocol::decode(&frame) {
+                Some((address, content)) => return Some(format!("/{address}{content}")),
+                None => log::warn!("Discarding pump frame with bad checksum [{}]", head.escape_debug()),
+            }
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |check, b| check ^ b)
+}
@@ -0,0 +1,193 @@
+use std::fmt::Write as _;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::CONFIG;
+use crate::{config, metadata, Controller};
+
+// What happened during one run, written out as a report for the lab notebook when it ends
+#[derive(Default)]
+pub struct RunReport {
+    steps: Vec<StepRecord>,
+    retries: Vec<String>,
+}
+
+struct StepRecord {
+    command: String,
+    started: SystemTime,
+    finished: SystemTime,
+    error: Option<String>,
+}
+
+impl RunReport {
+    pub fn record(&mut self, command: &str, started: SystemTime, result: &ControlFlow<String>) {
+        self.steps.push(StepRecord {
+            command: command.to_string(),
+            started,
+            finished: SystemTime::now(),
+            error: result.clone().break_value(),
+        });
+    }
+
+    pub fn retry(&mut self, description: String) {
+        self.retries.push(description);
+    }
+
+    // TC_<celsius> holds the temperature until the next TC step or the end of the run
+    fn temperatures(&self, ended: SystemTime) -> Vec<(&str, SystemTime, SystemTime)> {
+        let changes: Vec<(&str, SystemTime)> = self.steps.iter()
+            .filter(|step| step.error.is_none())
+            .filter_map(|step| Some((step.command.strip_prefix("TC_")?, step.finished)))
+            .collect();
+        changes.iter().enumerate()
+            .map(|(i, (celsius, from))| (*celsius, *from, changes.get(i + 1).map_or(ended, |(_, until)| *until)))
+            .collect()
+    }
+}
+
+// Nothing is written unless [run-report] is enabled; failures are logged and never fail the run
+pub fn write(controller: &Controller, started: SystemTime, outcome: &str) {
+    let settings = &CONFIG.run_report;
+    if !settings.enabled {
+        return;
+    }
+    let run_id = controller.runs.current.clone().or_else(|| controller.metadata.fields.get("run").cloned());
+    let name = file_name("run", started, run_id.as_deref());
+    let html_path = Path::new(&settings.directory).join(format!("{name}.html"));
+    let html = render(controller, run_id.as_deref(), started, outcome);
+    if let Err(e) = std::fs::create_dir_all(&settings.directory).and_then(|_| std::fs::write(&html_path, html)) {
+        log::error!("Failed to write run report {}: {}", html_path.display(), e);
+        return;
+    }
+    log::info!("Run report written to {}", html_path.display());
+    if let Some(pdf_command) = &settings.pdf_command {
+        let pdf_path = html_path.with_extension("pdf");
+        let command = pdf_command
+            .replace("{html}", &html_path.to_string_lossy())
+            .replace("{pdf}", &pdf_path.to_string_lossy());
+        let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+        // Converters can take a while, so the executor does not wait for them
+        config::spawn(move || match Command::new(shell).args([flag, &command]).status() {
+            Ok(status) if status.success() => log::info!("Run report PDF written to {}", pdf_path.display()),
+            Ok(status) => log::error!("Run report PDF conversion [{}] failed with {}", command, status),
+            Err(e) => log::error!("Run report PDF conversion [{}] failed: {}", command, e),
+        });
+    }
+}
+
+fn render(controller: &Controller, run_id: Option<&str>, started: SystemTime, outcome: &str) -> String {
+    let report = &controller.report;
+    let ended = SystemTime::now();
+    let title = format!("Run {}", run_id.unwrap_or("report"));
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>", escape(&title)).ok();
+    html += "<style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;margin-bottom:1.5em}\
+             td,th{border:1px solid #999;padding:3px 8px;text-align:left}.error{color:#b00}</style>\n</head>\n<body>\n";
+    writeln!(html, "<h1>{}</h1>", escape(&title)).ok();
+    html += "<table>\n";
+    row(&mut html, &["Instance", &CONFIG.instance_name]);
+    row(&mut html, &["Started", &timestamp(started)]);
+    row(&mut html, &["Finished", &timestamp(ended)]);
+    row(&mut html, &["Duration", &format_duration(elapsed(started, ended))]);
+    row(&mut html, &["Outcome", outcome]);
+    if !controller.metadata.fields.is_empty() {
+        row(&mut html, &["Metadata", &controller.metadata.redacted()]);
+    }
+    html += "</table>\n";
+
+    html += "<h2>Protocol steps</h2>\n<table>\n<tr><th>#</th><th>Step</th><th>Started</th><th>Duration</th><th>Result</th></tr>\n";
+    for (i, step) in report.steps.iter().enumerate() {
+        let result = step.error.as_deref().map_or("ok".to_string(), |e| format!("ERROR {e}"));
+        let class = if step.error.is_some() { " class=\"error\"" } else { "" };
+        writeln!(html, "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>", class, i + 1,
+                 escape(&metadata::redact(&step.command)), timestamp(step.started),
+                 format_duration(elapsed(step.started, step.finished)), escape(&result)).ok();
+    }
+    html += "</table>\n";
+
+    html += "<h2>Volumes</h2>\n<table>\n<tr><th>Source</th><th>Dispensed</th></tr>\n";
+    for (source, microliters) in &controller.volumes.consumption {
+        row(&mut html, &[source, &format!("{microliters} µl")]);
+    }
+    row(&mut html, &["Waste", &format!("{} µl", controller.volumes.waste)]);
+    html += "</table>\n";
+
+    let temperatures = report.temperatures(ended);
+    if !temperatures.is_empty() {
+        html += "<h2>Temperatures</h2>\n<table>\n<tr><th>Held</th><th>From</th><th>Until</th><th>Duration</th></tr>\n";
+        for (celsius, from, until) in temperatures {
+            row(&mut html, &[&format!("{celsius} °C"), &timestamp(from), &timestamp(until), &format_duration(elapsed(from, until))]);
+        }
+        html += "</table>\n";
+    }
+
+    if !report.retries.is_empty() || !controller.notes.is_empty() {
+        html += "<h2>Errors, retries and notes</h2>\n<ul>\n";
+        for line in report.retries.iter().chain(&controller.notes) {
+            writeln!(html, "<li>{}</li>", escape(line)).ok();
+        }
+        html += "</ul>\n";
+    }
+    html += "</body>\n</html>\n";
+    html
+}
+
+fn row(html: &mut String, cells: &[&str]) {
+    let cells: String = cells.iter().map(|cell| format!("<td>{}</td>", escape(cell))).collect();
+    writeln!(html, "<tr>{cells}</tr>").ok();
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn elapsed(from: SystemTime, until: SystemTime) -> Duration {
+    until.duration_since(from).unwrap_or_default()
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+// UTC, e.g. "2024-05-01 13:45:07"
+fn timestamp(time: SystemTime) -> String {
+    let ((year, month, day), secs) = civil(time);
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// <prefix>-<start time>[-<run id>], with anything but letters, digits, - and _ in the id replaced
+pub fn file_name(prefix: &str, started: SystemTime, run_id: Option<&str>) -> String {
+    let mut name = format!("{prefix}-{}", file_timestamp(started));
+    if let Some(id) = run_id {
+        name += "-";
+        name.extend(id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }));
+    }
+    name
+}
+
+fn file_timestamp(time: SystemTime) -> String {
+    let ((year, month, day), secs) = civil(time);
+    format!("{year:04}{month:02}{day:02}-{:02}{:02}{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// Calendar date and seconds into the day of a UTC time, after Howard Hinnant's days_from_civil inverse
+fn civil(time: SystemTime) -> ((i64, u64, u64), u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097) as u64;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era as i64 + era * 400 + i64::from(month <= 2);
+    ((year, month, day), secs % 86400)
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::units::Microliters;
+
+// Volume drawn from each of [reservoirs] since it was last refilled, kept across restarts in
+// reservoir_levels_path. What is left is estimated from the configured capacity.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Reservoirs {
+    drawn: BTreeMap<String, Microliters>,
+}
+
+impl Reservoirs {
+    pub fn load() -> Reservoirs {
+        let Ok(text) = std::fs::read_to_string(&CONFIG.reservoir_levels_path) else {
+            return Reservoirs::default();
+        };
+        toml::from_str(&text)
+            .map_err(|e| log::error!("Ignoring unreadable reservoir levels {}: {}", CONFIG.reservoir_levels_path, e))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        let result = toml::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(&CONFIG.reservoir_levels_path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::error!("Failed to write reservoir levels {}: {}", CONFIG.reservoir_levels_path, e);
+        }
+    }
+
+    // Counts what pump 1 drew through `channel` against the reservoir on it. Returns the refill
+    // warning when the estimated remaining volume just dropped below warn_below_ul.
+    pub fn draw(&mut self, channel: u8, microliters: Microliters) -> Option<String> {
+        let (id, settings) = CONFIG.reservoirs.iter().find(|(_, r)| r.channel == channel)?;
+        let before = self.remaining(id)?;
+        *self.drawn.entry(id.clone()).or_default() += microliters;
+        let after = self.remaining(id)?;
+        if before < settings.warn_below_ul || after >= settings.warn_below_ul {
+            return None;
+        }
+        let warning = format!("RESERVOIR {id} low: about {after}ul of {}ul left, refill and send REFILLED_{id}", settings.capacity_ul);
+        log::warn!("{}", warning);
+        Some(warning)
+    }
+
+    // REFILLED_<id>
+    pub fn refilled(&mut self, id: &str) -> Result<String, String> {
+        let settings = CONFIG.reservoirs.get(id).ok_or(format!("Unknown reservoir {id}"))?;
+        self.drawn.remove(id);
+        self.save();
+        log::info!("Reservoir {} refilled to {}ul", id, settings.capacity_ul);
+        Ok(format!("RESERVOIR {id} refilled remaining={}ul", settings.capacity_ul))
+    }
+
+    fn remaining(&self, id: &str) -> Option<Microliters> {
+        let settings = CONFIG.reservoirs.get(id)?;
+        Some(settings.capacity_ul.saturating_sub(self.drawn.get(id).copied().unwrap_or_default()))
+    }
+
+    pub fn describe(&self) -> String {
+        let mut ids: Vec<&String> = CONFIG.reservoirs.keys().collect();
+        ids.sort();
+        let levels: Vec<String> = ids.iter()
+            .filter_map(|id| Some(format!("{id}={}ul/{}ul", self.remaining(id)?, CONFIG.reservoirs[*id].capacity_ul)))
+            .collect();
+        format!("RESERVOIRS [{}]", levels.join(", "))
+    }
+}
@@ -0,0 +1,128 @@
+use std::ops::ControlFlow;
+
+use crate::command_id::CommandId;
+use crate::config::CONFIG;
+use crate::deck::{self, Coordinates};
+use crate::pump::{PumpCommand, Resolution, FULL_STROKE};
+use crate::units::{Microliters, PumpUnits};
+use crate::{drain_command, estimation, microliter_to_pumpunit, motion, wash_needle, Controller};
+
+// A liquid application is a transaction: once liquid is taken up it has to reach its destination.
+// When the step fails or is skipped before it does, what is still held is put back into its source
+// when that is safe, or else into the waste, so no later step starts with unknown liquid in the
+// needle or the line to the slot.
+pub struct Uncommitted {
+    from: String,
+    volume: Microliters,
+    held: Held,
+    // Some of it may already be in the destination
+    dispensing: bool,
+    command_id: CommandId,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Held {
+    // In pump 1, to be dispensed through the needle
+    Syringe,
+    // Pushed on into the line to the slot
+    Line,
+}
+
+pub fn taken_up(controller: &mut Controller, from: &str, volume: Microliters, held: Held) {
+    controller.uncommitted = Some(Uncommitted { from: from.to_string(), volume, held, dispensing: false, command_id: controller.command_id });
+}
+
+pub fn dispensing(controller: &mut Controller) {
+    if let Some(uncommitted) = controller.uncommitted.as_mut() {
+        uncommitted.dispensing = true;
+    }
+}
+
+pub fn committed(controller: &mut Controller) {
+    controller.uncommitted = None;
+}
+
+// Only what the running step took up, e.g. for a skipped step; a pre-staged application belongs to a later one
+pub fn undo_step(controller: &mut Controller) {
+    if controller.uncommitted.as_ref().is_some_and(|uncommitted| uncommitted.command_id == controller.command_id) {
+        undo(controller);
+    }
+}
+
+pub fn undo(controller: &mut Controller) {
+    let Some(uncommitted) = controller.uncommitted.take() else {
+        return;
+    };
+    let source = estimation::tube_label(&uncommitted.from);
+    let to_source = CONFIG.rollback.return_to_source && uncommitted.held == Held::Syringe && !uncommitted.dispensing;
+    let (result, to) = if to_source {
+        (return_to_source(controller, &uncommitted), "source")
+    } else {
+        (discard(controller, &uncommitted), "waste")
+    };
+    let fields = [("source", source.clone()), ("volume_ul", uncommitted.volume.to_string()), ("to", to.to_string()),
+        ("command_id", uncommitted.command_id.to_string())];
+    match result {
+        ControlFlow::Continue(()) => {
+            log::warn!("Rolled back {} ul from {} to {}", uncommitted.volume, source, to);
+            controller.events.emit("rollback", &fields);
+            controller.notes.push(format!("{} ul from {source} put back to {to}", uncommitted.volume));
+            controller.application.send_status(&format!("ROLLBACK source={source} volume={}ul to={to}", uncommitted.volume));
+        }
+        ControlFlow::Break(e) => {
+            log::error!("Failed to roll back {} ul from {}: {}", uncommitted.volume, source, e);
+            controller.events.emit("rollback_failed", &fields);
+            controller.notes.push(format!("{} ul from {source} could not be put back ({e}), clear the needle before the next run", uncommitted.volume));
+            controller.application.send_status(&format!("ROLLBACK_FAILED source={source} volume={}ul reason={e}", uncommitted.volume));
+        }
+    }
+}
+
+fn raise(controller: &mut Controller) -> ControlFlow<String> {
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..controller.router.position })
+}
+
+// The needle only went up out of the source since, so nothing else got into the liquid
+fn return_to_source(controller: &mut Controller, uncommitted: &Uncommitted) -> ControlFlow<String> {
+    match deck::external_channel(&uncommitted.from) {
+        Some(channel) => controller.pump_execute(&PumpCommand::new(1).valve_out(channel).move_to(PumpUnits::ZERO))?,
+        None => {
+            let tube = match controller.calibration.tube_position(&uncommitted.from) {
+                Ok(tube) => tube,
+                Err(e) => return ControlFlow::Break(e),
+            };
+            raise(controller)?;
+            controller.router_move(tube)?;
+            controller.pump_execute(&PumpCommand::new(1).valve_out(1).move_to(PumpUnits::ZERO))?;
+            controller.router_move(Coordinates { z: motion::SAFE_Z, ..tube })?;
+        }
+    }
+    controller.tubes.refill(&uncommitted.from, uncommitted.volume);
+    controller.volumes.give_back(&estimation::tube_label(&uncommitted.from), uncommitted.volume);
+    ControlFlow::Continue(())
+}
+
+// Liquid held by pump 1 or still in the line is dispensed into the wash well; once some has
+// reached the slot, the rest is pushed after it and the slot drained
+fn discard(controller: &mut Controller, uncommitted: &Uncommitted) -> ControlFlow<String> {
+    if uncommitted.held == Held::Line && uncommitted.dispensing {
+        controller.pump_execute(&PumpCommand::new(1).valve_in(1).move_to(FULL_STROKE).valve_out(2).move_to(PumpUnits::ZERO).repeat(6))?;
+        let volume = controller.slot_occupancy + uncommitted.volume;
+        controller.pump_execute(&drain_command(volume))?;
+        controller.volumes.discard(volume);
+        controller.slot_occupancy = Microliters(0);
+        controller.slot_recovery = None;
+        return ControlFlow::Continue(());
+    }
+    raise(controller)?;
+    controller.router_move(CONFIG.wash_station.position)?;
+    match uncommitted.held {
+        Held::Syringe => controller.pump_execute(&PumpCommand::new(1).valve_out(1).move_to(PumpUnits::ZERO))?,
+        Held::Line => {
+            let units = microliter_to_pumpunit(uncommitted.volume, Resolution::Standard)?;
+            controller.pump_execute(&PumpCommand::new(1).valve_in(2).move_to(units).valve_out(1).move_to(PumpUnits::ZERO))?;
+        }
+    }
+    controller.volumes.discard(uncommitted.volume);
+    wash_needle(controller)
+}
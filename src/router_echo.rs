@@ -0,0 +1,62 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::config::{RouterEcho, CONFIG};
+use crate::escape_chars;
+use crate::port_operations::same_port;
+
+// Echoes of the lines written to a router port that are still to come back, by port, and the last
+// echo that did not match what was sent; see [router-echo]
+lazy_static! {
+    static ref EXPECTED: Mutex<HashMap<String, VecDeque<String>>> = Mutex::new(HashMap::new());
+    static ref MISMATCHES: Mutex<HashMap<String, Mismatch>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub sent: String,
+    pub echoed: String,
+}
+
+fn echoes(port: &str) -> bool {
+    CONFIG.router_echo.mode != RouterEcho::Off && same_port(port, &CONFIG.router_port_path)
+}
+
+// Every complete line written is echoed
+pub fn sent(port: &str, text: &str) {
+    if !echoes(port) {
+        return;
+    }
+    let mut expected = EXPECTED.lock().unwrap_or_else(|e| e.into_inner());
+    let queue = expected.entry(port.to_string()).or_default();
+    let lines = text.split_inclusive('\n').filter(|line| line.ends_with('\n'));
+    queue.extend(lines.map(|line| line.trim_end().to_string()).filter(|line| !line.is_empty()));
+}
+
+// Whether `line` read from `port` is an echo, which the caller then drops
+pub fn received(port: &str, line: &str) -> bool {
+    if !echoes(port) {
+        return false;
+    }
+    let Some(sent) = EXPECTED.lock().unwrap_or_else(|e| e.into_inner()).get_mut(port).and_then(VecDeque::pop_front) else {
+        return false;
+    };
+    let settings = &CONFIG.router_echo;
+    let echoed = line.strip_prefix(settings.prefix.as_str()).unwrap_or(line).trim_end();
+    if settings.mode == RouterEcho::Verify && echoed != sent {
+        log::warn!("Router echoed [{}] for [{}]", escape_chars(echoed), sent);
+        MISMATCHES.lock().unwrap_or_else(|e| e.into_inner()).insert(port.to_string(), Mismatch { sent, echoed: echoed.to_string() });
+    }
+    true
+}
+
+// Echoes still to come are discarded with the rest of the input
+pub fn flushed(port: &str) {
+    EXPECTED.lock().unwrap_or_else(|e| e.into_inner()).remove(port);
+}
+
+pub fn take_mismatch(port: &str) -> Option<Mismatch> {
+    MISMATCHES.lock().unwrap_or_else(|e| e.into_inner()).remove(port)
+}
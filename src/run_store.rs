@@ -0,0 +1,234 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{RunStoreBackend, CONFIG};
+use crate::history::RunRecord;
+use crate::journal::Journal;
+use crate::metadata::RunMetadata;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::{quote, Connection};
+use crate::{config, notifications};
+
+// Where run records and the journal of the running message are kept, chosen by [run-store] backend
+pub trait RunStore {
+    fn append(&self, record: &RunRecord) -> Result<(), String>;
+    // Oldest first
+    fn load(&self) -> Result<Vec<RunRecord>, String>;
+    fn save_journal(&self, journal: &Journal) -> Result<(), String>;
+    fn load_journal(&self) -> Result<Option<Journal>, String>;
+    fn clear_journal(&self);
+    // Whether records can be written, checked before a message runs
+    fn check(&self) -> Result<(), String>;
+}
+
+pub fn open() -> Box<dyn RunStore> {
+    match CONFIG.run_store.backend {
+        RunStoreBackend::File => Box::new(FileStore),
+        #[cfg(feature = "sqlite")]
+        RunStoreBackend::Sqlite => Box::new(SqliteStore),
+        #[cfg(not(feature = "sqlite"))]
+        RunStoreBackend::Sqlite => unreachable!("the configuration check refuses the sqlite backend without the feature"),
+        RunStoreBackend::Http => Box::new(HttpStore),
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct History {
+    #[serde(default)]
+    runs: Vec<RunRecord>,
+}
+
+// Records appended to run_history_path as [[runs]] tables, the journal in journal_path
+pub struct FileStore;
+
+impl RunStore for FileStore {
+    fn append(&self, record: &RunRecord) -> Result<(), String> {
+        let entry = toml::to_string(&History { runs: vec![record.clone()] }).map_err(|e| e.to_string())?;
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        // Windows files inherit the ACL of their directory instead
+        #[cfg(unix)]
+        options.mode(0o600);
+        options
+            .open(&CONFIG.run_history_path)
+            .and_then(|mut f| f.write_all(format!("\n{entry}").as_bytes()))
+            .map_err(|e| format!("Failed to write run history {}: {}", CONFIG.run_history_path, e))
+    }
+
+    fn load(&self) -> Result<Vec<RunRecord>, String> {
+        let content = match std::fs::read_to_string(&CONFIG.run_history_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Failed to read run history {}: {}", CONFIG.run_history_path, e)),
+        };
+        toml::from_str::<History>(&content)
+            .map(|h| h.runs)
+            .map_err(|e| format!("Invalid run history {}: {}", CONFIG.run_history_path, e))
+    }
+
+    fn save_journal(&self, journal: &Journal) -> Result<(), String> {
+        toml::to_string(journal)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(&CONFIG.journal_path, text).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Failed to write journal {}: {}", CONFIG.journal_path, e))
+    }
+
+    fn load_journal(&self) -> Result<Option<Journal>, String> {
+        let Ok(text) = std::fs::read_to_string(&CONFIG.journal_path) else {
+            return Ok(None);
+        };
+        toml::from_str(&text).map(Some).map_err(|e| format!("unreadable journal {}: {}", CONFIG.journal_path, e))
+    }
+
+    fn clear_journal(&self) {
+        std::fs::remove_file(&CONFIG.journal_path).ok();
+    }
+
+    fn check(&self) -> Result<(), String> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&CONFIG.run_history_path)
+            .map(|_| ())
+            .map_err(|e| format!("Cannot open run history {}: {}", CONFIG.run_history_path, e))
+    }
+}
+
+// Runs and the journal in the [run-store] sqlite_path database, for sites that query or replicate
+// it. The columns are for them; the controller reads records back from the TOML in `record`.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore;
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    fn connect(&self) -> Result<Connection, String> {
+        let path = &CONFIG.run_store.sqlite_path;
+        let connection = Connection::open(path).map_err(|e| format!("Failed to open run database {path}: {e}"))?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS runs (id INTEGER PRIMARY KEY, instance TEXT, started INTEGER, duration_secs INTEGER, \
+             tenant TEXT, outcome TEXT, commands INTEGER, consumed_ul INTEGER, waste_ul INTEGER, metadata TEXT, record TEXT NOT NULL); \
+             CREATE TABLE IF NOT EXISTS journal (instance TEXT PRIMARY KEY, state TEXT NOT NULL);"
+        ).map_err(|e| format!("Failed to set up run database {path}: {e}"))?;
+        // Metadata is kept unredacted, as in the history file
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).ok();
+        }
+        Ok(connection)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl RunStore for SqliteStore {
+    fn append(&self, record: &RunRecord) -> Result<(), String> {
+        let text = toml::to_string(record).map_err(|e| e.to_string())?;
+        let metadata: Vec<String> = record.metadata.iter().map(|(key, value)| format!("{key}={value}")).collect();
+        self.connect()?.execute(&format!(
+            "INSERT INTO runs (instance, started, duration_secs, tenant, outcome, commands, consumed_ul, waste_ul, metadata, record) \
+             VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {}, {})",
+            quote(&CONFIG.instance_name), record.started, record.duration_secs, record.tenant.as_deref().map_or("NULL".to_string(), quote),
+            quote(&record.outcome), record.commands, record.consumed_ul, record.waste_ul, quote(&metadata.join(" ")), quote(&text)
+        )).map_err(|e| format!("Failed to write run to {}: {}", CONFIG.run_store.sqlite_path, e))
+    }
+
+    fn load(&self) -> Result<Vec<RunRecord>, String> {
+        let rows = self.connect()?
+            .query(&format!("SELECT record FROM runs WHERE instance = {} ORDER BY id", quote(&CONFIG.instance_name)))
+            .map_err(|e| format!("Failed to read runs from {}: {}", CONFIG.run_store.sqlite_path, e))?;
+        rows.into_iter()
+            .filter_map(|row| row.into_iter().next().flatten())
+            .map(|text| toml::from_str(&text).map_err(|e| format!("Invalid run in {}: {}", CONFIG.run_store.sqlite_path, e)))
+            .collect()
+    }
+
+    fn save_journal(&self, journal: &Journal) -> Result<(), String> {
+        let text = toml::to_string(journal).map_err(|e| e.to_string())?;
+        self.connect()?
+            .execute(&format!("INSERT OR REPLACE INTO journal (instance, state) VALUES ({}, {})", quote(&CONFIG.instance_name), quote(&text)))
+            .map_err(|e| format!("Failed to write journal to {}: {}", CONFIG.run_store.sqlite_path, e))
+    }
+
+    fn load_journal(&self) -> Result<Option<Journal>, String> {
+        let rows = self.connect()?
+            .query(&format!("SELECT state FROM journal WHERE instance = {}", quote(&CONFIG.instance_name)))
+            .map_err(|e| format!("Failed to read journal from {}: {}", CONFIG.run_store.sqlite_path, e))?;
+        let Some(text) = rows.into_iter().next().and_then(|row| row.into_iter().next().flatten()) else {
+            return Ok(None);
+        };
+        toml::from_str(&text).map(Some).map_err(|e| format!("unreadable journal in {}: {}", CONFIG.run_store.sqlite_path, e))
+    }
+
+    fn clear_journal(&self) {
+        let cleared = self.connect().and_then(|connection| {
+            connection.execute(&format!("DELETE FROM journal WHERE instance = {}", quote(&CONFIG.instance_name)))
+        });
+        if let Err(e) = cleared {
+            log::error!("Failed to clear journal in {}: {}", CONFIG.run_store.sqlite_path, e);
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        self.connect().map(|_| ())
+    }
+}
+
+// Files as with the file backend, and every record is also posted as JSON to [run-store] url.
+// There is no retry yet: a record whose post fails is only in the local history.
+pub struct HttpStore;
+
+impl RunStore for HttpStore {
+    fn append(&self, record: &RunRecord) -> Result<(), String> {
+        FileStore.append(record)?;
+        let Some(url) = CONFIG.run_store.url.clone() else {
+            return Err("[run-store] backend is http but no url is set".to_string());
+        };
+        let payload = run_json(record);
+        config::spawn(move || {
+            if let Err(e) = notifications::post_json(&url, &payload) {
+                log::error!("Pushing run record to {} failed: {}", url, e);
+            }
+        });
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Vec<RunRecord>, String> {
+        FileStore.load()
+    }
+
+    fn save_journal(&self, journal: &Journal) -> Result<(), String> {
+        FileStore.save_journal(journal)
+    }
+
+    fn load_journal(&self) -> Result<Option<Journal>, String> {
+        FileStore.load_journal()
+    }
+
+    fn clear_journal(&self) {
+        FileStore.clear_journal()
+    }
+
+    // The push itself isn't retried either way, so only the local history has to be writable
+    fn check(&self) -> Result<(), String> {
+        FileStore.check()?;
+        match CONFIG.run_store.url {
+            Some(_) => Ok(()),
+            None => Err("[run-store] backend is http but no url is set".to_string()),
+        }
+    }
+}
+
+fn run_json(record: &RunRecord) -> String {
+    let json = notifications::json_string;
+    format!(
+        "{{\"instance\":{},\"started\":{},\"duration_secs\":{},\"tenant\":{},\"outcome\":{},\"commands\":{},\"consumed_ul\":{},\"waste_ul\":{},\"metadata\":{{{}}}}}",
+        json(&CONFIG.instance_name), record.started, record.duration_secs, record.tenant.as_deref().map_or("null".to_string(), json),
+        json(&record.outcome), record.commands, record.consumed_ul, record.waste_ul,
+        // Leaves the instrument, so sensitive metadata is redacted as in notifications
+        RunMetadata { fields: record.metadata.clone() }.redacted_fields().iter().map(|(key, value)| format!("{}:{}", json(key), json(value))).collect::<Vec<String>>().join(","),
+    )
+}
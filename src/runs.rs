@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+
+const PREFIX: &str = "RUN_";
+
+// Protocols submitted as `RUN_<id> <commands>` wait here until the controller is idle
+pub struct QueuedRun {
+    pub id: String,
+    pub data: String,
+}
+
+#[derive(Default)]
+pub struct RunQueue {
+    queue: VecDeque<QueuedRun>,
+    pub current: Option<String>,
+}
+
+pub fn parse_run(data: &str) -> Option<QueuedRun> {
+    let (first, rest) = data.split_once(' ').unwrap_or((data, ""));
+    let id = first.strip_prefix(PREFIX)?;
+    Some(QueuedRun { id: id.to_string(), data: rest.to_string() })
+}
+
+impl RunQueue {
+    // Returns the 1-based queue position
+    pub fn enqueue(&mut self, run: QueuedRun) -> Result<usize, String> {
+        if self.current.as_ref() == Some(&run.id) || self.position(&run.id).is_some() {
+            return Err(format!("run {} is already queued", run.id));
+        }
+        self.queue.push_back(run);
+        Ok(self.queue.len())
+    }
+
+    pub fn next(&mut self) -> Option<QueuedRun> {
+        self.queue.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        self.queue.iter().position(|run| run.id == id)
+    }
+
+    pub fn cancel(&mut self, id: &str) -> bool {
+        self.position(id).and_then(|i| self.queue.remove(i)).is_some()
+    }
+
+    pub fn move_to(&mut self, id: &str, position: usize) -> bool {
+        let Some(run) = self.position(id).and_then(|i| self.queue.remove(i)) else {
+            return false;
+        };
+        let index = position.saturating_sub(1).min(self.queue.len());
+        self.queue.insert(index, run);
+        true
+    }
+
+    pub fn describe(&self) -> String {
+        let queued = self.queue.iter()
+            .enumerate()
+            .map(|(i, run)| format!("{}:{}", i + 1, run.id))
+            .collect::<Vec<String>>()
+            .join(" ");
+        format!("RUNS current={} queued=[{}]", self.current.as_deref().unwrap_or("-"), queued)
+    }
+}
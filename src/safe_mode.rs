@@ -0,0 +1,177 @@
+use std::io;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+// Subsystems that couldn't be opened at startup. The controller comes up without them instead of
+// exiting, answers queries and diagnostics, and refuses liquid handling until it is restarted with
+// everything in place.
+#[derive(Default)]
+pub struct SafeMode {
+    // Subsystem and why it couldn't be opened, in startup order
+    missing: Vec<(String, String)>,
+}
+
+impl SafeMode {
+    pub fn record(&mut self, subsystem: &str, error: String) {
+        log::error!("{} unavailable, starting in safe mode: {}", subsystem, error);
+        self.missing.push((subsystem.to_string(), error));
+    }
+
+    pub fn active(&self) -> bool {
+        !self.missing.is_empty()
+    }
+
+    pub fn lacks(&self, subsystem: &str) -> bool {
+        self.missing.iter().any(|(name, _)| name == subsystem)
+    }
+
+    pub fn subsystems(&self) -> Vec<String> {
+        self.missing.iter().map(|(name, _)| name.replace(' ', "_")).collect()
+    }
+
+    // "off", or each missing subsystem with its error
+    pub fn describe(&self) -> String {
+        if !self.active() {
+            return "off".to_string();
+        }
+        self.missing.iter().map(|(name, error)| format!("{name} ({error})")).collect::<Vec<_>>().join("; ")
+    }
+}
+
+// Stands in for a port that failed to open: every write and read fails at once, so exchanges with
+// the subsystem end with the same errors as a device that stopped answering
+pub struct MissingPort {
+    name: String,
+    timeout: Duration,
+}
+
+impl MissingPort {
+    pub fn open(path: &str) -> Box<dyn SerialPort> {
+        Box::new(MissingPort { name: path.to_string(), timeout: Duration::ZERO })
+    }
+
+    fn error(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::NotConnected, format!("{} was not available at startup", self.name))
+    }
+}
+
+impl Read for MissingPort {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(self.error())
+    }
+}
+
+impl Write for MissingPort {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(self.error())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MissingPort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(9600)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(MissingPort { name: self.name.clone(), timeout: self.timeout }))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,682 @@
+use std::collections::HashMap;
+
+use toml::Value;
+
+use crate::{migration, units};
+
+// Layout of config.toml, checked before deserializing so that every mistake is reported at once
+// rather than only the first one serde trips over
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Bool,
+    Int { min: i64, max: i64 },
+    Float { min: f64 },
+    // Microliters, or a string with a unit such as "1.5ml"
+    Volume { min: u64 },
+    Coordinates,
+    // Coordinates, or a label such as EXT1 for positions the router never moves to
+    Position,
+    Choice(&'static [&'static str]),
+    List(&'static Kind),
+    Table(&'static [Field]),
+    Tables(&'static [Field]),
+    // Table with free-form keys
+    Map(&'static Kind),
+}
+
+struct Field {
+    key: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+const fn required(key: &'static str, kind: Kind) -> Field {
+    Field { key, kind, required: true }
+}
+
+const fn optional(key: &'static str, kind: Kind) -> Field {
+    Field { key, kind, required: false }
+}
+
+const COUNT: Kind = Kind::Int { min: 0, max: i64::MAX };
+const POSITIVE: Kind = Kind::Int { min: 1, max: i64::MAX };
+const VALVE_PORT: Kind = Kind::Int { min: 1, max: 12 };
+const NUMBER: Kind = Kind::Float { min: f64::MIN };
+const VOLUME: Kind = Kind::Volume { min: 0 };
+const POSITIVE_VOLUME: Kind = Kind::Volume { min: 1 };
+const LOG_LEVEL: Kind = Kind::Choice(&["error", "warn", "info", "debug", "trace", "off"]);
+
+const COORDINATES: &[Field] = &[
+    required("x", NUMBER),
+    required("y", NUMBER),
+    required("z", NUMBER),
+];
+
+const DEVICE: &[Field] = &[
+    required("port_path", Kind::Str),
+    optional("baud_rate", POSITIVE),
+    optional("optional", Kind::Bool),
+];
+
+const ROOT: &[Field] = &[
+    optional("config_version", POSITIVE),
+    optional("instance_name", Kind::Str),
+    required("application_port_path", Kind::Str),
+    required("pump_port_path", Kind::Str),
+    required("router_port_path", Kind::Str),
+    optional("application_baud_rate", POSITIVE),
+    optional("pump_baud_rate", POSITIVE),
+    optional("router_baud_rate", POSITIVE),
+    optional("pump_protocol", Kind::Choice(&["dt", "oem"])),
+    optional("application_flow_control", Kind::Choice(&["none", "software", "hardware"])),
+    required("constant_cleaning", Kind::Bool),
+    optional("waste_capacity_ul", POSITIVE_VOLUME),
+    optional("over_range_policy", Kind::Choice(&["reject", "clamp", "split"])),
+    optional("wash_between_cycles", Kind::Bool),
+    optional("dual_pump_wash", Kind::Bool),
+    optional("drain_overdraw_ul", VOLUME),
+    optional("legacy_unitless_volumes", Kind::Bool),
+    optional("sensitive_metadata", Kind::List(&Kind::Str)),
+    optional("metadata_redaction", Kind::Choice(&["mask", "hash"])),
+    optional("metadata_hash_salt", Kind::Str),
+    optional("required_metadata", Kind::List(&Kind::Str)),
+    optional("framing_failure_threshold", POSITIVE),
+    optional("application_queue_capacity", POSITIVE),
+    optional("inter_run_washes", COUNT),
+    optional("manifest_timeout_secs", POSITIVE),
+    optional("console_socket_path", Kind::Str),
+    optional("run_history_path", Kind::Str),
+    optional("journal_path", Kind::Str),
+    optional("wear_counters_path", Kind::Str),
+    optional("outbox_path", Kind::Str),
+    optional("outbox_capacity", COUNT),
+    optional("calibration_path", Kind::Str),
+    optional("reservoir_levels_path", Kind::Str),
+    optional("reagent_expiry_path", Kind::Str),
+    optional("tenant_metadata_key", Kind::Str),
+    optional("log_level", LOG_LEVEL),
+    optional("log-levels", Kind::Map(&LOG_LEVEL)),
+    optional("wait_progress_interval_secs", POSITIVE),
+    optional("framing", Kind::Table(&[
+        optional("protocol_version", Kind::Int { min: 1, max: 2 }),
+        optional("crc", Kind::Choice(&["crc32", "crc16"])),
+    ])),
+    optional("message-limits", Kind::Table(&[
+        optional("max_frame_bytes", POSITIVE),
+        optional("max_commands", POSITIVE),
+        optional("frames_per_sec", Kind::Float { min: 0.0 }),
+        optional("burst", POSITIVE),
+    ])),
+    optional("handshake", Kind::Table(&[
+        optional("required", Kind::Bool),
+    ])),
+    optional("serial-write", Kind::Table(&[
+        optional("chunk_size", POSITIVE),
+        optional("application_timeout_ms", POSITIVE),
+        optional("pump_timeout_ms", POSITIVE),
+        optional("router_timeout_ms", POSITIVE),
+    ])),
+    optional("idle-maintenance", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("idle_minutes", POSITIVE),
+        optional("routines", Kind::List(&Kind::Choice(&["pump_stroke", "needle_rinse", "park"]))),
+        optional("park_position", Kind::Coordinates),
+    ])),
+    optional("feedrates", Kind::Table(&[
+        optional("travel_mm_per_min", Kind::Float { min: 1.0 }),
+        optional("plunge_mm_per_min", Kind::Float { min: 1.0 }),
+    ])),
+    optional("http", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("bind", Kind::Str),
+        optional("operator_token", Kind::Str),
+        optional("observer_token", Kind::Str),
+    ])),
+    optional("end-of-run", Kind::Table(&[
+        optional("drain_slot", Kind::Bool),
+        optional("final_wash", Kind::Bool),
+        optional("park_position", Kind::Coordinates),
+        optional("zero_pumps", Kind::Bool),
+        optional("thermal_off", Kind::Bool),
+    ])),
+    optional("devices", Kind::Table(&[
+        optional("thermal", Kind::Table(DEVICE)),
+        optional("barcode", Kind::Table(DEVICE)),
+        optional("balance", Kind::Table(DEVICE)),
+        optional("shaker", Kind::Table(DEVICE)),
+    ])),
+    optional("peripherals", Kind::Tables(&[
+        required("name", Kind::Str),
+        optional("kind", Kind::Choice(&["serial"])),
+        required("prefix", Kind::Str),
+        required("port_path", Kind::Str),
+        optional("baud_rate", POSITIVE),
+        optional("optional", Kind::Bool),
+        optional("init", Kind::Str),
+        optional("shutdown", Kind::Str),
+        optional("status_query", Kind::Str),
+        optional("reply", Kind::Str),
+        optional("reply_timeout_ms", POSITIVE),
+        optional("commands", Kind::Map(&Kind::Str)),
+    ])),
+    optional("startup", Kind::Table(&[
+        optional("position_query", Kind::Str),
+        optional("homed_query", Kind::Str),
+        optional("homed_reply", Kind::Str),
+        optional("query_timeout_ms", POSITIVE),
+        optional("pump_waste_port", VALVE_PORT),
+        optional("confirm_when_unsure", Kind::Bool),
+        optional("pump_init_timeout_secs", POSITIVE),
+    ])),
+    optional("router-halt", Kind::Table(&[
+        optional("hold_command", Kind::Str),
+        optional("reply_timeout_ms", POSITIVE),
+        optional("home_timeout_secs", POSITIVE),
+    ])),
+    optional("router-echo", Kind::Table(&[
+        optional("mode", Kind::Choice(&["off", "skip", "verify"])),
+        optional("prefix", Kind::Str),
+        optional("max_resends", COUNT),
+    ])),
+    optional("pump-runtime", Kind::Table(&[
+        optional("max_secs", POSITIVE),
+    ])),
+    optional("polling", Kind::Table(&[
+        optional("pump_busy_interval_ms", POSITIVE),
+        optional("pump_ready_interval_ms", POSITIVE),
+        optional("jitter_ms", COUNT),
+    ])),
+    optional("watchdog", Kind::Table(&[
+        optional("systemd", Kind::Bool),
+        optional("stall_secs", POSITIVE),
+        optional("port_path", Kind::Str),
+        optional("baud_rate", POSITIVE),
+        optional("pet", Kind::Str),
+        optional("interval_ms", POSITIVE),
+    ])),
+    optional("firmware", Kind::Table(&[
+        optional("router_query", Kind::Str),
+        optional("reply_timeout_ms", POSITIVE),
+        optional("min_router_version", Kind::Str),
+        optional("min_pump_version", Kind::Str),
+    ])),
+    optional("notifications", Kind::Table(&[
+        optional("events", Kind::List(&Kind::Choice(&["completion", "fault", "estop", "reservoir", "temperature"]))),
+        optional("log_lines", COUNT),
+        optional("webhooks", Kind::Tables(&[
+            required("kind", Kind::Choice(&["slack", "http"])),
+            required("url", Kind::Str),
+        ])),
+        optional("email", Kind::Table(&[
+            required("smtp_server", Kind::Str),
+            required("from", Kind::Str),
+            required("to", Kind::List(&Kind::Str)),
+        ])),
+    ])),
+    optional("router-selftest", Kind::Table(&[
+        optional("query", Kind::Str),
+        optional("expected_reply", Kind::Str),
+        optional("duration_secs", POSITIVE),
+        optional("reply_timeout_ms", POSITIVE),
+    ])),
+    optional("tube-detection", Kind::Table(&[
+        optional("method", Kind::Choice(&["none", "sensor", "pressure"])),
+        optional("hover_mm", Kind::Float { min: 0.0 }),
+        optional("sensor_query", Kind::Str),
+        optional("sensor_present_reply", Kind::Str),
+        optional("probe_ul", POSITIVE_VOLUME),
+        optional("pressure_query", Kind::Str),
+        optional("min_pressure", COUNT),
+    ])),
+    optional("probing", Kind::Table(&[
+        optional("command", Kind::Str),
+        optional("feedrate_mm_per_min", Kind::Float { min: 1.0 }),
+        optional("search_mm", Kind::Float { min: 0.0 }),
+        optional("timeout_secs", POSITIVE),
+        optional("slot", Kind::Coordinates),
+        optional("tube_top_mm", Kind::Float { min: 0.0 }),
+    ])),
+    optional("clog-detection", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("load_query", Kind::Str),
+        optional("max_load", POSITIVE),
+        optional("min_stroke_units", COUNT),
+        optional("poll_interval_ms", POSITIVE),
+        optional("unclog_attempts", COUNT),
+        optional("reverse_units", COUNT),
+        optional("purge_port", VALVE_PORT),
+    ])),
+    optional("pump-resolution", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("fine_below_ul", POSITIVE_VOLUME),
+        optional("fine_mode", Kind::Int { min: 1, max: 2 }),
+        optional("fine_scale", Kind::Int { min: 2, max: 64 }),
+        optional("mode_query", Kind::Str),
+    ])),
+    optional("oem-commands", Kind::Table(&[
+        optional("initialize", Kind::Str),
+        optional("valve_in", Kind::Str),
+        optional("valve_out", Kind::Str),
+        optional("move_to", Kind::Str),
+        optional("pick_up", Kind::Str),
+        optional("dispense", Kind::Str),
+        optional("loop_start", Kind::Str),
+        optional("loop_end", Kind::Str),
+        optional("speed", Kind::Str),
+        optional("resolution", Kind::Str),
+        optional("execute", Kind::Str),
+    ])),
+    optional("tips", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("rack", Kind::Str),
+        optional("eject_position", Kind::Coordinates),
+        optional("eject_command", Kind::Str),
+        optional("press_depth_mm", Kind::Float { min: 0.0 }),
+        optional("max_uses", COUNT),
+        optional("change_on_contamination", Kind::Bool),
+    ])),
+    optional("wash-station", Kind::Table(&[
+        optional("position", Kind::Coordinates),
+        optional("flush_ul", POSITIVE_VOLUME),
+        optional("oscillation_mm", Kind::Float { min: 0.0 }),
+        optional("oscillations", COUNT),
+        optional("air_strokes", POSITIVE),
+        optional("on_reagent_change", Kind::Bool),
+    ])),
+    optional("run-store", Kind::Table(&[
+        optional("backend", Kind::Choice(&["file", "sqlite", "http"])),
+        optional("sqlite_path", Kind::Str),
+        optional("url", Kind::Str),
+    ])),
+    optional("preflight", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("min_free_mb", COUNT),
+    ])),
+    optional("rollback", Kind::Table(&[
+        optional("return_to_source", Kind::Bool),
+    ])),
+    optional("barcode-scan", Kind::Table(&[
+        optional("trigger", Kind::Str),
+        optional("timeout_ms", POSITIVE),
+        optional("scanner_offset", Kind::Coordinates),
+    ])),
+    optional("shaker", Kind::Table(&[
+        optional("start_command", Kind::Str),
+        optional("stop_command", Kind::Str),
+        optional("reply", Kind::Str),
+        optional("reply_timeout_ms", POSITIVE),
+        optional("max_rpm", POSITIVE),
+        optional("spin_down_ms", COUNT),
+    ])),
+    optional("tube-mixing", Kind::Table(&[
+        optional("aspirate_speed", POSITIVE),
+        optional("dispense_speed", POSITIVE),
+        optional("default_speed", POSITIVE),
+        optional("max_cycles", POSITIVE),
+    ])),
+    optional("run-report", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("directory", Kind::Str),
+        optional("pdf_command", Kind::Str),
+    ])),
+    optional("simulation-timeline", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("directory", Kind::Str),
+        optional("formats", Kind::List(&Kind::Choice(&["json", "csv", "html"]))),
+        optional("pump_speed", POSITIVE),
+        optional("valve_move_ms", COUNT),
+    ])),
+    optional("pump-poll-log", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("interval_secs", POSITIVE),
+    ])),
+    optional("sensor-log", Kind::Table(&[
+        optional("enabled", Kind::Bool),
+        optional("interval_ms", POSITIVE),
+        optional("sensors", Kind::List(&Kind::Str)),
+    ])),
+    optional("wear-limits", Kind::Table(&[
+        optional("pump_strokes", COUNT),
+        optional("valve_actuations", COUNT),
+        optional("router_travel_m", COUNT),
+        optional("tips_used", COUNT),
+    ])),
+    optional("event-log", Kind::Table(&[
+        optional("file", Kind::Str),
+        optional("socket", Kind::Str),
+    ])),
+    optional("tracing", Kind::Table(&[
+        optional("file", Kind::Str),
+        optional("otlp_endpoint", Kind::Str),
+        optional("service_name", Kind::Str),
+    ])),
+    required("tube-holder-coordinates", Kind::Map(&Kind::Position)),
+    optional("racks", Kind::Tables(&[
+        required("name", Kind::Str),
+        required("origin", Kind::Coordinates),
+        required("pitch", Kind::Float { min: 0.0 }),
+        optional("row_pitch", Kind::Float { min: 0.0 }),
+        required("rows", POSITIVE),
+        required("cols", POSITIVE),
+        optional("orientation", NUMBER),
+    ])),
+    optional("tube-volumes", Kind::Map(&VOLUME)),
+    optional("reagent-expiry", Kind::Table(&[
+        optional("block", Kind::Bool),
+        optional("minutes", Kind::Map(&POSITIVE)),
+    ])),
+    optional("reagent-classes", Kind::Map(&Kind::Str)),
+    optional("slot-recovery", Kind::Map(&Kind::Str)),
+    optional("external-sources", Kind::Map(&VALVE_PORT)),
+    optional("reservoirs", Kind::Map(&Kind::Table(&[
+        required("channel", VALVE_PORT),
+        required("capacity_ul", POSITIVE_VOLUME),
+        optional("warn_below_ul", VOLUME),
+    ]))),
+    optional("flow-cell", Kind::Table(&[
+        optional("position", Kind::Coordinates),
+        optional("wells", Kind::Map(&Kind::Table(&[
+            required("offset", Kind::Coordinates),
+            optional("drain_port", VALVE_PORT),
+        ]))),
+    ])),
+    optional("contamination-rules", Kind::Tables(&[
+        required("before", Kind::Str),
+        required("after", Kind::Str),
+        required("action", Kind::Choice(&["wash", "reject"])),
+    ])),
+    optional("temperature-hold", Kind::Table(&[
+        optional("audit_interval_secs", COUNT),
+        optional("tolerance_celsius", Kind::Float { min: 0.0 }),
+        optional("settle_secs", COUNT),
+        optional("pause_after", POSITIVE),
+    ])),
+    optional("thermal-zones", Kind::Tables(&[
+        required("name", Kind::Str),
+        required("driver", Kind::Choice(&["router", "thermal"])),
+        required("heater", COUNT),
+        optional("set_command", Kind::Str),
+        optional("pid", Kind::Table(&[
+            required("p", NUMBER),
+            required("i", NUMBER),
+            required("d", NUMBER),
+        ])),
+        optional("pid_command", Kind::Str),
+        required("min_celsius", NUMBER),
+        required("max_celsius", NUMBER),
+        optional("software", Kind::Table(&[
+            optional("poll_interval_ms", POSITIVE),
+            optional("sensor_command", Kind::Str),
+            optional("sensor_prefix", Kind::Str),
+            optional("output_command", Kind::Str),
+            optional("output_min", NUMBER),
+            optional("output_max", NUMBER),
+            optional("reply_timeout_ms", POSITIVE),
+            optional("tune_cycles", POSITIVE),
+            optional("tune_hysteresis", Kind::Float { min: 0.0 }),
+            optional("tune_timeout_secs", POSITIVE),
+        ])),
+    ])),
+    optional("error-hints", Kind::Tables(&[
+        optional("device", Kind::Str),
+        optional("contains", Kind::Str),
+        required("code", Kind::Str),
+        optional("description", Kind::Str),
+    ])),
+    // Which bounds apply depends on the shape; missing ones are reported when deserializing
+    optional("keep-out-zones", Kind::Tables(&[
+        required("name", Kind::Str),
+        required("shape", Kind::Choice(&["rectangle", "cylinder"])),
+        optional("x_min", NUMBER),
+        optional("x_max", NUMBER),
+        optional("y_min", NUMBER),
+        optional("y_max", NUMBER),
+        optional("x", NUMBER),
+        optional("y", NUMBER),
+        optional("radius", Kind::Float { min: 0.0 }),
+        required("z_min", NUMBER),
+        required("z_max", NUMBER),
+    ])),
+];
+
+type Problems = Vec<(Option<usize>, String)>;
+
+// Every problem in `config` in file order, prefixed with its line in `text` where it can be found there.
+// [[instances]] entries are checked like the top level, except that nothing is required in them.
+pub fn validate(text: &str, config: &Value) -> Vec<String> {
+    let lines = locate_lines(text);
+    let mut problems = Problems::new();
+    let Some(table) = config.as_table() else {
+        return vec!["configuration is not a table".to_string()];
+    };
+    let mut base = table.clone();
+    let instances = base.remove("instances");
+    check_table(ROOT, &base, "", true, &lines, &mut problems);
+    match &instances {
+        Some(Value::Array(instances)) => {
+            for (i, instance) in instances.iter().enumerate() {
+                let path = format!("instances[{i}]");
+                match instance.as_table() {
+                    Some(instance) => check_table(ROOT, instance, &path, false, &lines, &mut problems),
+                    None => report(&mut problems, &lines, &path, "instances entries must be tables".to_string()),
+                }
+            }
+        }
+        Some(_) => report(&mut problems, &lines, "instances", "instances must be an array of tables".to_string()),
+        None => {}
+    }
+    problems.sort_by_key(|(line, _)| *line);
+    problems.into_iter()
+        .map(|(line, problem)| match line {
+            Some(line) => format!("line {line}: {problem}"),
+            None => problem,
+        })
+        .collect()
+}
+
+fn check_table(fields: &[Field], table: &toml::map::Map<String, Value>, path: &str, enforce_required: bool, lines: &HashMap<String, usize>,
+               problems: &mut Problems) {
+    for field in fields.iter().filter(|f| enforce_required && f.required && !table.contains_key(f.key)) {
+        let table_name = if path.is_empty() { "the top level".to_string() } else { format!("[{path}]") };
+        report(problems, lines, path, format!("{} is missing required key {}", table_name, field.key));
+    }
+    for (key, value) in table {
+        let key_path = join(path, key);
+        match fields.iter().find(|f| f.key == key) {
+            Some(field) => check_value(field.kind, value, &key_path, enforce_required, lines, problems),
+            None => {
+                let normalize = |key: &str| key.replace(['-', '_'], "").to_lowercase();
+                let problem = match fields.iter().find(|f| normalize(f.key) == normalize(key)) {
+                    Some(field) => format!("unknown key {key_path}, did you mean {}?", field.key),
+                    None => format!("unknown key {key_path}"),
+                };
+                report(problems, lines, &key_path, problem);
+            }
+        }
+    }
+}
+
+fn check_value(kind: Kind, value: &Value, path: &str, enforce_required: bool, lines: &HashMap<String, usize>, problems: &mut Problems) {
+    let name = path;
+    let problem = match (kind, value) {
+        (Kind::Str, Value::String(_)) | (Kind::Bool, Value::Boolean(_)) => None,
+        (Kind::Int { min, max }, Value::Integer(n)) if *n < min || *n > max => {
+            Some(if max == i64::MAX { format!("{name} must be at least {min}, got {n}") } else { format!("{name} must be between {min} and {max}, got {n}") })
+        }
+        (Kind::Int { .. }, Value::Integer(_)) => None,
+        (Kind::Float { min }, Value::Float(n)) if *n < min => Some(format!("{name} must be at least {min}, got {n}")),
+        (Kind::Float { min }, Value::Integer(n)) if (*n as f64) < min => Some(format!("{name} must be at least {min}, got {n}")),
+        (Kind::Float { .. }, Value::Float(_) | Value::Integer(_)) => None,
+        (Kind::Volume { min }, Value::Integer(n)) if *n < min as i64 => Some(format!("{name} must be at least {min} ul, got {n}")),
+        (Kind::Volume { .. }, Value::Integer(_)) => None,
+        (Kind::Volume { min }, Value::String(s)) => match units::parse_volume_with_unit(s) {
+            Ok(volume) if volume.0 < min => Some(format!("{name} must be at least {min} ul, got {s}")),
+            Ok(_) => None,
+            Err(e) => Some(format!("{name}: {e}")),
+        },
+        (Kind::Coordinates | Kind::Position, Value::Table(table)) => {
+            // Axes are required wherever coordinates are given, [[instances]] included
+            check_table(COORDINATES, table, path, true, lines, problems);
+            None
+        }
+        (Kind::Coordinates | Kind::Position, Value::String(s)) if s.contains(':') => {
+            Some(format!("{name}: coordinates are written as {{ x = .., y = .., z = .. }} since config_version {}, got \"{s}\"",
+                         migration::CURRENT_VERSION))
+        }
+        (Kind::Position, Value::String(_)) => None,
+        (Kind::Choice(choices), Value::String(s)) if !choices.contains(&s.as_str()) => {
+            Some(format!("{name} must be one of {}, got \"{s}\"", choices.join(", ")))
+        }
+        (Kind::Choice(_), Value::String(_)) => None,
+        (Kind::List(item), Value::Array(items)) => {
+            for (i, element) in items.iter().enumerate() {
+                // Array elements share the line of the key
+                check_value(*item, element, &format!("{path}[{i}]"), enforce_required, lines, problems);
+            }
+            None
+        }
+        (Kind::Table(fields), Value::Table(table)) => {
+            check_table(fields, table, path, enforce_required, lines, problems);
+            None
+        }
+        (Kind::Tables(fields), Value::Array(tables)) => {
+            for (i, table) in tables.iter().enumerate() {
+                let table_path = format!("{path}[{i}]");
+                match table.as_table() {
+                    Some(table) => check_table(fields, table, &table_path, enforce_required, lines, problems),
+                    None => report(problems, lines, &table_path, format!("{name} entries must be tables")),
+                }
+            }
+            None
+        }
+        (Kind::Map(item), Value::Table(table)) => {
+            for (key, value) in table {
+                check_value(*item, value, &join(path, key), enforce_required, lines, problems);
+            }
+            None
+        }
+        (kind, value) => Some(format!("{name} must be {}, got {}", expected(kind), value.type_str())),
+    };
+    if let Some(problem) = problem {
+        report(problems, lines, path, problem);
+    }
+}
+
+fn expected(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Str | Kind::Choice(_) => "a string",
+        Kind::Bool => "true or false",
+        Kind::Int { .. } => "an integer",
+        Kind::Float { .. } => "a number",
+        Kind::Volume { .. } => "microliters or a volume with a unit",
+        Kind::Coordinates => "an { x, y, z } table",
+        Kind::Position => "an { x, y, z } table or a label",
+        Kind::List(_) => "an array",
+        Kind::Table(_) | Kind::Map(_) => "a table",
+        Kind::Tables(_) => "an array of tables",
+    }
+}
+
+fn report(problems: &mut Problems, lines: &HashMap<String, usize>, path: &str, problem: String) {
+    // Array elements and keys missing from a table are reported at the closest line known
+    let mut lookup = path;
+    loop {
+        if let Some(line) = lines.get(lookup) {
+            problems.push((Some(*line), problem));
+            return;
+        }
+        let parent = match lookup.strip_suffix(']') {
+            Some(indexed) => indexed.rsplit_once('[').map(|(parent, _)| parent),
+            None => lookup.rsplit_once('.').map(|(parent, _)| parent),
+        };
+        match parent {
+            Some(parent) => lookup = parent,
+            None => break,
+        }
+    }
+    // Set by an RC_* variable or --set flag, or a table only implied by its subtables
+    problems.push((None, problem));
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{path}.{key}") }
+}
+
+// Line numbers of table headers and keys, by dotted path with array-of-tables indices, e.g.
+// "racks[1]" for the second [[racks]] and "racks[1].origin" for its origin key
+fn locate_lines(text: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    let mut array_counts: HashMap<String, usize> = HashMap::new();
+    let mut table = String::new();
+    for (number, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix("[[").and_then(|l| l.split_once("]]")).map(|(h, _)| h) {
+            let keys = split_key(header);
+            let Some((last, parents)) = keys.split_last() else {
+                continue;
+            };
+            let path = join(&resolve(parents, &array_counts), last);
+            let count = array_counts.entry(keys.join(".")).or_default();
+            table = format!("{path}[{count}]");
+            *count += 1;
+            lines.insert(table.clone(), number);
+        } else if let Some(header) = line.strip_prefix('[').and_then(|l| l.split_once(']')).map(|(h, _)| h) {
+            table = resolve(&split_key(header), &array_counts);
+            lines.entry(table.clone()).or_insert(number);
+        } else if let Some((key, _)) = line.split_once('=') {
+            lines.entry(join(&table, &split_key(key).join("."))).or_insert(number);
+        }
+    }
+    lines
+}
+
+// Inserts the index of the latest entry after every array of tables along the path
+fn resolve(keys: &[String], array_counts: &HashMap<String, usize>) -> String {
+    let mut path = String::new();
+    for (i, key) in keys.iter().enumerate() {
+        path = join(&path, key);
+        if let Some(count) = array_counts.get(&keys[..=i].join(".")) {
+            path = format!("{path}[{}]", count.saturating_sub(1));
+        }
+    }
+    path
+}
+
+fn split_key(key: &str) -> Vec<String> {
+    key.split('.').map(|k| k.trim().trim_matches('"').to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(text: &str) -> Vec<String> {
+        validate(text, &text.parse::<Value>().unwrap())
+    }
+
+    #[test]
+    fn shipped_config_is_valid() {
+        let text = include_str!("../config.toml");
+        assert_eq!(problems(text), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_problems_with_their_line() {
+        let text = include_str!("../config.toml").replacen("framing_failure_threshold = 3", "framing_failure_threshold = \"3\"", 1);
+        let line = text.lines().position(|l| l.starts_with("framing_failure_threshold")).unwrap() + 1;
+        let found = problems(&text);
+        assert_eq!(found.len(), 1, "{found:?}");
+        assert!(found[0].starts_with(&format!("line {line}: ")), "{found:?}");
+    }
+
+    #[test]
+    fn suggests_keys_spelled_with_dashes_in_instances() {
+        let text = format!("{}\n[[instances]]\ninstance_name = \"b\"\njournal-path = \"./b.toml\"\n", include_str!("../config.toml"));
+        let found = problems(&text);
+        assert_eq!(found.len(), 1, "{found:?}");
+        assert!(found[0].ends_with("unknown key instances[0].journal-path, did you mean journal_path?"), "{found:?}");
+    }
+}
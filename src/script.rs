@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+
+use crate::Controller;
+
+// Control flow inside a command message, so washes and conditional drains need not be unrolled upstream:
+//
+//   REPEAT_3 LA_35_1_500 ENDREPEAT                   unrolled when the message arrives
+//   IF_slot_occupied LA_36_1_0 ELSE W_1000 ENDIF     decided when the IF is reached
+//
+// Blocks nest. A condition is slot_occupied or slot_empty, optionally negated as NOT_<condition>.
+// Decisions are kept in the journal, so `--resume` takes the branches the interrupted run took.
+const REPEAT: &str = "REPEAT_";
+const END_REPEAT: &str = "ENDREPEAT";
+const IF: &str = "IF_";
+const ELSE: &str = "ELSE";
+const END_IF: &str = "ENDIF";
+const MAX_REPEAT: u32 = 1000;
+
+pub fn is_branch(command: &str) -> bool {
+    command.starts_with(IF) || command == ELSE || command == END_IF
+}
+
+// Commands of a REPEAT block, or of the whole message, as they are read
+#[derive(Default)]
+struct Block {
+    count: u32,
+    commands: Vec<String>,
+    // Whether each IF opened in this block has had its ELSE yet
+    open_ifs: Vec<bool>,
+}
+
+// Unrolls REPEAT blocks and checks that IF blocks are closed, within the same REPEAT, and name known conditions
+pub fn expand(commands: &[&str]) -> Result<Vec<String>, String> {
+    let mut blocks = vec![Block { count: 1, ..Block::default() }];
+    for command in commands {
+        let block = blocks.last_mut().expect("the message is the outermost block");
+        if let Some(count) = command.strip_prefix(REPEAT) {
+            let count = count.parse::<u32>().ok().filter(|n| (1..=MAX_REPEAT).contains(n))
+                .ok_or(format!("{command}: the count must be between 1 and {MAX_REPEAT}"))?;
+            blocks.push(Block { count, ..Block::default() });
+            continue;
+        }
+        if *command == END_REPEAT {
+            if !block.open_ifs.is_empty() {
+                return Err(format!("{IF}<condition> without {END_IF} before {END_REPEAT}"));
+            }
+            let repeated = blocks.pop().expect("the message is the outermost block");
+            let Some(outer) = blocks.last_mut() else {
+                return Err(format!("{END_REPEAT} without {REPEAT}<n>"));
+            };
+            (0..repeated.count).for_each(|_| outer.commands.extend(repeated.commands.iter().cloned()));
+            continue;
+        }
+        if let Some(condition) = command.strip_prefix(IF) {
+            condition_known(condition)?;
+            block.open_ifs.push(false);
+        } else if *command == ELSE {
+            match block.open_ifs.last_mut() {
+                Some(seen_else) if !*seen_else => *seen_else = true,
+                Some(_) => return Err(format!("Second {ELSE} in one {IF}<condition> block")),
+                None => return Err(format!("{ELSE} without {IF}<condition>")),
+            }
+        } else if *command == END_IF && block.open_ifs.pop().is_none() {
+            return Err(format!("{END_IF} without {IF}<condition>"));
+        }
+        block.commands.push(command.to_string());
+    }
+    let message = blocks.pop().expect("the message is the outermost block");
+    if !blocks.is_empty() {
+        return Err(format!("{REPEAT}<n> without {END_REPEAT}"));
+    }
+    if !message.open_ifs.is_empty() {
+        return Err(format!("{IF}<condition> without {END_IF}"));
+    }
+    Ok(message.commands)
+}
+
+fn condition_known(condition: &str) -> Result<(), String> {
+    match condition.strip_prefix("NOT_").unwrap_or(condition) {
+        "slot_occupied" | "slot_empty" => Ok(()),
+        other => Err(format!("Unknown condition {other}, expected slot_occupied or slot_empty")),
+    }
+}
+
+fn holds(controller: &Controller, condition: &str) -> bool {
+    match condition.strip_prefix("NOT_") {
+        Some(negated) => !holds(controller, negated),
+        None if condition == "slot_occupied" => controller.slot_occupancy.0 > 0,
+        None => controller.slot_occupancy.0 == 0,
+    }
+}
+
+// Open IF blocks of the executing message, innermost last, with whether commands in them run
+#[derive(Default)]
+pub struct Branches {
+    taken: Vec<bool>,
+    // Decisions made before a restart, replayed instead of evaluating the conditions again
+    replayed: VecDeque<bool>,
+}
+
+impl Branches {
+    pub fn replaying(decisions: &[bool]) -> Branches {
+        Branches { taken: Vec::new(), replayed: decisions.iter().copied().collect() }
+    }
+
+    pub fn skipping(&self) -> bool {
+        self.taken.contains(&false)
+    }
+
+    // `command` is an IF, ELSE or ENDIF of a message that went through expand(). Returns the
+    // decision when a condition was evaluated, for the journal.
+    pub fn enter(&mut self, controller: &Controller, command: &str) -> Option<bool> {
+        let mut evaluated = None;
+        if let Some(condition) = command.strip_prefix(IF) {
+            // Inside a skipped block the condition doesn't matter and isn't evaluated
+            let taken = !self.skipping() && match self.replayed.pop_front() {
+                Some(taken) => {
+                    log::info!("Condition {} was {} before the restart", condition, taken);
+                    taken
+                }
+                None => {
+                    let holds = holds(controller, condition);
+                    log::info!("Condition {} is {}", condition, holds);
+                    evaluated = Some(holds);
+                    holds
+                }
+            };
+            self.taken.push(taken);
+        } else if command == ELSE {
+            let outer_skipping = self.taken[..self.taken.len().saturating_sub(1)].contains(&false);
+            if let Some(taken) = self.taken.last_mut() {
+                *taken = !*taken && !outer_skipping;
+            }
+        } else {
+            self.taken.pop();
+        }
+        evaluated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(message: &str) -> Result<String, String> {
+        expand(&message.split(' ').collect::<Vec<&str>>()).map(|commands| commands.join(" "))
+    }
+
+    #[test]
+    fn unrolls_nested_repeats() {
+        assert_eq!(expanded("REPEAT_2 LA_35_1_500ul REPEAT_2 W_10 ENDREPEAT ENDREPEAT").unwrap(),
+            "LA_35_1_500ul W_10 W_10 LA_35_1_500ul W_10 W_10");
+    }
+
+    #[test]
+    fn keeps_if_blocks_for_the_executor() {
+        assert_eq!(expanded("REPEAT_2 IF_NOT_slot_empty W_1 ELSE W_2 ENDIF ENDREPEAT").unwrap(),
+            "IF_NOT_slot_empty W_1 ELSE W_2 ENDIF IF_NOT_slot_empty W_1 ELSE W_2 ENDIF");
+    }
+
+    #[test]
+    fn refuses_unbalanced_blocks() {
+        assert!(expanded("REPEAT_2 W_1").is_err());
+        assert!(expanded("W_1 ENDREPEAT").is_err());
+        assert!(expanded("REPEAT_0 W_1 ENDREPEAT").is_err());
+        assert!(expanded("IF_slot_occupied W_1").is_err());
+        assert!(expanded("IF_slot_occupied W_1 ELSE W_2 ELSE W_3 ENDIF").is_err());
+        assert!(expanded("REPEAT_2 IF_slot_occupied W_1 ENDREPEAT ENDIF").is_err());
+        assert!(expanded("IF_slot_wet W_1 ENDIF").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::CONFIG;
+use crate::notifications::json_string;
+use crate::{thermal, websocket, Controller};
+
+// WebSocket clients of GET /sensors, fed with every sample
+pub type Feed = Arc<Mutex<Vec<TcpStream>>>;
+
+// Readings of [sensor-log] sensors, sampled every interval_ms while a run executes and written to
+// a CSV time series in the directory of the run journal
+pub struct SensorLog {
+    file: Option<File>,
+    started: Instant,
+    next_sample: Option<Instant>,
+    latest: Vec<(String, String)>,
+    feed: Feed,
+}
+
+impl SensorLog {
+    pub fn new(feed: Feed) -> SensorLog {
+        SensorLog { file: None, started: Instant::now(), next_sample: None, latest: Vec::new(), feed }
+    }
+
+    pub fn start_run(&mut self, run_id: Option<&str>) {
+        let settings = &CONFIG.sensor_log;
+        if !settings.enabled || settings.sensors.is_empty() {
+            return;
+        }
+        for sensor in settings.sensors.iter().filter(|s| *s != "pressure" && thermal::zone(s).is_none()) {
+            log::warn!("Unknown sensor {} in [sensor-log], leaving its column empty", sensor);
+        }
+        let wall_secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let name = match run_id.map(|id| id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")) {
+            Some(id) => format!("sensors-{wall_secs}-{id}.csv"),
+            None => format!("sensors-{wall_secs}.csv"),
+        };
+        let path = Path::new(&CONFIG.journal_path).parent().unwrap_or(Path::new(".")).join(name);
+        let file = File::create(&path).and_then(|mut file| {
+            writeln!(file, "wall_ms,run_ms,{}", settings.sensors.join(","))?;
+            Ok(file)
+        });
+        match file {
+            Ok(file) => {
+                log::info!("Logging sensors to {}", path.display());
+                self.file = Some(file);
+            }
+            Err(e) => log::error!("Failed to create sensor log {}: {}", path.display(), e),
+        }
+        self.started = Instant::now();
+        self.next_sample = Some(Instant::now());
+    }
+
+    pub fn end_run(&mut self) {
+        self.file = None;
+        self.next_sample = None;
+    }
+
+    // Latest reading of every sensor, e.g. "reagent=36.98 pressure=1204"
+    pub fn describe(&self) -> String {
+        self.latest.iter().map(|(sensor, value)| format!("{sensor}={value}")).collect::<Vec<_>>().join(" ")
+    }
+
+    fn record(&mut self, readings: Vec<(String, String)>) {
+        let wall_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let run_ms = self.started.elapsed().as_millis();
+        if let Some(file) = self.file.as_mut() {
+            let values: Vec<&str> = readings.iter().map(|(_, value)| value.as_str()).collect();
+            if let Err(e) = writeln!(file, "{wall_ms},{run_ms},{}", values.join(",")) {
+                log::error!("Failed to write sensor log: {}", e);
+                self.file = None;
+            }
+        }
+        let fields: String = readings.iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(sensor, value)| match value.parse::<f64>() {
+                Ok(number) if number.is_finite() => format!(",{}:{value}", json_string(sensor)),
+                _ => format!(",{}:{}", json_string(sensor), json_string(value)),
+            })
+            .collect();
+        let frame = websocket::text_frame(&format!("{{\"wall_ms\":{wall_ms},\"run_ms\":{run_ms}{fields}}}"));
+        // Clients that went away are dropped
+        self.feed.lock().unwrap_or_else(|e| e.into_inner()).retain_mut(|client| client.write_all(&frame).is_ok());
+        self.latest = readings;
+    }
+}
+
+// Takes a sample when one is due; called between steps and while waiting, like the thermal loops
+pub fn sample(controller: &mut Controller) {
+    let now = Instant::now();
+    match controller.sensor_log.next_sample {
+        Some(due) if now >= due => {}
+        _ => return,
+    }
+    controller.sensor_log.next_sample = Some(now + Duration::from_millis(CONFIG.sensor_log.interval_ms));
+    let readings = CONFIG.sensor_log.sensors.iter()
+        .map(|sensor| (sensor.clone(), read(controller, sensor).unwrap_or_default()))
+        .collect();
+    controller.sensor_log.record(readings);
+}
+
+// "pressure" is pump 1's pressure register; any other name is a thermal zone's temperature
+fn read(controller: &mut Controller, sensor: &str) -> Option<String> {
+    if sensor == "pressure" {
+        return controller.pumps.pump(1).query_position(&CONFIG.tube_detection.pressure_query);
+    }
+    let zone = thermal::zone(sensor)?;
+    thermal::read_zone(controller, zone)
+        .map(|celsius| format!("{celsius:.2}"))
+        .map_err(|e| log::warn!("{}", e))
+        .ok()
+}
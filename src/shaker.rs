@@ -0,0 +1,62 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::devices::DeviceKind;
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::{unwrap_option, Controller};
+
+// SHAKE_<rpm>_<ms> starts orbital mixing and returns, so the following W_ and TC_ steps run while the
+// slot is shaken. The shaker is stopped once the time is up, and always before the router moves.
+pub fn start(controller: &mut Controller, command: &str) -> ControlFlow<String> {
+    let settings = &CONFIG.shaker;
+    let parsed = command.strip_prefix("SHAKE_")
+        .and_then(|args| args.split_once('_'))
+        .and_then(|(rpm, ms)| Some((rpm.parse::<u32>().ok()?, ms.parse::<u64>().ok()?)));
+    let (rpm, ms) = unwrap_option!(parsed, format!("Cannot deduce speed and duration from {command}"));
+    if rpm == 0 || rpm > settings.max_rpm {
+        return ControlFlow::Break(format!("{command}: speed must be between 1 and {} rpm", settings.max_rpm));
+    }
+    send(controller, command, &settings.start_command.replace("{rpm}", &rpm.to_string()))?;
+    log::info!("Shaking at {} rpm for {} milliseconds", rpm, ms);
+    controller.shaking_until = Some(controller.clock.now() + Duration::from_millis(ms));
+    controller.events.emit("shake_start", &[("rpm", rpm.to_string()), ("duration_ms", ms.to_string())]);
+    ControlFlow::Continue(())
+}
+
+pub fn stop(controller: &mut Controller) -> ControlFlow<String> {
+    if controller.shaking_until.is_none() {
+        return ControlFlow::Continue(());
+    }
+    send(controller, "SHAKE", &CONFIG.shaker.stop_command)?;
+    controller.shaking_until = None;
+    log::info!("Shaker stopped");
+    controller.events.emit("shake_stop", &[]);
+    // The platform has to come to rest before the needle can go near it
+    controller.clock.sleep(Duration::from_millis(CONFIG.shaker.spin_down_ms));
+    ControlFlow::Continue(())
+}
+
+pub fn stop_when_done(controller: &mut Controller) -> ControlFlow<String> {
+    match controller.shaking_until {
+        Some(until) if controller.clock.now() >= until => stop(controller),
+        _ => ControlFlow::Continue(()),
+    }
+}
+
+fn send(controller: &mut Controller, command: &str, text: &str) -> ControlFlow<String> {
+    let settings = &CONFIG.shaker;
+    let shaker = controller.devices.require(DeviceKind::Shaker, command)?;
+    flush_port(shaker);
+    if serial_write(shaker, text).is_err() {
+        return ControlFlow::Break(format!("Shaker - failed to send command: [{}]", text.trim_end()));
+    }
+    if settings.reply.is_empty() {
+        return ControlFlow::Continue(());
+    }
+    match serial_readline_timeout(shaker, "\r\n", Duration::from_millis(settings.reply_timeout_ms)) {
+        Some(reply) if reply.trim() == settings.reply => ControlFlow::Continue(()),
+        Some(reply) => ControlFlow::Break(format!("Shaker - error executing command: [{}] replied [{}]", text.trim_end(), reply.trim())),
+        None => ControlFlow::Break(format!("Shaker - no reply to command: [{}]", text.trim_end())),
+    }
+}
@@ -0,0 +1,58 @@
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::deck::Coordinates;
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::{motion, shaker, thermal, Controller};
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Leaves the instrument safe when the controller goes away, including while a panic unwinds the
+// executor: strokes are stopped, the shaker, peripherals and heater switched off and the needle raised. Every
+// step is attempted even if an earlier one fails, and none of them waits for a reply indefinitely.
+pub fn park(controller: &mut Controller) {
+    log::warn!("Shutting down, parking hardware");
+    for pump in controller.pumps.pumps() {
+        if let Err(e) = pump.terminate() {
+            log::error!("{}", e);
+        }
+    }
+    if let ControlFlow::Break(e) = shaker::stop(controller) {
+        log::error!("{}", e);
+    }
+    controller.peripherals.shutdown();
+    let position = controller.router.position;
+    if controller.router.halted.is_some() {
+        log::warn!("Router is halted, leaving the needle where it is");
+    } else if position.z != motion::SAFE_Z {
+        let raised = Coordinates { z: motion::SAFE_Z, ..position };
+        flush_port(&mut controller.router_port);
+        let sent = serial_write(&mut controller.router_port, &motion::move_gcode(position, raised));
+        match sent.ok().and_then(|_| serial_readline_timeout(&mut controller.router_port, "\r\n", REPLY_TIMEOUT)) {
+            Some(reply) if reply == "G1:OK" => log::info!("Needle raised"),
+            _ => log::error!("Failed to raise the needle"),
+        }
+    }
+    match controller.thermal_port().map(|port| serial_write(port, "M104S0")) {
+        Some(Ok(())) => log::info!("Heater switched off"),
+        _ => log::error!("Failed to switch the heater off"),
+    }
+    thermal::zones_off(controller);
+    controller.wear.save();
+    controller.reservoirs.save();
+    controller.events.emit("shutdown", &[]);
+    controller.application.send_status("SHUTDOWN");
+    controller.router_port.flush().ok();
+}
+
+// Logs panics from every thread before the default hook prints them. The controller of a panicking
+// executor thread is dropped while unwinding, which parks its hardware.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = std::thread::current();
+        log::error!("Panic in thread {}: {}", thread.name().unwrap_or("unnamed"), info);
+        default_hook(info);
+    }));
+}
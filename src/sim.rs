@@ -0,0 +1,412 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::config;
+use crate::config::{PumpDialect, RouterEcho, CONFIG};
+use crate::fault_injection::{self, Injected};
+use crate::port_operations::same_port;
+use crate::pump_protocol::OemProtocol;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimDevice {
+    Application,
+    Pump,
+    Router,
+    Barcode,
+    Shaker,
+    Thermal,
+    Peripheral,
+}
+
+// In-process stand-in for a device on a serial port, answering the way the real firmware does
+#[derive(Clone)]
+pub struct SimulatedPort {
+    name: String,
+    device: SimDevice,
+    input: Arc<Mutex<String>>,
+    output: Arc<Mutex<VecDeque<u8>>>,
+    banner: Arc<Mutex<Option<Instant>>>,
+    // Pump addresses that received an initialization command since power-on
+    initialized: Arc<Mutex<HashSet<char>>>,
+    // Resolution mode of each pump set with the N command
+    resolutions: Arc<Mutex<HashMap<char, char>>>,
+    heater: Arc<Mutex<SimHeater>>,
+    timeout: Duration,
+}
+
+// Heater output (0 to 255) warms the element, the element the sensed block, and the block loses heat
+// to the room; the two lags make the relay auto-tune oscillate like a real block
+struct SimHeater {
+    output: f64,
+    element: f64,
+    block: f64,
+    updated: Instant,
+}
+
+const AMBIENT_CELSIUS: f64 = 22.0;
+
+// Bits of the f64 simulation time scale, so temperatures change at the pace of the scaled clock
+static TIME_SCALE: AtomicU64 = AtomicU64::new(0x3FF0_0000_0000_0000);
+
+pub fn set_time_scale(scale: f64) {
+    TIME_SCALE.store(scale.to_bits(), Ordering::Relaxed);
+}
+
+impl SimHeater {
+    fn new() -> SimHeater {
+        SimHeater { output: 0.0, element: AMBIENT_CELSIUS, block: AMBIENT_CELSIUS, updated: Instant::now() }
+    }
+
+    fn advance(&mut self) {
+        let scale = f64::from_bits(TIME_SCALE.load(Ordering::Relaxed));
+        let elapsed = (self.updated.elapsed().as_secs_f64() * scale).min(3600.0);
+        self.updated = Instant::now();
+        let steps = (elapsed / 0.1).ceil().max(1.0);
+        let h = elapsed / steps;
+        for _ in 0..steps as u32 {
+            self.element += h * (self.output / 255.0 * 4.0 - (self.element - self.block) / 10.0);
+            self.block += h * ((self.element - self.block) / 30.0 - (self.block - AMBIENT_CELSIUS) / 300.0);
+        }
+    }
+
+    // M105 reads the block, M42 ..S<output> drives the element
+    fn answer(&mut self, line: &str) -> Option<String> {
+        self.advance();
+        if line.starts_with("M105") {
+            return Some(format!("T:{:.2}", self.block));
+        }
+        let args = line.strip_prefix("M42")?;
+        if let Some(output) = args.split_once('S').and_then(|(_, output)| output.parse().ok()) {
+            self.output = output;
+        }
+        Some("M42:OK".to_string())
+    }
+}
+
+// Only the output_command of a software-regulated zone drives the heater, with that zone's pin
+fn drives_heater(line: &str) -> bool {
+    CONFIG.thermal_zones.iter().filter_map(|zone| {
+        let software = zone.software.as_ref()?;
+        let command = software.output_command.replace("{heater}", &zone.heater.to_string());
+        Some(command.split("{output}").next().unwrap_or_default().trim().to_string())
+    }).any(|prefix| !prefix.is_empty() && line.starts_with(&prefix))
+}
+
+// The router prints its banner a moment after the port is opened, like the real board after reset
+const BANNER_DELAY: Duration = Duration::from_millis(50);
+
+impl SimulatedPort {
+    pub fn open(name: &str, device: SimDevice) -> Box<dyn SerialPort> {
+        let port = SimulatedPort {
+            name: name.to_string(),
+            device,
+            input: Arc::default(),
+            output: Arc::default(),
+            banner: Arc::new(Mutex::new((device == SimDevice::Router).then(|| Instant::now() + BANNER_DELAY))),
+            initialized: Arc::default(),
+            resolutions: Arc::default(),
+            heater: Arc::new(Mutex::new(SimHeater::new())),
+            timeout: Duration::from_secs(1),
+        };
+        if device == SimDevice::Application {
+            port.deliver_frames();
+        }
+        Box::new(port)
+    }
+
+    // Plays the scenario's frames to the controller as if the application sent them
+    fn deliver_frames(&self) {
+        let port = self.clone();
+        config::spawn(move || {
+            while let Some((delay, frame)) = fault_injection::next_frame() {
+                sleep(delay.div_f64(f64::from_bits(TIME_SCALE.load(Ordering::Relaxed))));
+                if let Some(bytes) = frame {
+                    port.reply(&bytes);
+                }
+            }
+        });
+    }
+
+    fn deliver_banner(&self) {
+        let mut banner = self.banner.lock().unwrap();
+        if banner.is_some_and(|at| Instant::now() >= at) {
+            *banner = None;
+            self.reply(b"start\r\n");
+        }
+    }
+
+    fn reply(&self, bytes: &[u8]) {
+        self.output.lock().unwrap().extend(bytes);
+    }
+
+    fn answer(&self, line: &str) {
+        let fault = fault_injection::inject(self.device, line);
+        match fault {
+            Some(Injected::NoReply) => {}
+            Some(Injected::Corrupt) => {
+                let start = self.output.lock().unwrap().len();
+                self.respond(line, None);
+                fault_injection::flip_middle_bit(&mut self.output.lock().unwrap().make_contiguous()[start..]);
+            }
+            fault => self.respond(line, fault),
+        }
+    }
+
+    fn respond(&self, line: &str, fault: Option<Injected>) {
+        if self.device == SimDevice::Router && CONFIG.router_echo.mode != RouterEcho::Off {
+            self.reply(format!("{}{line}\r\n", CONFIG.router_echo.prefix).as_bytes());
+        }
+        if let Some(Injected::Error(_)) = fault.filter(|_| self.device != SimDevice::Pump) {
+            // The command word, e.g. G1 of G1X10Y20
+            let end = line.char_indices().skip(1).find(|(_, c)| !c.is_ascii_digit()).map_or(line.len(), |(i, _)| i);
+            self.reply(format!("{}:ERR\r\n", &line[..end]).as_bytes());
+            return;
+        }
+        match self.device {
+            SimDevice::Application | SimDevice::Barcode => {}
+            SimDevice::Router | SimDevice::Thermal if line.starts_with("M105") || drives_heater(line) => {
+                if let Some(reply) = self.heater.lock().unwrap().answer(line) {
+                    self.reply(format!("{reply}\r\n").as_bytes());
+                }
+            }
+            SimDevice::Thermal => {}
+            SimDevice::Router => {
+                let reply = match line {
+                    l if l.starts_with("G1") => "G1:OK",
+                    l if l.starts_with("G28") => "G28:OK",
+                    // Touches down halfway into the search range, as if the holder sits where configured
+                    l if l.starts_with("G38") => {
+                        let lowest = l.split_once('Z').and_then(|(_, z)| z.split('F').next()?.trim().parse::<f64>().ok()).unwrap_or_default();
+                        self.reply(format!("PRB:0.000,0.000,{:.3}:1\r\n", lowest + CONFIG.probing.search_mm.0).as_bytes());
+                        return;
+                    }
+                    // Outputs other than a heater's, e.g. the tip ejector
+                    l if l.starts_with("M42") => "M42:OK",
+                    l if l.starts_with("M119") => "TUBE:PRESENT",
+                    l if l.starts_with("M114") => "X:0.00 Y:0.00 Z:0.00",
+                    l if l.starts_with("M115") => "FIRMWARE_NAME:SimRouter FIRMWARE_VERSION:1.0\r\nCap:TUBE_SENSOR:1\r\nok",
+                    _ => return,
+                };
+                self.reply(format!("{reply}\r\n").as_bytes());
+            }
+            SimDevice::Shaker if !CONFIG.shaker.reply.is_empty() => self.reply(format!("{}\r\n", CONFIG.shaker.reply).as_bytes()),
+            SimDevice::Shaker => {}
+            // Acknowledges commands with the configured reply and reports itself as the simulator
+            SimDevice::Peripheral => {
+                let Some(settings) = CONFIG.peripherals.iter().find(|p| same_port(&p.device.port_path, &self.name)) else {
+                    return;
+                };
+                if line == settings.status_query.trim_end() {
+                    self.reply(b"SIM\r\n");
+                } else if !settings.reply.is_empty() {
+                    self.reply(format!("{}\r\n", settings.reply).as_bytes());
+                }
+            }
+            SimDevice::Pump => {
+                let Some(query) = line.strip_prefix('/').and_then(|l| l.get(1..)) else {
+                    return;
+                };
+                let address = line.chars().nth(1).unwrap_or('1');
+                let query = match query.strip_prefix('N').and_then(|q| q.chars().next().map(|mode| (mode, &q[1..]))) {
+                    Some((mode, rest)) => {
+                        self.resolutions.lock().unwrap().insert(address, mode);
+                        rest
+                    }
+                    None => query,
+                };
+                let mode = self.resolutions.lock().unwrap().get(&address).copied().unwrap_or('0');
+                let mode_reply = format!("`{mode}");
+                let mut initialized = self.initialized.lock().unwrap();
+                if query.starts_with('Z') || query.starts_with("gZ") {
+                    initialized.insert(address);
+                }
+                // Ready, with the error code in the low bits
+                let error_reply = match fault {
+                    Some(Injected::Error(code)) => char::from(0x60 | code).to_string(),
+                    _ => String::new(),
+                };
+                let data = match (fault, query) {
+                    (Some(Injected::Busy), _) => "@",
+                    (Some(Injected::Error(_)), _) => &error_reply,
+                    (_, "Q") if !initialized.contains(&address) => "g",
+                    (_, "Q29") => "c",
+                    (_, "&") => "`SIM 1.0",
+                    (_, q) if q == CONFIG.pump_resolution.mode_query => &mode_reply,
+                    (_, q) if q.starts_with('?') => "`0",
+                    (_, _) => "`",
+                };
+                match CONFIG.pump_protocol {
+                    PumpDialect::Dt => self.reply(&[&[0xFF], format!("/0{data}").as_bytes(), &[0x03], b"\r\n"].concat()),
+                    PumpDialect::Oem => self.reply(&OemProtocol::encode('0', None, data)),
+                }
+            }
+        }
+    }
+}
+
+impl Read for SimulatedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            self.deliver_banner();
+            {
+                let mut output = self.output.lock().unwrap();
+                if !output.is_empty() {
+                    let n = buf.len().min(output.len());
+                    output.drain(..n).enumerate().for_each(|(i, b)| buf[i] = b);
+                    return Ok(n);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "simulated read timed out"));
+            }
+            sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl Write for SimulatedPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut input = self.input.lock().unwrap();
+        input.extend(buf.iter().map(|b| char::from(*b)));
+        // Any trigger makes the scanner read the same label
+        if self.device == SimDevice::Barcode {
+            input.clear();
+            self.reply(b"SIM-0001\r\n");
+            return Ok(buf.len());
+        }
+        if self.device == SimDevice::Pump && CONFIG.pump_protocol == PumpDialect::Oem {
+            // A frame is complete once the checksum after ETX has arrived
+            while let Some(end) = input.find('\u{3}').filter(|end| *end + 1 < input.len()) {
+                let frame: Vec<u8> = input.drain(..=end + 1).map(|c| c as u8).collect();
+                if let Some((address, text)) = OemProtocol::decode(&frame) {
+                    self.answer(&format!("/{address}{text}"));
+                }
+            }
+            return Ok(buf.len());
+        }
+        while let Some(end) = input.find('\n') {
+            let line: String = input.drain(..=end).collect();
+            self.answer(line.trim_end());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for SimulatedPort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(9600)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.deliver_banner();
+        Ok(self.output.lock().unwrap().len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if matches!(buffer_to_clear, ClearBuffer::Input | ClearBuffer::All) {
+            self.output.lock().unwrap().clear();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
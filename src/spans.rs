@@ -0,0 +1,185 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::sync::mpsc::{channel, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::config::CONFIG;
+use crate::notifications::{json_string, post_json};
+
+// Finished spans are handed to the exporter in batches of at most this many, and when the run ends
+const BATCH: usize = 1000;
+
+// Timed spans of a run: the run, each of its commands and each router or pump transaction of a
+// command. Nothing is recorded outside runs or without a [tracing] file or otlp_endpoint.
+pub struct Tracer {
+    sender: Option<Sender<Vec<Span>>>,
+    trace_id: String,
+    next_id: u64,
+    // Innermost last; None for spans entered while not recording
+    open: Vec<Option<OpenSpan>>,
+    finished: Vec<Span>,
+}
+
+struct OpenSpan {
+    id: String,
+    parent: Option<String>,
+    name: &'static str,
+    start_ns: u128,
+    attributes: Vec<(&'static str, String)>,
+}
+
+pub struct Span {
+    trace_id: String,
+    id: String,
+    parent: Option<String>,
+    name: &'static str,
+    start_ns: u128,
+    end_ns: u128,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+fn now_ns() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+impl Tracer {
+    pub fn open() -> Tracer {
+        let settings = &CONFIG.tracing;
+        let mut tracer = Tracer { sender: None, trace_id: String::new(), next_id: now_ns() as u64, open: Vec::new(), finished: Vec::new() };
+        if settings.file.is_none() && settings.otlp_endpoint.is_none() {
+            return tracer;
+        }
+        let (sender, batches) = channel::<Vec<Span>>();
+        config::spawn(move || {
+            let settings = &CONFIG.tracing;
+            let mut file = settings.file.as_ref().and_then(|path| {
+                OpenOptions::new().create(true).append(true).open(path)
+                    .map_err(|e| log::error!("Failed to open span log {}: {}", path, e))
+                    .ok()
+            });
+            for batch in batches.iter() {
+                if let Some(f) = file.as_mut() {
+                    if let Err(e) = batch.iter().try_for_each(|span| writeln!(f, "{}", span.json_line())) {
+                        log::error!("Failed to write span log: {}", e);
+                        file = None;
+                    }
+                }
+                if let Some(endpoint) = &settings.otlp_endpoint {
+                    if let Err(e) = post_json(endpoint, &otlp_payload(&batch)) {
+                        log::error!("Exporting {} spans to {} failed: {}", batch.len(), endpoint, e);
+                    }
+                }
+            }
+        });
+        tracer.sender = Some(sender);
+        tracer
+    }
+
+    pub fn start_run(&mut self, attributes: &[(&'static str, String)]) {
+        if self.sender.is_none() {
+            return;
+        }
+        self.trace_id = format!("{:016x}{:08x}{:08x}", now_ns() as u64, crc32fast::hash(CONFIG.instance_name.as_bytes()), self.next_id as u32);
+        let run = self.new_span("run", None, attributes);
+        self.open = vec![Some(run)];
+    }
+
+    pub fn end_run(&mut self, error: Option<&str>) {
+        while !self.open.is_empty() {
+            self.close(error.map(str::to_string));
+        }
+        self.send();
+    }
+
+    pub fn enter(&mut self, name: &'static str, attributes: &[(&'static str, String)]) {
+        let span = match self.open.last() {
+            Some(Some(parent)) => Some(self.new_span(name, Some(parent.id.clone()), attributes)),
+            _ => None,
+        };
+        self.open.push(span);
+    }
+
+    pub fn exit<T>(&mut self, result: &ControlFlow<String, T>) {
+        let error = match result {
+            ControlFlow::Break(e) => Some(e.clone()),
+            ControlFlow::Continue(_) => None,
+        };
+        self.close(error);
+        if self.finished.len() >= BATCH {
+            self.send();
+        }
+    }
+
+    fn new_span(&mut self, name: &'static str, parent: Option<String>, attributes: &[(&'static str, String)]) -> OpenSpan {
+        self.next_id = self.next_id.wrapping_add(1);
+        OpenSpan { id: format!("{:016x}", self.next_id), parent, name, start_ns: now_ns(), attributes: attributes.to_vec() }
+    }
+
+    fn close(&mut self, error: Option<String>) {
+        let Some(Some(span)) = self.open.pop() else {
+            return;
+        };
+        self.finished.push(Span {
+            trace_id: self.trace_id.clone(),
+            id: span.id,
+            parent: span.parent,
+            name: span.name,
+            start_ns: span.start_ns,
+            end_ns: now_ns(),
+            attributes: span.attributes,
+            error,
+        });
+    }
+
+    fn send(&mut self) {
+        if let Some(sender) = &self.sender {
+            if !self.finished.is_empty() {
+                sender.send(std::mem::take(&mut self.finished)).ok();
+            }
+        }
+    }
+}
+
+impl Span {
+    // {"trace_id":..,"span_id":..,"parent_span_id":..,"name":"command","start_ns":..,"duration_ms":..,<attributes>,"error":..}
+    fn json_line(&self) -> String {
+        let mut line = format!("{{\"trace_id\":{},\"span_id\":{},\"parent_span_id\":{},\"name\":{},\"start_ns\":{},\"duration_ms\":{:.3}",
+            json_string(&self.trace_id), json_string(&self.id), self.parent.as_deref().map_or("null".to_string(), json_string),
+            json_string(self.name), self.start_ns, (self.end_ns.saturating_sub(self.start_ns)) as f64 / 1e6);
+        for (key, value) in &self.attributes {
+            line += &format!(",{}:{}", json_string(key), json_string(value));
+        }
+        if let Some(error) = &self.error {
+            line += &format!(",\"error\":{}", json_string(error));
+        }
+        line.push('}');
+        line
+    }
+
+    fn otlp(&self) -> String {
+        let attributes: Vec<String> = self.attributes.iter().map(|(key, value)| otlp_attribute(key, value)).collect();
+        let status = match &self.error {
+            Some(error) => format!("{{\"code\":2,\"message\":{}}}", json_string(error)),
+            None => "{\"code\":1}".to_string(),
+        };
+        let parent = self.parent.as_deref().map_or(String::new(), |id| format!("\"parentSpanId\":{},", json_string(id)));
+        format!("{{\"traceId\":{},\"spanId\":{},{}\"name\":{},\"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{}],\"status\":{}}}",
+            json_string(&self.trace_id), json_string(&self.id), parent, json_string(self.name), self.start_ns, self.end_ns,
+            attributes.join(","), status)
+    }
+}
+
+fn otlp_attribute(key: &str, value: &str) -> String {
+    format!("{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}", json_string(key), json_string(value))
+}
+
+// OTLP/HTTP JSON encoding of an ExportTraceServiceRequest
+fn otlp_payload(spans: &[Span]) -> String {
+    let resource = [otlp_attribute("service.name", &CONFIG.tracing.service_name), otlp_attribute("service.instance.id", &CONFIG.instance_name)];
+    let spans: Vec<String> = spans.iter().map(Span::otlp).collect();
+    format!("{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{}]}},\"scopeSpans\":[{{\"scope\":{{\"name\":\"rusty_controller\"}},\"spans\":[{}]}}]}}]}}",
+        resource.join(","), spans.join(","))
+}
@@ -0,0 +1,98 @@
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+
+// Just enough of the SQLite C library for the run store: statements go in as text with their values
+// quoted, rows come back as strings
+#[allow(non_camel_case_types)]
+enum sqlite3 {}
+
+type RowCallback = extern "C" fn(*mut c_void, c_int, *mut *mut c_char, *mut *mut c_char) -> c_int;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open(filename: *const c_char, db: *mut *mut sqlite3) -> c_int;
+    fn sqlite3_close(db: *mut sqlite3) -> c_int;
+    fn sqlite3_busy_timeout(db: *mut sqlite3, ms: c_int) -> c_int;
+    fn sqlite3_exec(db: *mut sqlite3, sql: *const c_char, callback: Option<RowCallback>, arg: *mut c_void, errmsg: *mut *mut c_char) -> c_int;
+    fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+    fn sqlite3_free(ptr: *mut c_void);
+}
+
+const SQLITE_OK: c_int = 0;
+// Another process, e.g. a site exporter, may be reading the database
+const BUSY_TIMEOUT_MS: c_int = 5000;
+
+type Rows = Vec<Vec<Option<String>>>;
+
+pub struct Connection {
+    db: *mut sqlite3,
+}
+
+impl Connection {
+    pub fn open(path: &str) -> Result<Connection, String> {
+        let path = CString::new(path).map_err(|e| e.to_string())?;
+        let mut db = ptr::null_mut();
+        let status = unsafe { sqlite3_open(path.as_ptr(), &mut db) };
+        // Even a failed open hands out a handle that has to be closed
+        let connection = Connection { db };
+        if status != SQLITE_OK {
+            return Err(connection.error());
+        }
+        unsafe { sqlite3_busy_timeout(db, BUSY_TIMEOUT_MS) };
+        Ok(connection)
+    }
+
+    fn error(&self) -> String {
+        if self.db.is_null() {
+            return "out of memory".to_string();
+        }
+        unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }.to_string_lossy().into_owned()
+    }
+
+    pub fn execute(&self, sql: &str) -> Result<(), String> {
+        self.exec(sql, None, ptr::null_mut())
+    }
+
+    // NULL columns are None
+    pub fn query(&self, sql: &str) -> Result<Rows, String> {
+        let mut rows: Rows = Vec::new();
+        self.exec(sql, Some(collect_row), &mut rows as *mut Rows as *mut c_void)?;
+        Ok(rows)
+    }
+
+    fn exec(&self, sql: &str, callback: Option<RowCallback>, arg: *mut c_void) -> Result<(), String> {
+        let sql = CString::new(sql).map_err(|e| e.to_string())?;
+        let mut message = ptr::null_mut();
+        let status = unsafe { sqlite3_exec(self.db, sql.as_ptr(), callback, arg, &mut message) };
+        if status == SQLITE_OK {
+            return Ok(());
+        }
+        if message.is_null() {
+            return Err(self.error());
+        }
+        let text = unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned();
+        unsafe { sqlite3_free(message as *mut c_void) };
+        Err(text)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.db) };
+    }
+}
+
+extern "C" fn collect_row(arg: *mut c_void, columns: c_int, values: *mut *mut c_char, _names: *mut *mut c_char) -> c_int {
+    let rows = unsafe { &mut *(arg as *mut Rows) };
+    let row = (0..columns as usize)
+        .map(|i| unsafe { *values.add(i) })
+        .map(|value| (!value.is_null()).then(|| unsafe { CStr::from_ptr(value) }.to_string_lossy().into_owned()))
+        .collect();
+    rows.push(row);
+    0
+}
+
+// String literal for a statement; SQLite has no backslash escapes
+pub fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
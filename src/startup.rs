@@ -0,0 +1,162 @@
+use std::io::{BufRead, Write};
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::deck::{Coordinates, HOME_POSITION};
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::pump::{PumpCommand, PumpError, Resolution};
+use crate::diagnostics::PumpDiagnosis;
+use crate::units::{Millimeters, PumpUnits};
+use crate::{diagnostics, motion, pump, Controller};
+
+const PUMP_EMPTY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// After a controller crash the devices may still be powered and positioned, so homing and pump
+// initialization are only repeated where they are needed and safe. Returns whether G28 was sent.
+// Homing is only skipped when the firmware says it is homed: a board that was just reset reports
+// the home position too. An error stops startup, with the controller dropped so the rest is parked.
+pub fn home_router(controller: &mut Controller) -> Result<bool, String> {
+    let settings = &CONFIG.startup;
+    let timeout = Duration::from_millis(settings.query_timeout_ms);
+    if serial_readline_timeout(&mut controller.router_port, "\r\n", timeout).is_none() {
+        log::info!("Router sent no start banner, it was not reset");
+    }
+    flush_port(&mut controller.router_port);
+    let position = serial_write(&mut controller.router_port, &format!("{}\r\n", settings.position_query)).ok()
+        .and_then(|_| serial_readline_timeout(&mut controller.router_port, "\r\n", timeout))
+        .and_then(|reply| parse_position(&reply));
+    let homed = position.is_some() && reports_homed(controller, timeout);
+    match position {
+        Some(position) if homed && position.z >= motion::SAFE_Z => {
+            log::info!("Router reports itself homed at {}, skipping homing", position);
+            controller.router.position = position;
+            return Ok(false);
+        }
+        Some(position) if position.z < motion::SAFE_Z => {
+            log::warn!("Needle is down at {}, raising it before homing", position);
+            controller.router.position = position;
+            let raised = Coordinates { z: motion::SAFE_Z, ..position };
+            if let ControlFlow::Break(e) = controller.router_execute(&motion::move_gcode(position, raised)) {
+                return Err(e);
+            }
+            controller.router.position = HOME_POSITION;
+        }
+        Some(position) => log::info!("Router at {}, homing", position),
+        None if settings.confirm_when_unsure => {
+            if !confirm("Router position unknown. Clear the deck and type 'home' to home the router: ", "home") {
+                return Err("Homing declined by operator".to_string());
+            }
+        }
+        None => log::info!("Router position unknown, homing"),
+    }
+    serial_write(&mut controller.router_port, "G28\r\n").map_err(|e| format!("Failed to home router: {e}"))?;
+    Ok(true)
+}
+
+fn reports_homed(controller: &mut Controller, timeout: Duration) -> bool {
+    let settings = &CONFIG.startup;
+    if settings.homed_query.is_empty() || settings.homed_reply.is_empty() {
+        return false;
+    }
+    flush_port(&mut controller.router_port);
+    serial_write(&mut controller.router_port, &format!("{}\r\n", settings.homed_query)).ok()
+        .and_then(|_| serial_readline_timeout(&mut controller.router_port, "\r\n", timeout))
+        .is_some_and(|reply| reply.contains(settings.homed_reply.as_str()))
+}
+
+// Pumps that are already initialized keep their prime; liquid left in a syringe goes to waste
+// instead of being pushed out wherever the initialization stroke points the valve
+// A failed initialization is sent once more before the pump's diagnosis is returned
+pub fn init_pump(controller: &mut Controller, address: char, init: &PumpCommand) -> Result<(), PumpDiagnosis> {
+    let mut port = controller.pumps.lock();
+    let status = pump::query_status(&mut port, address);
+    let plunger = pump::query_position(&mut port, address, "?").and_then(|p| p.parse::<u64>().ok());
+    match (status, plunger) {
+        (Some(status), Some(0)) if status.error == PumpError::None => {
+            log::info!("Pump {} already initialized, skipping initialization", address);
+            return Ok(());
+        }
+        (Some(status), Some(units)) if status.error == PumpError::None => {
+            log::warn!("Pump {} holds {} units after restart, emptying to waste port", address, units);
+            let empty = PumpCommand::new(init.address()).valve_out(CONFIG.startup.pump_waste_port).move_to(PumpUnits::ZERO);
+            if let Err(e) = pump::write_command(&mut port, &empty) {
+                return Err(PumpDiagnosis::Unknown(format!("emptying not sent: {e}")));
+            }
+            return match pump::wait_ready(&mut port, address, PUMP_EMPTY_TIMEOUT) {
+                Some(status) if status.ready && status.error == PumpError::None => Ok(()),
+                Some(status) if status.error != PumpError::None => Err(PumpDiagnosis::NotEmptied(Some(status.error))),
+                _ => Err(PumpDiagnosis::NotEmptied(None)),
+            };
+        }
+        _ => {}
+    }
+    let timeout = Duration::from_secs(CONFIG.startup.pump_init_timeout_secs);
+    let mut attempt = 1;
+    loop {
+        flush_port(&mut port);
+        let result = match pump::write_command(&mut port, init) {
+            Ok(()) => diagnostics::check_pump_init(&mut port, address, timeout),
+            Err(e) => Err(PumpDiagnosis::Unknown(format!("initialization not sent: {e}"))),
+        };
+        match result {
+            Ok(_) => {
+                log::info!("Pump {} initialized and ready", address);
+                drop(port);
+                // The priming strokes draw from the reservoir on the intake channel like any other
+                controller.record_reservoirs(init);
+                controller.reservoirs.save();
+                return Ok(());
+            }
+            Err(diagnosis) if attempt == 1 => {
+                log::warn!("Pump {} initialization failed: {}, retrying once", address, diagnosis);
+                attempt += 1;
+            }
+            Err(diagnosis) => return Err(diagnosis),
+        }
+    }
+}
+
+// Pump 1 is switched to fine and back to standard resolution, and each mode must be reported back.
+// Returns whether aspirations below [pump-resolution] fine_below_ul may use fine mode.
+pub fn check_resolution(controller: &mut Controller) -> bool {
+    let settings = &CONFIG.pump_resolution;
+    if !settings.enabled {
+        return false;
+    }
+    let pump = controller.pumps.pump(1);
+    for resolution in [Resolution::Fine, Resolution::Standard] {
+        let reported = pump.send(&PumpCommand::new(1).resolution(resolution))
+            .map(|_| pump.query_position(&settings.mode_query));
+        match reported {
+            Ok(Some(mode)) if mode == resolution.mode().to_string() => {}
+            Ok(reported) => {
+                log::error!("Pump 1 reports resolution mode {} instead of N{}, using standard resolution for all volumes",
+                    reported.as_deref().unwrap_or("nothing"), resolution.mode());
+                return false;
+            }
+            Err(e) => {
+                log::error!("{}, using standard resolution for all volumes", e);
+                return false;
+            }
+        }
+    }
+    log::info!("Pump 1 verified in N{}, aspirations below {} ul use it", settings.fine_mode, settings.fine_below_ul);
+    true
+}
+
+// Reads replies like "X:10.00 Y:20.00 Z:-5.00 E:0.00"
+pub fn parse_position(reply: &str) -> Option<Coordinates> {
+    let axis = |name: &str| reply.split_whitespace()
+        .find_map(|token| token.strip_prefix(name))
+        .and_then(|value| value.parse::<f64>().ok())
+        .map(Millimeters);
+    Some(Coordinates { x: axis("X:")?, y: axis("Y:")?, z: axis("Z:")? })
+}
+
+fn confirm(prompt: &str, expected: &str) -> bool {
+    print!("{prompt}");
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer).is_ok() && answer.trim() == expected
+}
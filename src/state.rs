@@ -0,0 +1,55 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControllerState {
+    Homing,
+    Idle,
+    Running,
+    Paused,
+    Faulted(String),
+    Maintenance,
+    // Idle upkeep is running; it gives way to any incoming request
+    IdleMaintenance,
+}
+
+impl ControllerState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControllerState::Homing => "HOMING",
+            ControllerState::Idle => "IDLE",
+            ControllerState::Running => "RUNNING",
+            ControllerState::Paused => "PAUSED",
+            ControllerState::Faulted(_) => "FAULTED",
+            ControllerState::Maintenance => "MAINTENANCE",
+            ControllerState::IdleMaintenance => "IDLE_MAINTENANCE",
+        }
+    }
+
+    // Control command that makes new commands acceptable again
+    fn required_action(&self) -> Option<&'static str> {
+        match self {
+            ControllerState::Homing => Some("WAIT_FOR_HOMING"),
+            ControllerState::Paused => Some("RESUME"),
+            ControllerState::Faulted(_) => Some("CLEARFAULT"),
+            ControllerState::Maintenance => Some("MAINTENANCE_OFF"),
+            ControllerState::Idle | ControllerState::Running | ControllerState::IdleMaintenance => None,
+        }
+    }
+
+    pub fn refusal(&self) -> Option<String> {
+        let action = self.required_action()?;
+        Some(match self {
+            ControllerState::Faulted(reason) => format!("REFUSED state={} reason={} action={}", self.name(), reason, action),
+            _ => format!("REFUSED state={} action={}", self.name(), action),
+        })
+    }
+}
+
+impl Display for ControllerState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControllerState::Faulted(reason) => write!(f, "{} ({})", self.name(), reason),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use crate::command_id::CommandId;
+use crate::units::Microliters;
+
+// Copy of the executor's state that other threads can read while a run is in progress
+#[derive(Default, Debug, Clone)]
+pub struct StatusSnapshot {
+    pub state: String,
+    pub queued: usize,
+    pub command_id: CommandId,
+    pub slot_occupancy: Microliters,
+    pub router_position: String,
+    pub volumes: String,
+    pub runs: String,
+    pub sensors: String,
+    pub safe_mode: String,
+    pub protocol: String,
+}
+
+pub type SharedStatus = Arc<Mutex<StatusSnapshot>>;
+
+pub fn read(status: &SharedStatus) -> StatusSnapshot {
+    status.lock().map(|s| s.clone()).unwrap_or_default()
+}
+
+impl Display for StatusSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "state={}", self.state)?;
+        writeln!(f, "queued={}", self.queued)?;
+        writeln!(f, "command_id={}", self.command_id)?;
+        writeln!(f, "slot_occupancy={}", self.slot_occupancy)?;
+        writeln!(f, "router_position={}", self.router_position)?;
+        writeln!(f, "volumes={}", self.volumes)?;
+        writeln!(f, "runs={}", self.runs)?;
+        writeln!(f, "sensors={}", self.sensors)?;
+        writeln!(f, "safe_mode={}", self.safe_mode)?;
+        writeln!(f, "{}", self.protocol)
+    }
+}
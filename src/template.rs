@@ -0,0 +1,187 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::Deserialize;
+use toml::Value;
+
+use crate::{metadata, script};
+
+// Recipe files are TOML with a command list and parameter defaults, e.g.
+//
+//   commands = ["LA_{antibody_tube}_1_100", "W_{incubation_min*60000}"]
+//   [parameters]
+//   incubation_min = 30
+//
+// A placeholder may scale a numeric parameter, which keeps recipes in operator units. Besides
+// commands the list takes blocks, rendered as script directives the controller interprets:
+//
+//   { repeat = "{washes}", commands = [...] }
+//   { if = "slot_occupied", commands = [...], else = [...] }
+//   { set = { wash_tube = 3 } }     changes a value for the entries rendered after it
+//
+// A `version` is sent along as META_protocol_version, so runs record which revision they followed.
+#[derive(Deserialize, Debug)]
+pub struct Recipe {
+    #[serde(default)]
+    pub version: Option<String>,
+    pub commands: Vec<Entry>,
+    #[serde(default)]
+    pub parameters: HashMap<String, Value>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Entry {
+    Command(String),
+    Repeat { repeat: Value, commands: Vec<Entry> },
+    If {
+        #[serde(rename = "if")]
+        condition: String,
+        commands: Vec<Entry>,
+        #[serde(default, rename = "else")]
+        otherwise: Vec<Entry>,
+    },
+    Set { set: HashMap<String, Value> },
+}
+
+pub fn load(path: &str) -> Result<Recipe, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read recipe {path}: {e}"))?;
+    toml::from_str(&text).map_err(|e| format!("Invalid recipe {path}: {e}"))
+}
+
+impl Recipe {
+    // Renders the commands as one space separated message
+    pub fn render(&self, overrides: &HashMap<String, String>) -> Result<String, String> {
+        let mut values: HashMap<String, String> = self.parameters.iter()
+            .map(|(name, value)| (name.clone(), value_text(value)))
+            .collect();
+        values.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let mut used = BTreeSet::new();
+        let mut commands: Vec<String> = self.version.iter().map(|version| metadata::token("protocol_version", version)).collect();
+        render_entries(&self.commands, &mut values, &mut used, &mut commands)?;
+        if let Some(unused) = overrides.keys().find(|k| !used.contains(*k)) {
+            return Err(format!("Recipe has no parameter {unused}"));
+        }
+        if let Some(command) = commands.iter().find(|c| c.is_empty() || c.contains(char::is_whitespace)) {
+            return Err(format!("Rendered command [{command}] is empty or contains whitespace"));
+        }
+        // The controller would refuse it anyway, but a mistake in the recipe is easier to find here
+        script::expand(&commands.iter().map(String::as_str).collect::<Vec<&str>>())?;
+        Ok(commands.join(" "))
+    }
+}
+
+fn render_entries(entries: &[Entry], values: &mut HashMap<String, String>, used: &mut BTreeSet<String>,
+                  commands: &mut Vec<String>) -> Result<(), String> {
+    for entry in entries {
+        match entry {
+            Entry::Command(command) => commands.push(substitute(command, values, used)?),
+            Entry::Repeat { repeat, commands: body } => {
+                commands.push(format!("REPEAT_{}", substitute(&value_text(repeat), values, used)?));
+                render_entries(body, values, used, commands)?;
+                commands.push("ENDREPEAT".to_string());
+            }
+            Entry::If { condition, commands: body, otherwise } => {
+                commands.push(format!("IF_{}", substitute(condition, values, used)?));
+                render_entries(body, values, used, commands)?;
+                if !otherwise.is_empty() {
+                    commands.push("ELSE".to_string());
+                    render_entries(otherwise, values, used, commands)?;
+                }
+                commands.push("ENDIF".to_string());
+            }
+            Entry::Set { set } => {
+                for (name, value) in set {
+                    let value = substitute(&value_text(value), values, used)?;
+                    values.insert(name.clone(), value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn substitute(template: &str, values: &HashMap<String, String>, used: &mut BTreeSet<String>) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or(format!("Unclosed placeholder in [{template}]"))? + start;
+        rendered.push_str(&rest[..start]);
+        let placeholder = rest[start + 1..end].trim();
+        let (name, factor) = match placeholder.split_once('*') {
+            Some((name, factor)) => (name.trim(), Some(factor.trim())),
+            None => (placeholder, None),
+        };
+        let value = values.get(name).ok_or(format!("Missing value for parameter {name} in [{template}]"))?;
+        used.insert(name.to_string());
+        match factor {
+            Some(factor) => rendered.push_str(&scale(name, value, factor)?),
+            None => rendered.push_str(value),
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn scale(name: &str, value: &str, factor: &str) -> Result<String, String> {
+    let value: f64 = value.parse().map_err(|_| format!("Parameter {name} must be a number to be scaled, got {value}"))?;
+    let factor: f64 = factor.parse().map_err(|_| format!("Invalid scale factor {factor} for parameter {name}"))?;
+    let scaled = value * factor;
+    if scaled.fract() == 0.0 {
+        Ok(format!("{}", scaled as i64))
+    } else {
+        Ok(scaled.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recipe(text: &str) -> Recipe {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn renders_scaled_parameters_and_blocks() {
+        let recipe = recipe(r#"
+            version = "3"
+            commands = [
+                "LA_{tube}_1_100ul",
+                { repeat = "{washes}", commands = ["W_{minutes*60000}"] },
+                { if = "slot_occupied", commands = ["LA_36_1_0ul"], else = ["W_1"] },
+                { set = { tube = 9 } },
+                "LA_{tube}_1_50ul",
+            ]
+            [parameters]
+            tube = 3
+            washes = 2
+            minutes = 0.5
+        "#);
+        assert_eq!(recipe.render(&HashMap::new()).unwrap(), format!("{} LA_3_1_100ul REPEAT_2 W_30000 ENDREPEAT \
+            IF_slot_occupied LA_36_1_0ul ELSE W_1 ENDIF LA_9_1_50ul", metadata::token("protocol_version", "3")));
+        let overrides = HashMap::from([("tube".to_string(), "5".to_string())]);
+        assert!(recipe.render(&overrides).unwrap().contains("LA_5_1_100ul"));
+    }
+
+    #[test]
+    fn refuses_unknown_and_missing_parameters() {
+        let recipe = recipe(r#"commands = ["LA_{tube}_1_100ul"]"#);
+        assert!(recipe.render(&HashMap::new()).is_err());
+        let overrides = HashMap::from([("tube".to_string(), "5".to_string()), ("volume".to_string(), "1".to_string())]);
+        assert!(recipe.render(&overrides).is_err());
+    }
+
+    #[test]
+    fn checks_the_rendered_blocks() {
+        let recipe = recipe(r#"commands = [{ if = "slot_wet", commands = ["W_1"] }]"#);
+        assert!(recipe.render(&HashMap::new()).is_err());
+    }
+}
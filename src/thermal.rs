@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::config::{PidGains, SoftwareLoop, ThermalZone, ZoneDriver, CONFIG};
+use crate::devices::DeviceKind;
+use crate::hold_audit;
+use crate::poll::{try_poll_until, Poll};
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::{unwrap_option, Controller};
+
+// State of the software PID loop of a zone with a set point
+struct Regulator {
+    setpoint: f64,
+    integral: f64,
+    last_error: Option<f64>,
+    last_poll: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct Regulators {
+    zones: HashMap<String, Regulator>,
+}
+
+pub fn zone(name: &str) -> Option<&'static ThermalZone> {
+    CONFIG.thermal_zones.iter().find(|zone| zone.name == name)
+}
+
+// The zone a TC_<zone>_<temp> step addresses; TC_<temp> addresses none
+pub fn addressed_zone(command: &str) -> Option<&'static ThermalZone> {
+    match command.split('_').collect::<Vec<&str>>()[..] {
+        ["TC", name, _] | ["TUNEPID", name, _] => zone(name),
+        _ => None,
+    }
+}
+
+pub fn set_zone(controller: &mut Controller, zone: &'static ThermalZone, command: &str) -> ControlFlow<String> {
+    let celsius = target(zone, command)?;
+    if zone.software.is_some() {
+        unwrap_option!(gains(controller, zone), format!("{command}: zone {} has no PID gains, set pid or run TUNEPID_{}_<temp>", zone.name, zone.name));
+        let regulator = controller.regulators.zones.entry(zone.name.clone())
+            .or_insert(Regulator { setpoint: celsius, integral: 0.0, last_error: None, last_poll: None });
+        regulator.setpoint = celsius;
+        regulate_zone(controller, zone);
+    } else {
+        send(controller, zone, &set_command(zone, &celsius.to_string()), command)?;
+    }
+    hold_audit::hold(controller, zone, celsius);
+    log::info!("Zone {} set to {} °C", zone.name, celsius);
+    controller.events.emit("temperature_set", &[("zone", zone.name.clone()), ("celsius", celsius.to_string())]);
+    ControlFlow::Continue(())
+}
+
+// Sends the configured PID gains after startup; a zone that cannot be configured is only logged
+pub fn configure_zones(controller: &mut Controller) {
+    for zone in &CONFIG.thermal_zones {
+        if zone.min_celsius >= zone.max_celsius {
+            log::error!("Thermal zone {}: min_celsius must be below max_celsius", zone.name);
+        }
+        let Some(pid) = zone.pid.filter(|_| zone.software.is_none()) else {
+            continue;
+        };
+        let text = zone.pid_command
+            .replace("{heater}", &zone.heater.to_string())
+            .replace("{p}", &pid.p.to_string())
+            .replace("{i}", &pid.i.to_string())
+            .replace("{d}", &pid.d.to_string());
+        match send(controller, zone, &text, "startup") {
+            ControlFlow::Continue(()) => log::info!("Zone {} PID gains set: P={} I={} D={}", zone.name, pid.p, pid.i, pid.d),
+            ControlFlow::Break(e) => log::error!("{}", e),
+        }
+    }
+}
+
+pub fn zones_off(controller: &mut Controller) {
+    controller.regulators.zones.clear();
+    hold_audit::release_all(controller);
+    for zone in &CONFIG.thermal_zones {
+        let result = match &zone.software {
+            Some(software) => set_output(controller, zone, software, 0.0),
+            None => send(controller, zone, &set_command(zone, "0"), "shutdown"),
+        };
+        if let ControlFlow::Break(e) = result {
+            log::error!("{}", e);
+        }
+    }
+}
+
+// Runs the software loops and hold audits that are due; called between steps and while waiting
+pub fn regulate(controller: &mut Controller) {
+    for zone in &CONFIG.thermal_zones {
+        if controller.regulators.zones.contains_key(&zone.name) {
+            regulate_zone(controller, zone);
+        }
+    }
+    hold_audit::audit(controller);
+}
+
+// How long the idle loop may block without delaying a software loop
+pub fn idle_wait(controller: &Controller, longest: Duration) -> Duration {
+    CONFIG.thermal_zones.iter()
+        .filter(|zone| controller.regulators.zones.contains_key(&zone.name))
+        .filter_map(|zone| zone.software.as_ref())
+        .map(|software| Duration::from_millis(software.poll_interval_ms))
+        .fold(longest, Duration::min)
+}
+
+fn regulate_zone(controller: &mut Controller, zone: &ThermalZone) {
+    let (Some(software), Some(gains)) = (&zone.software, gains(controller, zone)) else {
+        return;
+    };
+    let now = controller.clock.now();
+    let Some(regulator) = controller.regulators.zones.get(&zone.name) else {
+        return;
+    };
+    let interval = Duration::from_millis(software.poll_interval_ms);
+    if regulator.last_poll.is_some_and(|last| now < last + interval) {
+        return;
+    }
+    let output = match read_temperature(controller, zone, software) {
+        Ok(celsius) if celsius > zone.max_celsius => {
+            log::error!("Zone {} at {} °C exceeds its limit of {} °C, heater off", zone.name, celsius, zone.max_celsius);
+            0.0
+        }
+        Ok(celsius) => {
+            let regulator = controller.regulators.zones.get_mut(&zone.name).expect("regulator checked above");
+            let dt = regulator.last_poll.map_or(interval, |last| now - last).as_secs_f64();
+            regulator.step(gains, software, celsius, dt)
+        }
+        Err(e) => {
+            log::error!("{}, heater off", e);
+            0.0
+        }
+    };
+    if let Some(regulator) = controller.regulators.zones.get_mut(&zone.name) {
+        regulator.last_poll = Some(now);
+    }
+    if let ControlFlow::Break(e) = set_output(controller, zone, software, output) {
+        log::error!("{}", e);
+    }
+}
+
+impl Regulator {
+    fn step(&mut self, gains: PidGains, software: &SoftwareLoop, celsius: f64, dt: f64) -> f64 {
+        let error = self.setpoint - celsius;
+        let derivative = self.last_error.map_or(0.0, |last| (error - last) / dt.max(f64::EPSILON));
+        self.last_error = Some(error);
+        let unclamped = gains.p * error + gains.i * (self.integral + error * dt) + gains.d * derivative;
+        // Anti-windup: the integral is held while the output is pinned at a limit it is pushing against
+        let pinned = (unclamped > software.output_max && error > 0.0) || (unclamped < software.output_min && error < 0.0);
+        if !pinned {
+            self.integral += error * dt;
+        }
+        let output = gains.p * error + gains.i * self.integral + gains.d * derivative;
+        output.clamp(software.output_min, software.output_max)
+    }
+}
+
+// TUNEPID_<zone>_<temp> switches the heater between output_max and output_min around temp (relay
+// auto-tune), measures the oscillation and stores Ziegler-Nichols gains in the calibration store.
+// The zone is left off afterwards.
+pub fn tune(controller: &mut Controller, command: &str) -> ControlFlow<String> {
+    let zone = unwrap_option!(addressed_zone(command), format!("Cannot deduce a thermal zone from {command}"));
+    let software = unwrap_option!(zone.software.as_ref(), format!("{command}: zone {} is regulated by its firmware", zone.name));
+    let setpoint = target(zone, command)?;
+    controller.regulators.zones.remove(&zone.name);
+    hold_audit::release(controller, zone);
+    log::info!("Auto-tuning zone {} around {} °C", zone.name, setpoint);
+    let result = relay_tune(controller, zone, software, setpoint);
+    set_output(controller, zone, software, 0.0)?;
+    let (ultimate_gain, period) = result?;
+    let gains = PidGains { p: 0.6 * ultimate_gain, i: 1.2 * ultimate_gain / period, d: 0.075 * ultimate_gain * period };
+    controller.calibration.pid.insert(zone.name.clone(), gains);
+    controller.calibration.save();
+    let reply = format!("PID zone={} p={:.3} i={:.4} d={:.3} ku={:.3} tu={:.1}s", zone.name, gains.p, gains.i, gains.d, ultimate_gain, period);
+    log::info!("{}", reply);
+    controller.application.send_status(&reply);
+    ControlFlow::Continue(())
+}
+
+// Ultimate gain and period in seconds of the relay oscillation
+fn relay_tune(controller: &mut Controller, zone: &ThermalZone, software: &SoftwareLoop, setpoint: f64) -> ControlFlow<String, (f64, f64)> {
+    let timeout = Duration::from_secs(software.tune_timeout_secs);
+    let mut heating = true;
+    set_output(controller, zone, software, software.output_max)?;
+    // Extremes of the current half cycle, then of every completed one
+    let (mut high, mut low) = (f64::MIN, f64::MAX);
+    let (mut peaks, mut troughs, mut switch_ons) = (Vec::new(), Vec::new(), Vec::new());
+    let poll = Poll::every(Duration::from_millis(software.poll_interval_ms)).timeout(timeout);
+    let tuned = try_poll_until(controller, poll, |controller| controller.clock.as_ref(), |controller| {
+        if switch_ons.len() > software.tune_cycles as usize {
+            return ControlFlow::Continue(Some(()));
+        }
+        let now = controller.clock.now();
+        controller.poll_controls()?;
+        regulate(controller);
+        let celsius = match read_temperature(controller, zone, software) {
+            Ok(celsius) => celsius,
+            Err(e) => return ControlFlow::Break(e),
+        };
+        if celsius > zone.max_celsius {
+            return ControlFlow::Break(format!("Zone {} reached {} °C while tuning, over its limit of {} °C", zone.name, celsius, zone.max_celsius));
+        }
+        high = high.max(celsius);
+        low = low.min(celsius);
+        if heating && celsius > setpoint + software.tune_hysteresis {
+            heating = false;
+            set_output(controller, zone, software, software.output_min)?;
+            troughs.push(low);
+            low = f64::MAX;
+        } else if !heating && celsius < setpoint - software.tune_hysteresis {
+            heating = true;
+            set_output(controller, zone, software, software.output_max)?;
+            peaks.push(high);
+            high = f64::MIN;
+            switch_ons.push(now);
+        }
+        ControlFlow::Continue(None)
+    })?;
+    if tuned.is_none() {
+        return ControlFlow::Break(format!("Zone {} did not oscillate around {} °C within {}s", zone.name, setpoint, timeout.as_secs()));
+    }
+    // The first trough is the start temperature and the first peak the overshoot from it
+    let amplitude = (mean(&peaks[1..]) - mean(&troughs[1..])) / 2.0;
+    let period = (*switch_ons.last().expect("cycles counted") - switch_ons[0]).as_secs_f64() / software.tune_cycles as f64;
+    if amplitude <= 0.0 || period <= 0.0 {
+        return ControlFlow::Break(format!("Zone {} gave no usable oscillation while tuning", zone.name));
+    }
+    let relay = (software.output_max - software.output_min) / 2.0;
+    ControlFlow::Continue((4.0 * relay / (PI * amplitude), period))
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len().max(1) as f64
+}
+
+fn target(zone: &ThermalZone, command: &str) -> ControlFlow<String, f64> {
+    let requested = command.rsplit('_').next().unwrap_or_default();
+    let Ok(celsius) = requested.parse::<f64>() else {
+        return ControlFlow::Break(format!("Cannot deduce target temperature from {command}"));
+    };
+    if celsius < zone.min_celsius || celsius > zone.max_celsius {
+        return ControlFlow::Break(format!("{command}: zone {} accepts {} to {} °C", zone.name, zone.min_celsius, zone.max_celsius));
+    }
+    ControlFlow::Continue(celsius)
+}
+
+// Tuned gains take precedence over the configured ones
+fn gains(controller: &Controller, zone: &ThermalZone) -> Option<PidGains> {
+    controller.calibration.pid.get(&zone.name).copied().or(zone.pid)
+}
+
+fn set_command(zone: &ThermalZone, celsius: &str) -> String {
+    zone.set_command.replace("{heater}", &zone.heater.to_string()).replace("{temp}", celsius)
+}
+
+fn set_output(controller: &mut Controller, zone: &ThermalZone, software: &SoftwareLoop, output: f64) -> ControlFlow<String> {
+    let text = software.output_command
+        .replace("{heater}", &zone.heater.to_string())
+        .replace("{output}", &format!("{output:.0}"));
+    match exchange(controller, zone, software, &text) {
+        Ok(_) => ControlFlow::Continue(()),
+        Err(e) => ControlFlow::Break(e),
+    }
+}
+
+// Zones regulated by their firmware are read with the default sensor command
+pub fn read_zone(controller: &mut Controller, zone: &ThermalZone) -> Result<f64, String> {
+    match &zone.software {
+        Some(software) => read_temperature(controller, zone, software),
+        None => read_temperature(controller, zone, &SoftwareLoop::default()),
+    }
+}
+
+fn read_temperature(controller: &mut Controller, zone: &ThermalZone, software: &SoftwareLoop) -> Result<f64, String> {
+    let reply = exchange(controller, zone, software, &software.sensor_command.replace("{heater}", &zone.heater.to_string()))?;
+    reply.split_once(&software.sensor_prefix)
+        .map(|(_, rest)| rest.chars().take_while(|c| c.is_ascii_digit() || *c == '.' || *c == '-').collect::<String>())
+        .and_then(|number| number.parse().ok())
+        .ok_or(format!("Zone {} - unreadable sensor reply [{}]", zone.name, reply.trim()))
+}
+
+// The software loop shares the port with steps, so every command's reply is read before going on
+fn exchange(controller: &mut Controller, zone: &ThermalZone, software: &SoftwareLoop, text: &str) -> Result<String, String> {
+    let port = match port(controller, zone, "thermal") {
+        ControlFlow::Continue(port) => port,
+        ControlFlow::Break(e) => return Err(e),
+    };
+    flush_port(port);
+    serial_write(port, text).map_err(|_| format!("Zone {} - failed to send command: [{}]", zone.name, text.trim_end()))?;
+    serial_readline_timeout(port, "\r\n", Duration::from_millis(software.reply_timeout_ms))
+        .ok_or(format!("Zone {} - no reply to command: [{}]", zone.name, text.trim_end()))
+}
+
+fn send(controller: &mut Controller, zone: &ThermalZone, text: &str, command: &str) -> ControlFlow<String> {
+    let port = port(controller, zone, command)?;
+    match serial_write(port, text) {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(_) => ControlFlow::Break(format!("Zone {} - failed to send command: [{}]", zone.name, text.trim_end())),
+    }
+}
+
+fn port<'a>(controller: &'a mut Controller, zone: &ThermalZone, command: &str) -> ControlFlow<String, &'a mut Box<dyn SerialPort>> {
+    match zone.driver {
+        ZoneDriver::Router => ControlFlow::Continue(&mut controller.router_port),
+        ZoneDriver::Thermal => controller.devices.require(DeviceKind::Thermal, command),
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{TimelineFormat, CONFIG};
+use crate::metadata;
+use crate::notifications::json_string;
+use crate::pump::PumpCommand;
+use crate::report::{escape, file_name, format_duration};
+
+const ACTIVITIES: [&str; 3] = ["router", "pump", "wait"];
+const ROUTER: usize = 0;
+const PUMP: usize = 1;
+const WAIT: usize = 2;
+// Row for work done outside the steps, e.g. draining the slot after the last one
+const BETWEEN_STEPS: &str = "(between steps)";
+
+// Where the time of a run would go on hardware, recorded while it executes in --simulate mode:
+// each step with its offset from the start of the run and its router moves, pump strokes and
+// waits. The simulated devices answer at once, so durations are modelled from the motion planner,
+// plunger travel at [simulation-timeline] pump_speed and the wait steps themselves. Drains pump 2
+// runs in the background while other steps execute are not counted.
+#[derive(Default)]
+pub struct Timeline {
+    enabled: bool,
+    steps: Vec<TimedStep>,
+    in_step: bool,
+}
+
+struct TimedStep {
+    command: String,
+    start: Duration,
+    // Modelled time per entry of ACTIVITIES
+    busy: [Duration; 3],
+}
+
+impl TimedStep {
+    fn duration(&self) -> Duration {
+        self.busy.iter().sum()
+    }
+
+    // What the step spends time on, e.g. "router+pump"
+    fn devices(&self) -> String {
+        let devices: Vec<&str> = ACTIVITIES.iter().zip(self.busy).filter(|(_, busy)| !busy.is_zero()).map(|(name, _)| *name).collect();
+        if devices.is_empty() { "-".to_string() } else { devices.join("+") }
+    }
+}
+
+impl Timeline {
+    pub fn new(simulated: bool) -> Timeline {
+        Timeline { enabled: simulated && CONFIG.simulation_timeline.enabled, steps: Vec::new(), in_step: false }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn start_run(&mut self) {
+        self.steps.clear();
+        self.in_step = false;
+    }
+
+    pub fn start_step(&mut self, command: &str) {
+        if self.enabled {
+            self.push(metadata::redact(command));
+            self.in_step = true;
+        }
+    }
+
+    pub fn finish_step(&mut self) {
+        self.in_step = false;
+    }
+
+    fn push(&mut self, command: String) {
+        let start = self.steps.last().map_or(Duration::ZERO, |step| step.start + step.duration());
+        self.steps.push(TimedStep { command, start, busy: [Duration::ZERO; 3] });
+    }
+
+    pub fn router(&mut self, duration: Duration) {
+        self.add(ROUTER, duration);
+    }
+
+    pub fn pump(&mut self, command: &PumpCommand) {
+        let settings = &CONFIG.simulation_timeline;
+        self.add(PUMP, command.duration(settings.pump_speed, Duration::from_millis(settings.valve_move_ms)));
+    }
+
+    pub fn wait(&mut self, duration: Duration) {
+        self.add(WAIT, duration);
+    }
+
+    fn add(&mut self, activity: usize, duration: Duration) {
+        if !self.enabled {
+            return;
+        }
+        if !self.in_step && self.steps.last().is_none_or(|step| step.command != BETWEEN_STEPS) {
+            self.push(BETWEEN_STEPS.to_string());
+        }
+        if let Some(step) = self.steps.last_mut() {
+            step.busy[activity] += duration;
+        }
+    }
+
+    // Writes every configured format to [simulation-timeline] directory; failures are only logged
+    pub fn write(&mut self, started: SystemTime, run_id: Option<&str>) {
+        let steps = std::mem::take(&mut self.steps);
+        if steps.is_empty() {
+            return;
+        }
+        let settings = &CONFIG.simulation_timeline;
+        let totals = totals(&steps);
+        let total: Duration = totals.iter().sum();
+        let shares: Vec<String> = ACTIVITIES.iter().zip(totals).map(|(name, busy)| format!("{} {} ({:.0}%)", name, format_duration(busy), share(busy, total))).collect();
+        log::info!("Modelled run time on hardware {}: {}", format_duration(total), shares.join(", "));
+        let name = file_name("timeline", started, run_id);
+        for format in &settings.formats {
+            let (extension, text) = match format {
+                TimelineFormat::Json => ("json", json(&steps, run_id)),
+                TimelineFormat::Csv => ("csv", csv(&steps)),
+                TimelineFormat::Html => ("html", html(&steps, run_id)),
+            };
+            let path = Path::new(&settings.directory).join(format!("{name}.{extension}"));
+            match std::fs::create_dir_all(&settings.directory).and_then(|_| std::fs::write(&path, text)) {
+                Ok(()) => log::info!("Timeline written to {}", path.display()),
+                Err(e) => log::error!("Failed to write timeline {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+fn totals(steps: &[TimedStep]) -> [Duration; 3] {
+    let mut totals = [Duration::ZERO; 3];
+    for step in steps {
+        totals.iter_mut().zip(step.busy).for_each(|(total, busy)| *total += busy);
+    }
+    totals
+}
+
+fn share(part: Duration, total: Duration) -> f64 {
+    if total.is_zero() { 0.0 } else { 100.0 * part.as_secs_f64() / total.as_secs_f64() }
+}
+
+fn ms(duration: Duration) -> u128 {
+    duration.as_millis()
+}
+
+// {"run":..,"total_ms":..,"router_ms":..,"pump_ms":..,"wait_ms":..,"steps":[{"step":1,"command":..,
+// "start_ms":..,"duration_ms":..,"device":"router+pump","router_ms":..,"pump_ms":..,"wait_ms":..}]}
+fn json(steps: &[TimedStep], run_id: Option<&str>) -> String {
+    let timings = |busy: &[Duration; 3]| ACTIVITIES.iter().zip(busy).map(|(name, d)| format!("\"{name}_ms\":{}", ms(*d))).collect::<Vec<_>>().join(",");
+    let rows: Vec<String> = steps.iter().enumerate()
+        .map(|(i, step)| format!("{{\"step\":{},\"command\":{},\"start_ms\":{},\"duration_ms\":{},\"device\":{},{}}}", i + 1,
+            json_string(&step.command), ms(step.start), ms(step.duration()), json_string(&step.devices()), timings(&step.busy)))
+        .collect();
+    let totals = totals(steps);
+    format!("{{\"run\":{},\"total_ms\":{},{},\"steps\":[\n{}\n]}}\n", run_id.map_or("null".to_string(), json_string),
+        totals.iter().map(|d| ms(*d)).sum::<u128>(), timings(&totals), rows.join(",\n"))
+}
+
+fn csv(steps: &[TimedStep]) -> String {
+    let mut text = "step,command,start_ms,duration_ms,device,router_ms,pump_ms,wait_ms\n".to_string();
+    for (i, step) in steps.iter().enumerate() {
+        let [router, pump, wait] = step.busy.map(ms);
+        writeln!(text, "{},\"{}\",{},{},{},{},{},{}", i + 1, step.command.replace('"', "\"\""), ms(step.start), ms(step.duration()),
+                 step.devices(), router, pump, wait).ok();
+    }
+    text
+}
+
+// Gantt chart: one bar per step, placed at its offset and split by what it spends its time on
+fn html(steps: &[TimedStep], run_id: Option<&str>) -> String {
+    let totals = totals(steps);
+    let total: Duration = totals.iter().sum();
+    let percent = |d: Duration| share(d, total);
+    let title = format!("Timeline of run {}", run_id.unwrap_or("in simulation"));
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>", escape(&title)).ok();
+    html += "<style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;margin-bottom:1.5em}\
+             td,th{border:1px solid #999;padding:3px 8px;text-align:left}.track{position:relative;width:600px;height:14px}\
+             .bar{position:absolute;height:100%;display:flex;min-width:1px}.router{background:#4a7fc1}.pump{background:#4caf50}\
+             .wait{background:#bbb}</style>\n</head>\n<body>\n";
+    writeln!(html, "<h1>{}</h1>", escape(&title)).ok();
+    html += "<table>\n<tr><th>Activity</th><th>Time</th><th>Share</th></tr>\n";
+    for (name, busy) in ACTIVITIES.iter().zip(totals) {
+        writeln!(html, "<tr><td><span class=\"{name}\">&nbsp;&nbsp;</span> {name}</td><td>{}</td><td>{:.0}%</td></tr>",
+                 format_duration(busy), percent(busy)).ok();
+    }
+    writeln!(html, "<tr><th>Total</th><th>{}</th><th></th></tr>\n</table>", format_duration(total)).ok();
+    html += "<table>\n<tr><th>#</th><th>Step</th><th>Start</th><th>Duration</th><th></th></tr>\n";
+    for (i, step) in steps.iter().enumerate() {
+        let segments: String = ACTIVITIES.iter().zip(step.busy)
+            .filter(|(_, busy)| !busy.is_zero())
+            .map(|(name, busy)| format!("<div class=\"{name}\" style=\"flex:{}\"></div>", ms(busy)))
+            .collect();
+        writeln!(html, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td><div class=\"track\"><div class=\"bar\" \
+                        style=\"left:{:.2}%;width:{:.2}%\">{}</div></div></td></tr>", i + 1, escape(&step.command),
+                 format_duration(step.start), format_duration(step.duration()), percent(step.start), percent(step.duration()), segments).ok();
+    }
+    html += "</table>\n</body>\n</html>\n";
+    html
+}
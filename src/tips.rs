@@ -0,0 +1,97 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::deck::Coordinates;
+use crate::port_operations::{flush_port, serial_readline_timeout, serial_write};
+use crate::{motion, unwrap_option, Controller};
+
+const EJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Tips taken from the rack since startup and the aspirations done with the one on the needle
+#[derive(Debug, Default)]
+pub struct TipTracker {
+    current: Option<(String, u64)>,
+    next: usize,
+    used: u64,
+}
+
+impl TipTracker {
+    pub fn describe(&self) -> String {
+        match &self.current {
+            Some((tip, uses)) => format!("tip={} tip_uses={} tips_used={}", tip, uses, self.used),
+            None => format!("tip=- tips_used={}", self.used),
+        }
+    }
+}
+
+// Called before every aspiration from a tube
+pub fn before_aspiration(controller: &mut Controller) -> ControlFlow<String> {
+    if !CONFIG.tips.enabled {
+        return ControlFlow::Continue(());
+    }
+    let max_uses = CONFIG.tips.max_uses;
+    match controller.tips.current {
+        None => change_tip(controller)?,
+        Some((_, uses)) if max_uses > 0 && uses >= max_uses => {
+            log::info!("Tip used {} times, changing it", uses);
+            change_tip(controller)?;
+        }
+        Some(_) => {}
+    }
+    if let Some((_, uses)) = &mut controller.tips.current {
+        *uses += 1;
+    }
+    ControlFlow::Continue(())
+}
+
+pub fn change_tip(controller: &mut Controller) -> ControlFlow<String> {
+    let settings = &CONFIG.tips;
+    if controller.tips.current.is_some() {
+        eject_tip(controller)?;
+    }
+    let rack = unwrap_option!(CONFIG.racks.iter().find(|r| r.name == settings.rack),
+        format!("Tip rack {} is not defined in [[racks]]", settings.rack));
+    let positions = rack.positions();
+    let (tip, position) = unwrap_option!(positions.get(controller.tips.next),
+        format!("TIPS EXHAUSTED rack={} used={}", settings.rack, controller.tips.used));
+    let position = match position {
+        Ok(position) => *position,
+        Err(e) => return ControlFlow::Break(e.clone()),
+    };
+    log::info!("Loading tip {}", tip);
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..controller.router.position })?;
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..position })?;
+    controller.router_move(position)?;
+    // Pressing below the rack position seats the tip on the needle
+    controller.router_move(Coordinates { z: position.z - settings.press_depth_mm, ..position })?;
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..position })?;
+    controller.tips.next += 1;
+    controller.tips.used += 1;
+    let warnings = controller.wear.record_tip();
+    controller.notes.extend(warnings);
+    controller.tips.current = Some((tip.clone(), 0));
+    controller.needle_residues.clear();
+    ControlFlow::Continue(())
+}
+
+fn eject_tip(controller: &mut Controller) -> ControlFlow<String> {
+    let settings = &CONFIG.tips;
+    let eject = settings.eject_position;
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..controller.router.position })?;
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..eject })?;
+    controller.router_move(eject)?;
+    // The router acknowledges other commands like moves, e.g. "M42:OK"
+    let code = settings.eject_command.split_whitespace().next().unwrap_or_default();
+    flush_port(&mut controller.router_port);
+    if serial_write(&mut controller.router_port, &format!("{}\r\n", settings.eject_command)).is_err() {
+        return ControlFlow::Break("Router - failed to send tip eject command".to_string());
+    }
+    match serial_readline_timeout(&mut controller.router_port, "\r\n", EJECT_TIMEOUT) {
+        Some(reply) if reply == format!("{code}:OK") => {}
+        reply => return ControlFlow::Break(format!("Router - tip eject failed: [{}]", reply.unwrap_or_default())),
+    }
+    let (tip, uses) = controller.tips.current.take().unwrap_or_default();
+    log::info!("Ejected tip {} after {} aspirations", tip, uses);
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..eject })
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+use crate::config::CONFIG;
+use crate::units::Microliters;
+
+// Liquid drawn from each tube since the controller started. Tubes with a fill volume in
+// [tube-volumes] also have a remaining volume; it resets when the controller restarts.
+#[derive(Default)]
+pub struct TubeInventory {
+    drawn: HashMap<String, Microliters>,
+}
+
+impl TubeInventory {
+    pub fn draw(&mut self, tube: &str, microliters: Microliters) {
+        *self.drawn.entry(tube.to_string()).or_default() += microliters;
+    }
+
+    // Recovered liquid put back into the tube
+    pub fn refill(&mut self, tube: &str, microliters: Microliters) {
+        let drawn = self.drawn(tube).saturating_sub(microliters);
+        self.drawn.insert(tube.to_string(), drawn);
+    }
+
+    pub fn remaining(&self, tube: &str) -> Option<Microliters> {
+        let fill = CONFIG.tube_volumes.get(tube)?;
+        Some(fill.saturating_sub(self.drawn(tube)))
+    }
+
+    fn drawn(&self, tube: &str) -> Microliters {
+        self.drawn.get(tube).copied().unwrap_or_default()
+    }
+
+    pub fn describe(&self, tube: &str) -> String {
+        let remaining = self.remaining(tube).map_or("untracked".to_string(), |r| format!("{r}ul"));
+        format!("TUBE {} remaining={} drawn={}ul", tube, remaining, self.drawn(tube))
+    }
+}
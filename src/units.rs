@@ -0,0 +1,236 @@
+use std::fmt::{Display, Formatter};
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Mul, Sub};
+
+use serde::de::{Error, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::config::CONFIG;
+use crate::pump::{Resolution, FULL_STROKE, UNITS_PER_MICROLITER};
+
+// Unit suffixes a volume may be written with and how many microliters one of each is. Pump units
+// are plunger increments at standard resolution.
+const VOLUME_UNITS: [(&str, f64); 5] = [
+    ("ul", 1.0),
+    ("\u{b5}l", 1.0),
+    ("\u{3bc}l", 1.0),
+    ("ml", 1000.0),
+    ("pu", 1.0 / UNITS_PER_MICROLITER as f64),
+];
+
+// Liquid volume. Displays as the bare number so existing status formats stay the same. Settings
+// take a bare number of microliters or a string with a unit, e.g. "1.5ml".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(transparent)]
+pub struct Microliters(pub u64);
+
+// "100ul", "0.25ml" or "2400pu"; the unit is case-insensitive and the volume must come to whole
+// microliters
+pub fn parse_volume_with_unit(text: &str) -> Result<Microliters, String> {
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let scale = VOLUME_UNITS.iter()
+        .find(|(suffix, _)| unit.eq_ignore_ascii_case(suffix))
+        .map(|(_, scale)| *scale)
+        .ok_or_else(|| format!("Unknown volume unit in [{text}], expected one of ul, ml, pu"))?;
+    let value: f64 = number.parse().map_err(|_| format!("Invalid volume [{text}]"))?;
+    let microliters = value * scale;
+    if (microliters - microliters.round()).abs() > 1e-6 {
+        return Err(format!("{text} is not a whole number of microliters"));
+    }
+    Ok(Microliters(microliters.round() as u64))
+}
+
+// Volume field of a command. A bare number is microliters while legacy_unitless_volumes is on and
+// refused otherwise, since it could as well have been meant as ml or pump units.
+pub fn parse_volume(text: &str) -> Result<Microliters, String> {
+    if text.bytes().all(|b| b.is_ascii_digit()) && !text.is_empty() {
+        if !CONFIG.legacy_unitless_volumes {
+            return Err(format!("Volume {text} has no unit, write e.g. {text}ul"));
+        }
+        return text.parse().map(Microliters).map_err(|_| format!("Invalid volume [{text}]"));
+    }
+    parse_volume_with_unit(text)
+}
+
+// Where the commands that take a volume have it: LA_<from>_<to>_<volume>[_R<tube>] and
+// MIXTUBE_<tube>_<volume>_<cycles>
+pub fn volume_field(command: &str) -> Option<&str> {
+    let parts: Vec<&str> = command.split('_').collect();
+    match parts[..] {
+        ["LA", _, _, volume, ..] | ["MIXTUBE", _, volume, _] => Some(volume),
+        _ => None,
+    }
+}
+
+// Every volume of the batch must parse before anything moves
+pub fn check_volumes(commands: &[&str]) -> Result<(), String> {
+    let invalid: Vec<String> = commands.iter()
+        .filter_map(|command| parse_volume(volume_field(command)?).err().map(|e| format!("{command}: {e}")))
+        .collect();
+    if invalid.is_empty() { Ok(()) } else { Err(format!("invalid volume: {}", invalid.join("; "))) }
+}
+
+impl<'de> Deserialize<'de> for Microliters {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct VolumeVisitor;
+
+        impl Visitor<'_> for VolumeVisitor {
+            type Value = Microliters;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                f.write_str("microliters or a volume with a unit, e.g. \"1.5ml\"")
+            }
+
+            fn visit_u64<E: Error>(self, value: u64) -> Result<Microliters, E> {
+                Ok(Microliters(value))
+            }
+
+            fn visit_i64<E: Error>(self, value: i64) -> Result<Microliters, E> {
+                u64::try_from(value).map(Microliters).map_err(|_| E::custom(format!("volume must not be negative, got {value}")))
+            }
+
+            fn visit_str<E: Error>(self, value: &str) -> Result<Microliters, E> {
+                parse_volume_with_unit(value).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(VolumeVisitor)
+    }
+}
+
+// Plunger position or travel in pump increments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PumpUnits(pub u64);
+
+// Router axis position or distance
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Millimeters(pub f64);
+
+impl Microliters {
+    // Fails instead of wrapping or driving the plunger past its end
+    pub fn to_pump_units(self) -> Result<PumpUnits, String> {
+        self.to_pump_units_at(Resolution::Standard)
+    }
+
+    pub fn to_pump_units_at(self, resolution: Resolution) -> Result<PumpUnits, String> {
+        match self.0.checked_mul(UNITS_PER_MICROLITER * resolution.scale()).map(PumpUnits) {
+            Some(units) if units.0 <= FULL_STROKE.0 * resolution.scale() => Ok(units),
+            _ => Err(format!("{} ul exceeds the plunger range of {} ul", self.0, FULL_STROKE.to_microliters().0)),
+        }
+    }
+
+    pub fn saturating_sub(self, other: Microliters) -> Microliters {
+        Microliters(self.0.saturating_sub(other.0))
+    }
+}
+
+impl PumpUnits {
+    pub const ZERO: PumpUnits = PumpUnits(0);
+
+    // Rounds down to whole microliters
+    pub const fn to_microliters(self) -> Microliters {
+        Microliters(self.0 / UNITS_PER_MICROLITER)
+    }
+
+    pub fn abs_diff(self, other: PumpUnits) -> PumpUnits {
+        PumpUnits(self.0.abs_diff(other.0))
+    }
+
+    pub fn saturating_sub(self, other: PumpUnits) -> PumpUnits {
+        PumpUnits(self.0.saturating_sub(other.0))
+    }
+}
+
+impl Add for Microliters {
+    type Output = Microliters;
+
+    fn add(self, other: Microliters) -> Microliters {
+        Microliters(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Microliters {
+    fn add_assign(&mut self, other: Microliters) {
+        self.0 += other.0;
+    }
+}
+
+impl Mul<u64> for Microliters {
+    type Output = Microliters;
+
+    fn mul(self, times: u64) -> Microliters {
+        Microliters(self.0 * times)
+    }
+}
+
+impl Sum for Microliters {
+    fn sum<I: Iterator<Item = Microliters>>(iter: I) -> Microliters {
+        Microliters(iter.map(|v| v.0).sum())
+    }
+}
+
+impl AddAssign for PumpUnits {
+    fn add_assign(&mut self, other: PumpUnits) {
+        self.0 += other.0;
+    }
+}
+
+impl Add for Millimeters {
+    type Output = Millimeters;
+
+    fn add(self, other: Millimeters) -> Millimeters {
+        Millimeters(self.0 + other.0)
+    }
+}
+
+impl Sub for Millimeters {
+    type Output = Millimeters;
+
+    fn sub(self, other: Millimeters) -> Millimeters {
+        Millimeters(self.0 - other.0)
+    }
+}
+
+impl Display for Microliters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Display for PumpUnits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Display for Millimeters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volumes_with_units() {
+        assert_eq!(parse_volume_with_unit("100ul"), Ok(Microliters(100)));
+        assert_eq!(parse_volume_with_unit("100\u{b5}L"), Ok(Microliters(100)));
+        assert_eq!(parse_volume_with_unit("0.25ml"), Ok(Microliters(250)));
+        assert_eq!(parse_volume_with_unit("1.5ML"), Ok(Microliters(1500)));
+        assert_eq!(parse_volume_with_unit(&format!("{}pu", 10 * UNITS_PER_MICROLITER)), Ok(Microliters(10)));
+    }
+
+    #[test]
+    fn refuses_unknown_units_and_fractions() {
+        assert!(parse_volume_with_unit("100").is_err());
+        assert!(parse_volume_with_unit("100nl").is_err());
+        assert!(parse_volume_with_unit("0.5ul").is_err());
+        assert!(parse_volume_with_unit("1pu").is_err());
+        assert!(parse_volume_with_unit("ml").is_err());
+    }
+}
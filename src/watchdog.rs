@@ -0,0 +1,188 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::config::CONFIG;
+use crate::port_operations::{device_path, serial_write};
+
+// Every executor registered in this process, so one stalled instance stops the systemd watchdog
+static EXECUTORS: Mutex<Vec<Arc<Heartbeat>>> = Mutex::new(Vec::new());
+static EXPECTED: AtomicUsize = AtomicUsize::new(1);
+
+struct Heartbeat {
+    instance: String,
+    started: Instant,
+    // Milliseconds after `started` the executor last made progress
+    last: AtomicU64,
+    ready: AtomicBool,
+}
+
+impl Heartbeat {
+    // How long the executor has been stuck, None while it makes progress. Startup counts as
+    // progress: systemd's start timeout covers a controller that never gets ready.
+    fn stalled(&self) -> Option<Duration> {
+        if !self.ready.load(Ordering::SeqCst) {
+            return None;
+        }
+        let age = self.started.elapsed().saturating_sub(Duration::from_millis(self.last.load(Ordering::SeqCst)));
+        (age >= Duration::from_secs(CONFIG.watchdog.stall_secs)).then_some(age)
+    }
+}
+
+// The executor's side of the watchdogs: beat() wherever it is between steps or polling for
+// controls, so a step that hangs stops the petting
+pub struct Watchdog {
+    heartbeat: Arc<Heartbeat>,
+}
+
+impl Watchdog {
+    pub fn register(simulated: bool) -> Watchdog {
+        let heartbeat = Arc::new(Heartbeat {
+            instance: CONFIG.instance_name.clone(),
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+            ready: AtomicBool::new(false),
+        });
+        EXECUTORS.lock().unwrap_or_else(|e| e.into_inner()).push(heartbeat.clone());
+        match &CONFIG.watchdog.port_path {
+            Some(path) if simulated => log::info!("Not petting hardware watchdog {} in simulation", path),
+            Some(path) => spawn_serial_pet(path.clone(), heartbeat.clone()),
+            None => {}
+        }
+        Watchdog { heartbeat }
+    }
+
+    pub fn beat(&self) {
+        self.heartbeat.last.store(self.heartbeat.started.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+
+    // Initialization is done; from now on the executor has to keep beating
+    pub fn ready(&self) {
+        self.beat();
+        self.heartbeat.ready.store(true, Ordering::SeqCst);
+    }
+}
+
+// Starts the systemd watchdog for `instances` executors when the service manager asked for one
+pub fn start(instances: usize) {
+    EXPECTED.store(instances, Ordering::SeqCst);
+    if !CONFIG.watchdog.systemd {
+        return;
+    }
+    match systemd_interval() {
+        Some(interval) => {
+            log::info!("Petting the systemd watchdog every {} ms", interval.as_millis());
+            config::spawn(move || pet_systemd(interval));
+        }
+        None if std::env::var_os("NOTIFY_SOCKET").is_some() => {
+            config::spawn(|| {
+                wait_ready();
+                sd_notify("READY=1");
+            });
+        }
+        None => {}
+    }
+}
+
+// Half of WATCHDOG_USEC, when it is meant for this process
+fn systemd_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid.parse() != Ok(std::process::id())) {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+fn wait_ready() {
+    while !all_ready() {
+        sleep(Duration::from_millis(100));
+    }
+}
+
+fn all_ready() -> bool {
+    let executors = EXECUTORS.lock().unwrap_or_else(|e| e.into_inner());
+    executors.len() >= EXPECTED.load(Ordering::SeqCst) && executors.iter().all(|e| e.ready.load(Ordering::SeqCst))
+}
+
+// First stalled executor and for how long
+fn stalled() -> Option<(String, Duration)> {
+    let executors = EXECUTORS.lock().unwrap_or_else(|e| e.into_inner());
+    executors.iter().find_map(|e| Some((e.instance.clone(), e.stalled()?)))
+}
+
+fn pet_systemd(interval: Duration) {
+    let mut ready = false;
+    let mut stopped = false;
+    loop {
+        if !ready && all_ready() {
+            sd_notify("READY=1");
+            ready = true;
+        }
+        match stalled() {
+            Some((instance, age)) => {
+                if !std::mem::replace(&mut stopped, true) {
+                    log::error!("Executor of {} made no progress for {}s, no longer petting the systemd watchdog", instance, age.as_secs());
+                }
+            }
+            None => {
+                stopped = false;
+                sd_notify("WATCHDOG=1");
+            }
+        }
+        sleep(interval);
+    }
+}
+
+fn spawn_serial_pet(path: String, heartbeat: Arc<Heartbeat>) {
+    let settings = &CONFIG.watchdog;
+    let opened = serialport::new(device_path(&path), settings.baud_rate).timeout(Duration::from_secs(1)).open();
+    let mut port = match opened {
+        Ok(port) => port,
+        Err(e) => {
+            log::error!("Failed to open hardware watchdog {}: {}", path, e);
+            return;
+        }
+    };
+    log::info!("Petting hardware watchdog {} every {} ms", path, settings.interval_ms);
+    config::spawn(move || {
+        let settings = &CONFIG.watchdog;
+        let mut failing = false;
+        loop {
+            if let Some(age) = heartbeat.stalled() {
+                log::error!("Executor made no progress for {}s, no longer petting hardware watchdog {}", age.as_secs(), path);
+                return;
+            }
+            match serial_write(&mut port, &settings.pet) {
+                Ok(()) => failing = false,
+                Err(e) if !std::mem::replace(&mut failing, true) => log::error!("Failed to pet hardware watchdog {}: {}", path, e),
+                Err(_) => {}
+            }
+            sleep(Duration::from_millis(settings.interval_ms));
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+fn sd_notify(state: &str) {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let address = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| socket.send_to_addr(state.as_bytes(), &address?));
+    if let Err(e) = sent {
+        log::error!("Failed to notify systemd of {}: {}", state, e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sd_notify(_: &str) {}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::pump::PumpCommand;
+use crate::units::Millimeters;
+
+// Use of the parts that wear out, kept across restarts in wear_counters_path. Removing an entry
+// from the file after replacing the part starts its count again.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Wear {
+    valve_actuations: u64,
+    router_travel_mm: f64,
+    tips_used: u64,
+    // Aspirations keyed by pump and the valve port drawn through, e.g. "pump1_port4"
+    strokes: BTreeMap<String, u64>,
+}
+
+impl Wear {
+    pub fn load() -> Wear {
+        let Ok(text) = std::fs::read_to_string(&CONFIG.wear_counters_path) else {
+            return Wear::default();
+        };
+        toml::from_str(&text)
+            .map_err(|e| log::error!("Ignoring unreadable wear counters {}: {}", CONFIG.wear_counters_path, e))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        let result = toml::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(&CONFIG.wear_counters_path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::error!("Failed to write wear counters {}: {}", CONFIG.wear_counters_path, e);
+        }
+    }
+
+    // Each method returns the maintenance warnings for limits the new count just reached
+    pub fn record_pump(&mut self, command: &PumpCommand) -> Vec<String> {
+        let (strokes, valve_moves) = command.wear();
+        let prefix = format!("pump{}_", command.address());
+        let pump_strokes = |wear: &Wear| wear.strokes.iter().filter(|(k, _)| k.starts_with(&prefix)).map(|(_, n)| n).sum::<u64>();
+        let strokes_before = pump_strokes(self);
+        for (port, count) in strokes {
+            *self.strokes.entry(format!("{prefix}port{port}")).or_insert(0) += count;
+        }
+        let valve_before = self.valve_actuations;
+        self.valve_actuations += valve_moves;
+        let limits = &CONFIG.wear_limits;
+        [
+            crossed(strokes_before, pump_strokes(self), limits.pump_strokes,
+                    &format!("pump {} syringe seal due for replacement", command.address())),
+            crossed(valve_before, self.valve_actuations, limits.valve_actuations, "valves due for service"),
+        ].into_iter().flatten().collect()
+    }
+
+    pub fn record_travel(&mut self, distance: Millimeters) -> Vec<String> {
+        let before = self.router_travel_mm;
+        self.router_travel_mm += distance.0;
+        let metres = |mm: f64| (mm / 1000.0) as u64;
+        crossed(metres(before), metres(self.router_travel_mm), CONFIG.wear_limits.router_travel_m,
+                "router rails due for lubrication").into_iter().collect()
+    }
+
+    pub fn record_tip(&mut self) -> Vec<String> {
+        self.tips_used += 1;
+        crossed(self.tips_used - 1, self.tips_used, CONFIG.wear_limits.tips_used, "tip stock due for reordering").into_iter().collect()
+    }
+
+    pub fn describe(&self) -> String {
+        let strokes: Vec<String> = self.strokes.iter().map(|(channel, n)| format!("{channel}={n}")).collect();
+        format!("WEAR strokes=[{}] valve_actuations={} router_travel={:.1}m tips_used={}", strokes.join(", "),
+                self.valve_actuations, self.router_travel_mm / 1000.0, self.tips_used)
+    }
+}
+
+// Limits of 0 are off
+fn crossed(before: u64, after: u64, limit: u64, warning: &str) -> Option<String> {
+    if limit == 0 || before >= limit || after < limit {
+        return None;
+    }
+    let warning = format!("MAINTENANCE {warning} (count {after}, limit {limit})");
+    log::warn!("{}", warning);
+    Some(warning)
+}
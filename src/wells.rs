@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::ops::ControlFlow;
+
+use crate::config::CONFIG;
+use crate::deck::Coordinates;
+use crate::pump::PumpCommand;
+use crate::rollback::{self, Held};
+use crate::units::{Microliters, PumpUnits};
+use crate::{aspiration_resolution, clean_needle_for, contamination, deck, detection, drain_command_from, estimation, expiry, microliter_to_pumpunit,
+            motion, plan_cycles, record_aspiration, tips, wash_needle, Controller, LiquidApplication};
+
+// LA_<from>_slot:<well>_<volume> for a flow cell on the slot: the liquid is taken up into pump 1
+// and dispensed through the needle into the well, which is drained first. The other wells keep
+// what is in them until the message is done.
+pub fn apply(controller: &mut Controller, application: &LiquidApplication, well: &str) -> ControlFlow<String> {
+    let position = match controller.calibration.well_position(well) {
+        Ok(position) => position,
+        Err(e) => return ControlFlow::Break(format!("{}: {e}", application.command)),
+    };
+    drain(controller, well)?;
+    expiry::before_use(controller, &application.from)?;
+    let cycles = plan_cycles(controller, application)?;
+    let count = cycles.len();
+    for (i, vol_microliter) in cycles.into_iter().enumerate() {
+        if count > 1 {
+            log::trace!("Transfer cycle {} of {}", i + 1, count);
+        }
+        let resolution = aspiration_resolution(controller, vol_microliter);
+        let vol = microliter_to_pumpunit(vol_microliter, resolution)?;
+        match deck::external_channel(&application.from) {
+            Some(channel) => controller.pump_execute(&PumpCommand::new(1).resolution(resolution).valve_in(channel).move_to(vol))?,
+            None => take_up_from_tube(controller, application, vol_microliter, PumpCommand::new(1).resolution(resolution).valve_in(1).move_to(vol))?,
+        }
+        record_aspiration(controller, application, vol_microliter);
+        rollback::taken_up(controller, &application.from, vol_microliter, Held::Syringe);
+        log::trace!("Dispensing into well {}", well);
+        controller.router_move(position)?;
+        rollback::dispensing(controller);
+        controller.pump_execute(&PumpCommand::new(1).resolution(resolution).valve_out(1).move_to(PumpUnits::ZERO))?;
+        rollback::committed(controller);
+        controller.router_move(Coordinates { z: motion::SAFE_Z, ..position })?;
+        // Even liquid from an external source leaves the needle through its tip
+        if let Some(class) = contamination::reagent_class(&application.from) {
+            controller.needle_residues.push(class.to_string());
+        }
+        record_dispense(controller, application, well, vol_microliter);
+        if CONFIG.constant_cleaning && (CONFIG.wash_between_cycles || i + 1 == count) {
+            wash_needle(controller)?;
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+fn take_up_from_tube(controller: &mut Controller, application: &LiquidApplication, vol: Microliters, aspirate: PumpCommand) -> ControlFlow<String> {
+    let tube = match controller.calibration.tube_position(&application.from) {
+        Ok(tube) => tube,
+        Err(e) => return ControlFlow::Break(e),
+    };
+    clean_needle_for(controller, &application.from, &application.command)?;
+    tips::before_aspiration(controller)?;
+    detection::check_tube_present(controller, &application.from, tube)?;
+    controller.router_move(tube)?;
+    log::trace!("Taking {} ul", vol);
+    controller.pump_execute(&aspirate)?;
+    controller.router_move(Coordinates { z: motion::SAFE_Z, ..tube })
+}
+
+fn record_dispense(controller: &mut Controller, application: &LiquidApplication, well: &str, vol: Microliters) {
+    let source = estimation::tube_label(&application.from);
+    *controller.wells.entry(well.to_string()).or_default() += vol;
+    controller.custody.record(&application.destination, &source, vol, controller.command_id);
+    controller.events.emit("dispense", &[("source", source), ("destination", application.destination.clone()),
+        ("volume_ul", vol.to_string()), ("command_id", controller.command_id.to_string())]);
+}
+
+// Empties one well through its own pump 2 channel
+pub fn drain(controller: &mut Controller, well: &str) -> ControlFlow<String> {
+    let Some(volume) = controller.wells.get(well).copied().filter(|v| *v > Microliters(0)) else {
+        return ControlFlow::Continue(());
+    };
+    let port = CONFIG.flow_cell.wells.get(well).map_or(1, |settings| settings.drain_port);
+    log::trace!("Pumping {} ul out of well {}", volume, well);
+    controller.pump_execute(&drain_command_from(port, volume))?;
+    controller.volumes.discard(volume);
+    controller.wells.remove(well);
+    ControlFlow::Continue(())
+}
+
+pub fn drain_all(controller: &mut Controller) -> ControlFlow<String> {
+    let wells: Vec<String> = controller.wells.keys().cloned().collect();
+    wells.iter().try_for_each(|well| drain(controller, well))
+}
+
+// " wells=[1=100ul, 2=0ul]" for QUERY_SLOT, empty without a flow cell
+pub fn describe(occupancy: &BTreeMap<String, Microliters>) -> String {
+    if CONFIG.flow_cell.wells.is_empty() {
+        return String::new();
+    }
+    let mut wells: Vec<&String> = CONFIG.flow_cell.wells.keys().collect();
+    wells.sort();
+    let levels: Vec<String> = wells.iter().map(|well| format!("{well}={}ul", occupancy.get(*well).copied().unwrap_or_default())).collect();
+    format!(" wells=[{}]", levels.join(", "))
+}